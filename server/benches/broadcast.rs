@@ -0,0 +1,141 @@
+// Broadcast fan-out benchmarks.
+//
+// Builds an AppState with N synthetic clients (MPSC senders whose receivers are kept
+// alive and drained between iterations) and measures:
+//   - broadcast_message: one serialization plus a send to every client
+//   - broadcast_market_and_position_updates: the MarketUpdate broadcast plus a UserSync
+//     for the fraction of clients holding the traded post
+//   - send_post_trade_syncs on a popular post: every client holds the traded post plus
+//     OTHER_POSITIONS other posts, so each UserSync prices several positions
+// Serialization is inside the timed region in both cases; draining the channels is not.
+//
+// Run with: cargo bench --features test-utils --bench broadcast
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use uuid::Uuid;
+use warp::filters::ws::Message;
+
+use server::models::{Client, ServerMessage};
+use server::state::AppState;
+use server::websocket::{broadcast_market_and_position_updates, broadcast_message, send_post_trade_syncs};
+
+type Receiver = UnboundedReceiver<Result<Message, warp::Error>>;
+
+const CLIENT_COUNTS: [usize; 3] = [100, 1_000, 10_000];
+const HOLDER_FRACTION: f64 = 0.1;
+const OTHER_POSITIONS: usize = 5;
+
+// State with `clients` connected users, the first `holders` of which hold `post_id`
+fn setup(clients: usize, holders: usize, post_id: Uuid) -> (AppState, Vec<Receiver>) {
+    let mut state = AppState::new_for_test().with_post(post_id, "creator", 100.0);
+    let mut receivers = Vec::with_capacity(clients);
+    for i in 0..clients {
+        let user_id = format!("user-{}", i);
+        state = state.with_user(&user_id, 1000.0);
+        if i < holders {
+            state = state.with_position(&user_id, post_id, 1.0, 10.0);
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state.clients.insert(Uuid::new_v4(), Client::new(user_id, sender));
+        receivers.push(receiver);
+    }
+    (state, receivers)
+}
+
+fn drain(receivers: &mut [Receiver]) {
+    for receiver in receivers.iter_mut() {
+        while receiver.try_recv().is_ok() {}
+    }
+}
+
+fn bench_broadcast_message(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("broadcast_message");
+    group.sample_size(10);
+
+    for &clients in &CLIENT_COUNTS {
+        let post_id = Uuid::new_v4();
+        let (state, mut receivers) = setup(clients, 0, post_id);
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, _| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iterations {
+                    let message = ServerMessage::MarketUpdate { post_id, price: 11.0, supply: 100.0 };
+                    let start = Instant::now();
+                    runtime.block_on(broadcast_message(message, &state));
+                    elapsed += start.elapsed();
+                    drain(&mut receivers);
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_market_and_position_updates(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("market_and_position_updates");
+    group.sample_size(10);
+
+    for &clients in &CLIENT_COUNTS {
+        let post_id = Uuid::new_v4();
+        let holders = (clients as f64 * HOLDER_FRACTION) as usize;
+        let (state, mut receivers) = setup(clients, holders, post_id);
+        let trading_client_id = Uuid::new_v4(); // Not connected, so every holder is updated
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, _| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iterations {
+                    let start = Instant::now();
+                    runtime.block_on(broadcast_market_and_position_updates(post_id, 11.0, 100.0, trading_client_id, &state));
+                    elapsed += start.elapsed();
+                    drain(&mut receivers);
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_popular_post_syncs(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("popular_post_syncs");
+    group.sample_size(10);
+
+    for &clients in &CLIENT_COUNTS {
+        let post_id = Uuid::new_v4();
+        let (mut state, mut receivers) = setup(clients, clients, post_id);
+        for _ in 0..OTHER_POSITIONS {
+            let other_post_id = Uuid::new_v4();
+            state = state.with_post(other_post_id, "creator", 50.0);
+            for i in 0..clients {
+                state = state.with_position(&format!("user-{}", i), other_post_id, 1.0, 5.0);
+            }
+        }
+        let trading_client_id = Uuid::new_v4();
+        let affected_user_ids = HashSet::new();
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, _| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iterations {
+                    let start = Instant::now();
+                    runtime.block_on(send_post_trade_syncs(post_id, trading_client_id, &affected_user_ids, &state));
+                    elapsed += start.elapsed();
+                    drain(&mut receivers);
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_broadcast_message, bench_market_and_position_updates, bench_popular_post_syncs);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+// Trade engine benchmarks in dry-run mode.
+//
+// Dry run skips broadcasts and webhooks, so this measures the economic engine alone:
+// execute_trade (effective cost across liquidation thresholds, ledger updates) followed
+// by update_liquidation_thresholds, as the market actor runs them. Each iteration buys
+// and then sells the same quantity so supply stays bounded. Every holder has a thin
+// short, giving the post one liquidation threshold per holder.
+//
+// Run with: cargo bench --features test-utils --bench engine
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use server::config::Config;
+use server::handlers::{execute_trade, update_liquidation_thresholds};
+use server::state::AppState;
+
+const HOLDER_COUNTS: [usize; 3] = [10, 100, 1_000];
+const TRADE_QUANTITY: f64 = 1.0;
+
+// Dry-run state with `holders` users short on `post_id` and their thresholds computed
+fn setup(holders: usize, post_id: Uuid, runtime: &Runtime) -> AppState {
+    let config = Config { dry_run: true, ..Config::default() };
+    let mut state = AppState::new_for_test()
+        .with_config(config)
+        .with_user("trader", 1_000_000.0)
+        .with_post(post_id, "creator", -(holders as f64));
+    for i in 0..holders {
+        let user_id = format!("user-{}", i);
+        state = state
+            .with_user(&user_id, 50.0 + i as f64)
+            .with_position(&user_id, post_id, -1.0, -0.5);
+    }
+    runtime.block_on(update_liquidation_thresholds(post_id, &state));
+    state
+}
+
+fn bench_dry_run_trades(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("dry_run_round_trip");
+    group.sample_size(10);
+
+    for &holders in &HOLDER_COUNTS {
+        let post_id = Uuid::new_v4();
+        let state = setup(holders, post_id, &runtime);
+        let client_id = Uuid::new_v4();
+        group.bench_with_input(BenchmarkId::from_parameter(holders), &holders, |b, _| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    for quantity in [TRADE_QUANTITY, -TRADE_QUANTITY] {
+                        execute_trade(client_id, "trader", post_id, quantity, true, &state).await.unwrap();
+                        update_liquidation_thresholds(post_id, &state).await;
+                    }
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_dry_run_trades);
+criterion_main!(benches);
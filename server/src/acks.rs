@@ -0,0 +1,102 @@
+use dashmap::DashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::SendError;
+use uuid::Uuid;
+use warp::filters::ws::Message;
+
+use super::models::{ClientSender, ServerMessage};
+use super::state::AppState;
+
+// --- Acknowledged Delivery ---
+//
+// The forwarder writes to the socket fire-and-forget, so a client can't tell a lost
+// TradeConfirmation or LiquidationEvent from one that was never sent. With
+// Config::ack_timeout_ms set, these critical messages go out with an `ack_id` field and
+// the client answers each with ClientMessage::Ack. A message still unacknowledged after
+// the timeout is sent again, up to Config::ack_max_retries times, then given up on.
+// Resends carry the same `ack_id`, so clients can drop the duplicates.
+
+// Money-affecting messages that need an acknowledgement when acks are enabled
+pub fn is_critical(message: &ServerMessage) -> bool {
+    matches!(message, ServerMessage::TradeConfirmation { .. } | ServerMessage::LiquidationEvent { .. })
+}
+
+// A connection's critical messages awaiting an Ack
+#[derive(Debug, Clone, Default)]
+pub struct PendingAcks {
+    next_id: Arc<AtomicU64>,
+    pending: Arc<DashSet<u64>>,
+}
+
+impl PendingAcks {
+    fn register(&self) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.pending.insert(id);
+        id
+    }
+
+    fn is_pending(&self, id: u64) -> bool {
+        self.pending.contains(&id)
+    }
+
+    // Returns false for an id that isn't pending (unknown, already acked or given up on)
+    pub fn acknowledge(&self, id: u64) -> bool {
+        self.pending.remove(&id).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// Queue `json` (the serialized `message`) for a client. Critical messages are stamped
+// with an ack id and resent until acknowledged when acks are enabled; everything else
+// is queued as is.
+pub fn queue_message(
+    client_id: Uuid,
+    sender: &ClientSender,
+    acks: &PendingAcks,
+    message: &ServerMessage,
+    json: &str,
+    state: &AppState,
+) -> Result<(), SendError<Result<Message, warp::Error>>> {
+    if state.config.ack_timeout_ms == 0 || !is_critical(message) {
+        return sender.send(Ok(Message::text(json)));
+    }
+    let mut stamped: serde_json::Value = serde_json::from_str(json).expect("a serialized ServerMessage is valid JSON");
+    let id = acks.register();
+    stamped["ack_id"] = id.into();
+    let stamped = stamped.to_string();
+    sender.send(Ok(Message::text(stamped.clone()))).inspect_err(|_| {
+        acks.acknowledge(id);
+    })?;
+    spawn_resends(client_id, sender.clone(), acks.clone(), id, stamped, state);
+    Ok(())
+}
+
+// Resend a stamped message every Config::ack_timeout_ms until it's acknowledged, the
+// client goes away or the retries run out
+fn spawn_resends(client_id: Uuid, sender: ClientSender, acks: PendingAcks, id: u64, stamped: String, state: &AppState) {
+    let timeout = Duration::from_millis(state.config.ack_timeout_ms);
+    let max_retries = state.config.ack_max_retries;
+    let state = state.clone();
+    tokio::spawn(async move {
+        for retry in 1..=max_retries {
+            tokio::time::sleep(timeout).await;
+            if !acks.is_pending(id) {
+                return;
+            }
+            if !state.clients.contains_key(&client_id) || sender.send(Ok(Message::text(stamped.clone()))).is_err() {
+                acks.acknowledge(id); // Nobody left to deliver to
+                return;
+            }
+            tracing::info!(%client_id, ack_id = id, retry, "queue_message: resent unacknowledged message");
+        }
+        tokio::time::sleep(timeout).await;
+        if acks.acknowledge(id) {
+            tracing::warn!(%client_id, ack_id = id, max_retries, "queue_message: message never acknowledged, giving up");
+        }
+    });
+}
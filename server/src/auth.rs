@@ -5,33 +5,44 @@ use super::state::AppState;
 use super::models::{Claims, AuthQuery};
 use super::errors::AuthError;
 
-// Function to validate the JWT
-pub fn validate_token(token: &str, secret: &str) -> Result<Claims, String> {
-    let key = DecodingKey::from_secret(secret.as_ref());
+// Function to validate the JWT against each accepted secret in turn (primary first),
+// so tokens signed before a secret rotation keep working. The `aud` claim must be one
+// of `audiences`; an empty list skips the audience check.
+pub fn validate_token(token: &str, secrets: &[String], audiences: &[String]) -> Result<Claims, String> {
     let mut validation = Validation::new(Algorithm::HS256);
     validation.validate_exp = true; // Check expiration
-    validation.set_audience(&["authenticated"]); // Verify audience
+    if audiences.is_empty() {
+        validation.validate_aud = false;
+    } else {
+        validation.set_audience(audiences); // Verify audience
+    }
 
-    decode::<Claims>(token, &key, &validation)
-        .map(|data| data.claims)
-        .map_err(|err| format!("JWT validation failed: {}", err))
+    let mut last_error = String::from("no JWT secrets configured");
+    for secret in secrets {
+        let key = DecodingKey::from_secret(secret.as_ref());
+        match decode::<Claims>(token, &key, &validation) {
+            Ok(data) => return Ok(data.claims),
+            Err(err) => last_error = err.to_string(),
+        }
+    }
+    Err(format!("JWT validation failed: {}", last_error))
 }
 
-// Warp filter to extract token, validate it, and pass user_id
+// Warp filter to extract token, validate it, and pass the claims (user_id in `sub`)
 pub fn with_auth(
     state: AppState,
-) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
     warp::query::<AuthQuery>()
         .and(warp::any().map(move || state.clone()))
         .and_then(|query: AuthQuery, current_state: AppState| async move {
-            match validate_token(&query.token, &current_state.jwt_secret) {
+            match validate_token(&query.token, &current_state.jwt_secrets, &current_state.config.jwt_audiences) {
                 Ok(claims) => {
                      if claims.sub.is_empty() {
                          eprintln!("JWT validation error: Missing or empty 'sub' claim.");
                          Err(warp::reject::custom(AuthError::InvalidToken))
                      } else {
                         println!("JWT validated for user: {}", claims.sub);
-                        Ok(claims.sub)
+                        Ok(claims)
                      }
                 }
                 Err(e) => {
@@ -40,4 +51,62 @@ pub fn with_auth(
                 }
             }
         })
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn token_for_audience(secret: &str, aud: &str) -> String {
+        let claims = Claims { sub: "alice".to_string(), aud: aud.to_string(), exp: 4_000_000_000 };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref())).unwrap()
+    }
+
+    fn token_signed_with(secret: &str) -> String {
+        token_for_audience(secret, "authenticated")
+    }
+
+    fn rotating_secrets() -> Vec<String> {
+        vec!["new-secret".to_string(), "old-secret".to_string()]
+    }
+
+    fn audiences(accepted: &[&str]) -> Vec<String> {
+        accepted.iter().map(|aud| aud.to_string()).collect()
+    }
+
+    #[test]
+    fn token_signed_with_primary_or_previous_secret_validates() {
+        for secret in ["new-secret", "old-secret"] {
+            let claims = validate_token(&token_signed_with(secret), &rotating_secrets(), &audiences(&["authenticated"]))
+                .unwrap_or_else(|e| panic!("{} should validate: {}", secret, e));
+            assert_eq!(claims.sub, "alice");
+        }
+    }
+
+    #[test]
+    fn token_signed_with_unlisted_secret_is_rejected() {
+        assert!(validate_token(&token_signed_with("retired-secret"), &rotating_secrets(), &audiences(&["authenticated"])).is_err());
+    }
+
+    #[test]
+    fn token_matching_any_configured_audience_validates() {
+        let accepted = audiences(&["authenticated", "partner-app"]);
+        for aud in ["authenticated", "partner-app"] {
+            assert!(validate_token(&token_for_audience("new-secret", aud), &rotating_secrets(), &accepted).is_ok(), "{} should validate", aud);
+        }
+    }
+
+    #[test]
+    fn token_with_other_audience_is_rejected() {
+        let token = token_for_audience("new-secret", "someone-else");
+        assert!(validate_token(&token, &rotating_secrets(), &audiences(&["authenticated"])).is_err());
+    }
+
+    #[test]
+    fn empty_audience_list_skips_audience_validation() {
+        let token = token_for_audience("new-secret", "someone-else");
+        let claims = validate_token(&token, &rotating_secrets(), &[]).expect("audience not checked");
+        assert_eq!(claims.aud, "someone-else");
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use super::state::AppState;
+
+// --- Client Backlog Sampler ---
+//
+// Client channels are unbounded, so a slow or stalled client just accumulates messages.
+// This task periodically reads every client's queue depth (see QueueDepth) into the
+// /metrics gauges and warns about clients over the threshold, to spot them before
+// moving to bounded channels.
+
+// Start the periodic sampler if an interval is configured
+pub fn spawn_backlog_sampler(state: AppState) {
+    let interval_secs = state.config.backlog_sample_interval_secs;
+    if interval_secs == 0 {
+        println!("Client backlog sampler disabled.");
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            sample_once(&state);
+        }
+    });
+}
+
+// One pass over the connected clients; updates the gauges and returns the deepest backlog
+pub fn sample_once(state: &AppState) -> usize {
+    let threshold = state.config.client_backlog_warn_threshold;
+    let (mut max, mut total, mut backlogged) = (0, 0, 0);
+    for entry in state.clients.iter() {
+        let depth = entry.value().sender.depth().get();
+        max = max.max(depth);
+        total += depth;
+        if depth >= threshold {
+            backlogged += 1;
+            eprintln!("Warning: client {} (user {}) has {} queued messages", entry.key(), entry.value().user_id, depth);
+        }
+    }
+    state.metrics.max_client_backlog.store(max, Ordering::Relaxed);
+    state.metrics.total_client_backlog.store(total, Ordering::Relaxed);
+    state.metrics.backlogged_clients.store(backlogged, Ordering::Relaxed);
+    max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::{Client, ServerMessage};
+    use crate::websocket::send_to_client;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn backlog_rises_for_an_undrained_client() {
+        let state = AppState::new_for_test().with_config(Config { client_backlog_warn_threshold: 5, ..Config::default() });
+        let (slow, mut slow_receiver) = mpsc::unbounded_channel();
+        let (fast, mut fast_receiver) = mpsc::unbounded_channel();
+        let (slow_id, fast_id) = (Uuid::new_v4(), Uuid::new_v4());
+        state.clients.insert(slow_id, Client::new("alice", slow));
+        state.clients.insert(fast_id, Client::new("bob", fast));
+
+        for _ in 0..3 {
+            send_to_client(slow_id, ServerMessage::error("tick"), &state).await;
+        }
+        assert_eq!(sample_once(&state), 3);
+
+        for _ in 0..4 {
+            send_to_client(slow_id, ServerMessage::error("tick"), &state).await;
+            send_to_client(fast_id, ServerMessage::error("tick"), &state).await;
+        }
+        // Drain the fast client the way its forwarder does, counting each message back down
+        let fast_depth = state.clients.get(&fast_id).unwrap().sender.depth();
+        while fast_receiver.try_recv().is_ok() {
+            fast_depth.dequeued();
+        }
+
+        assert_eq!(sample_once(&state), 7);
+        assert_eq!(state.metrics.total_client_backlog.load(Ordering::Relaxed), 7);
+        assert_eq!(state.metrics.backlogged_clients.load(Ordering::Relaxed), 1);
+        assert!(state.metrics.render().contains("flvke_client_backlog_max 7"));
+        assert!(slow_receiver.try_recv().is_ok());
+    }
+}
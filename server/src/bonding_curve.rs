@@ -1,12 +1,33 @@
-use super::constants::BONDING_CURVE_EPSILON;
-
 // --- Bonding Curve Logic ---
+//
+// `epsilon` is the width of the band around s = 0 treated as exactly zero
+// (Config::bonding_curve_epsilon, default BONDING_CURVE_EPSILON). It picks which
+// branch of the curve applies, so changing it moves where the s > 0 and s < 0 formulas
+// take over: larger values flatten prices and costs for small supplies near zero, and
+// liquidation thresholds or trades in that band are priced as if supply were zero.
+//
+// `flat_width` (Post::flat_width, default 0) adds a flat zone: for |s| <= flat_width the
+// price stays at the base price 1, and beyond it the usual curve takes over, shifted out
+// by flat_width so the price is continuous. Costs inside the zone are linear (1 per share).
+
+// Distance of `supply` past the flat zone, signed; 0 inside it. The sqrt curve is
+// evaluated at this shifted supply.
+fn beyond_flat_zone(supply: f64, flat_width: f64) -> f64 {
+    if supply > flat_width {
+        supply - flat_width
+    } else if supply < -flat_width {
+        supply + flat_width
+    } else {
+        0.0
+    }
+}
 
 // Price function P(s)
-pub fn get_price(supply: f64) -> f64 {
-    if supply > BONDING_CURVE_EPSILON { // s > 0
+pub fn get_price(supply: f64, flat_width: f64, epsilon: f64) -> f64 {
+    let supply = beyond_flat_zone(supply, flat_width);
+    if supply > epsilon { // s > 0
         1.0 + supply.sqrt()
-    } else if supply < -BONDING_CURVE_EPSILON { // s < 0
+    } else if supply < -epsilon { // s < 0
         let t = supply.abs();
         1.0 / (1.0 + t.sqrt())
     } else { // s == 0
@@ -14,10 +35,27 @@ pub fn get_price(supply: f64) -> f64 {
     }
 }
 
+// Inverse of P(s): the supply at which the curve quotes `price`. Prices >= 1 sit on the
+// s >= 0 branch, prices in (0, 1) on the s < 0 branch; None for prices the curve never
+// reaches (non-positive or not finite).
+//
+// With a flat zone the base price 1 is quoted by every supply in [-flat_width, flat_width].
+// This returns the upper edge, flat_width: the supply where a falling price first reaches
+// 1. Callers after the lower edge (a rising price, e.g. a short's liquidation) negate it.
+pub fn supply_at_price(price: f64, flat_width: f64) -> Option<f64> {
+    if !price.is_finite() || price <= 0.0 {
+        None
+    } else if price >= 1.0 {
+        Some(flat_width + (price - 1.0).powi(2)) // 1 + sqrt(s - w) = p
+    } else {
+        Some(-flat_width - (1.0 / price - 1.0).powi(2)) // 1 / (1 + sqrt(t)) = p, s = -(w + t)
+    }
+}
+
 // Integral of P(s) from 0 to s, for s > 0
 // Int(1 + sqrt(x) dx) = x + (2/3)x^(3/2)
-fn integral_pos(s: f64) -> f64 {
-    if s <= BONDING_CURVE_EPSILON { // Treat s<=0 as 0
+fn integral_pos(s: f64, epsilon: f64) -> f64 {
+    if s <= epsilon { // Treat s<=0 as 0
         0.0
     } else {
         s + (2.0 / 3.0) * s.powf(1.5)
@@ -26,8 +64,8 @@ fn integral_pos(s: f64) -> f64 {
 
 // Integral of P(s) from s to 0, for s < 0. Result is >= 0.
 // See original code for derivation
-fn integral_neg_to_zero(s: f64) -> f64 {
-    if s >= -BONDING_CURVE_EPSILON { // Treat s>=0 as 0
+fn integral_neg_to_zero(s: f64, epsilon: f64) -> f64 {
+    if s >= -epsilon { // Treat s>=0 as 0
         0.0
     } else {
         let t = s.abs(); // t = |s|
@@ -35,28 +73,26 @@ fn integral_neg_to_zero(s: f64) -> f64 {
     }
 }
 
+// Integral of P(s) from 0 to s, any sign: the linear part inside the flat zone plus the
+// shifted curve beyond it
+fn integral_from_zero(s: f64, flat_width: f64, epsilon: f64) -> f64 {
+    let shifted = beyond_flat_zone(s, flat_width);
+    let curve = if shifted > epsilon {
+        integral_pos(shifted, epsilon)
+    } else if shifted < -epsilon {
+        -integral_neg_to_zero(shifted, epsilon)
+    } else {
+        0.0
+    };
+    (s - shifted) + curve // s - shifted is the part of [0, s] inside the flat zone
+}
+
 // Calculate the base cost (definite integral) using the smooth curve P(s)
 // from supply s1 to s2.
-pub fn calculate_smooth_cost(s1: f64, s2: f64) -> f64 {
+pub fn calculate_smooth_cost(s1: f64, s2: f64, flat_width: f64, epsilon: f64) -> f64 {
     if s1.is_nan() || s1.is_infinite() || s2.is_nan() || s2.is_infinite() {
         return f64::NAN;
     }
 
-    let integral_at_s2 = if s2 > BONDING_CURVE_EPSILON {
-        integral_pos(s2)
-    } else if s2 < -BONDING_CURVE_EPSILON {
-        -integral_neg_to_zero(s2)
-    } else {
-        0.0
-    };
-
-    let integral_at_s1 = if s1 > BONDING_CURVE_EPSILON {
-        integral_pos(s1)
-    } else if s1 < -BONDING_CURVE_EPSILON {
-        -integral_neg_to_zero(s1)
-    } else {
-        0.0
-    };
-
-    integral_at_s2 - integral_at_s1
+    integral_from_zero(s2, flat_width, epsilon) - integral_from_zero(s1, flat_width, epsilon)
 } 
\ No newline at end of file
@@ -0,0 +1,693 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use super::constants::{EPSILON, BONDING_CURVE_EPSILON, DEFAULT_JWT_AUDIENCE};
+
+// --- Runtime Configuration ---
+
+// Who receives a post's MarketUpdate after a trade. Users whose accounts the trade
+// changed still get their UserSync under every strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BroadcastStrategy {
+    #[default]
+    All, // Every connected client
+    HoldersOnly, // Clients of users with an open position in the post
+    Subscribers, // Clients that sent a Subscribe for the post
+}
+
+// What happens to a quantity with more decimal places than Config::quantity_decimals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrecisionPolicy {
+    #[default]
+    Reject, // Refuse the request with an InvalidField error
+    Snap, // Round to the allowed precision and carry on
+}
+
+// What happens to a trade that would cross more than Config::max_liquidations_per_trade
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LiquidationCapPolicy {
+    #[default]
+    Truncate, // Fill up to the last threshold within the cap and reject the rest of the quantity
+    Reject, // Refuse the whole trade
+}
+
+impl FromStr for LiquidationCapPolicy {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.to_ascii_lowercase().as_str() {
+            "truncate" => Ok(LiquidationCapPolicy::Truncate),
+            "reject" => Ok(LiquidationCapPolicy::Reject),
+            other => Err(format!("unknown liquidation cap policy '{}'", other)),
+        }
+    }
+}
+
+impl FromStr for PrecisionPolicy {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.to_ascii_lowercase().as_str() {
+            "reject" => Ok(PrecisionPolicy::Reject),
+            "snap" => Ok(PrecisionPolicy::Snap),
+            other => Err(format!("unknown precision policy '{}'", other)),
+        }
+    }
+}
+
+impl FromStr for BroadcastStrategy {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.to_ascii_lowercase().as_str() {
+            "all" => Ok(BroadcastStrategy::All),
+            "holders_only" => Ok(BroadcastStrategy::HoldersOnly),
+            "subscribers" => Ok(BroadcastStrategy::Subscribers),
+            other => Err(format!("unknown broadcast strategy '{}'", other)),
+        }
+    }
+}
+
+// Trading fee rates by the trader's cumulative traded quantity. Parsed from
+// `min_volume:rate` pairs, e.g. "0:0.003,10000:0.002,100000:0.001": a trader pays the
+// rate of the highest tier their volume has reached, and nothing below the first tier.
+// Rates may not rise with volume.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    tiers: Vec<(f64, f64)>, // (min_volume, rate), ascending by min_volume
+}
+
+impl FeeSchedule {
+    pub fn new(mut tiers: Vec<(f64, f64)>) -> Self {
+        tiers.sort_by(|a, b| a.0.total_cmp(&b.0));
+        FeeSchedule { tiers }
+    }
+
+    // Lowest rate any trader can pay: the cheapest tier's, or 0 when a trader with no
+    // volume is below the first tier (or there are no tiers)
+    pub fn min_rate(&self) -> f64 {
+        match self.tiers.first() {
+            Some((min_volume, _)) if *min_volume <= 0.0 => self.tiers.iter().map(|(_, rate)| *rate).fold(f64::INFINITY, f64::min),
+            _ => 0.0,
+        }
+    }
+
+    // Fee rate for a trader who has traded `volume` so far
+    pub fn rate_for(&self, volume: f64) -> f64 {
+        self.tiers.iter().rev().find(|(min_volume, _)| volume >= *min_volume).map_or(0.0, |(_, rate)| *rate)
+    }
+}
+
+impl FromStr for FeeSchedule {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let tiers = raw.split(',')
+            .map(str::trim)
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| {
+                let (min_volume, rate) = tier.split_once(':').ok_or_else(|| format!("fee tier '{}' is not min_volume:rate", tier))?;
+                let min_volume: f64 = min_volume.trim().parse().map_err(|_| format!("bad min_volume in fee tier '{}'", tier))?;
+                let rate: f64 = rate.trim().parse().map_err(|_| format!("bad rate in fee tier '{}'", tier))?;
+                if !(0.0..1.0).contains(&rate) || min_volume.is_nan() {
+                    return Err(format!("fee tier '{}' is out of range", tier));
+                }
+                Ok((min_volume, rate))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let schedule = FeeSchedule::new(tiers);
+        // Volume discounts only: trading more must never cost a higher rate
+        if let Some(pair) = schedule.tiers.windows(2).find(|pair| pair[1].1 > pair[0].1) {
+            return Err(format!("fee tier {}:{} charges more than the tier below it ({}:{})", pair[1].0, pair[1].1, pair[0].0, pair[0].1));
+        }
+        Ok(schedule)
+    }
+}
+
+// Tunables read once at startup. Every field but jwt_secrets has a default; from_env
+// refuses to build a config without them (see RequiredEnv).
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Float tolerance for dust positions, quantity validation and detecting that a
+    // trade landed exactly on a liquidation threshold
+    pub epsilon: f64,
+    // Band around s = 0 treated as zero supply by the bonding curve. Changing this
+    // alters prices and costs near zero (see bonding_curve.rs); keep it tiny.
+    pub bonding_curve_epsilon: f64,
+    // Fraction of the unwound notional charged to a liquidated user and
+    // credited to the post's insurance fund
+    pub liquidation_penalty_rate: f64,
+    // Fee rate on forced unwinds, charged to the liquidated user on the unwound notional
+    // (split like any fee, see creator_fee_share). None (the default) charges no fee on
+    // them but leaves the unwinds crossed by a trade in the trader's fee base; once set,
+    // a trader's fee covers only their own quantity. 0 waives the fee on both sides while
+    // keeping the penalty.
+    pub liquidation_fee_rate: Option<f64>,
+    // Most threshold liquidations one trade may trigger, to bound a cascade; 0 is
+    // unlimited. A threshold's users are unwound together, so the trade stops before the
+    // first threshold that would take it over the cap, per liquidation_cap_policy. Margin
+    // liquidations are exempt, since a forced close is never refused.
+    pub max_liquidations_per_trade: usize,
+    pub liquidation_cap_policy: LiquidationCapPolicy,
+    // Slippage allowed on a buy or sell that sets no max_cost / min_proceeds, as a fraction
+    // of the smooth-curve quote (0.05 = 5%); a fill crossing enough thresholds to exceed it
+    // is rejected. None leaves such trades unlimited.
+    pub default_slippage_tolerance: Option<f64>,
+    // Margin ratio (margin / exposure) below which the margin sweep liquidates a user
+    pub maintenance_margin_ratio: f64,
+    // Seconds between margin sweeps; 0 disables the sweep
+    pub margin_sweep_interval_secs: u64,
+    // Endpoint POSTed on every liquidation; webhooks are off when unset
+    pub webhook_url: Option<String>,
+    // Shared secret for the HMAC-SHA256 signature header on webhook requests
+    pub webhook_secret: Option<String>,
+    // Accepted JWT signing secrets, primary first, then the previous secret during a
+    // rotation. Empty by default; AppState::new requires at least one.
+    pub jwt_secrets: Vec<String>,
+    // Accepted JWT `aud` values (a token must match one). Empty disables audience
+    // validation, for issuers that don't set it.
+    pub jwt_audiences: Vec<String>,
+    // Fan-out policy for MarketUpdates
+    pub broadcast_strategy: BroadcastStrategy,
+    // Trades still execute in memory, but nothing leaves the process: no webhooks and,
+    // unless dry_run_broadcasts is set, no post-trade broadcasts or UserSyncs. The trader
+    // still gets their TradeConfirmation. For load testing the trade engine.
+    pub dry_run: bool,
+    pub dry_run_broadcasts: bool,
+    // Reject a post whose trimmed content matches one of the creator's existing posts
+    pub unique_post_content: bool,
+    // Terms a new post may not contain, matched case-insensitively anywhere in its content
+    pub moderation_blocklist: Vec<String>,
+    // Milliseconds the external content check (AppState::content_check) may take before
+    // the post is rejected
+    pub moderation_timeout_ms: u64,
+    // Only users with a profile may connect, post or trade; otherwise any valid token
+    // creates an account on first use. Leave off for development.
+    pub strict_users: bool,
+    // Profiles registered at startup (state.user_profiles)
+    pub known_users: Vec<String>,
+    // Users allowed to send operator requests (e.g. GetLiquidationLadder)
+    pub admin_users: Vec<String>,
+    // Seconds between sweeps removing liquidation thresholds of dead posts; 0 disables
+    pub threshold_gc_interval_secs: u64,
+    // Only register liquidation thresholds within this supply distance of a post's current
+    // supply; 0 registers all. A trade that would leave the registered range is priced
+    // against the full ladder instead (see handlers::price_trade).
+    pub threshold_grace_distance: f64,
+    // A threshold recompute taking at least this many milliseconds is logged at warn with
+    // the post's holder count; 0 warns on every recompute
+    pub slow_threshold_recompute_ms: u64,
+    // Realized-PnL bookings kept per user for GetPnlHistory (oldest dropped first); 0 disables
+    pub pnl_history_cap: usize,
+    // Executed trades kept per user for GetTradeHistory (oldest dropped first); 0 disables
+    pub trade_history_cap: usize,
+    // Milliseconds a single WebSocket send may take before the socket is treated as
+    // wedged and the client dropped; 0 waits forever
+    pub ws_send_timeout_ms: u64,
+    // Messages a WebSocket connection may send per second; a client sending more is
+    // disconnected with a policy-violation close frame. 0 (the default) doesn't limit.
+    pub max_messages_per_sec: u32,
+    // Milliseconds to wait for a client's Ack of a critical message (TradeConfirmation,
+    // LiquidationEvent) before resending it, at most ack_max_retries times; 0 disables
+    // acknowledgements and critical messages are sent once, without an ack_id
+    pub ack_timeout_ms: u64,
+    pub ack_max_retries: u32,
+    // Seconds between failing readiness on shutdown and closing client connections, for
+    // load balancers to stop routing to the instance; 0 closes them right away
+    pub shutdown_drain_secs: u64,
+    // Milliseconds a trade arriving during warmup is held, waiting for trading to open,
+    // before it is refused with WarmingUp; 0 refuses it at once. At most warmup_hold_cap
+    // trades are held at a time; any beyond that are refused at once.
+    pub warmup_hold_ms: u64,
+    pub warmup_hold_cap: usize,
+    // Record every segment and liquidation jump of a trade's supply path and log it
+    // with the fill, for reconciling disputed fills. Off by default (no allocation).
+    pub trace_trade_paths: bool,
+    // Unsettled posts a single user may have at once; 0 means unlimited
+    pub max_posts_per_user: usize,
+    // How long a Buy/Sell idempotency key is remembered, and how many are kept at most
+    pub idempotency_key_ttl_secs: u64,
+    pub idempotency_key_cap: usize,
+    // Seconds a user must wait between creating posts; 0 disables the cooldown
+    pub post_cooldown_secs: u64,
+    // Most positions listed in a UserSync (has_more_positions flags the rest, which
+    // GetPositions pages through); 0 lists them all
+    pub user_sync_position_cap: usize,
+    // Seconds between samples of every client's outbound queue depth; 0 disables
+    pub backlog_sample_interval_secs: u64,
+    // Queue depth at which a sample logs a warning about the client
+    pub client_backlog_warn_threshold: usize,
+    // When false the market is spot-only: sells are capped at the seller's long position
+    // and supply never goes below zero
+    pub allow_shorts: bool,
+    // Decimal places allowed in trade quantities and deposit/withdrawal amounts; None
+    // (the default) allows any. Over-precise values are handled per quantity_precision.
+    pub quantity_decimals: Option<u32>,
+    pub quantity_precision: PrecisionPolicy,
+    // Reject trades by a post's creator on their own post
+    pub forbid_self_trade: bool,
+    // Highest supply a buy may take a post to (a post's own max_supply overrides it);
+    // 0 means uncapped. Forced unwinds may still cross it.
+    pub max_supply: f64,
+    // Fee on voluntary trades, a rate of the trade's absolute cost picked by the trader's
+    // volume. Empty (the default) charges no fee.
+    pub fee_tiers: FeeSchedule,
+    // Rebate, as a rate of the trade's absolute cost, paid instead of the fee on a trade
+    // that moves the post's supply toward zero (taking the other side of its skew).
+    // Funded by the protocol. None (the default) charges such trades the normal fee. Capped
+    // at the lowest fee rate, so a round trip never pays.
+    pub maker_rebate_rate: Option<f64>,
+    // How each fee is split: these shares go to the post's creator and the protocol, and
+    // the rest to the post's insurance fund. Both 0 by default (all to insurance).
+    pub creator_fee_share: f64,
+    pub protocol_fee_share: f64,
+    // Collateral a trade that spends cash may not dip into: the larger of the absolute
+    // reserve and the rate times the user's balance. Closing trades are never held back.
+    pub min_collateral_reserve: f64,
+    pub collateral_reserve_rate: f64,
+    // After every fill, check the post's supply still equals its holders' net position
+    // and log an error if not (panic in tests). O(users) per trade; debugging aid only.
+    pub check_supply_invariant: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            epsilon: EPSILON,
+            bonding_curve_epsilon: BONDING_CURVE_EPSILON,
+            liquidation_penalty_rate: 0.0,
+            liquidation_fee_rate: None,
+            max_liquidations_per_trade: 0,
+            liquidation_cap_policy: LiquidationCapPolicy::default(),
+            default_slippage_tolerance: None,
+            maintenance_margin_ratio: 0.0,
+            margin_sweep_interval_secs: 0,
+            webhook_url: None,
+            webhook_secret: None,
+            jwt_secrets: Vec::new(),
+            jwt_audiences: vec![DEFAULT_JWT_AUDIENCE.to_string()],
+            broadcast_strategy: BroadcastStrategy::default(),
+            dry_run: false,
+            dry_run_broadcasts: false,
+            unique_post_content: false,
+            moderation_blocklist: Vec::new(),
+            moderation_timeout_ms: 2_000,
+            strict_users: false,
+            known_users: Vec::new(),
+            admin_users: Vec::new(),
+            threshold_gc_interval_secs: 0,
+            threshold_grace_distance: 0.0,
+            slow_threshold_recompute_ms: 50,
+            pnl_history_cap: 1000,
+            trade_history_cap: 1000,
+            ws_send_timeout_ms: 10_000,
+            max_messages_per_sec: 0,
+            ack_timeout_ms: 0,
+            ack_max_retries: 3,
+            shutdown_drain_secs: 0,
+            warmup_hold_ms: 0,
+            warmup_hold_cap: 256,
+            trace_trade_paths: false,
+            max_posts_per_user: 0,
+            post_cooldown_secs: 0,
+            idempotency_key_ttl_secs: 600,
+            idempotency_key_cap: 100_000,
+            user_sync_position_cap: 0,
+            backlog_sample_interval_secs: 10,
+            client_backlog_warn_threshold: 1000,
+            allow_shorts: true,
+            forbid_self_trade: false,
+            quantity_decimals: None,
+            quantity_precision: PrecisionPolicy::default(),
+            max_supply: 0.0,
+            fee_tiers: FeeSchedule::default(),
+            maker_rebate_rate: None,
+            creator_fee_share: 0.0,
+            protocol_fee_share: 0.0,
+            min_collateral_reserve: 0.0,
+            collateral_reserve_rate: 0.0,
+            check_supply_invariant: false,
+        }
+    }
+}
+
+impl Config {
+    // Build the config from environment variables, falling back to defaults for all but
+    // the required ones (see RequiredEnv), which are reported together when missing
+    pub fn from_env() -> Result<Self, MissingEnvVars> {
+        let required = RequiredEnv::from_env()?;
+        for warning in &required.deprecation_warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        let defaults = Config::default();
+        let mut config = Config {
+            epsilon: env_or("EPSILON", defaults.epsilon),
+            bonding_curve_epsilon: env_or("BONDING_CURVE_EPSILON", defaults.bonding_curve_epsilon),
+            liquidation_penalty_rate: env_or("LIQUIDATION_PENALTY_RATE", defaults.liquidation_penalty_rate),
+            liquidation_fee_rate: env_opt("LIQUIDATION_FEE_RATE").and_then(|raw| raw.parse().map_err(|_| {
+                eprintln!("Warning: Could not parse LIQUIDATION_FEE_RATE='{}', charging no liquidation fee.", raw);
+            }).ok()),
+            max_liquidations_per_trade: env_or("MAX_LIQUIDATIONS_PER_TRADE", defaults.max_liquidations_per_trade),
+            liquidation_cap_policy: env_or("LIQUIDATION_CAP_POLICY", defaults.liquidation_cap_policy),
+            default_slippage_tolerance: env_opt("DEFAULT_SLIPPAGE_TOLERANCE").and_then(|raw| raw.parse().map_err(|_| {
+                eprintln!("Warning: Could not parse DEFAULT_SLIPPAGE_TOLERANCE='{}', leaving trades without a limit unlimited.", raw);
+            }).ok()),
+            maintenance_margin_ratio: env_or("MAINTENANCE_MARGIN_RATIO", defaults.maintenance_margin_ratio),
+            margin_sweep_interval_secs: env_or("MARGIN_SWEEP_INTERVAL_SECS", defaults.margin_sweep_interval_secs),
+            webhook_url: env_opt("WEBHOOK_URL"),
+            webhook_secret: env_opt("WEBHOOK_SECRET"),
+            jwt_secrets: required.jwt_secrets,
+            jwt_audiences: env_list("JWT_AUDIENCE").unwrap_or(defaults.jwt_audiences),
+            broadcast_strategy: env_or("BROADCAST_STRATEGY", defaults.broadcast_strategy),
+            dry_run: env_or("DRY_RUN", defaults.dry_run),
+            dry_run_broadcasts: env_or("DRY_RUN_BROADCASTS", defaults.dry_run_broadcasts),
+            moderation_blocklist: env_list("MODERATION_BLOCKLIST").unwrap_or(defaults.moderation_blocklist),
+            moderation_timeout_ms: env_or("MODERATION_TIMEOUT_MS", defaults.moderation_timeout_ms),
+            unique_post_content: env_or("UNIQUE_POST_CONTENT", defaults.unique_post_content),
+            strict_users: env_or("STRICT_USERS", defaults.strict_users),
+            known_users: env_list("KNOWN_USERS").unwrap_or(defaults.known_users),
+            admin_users: env_list("ADMIN_USERS").unwrap_or(defaults.admin_users),
+            threshold_gc_interval_secs: env_or("THRESHOLD_GC_INTERVAL_SECS", defaults.threshold_gc_interval_secs),
+            threshold_grace_distance: env_or("THRESHOLD_GRACE_DISTANCE", defaults.threshold_grace_distance),
+            slow_threshold_recompute_ms: env_or("SLOW_THRESHOLD_RECOMPUTE_MS", defaults.slow_threshold_recompute_ms),
+            pnl_history_cap: env_or("PNL_HISTORY_CAP", defaults.pnl_history_cap),
+            trade_history_cap: env_or("TRADE_HISTORY_CAP", defaults.trade_history_cap),
+            ws_send_timeout_ms: env_or("WS_SEND_TIMEOUT_MS", defaults.ws_send_timeout_ms),
+            max_messages_per_sec: env_or("MAX_MESSAGES_PER_SEC", defaults.max_messages_per_sec),
+            ack_timeout_ms: env_or("ACK_TIMEOUT_MS", defaults.ack_timeout_ms),
+            ack_max_retries: env_or("ACK_MAX_RETRIES", defaults.ack_max_retries),
+            shutdown_drain_secs: env_or("SHUTDOWN_DRAIN_SECS", defaults.shutdown_drain_secs),
+            warmup_hold_ms: env_or("WARMUP_HOLD_MS", defaults.warmup_hold_ms),
+            warmup_hold_cap: env_or("WARMUP_HOLD_CAP", defaults.warmup_hold_cap),
+            trace_trade_paths: env_or("TRACE_TRADE_PATHS", defaults.trace_trade_paths),
+            max_posts_per_user: env_or("MAX_POSTS_PER_USER", defaults.max_posts_per_user),
+            post_cooldown_secs: env_or("POST_COOLDOWN_SECS", defaults.post_cooldown_secs),
+            idempotency_key_ttl_secs: env_or("IDEMPOTENCY_KEY_TTL_SECS", defaults.idempotency_key_ttl_secs),
+            idempotency_key_cap: env_or("IDEMPOTENCY_KEY_CAP", defaults.idempotency_key_cap),
+            user_sync_position_cap: env_or("USER_SYNC_POSITION_CAP", defaults.user_sync_position_cap),
+            backlog_sample_interval_secs: env_or("BACKLOG_SAMPLE_INTERVAL_SECS", defaults.backlog_sample_interval_secs),
+            client_backlog_warn_threshold: env_or("CLIENT_BACKLOG_WARN_THRESHOLD", defaults.client_backlog_warn_threshold),
+            allow_shorts: env_or("ALLOW_SHORTS", defaults.allow_shorts),
+            forbid_self_trade: env_or("FORBID_SELF_TRADE", defaults.forbid_self_trade),
+            quantity_decimals: env_opt("QUANTITY_DECIMALS").and_then(|raw| raw.parse().map_err(|_| {
+                eprintln!("Warning: Could not parse QUANTITY_DECIMALS='{}', allowing any precision.", raw);
+            }).ok()),
+            quantity_precision: env_or("QUANTITY_PRECISION", defaults.quantity_precision),
+            max_supply: env_or("MAX_SUPPLY", defaults.max_supply),
+            fee_tiers: env_or("FEE_TIERS", defaults.fee_tiers),
+            maker_rebate_rate: env_opt("MAKER_REBATE_RATE").and_then(|raw| raw.parse().map_err(|_| {
+                eprintln!("Warning: Could not parse MAKER_REBATE_RATE='{}', paying no maker rebates.", raw);
+            }).ok()),
+            creator_fee_share: env_or("CREATOR_FEE_SHARE", defaults.creator_fee_share),
+            protocol_fee_share: env_or("PROTOCOL_FEE_SHARE", defaults.protocol_fee_share),
+            min_collateral_reserve: env_or("MIN_COLLATERAL_RESERVE", defaults.min_collateral_reserve),
+            collateral_reserve_rate: env_or("COLLATERAL_RESERVE_RATE", defaults.collateral_reserve_rate),
+            check_supply_invariant: env_or("CHECK_SUPPLY_INVARIANT", defaults.check_supply_invariant),
+        };
+        // The insurance fund takes the rest, so the shares can't exceed the whole fee
+        let fee_shares = [config.creator_fee_share, config.protocol_fee_share];
+        if fee_shares.iter().any(|share| !(0.0..=1.0).contains(share)) || fee_shares.iter().sum::<f64>() > 1.0 {
+            eprintln!("Warning: CREATOR_FEE_SHARE and PROTOCOL_FEE_SHARE must be in [0, 1] and sum to at most 1; sending every fee to the insurance fund.");
+            config.creator_fee_share = defaults.creator_fee_share;
+            config.protocol_fee_share = defaults.protocol_fee_share;
+        }
+        config.maker_rebate_rate = capped_maker_rebate(config.maker_rebate_rate, &config.fee_tiers);
+        // A zero, negative or non-finite tolerance would make every comparison against it
+        // meaningless (no dust, or every position dust)
+        config.epsilon = positive_or_default("EPSILON", config.epsilon, defaults.epsilon);
+        config.bonding_curve_epsilon = positive_or_default("BONDING_CURVE_EPSILON", config.bonding_curve_epsilon, defaults.bonding_curve_epsilon);
+        Ok(config)
+    }
+}
+
+// `value` if it is finite and positive, otherwise the default (with a warning)
+fn positive_or_default(name: &str, value: f64, default: f64) -> f64 {
+    if value.is_finite() && value > 0.0 {
+        return value;
+    }
+    eprintln!("Warning: {}={} must be a finite positive number, using the default {}.", name, value, default);
+    default
+}
+
+// A maker rebate above the lowest fee rate would let a trader profit from buying and
+// selling straight back, the rebate on one leg outweighing the fee on the other. Such a
+// rate is clamped to the lowest fee rate, and dropped when that is zero.
+fn capped_maker_rebate(rate: Option<f64>, fee_tiers: &FeeSchedule) -> Option<f64> {
+    let rate = rate?;
+    let max_rate = fee_tiers.min_rate();
+    if rate.is_finite() && (0.0..=max_rate).contains(&rate) {
+        return Some(rate);
+    }
+    let capped = if rate.is_nan() { 0.0 } else { rate.clamp(0.0, max_rate) };
+    eprintln!("Warning: MAKER_REBATE_RATE={} must be in [0, {}] (the lowest FEE_TIERS rate); using {}.", rate, max_rate, capped);
+    (capped > 0.0).then_some(capped)
+}
+
+// Read and parse an env var, keeping the default (with a warning) if it is malformed
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(raw) => raw.trim().parse().unwrap_or_else(|_| {
+            eprintln!("Warning: Could not parse {}='{}', using default.", name, raw);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+// Read an optional string env var, treating an empty value as unset
+fn env_opt(name: &str) -> Option<String> {
+    env::var(name).ok().map(|raw| raw.trim().to_string()).filter(|raw| !raw.is_empty())
+}
+
+// Read a comma-separated env var. Unset is None; set but empty is an empty list.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    env::var(name).ok().map(|raw| {
+        raw.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect()
+    })
+}
+
+// --- Startup Environment ---
+
+// Load every env file that exists, earlier files taking precedence. Variables already
+// set in the process environment are never overridden. Returns the files loaded.
+pub fn load_env_files<'a>(paths: &[&'a str]) -> Vec<&'a str> {
+    paths.iter().copied().filter(|path| dotenvy::from_filename(path).is_ok()).collect()
+}
+
+// Shape of the Tokio runtime, read before the runtime exists (so not part of Config):
+//   WORKER_THREADS       worker thread count; unset keeps Tokio's default of one per CPU
+//   RUNTIME_THREAD_NAME  name given to the runtime's threads, e.g. for `top -H`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuntimeSettings {
+    pub worker_threads: Option<usize>,
+    pub thread_name: Option<String>,
+}
+
+impl RuntimeSettings {
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    // Same as from_env with a custom variable source, so the parsing can be tested
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let non_empty = |name: &str| lookup(name).map(|raw| raw.trim().to_string()).filter(|raw| !raw.is_empty());
+        let worker_threads = non_empty("WORKER_THREADS").and_then(|raw| match raw.parse::<usize>() {
+            Ok(count) if count > 0 => Some(count),
+            _ => {
+                eprintln!("Warning: WORKER_THREADS='{}' is not a positive integer, using one worker per CPU.", raw);
+                None
+            }
+        });
+        RuntimeSettings { worker_threads, thread_name: non_empty("RUNTIME_THREAD_NAME") }
+    }
+
+    // The multi-threaded runtime main runs the server on
+    pub fn build_runtime(&self) -> io::Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+        if let Some(thread_name) = &self.thread_name {
+            builder.thread_name(thread_name);
+        }
+        builder.build()
+    }
+}
+
+// Values the server can't start without, read and validated by Config::from_env before
+// anything else
+#[derive(Debug)]
+pub struct RequiredEnv {
+    pub jwt_secrets: Vec<String>, // Primary first, then the previous secret during a rotation
+    pub deprecation_warnings: Vec<String>, // Deprecated variable names that were used, for main to log
+}
+
+// Every required variable that was unset or empty, reported together
+#[derive(Debug, PartialEq)]
+pub struct MissingEnvVars(pub Vec<&'static str>);
+
+impl fmt::Display for MissingEnvVars {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing required environment variables: {} (set them in the environment or a .env file)", self.0.join(", "))
+    }
+}
+
+impl RequiredEnv {
+    pub fn from_env() -> Result<Self, MissingEnvVars> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    // Same as from_env with a custom variable source, so the checks can be tested
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, MissingEnvVars> {
+        let mut missing = Vec::new();
+        let mut deprecation_warnings = Vec::new();
+
+        let jwt_secret = renamed_var(&lookup, "JWT_SECRET", "JTW_SECRET", &mut deprecation_warnings);
+        if jwt_secret.is_none() {
+            missing.push("JWT_SECRET");
+        }
+
+        match jwt_secret {
+            Some(jwt_secret) if missing.is_empty() => {
+                let mut jwt_secrets = vec![jwt_secret];
+                // During a rotation, tokens signed with the previous secret stay valid until they expire
+                if let Some(previous) = renamed_var(&lookup, "JWT_SECRET_PREVIOUS", "JTW_SECRET_PREVIOUS", &mut deprecation_warnings) {
+                    jwt_secrets.push(previous);
+                }
+                Ok(RequiredEnv { jwt_secrets, deprecation_warnings })
+            }
+            _ => Err(MissingEnvVars(missing)),
+        }
+    }
+}
+
+// A variable that was renamed: the canonical name wins when both are set, and falling
+// back to the old name records a deprecation warning. The old JWT names (JTW_*) were a
+// transposed typo and are still accepted for one release.
+fn renamed_var(
+    lookup: &impl Fn(&str) -> Option<String>,
+    canonical: &str,
+    deprecated: &str,
+    warnings: &mut Vec<String>,
+) -> Option<String> {
+    let non_empty = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+    if let Some(value) = non_empty(canonical) {
+        return Some(value);
+    }
+    let value = non_empty(deprecated)?;
+    warnings.push(format!("{} is deprecated and will stop being read in the next release; rename it to {}", deprecated, canonical));
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn lookup_in(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn missing_vars_are_all_listed() {
+        let error = RequiredEnv::from_lookup(lookup_in(&[("JWT_SECRET", "  ")])).unwrap_err();
+
+        assert_eq!(error, MissingEnvVars(vec!["JWT_SECRET"]));
+        assert_eq!(
+            error.to_string(),
+            "Missing required environment variables: JWT_SECRET (set them in the environment or a .env file)"
+        );
+    }
+
+    #[test]
+    fn previous_secret_follows_the_primary() {
+        let required = RequiredEnv::from_lookup(lookup_in(&[("JWT_SECRET", "new"), ("JWT_SECRET_PREVIOUS", "old")])).unwrap();
+
+        assert_eq!(required.jwt_secrets, vec!["new".to_string(), "old".to_string()]);
+    }
+
+    #[test]
+    fn either_secret_name_satisfies_the_requirement() {
+        let canonical = RequiredEnv::from_lookup(lookup_in(&[("JWT_SECRET", "s")])).unwrap();
+        let deprecated = RequiredEnv::from_lookup(lookup_in(&[("JTW_SECRET", "s")])).unwrap();
+
+        assert_eq!(canonical.jwt_secrets, vec!["s".to_string()]);
+        assert!(canonical.deprecation_warnings.is_empty());
+        assert_eq!(deprecated.jwt_secrets, vec!["s".to_string()]);
+        assert_eq!(deprecated.deprecation_warnings.len(), 1);
+        assert!(deprecated.deprecation_warnings[0].starts_with("JTW_SECRET is deprecated"));
+    }
+
+    #[test]
+    fn fee_schedule_picks_the_highest_tier_reached() {
+        let schedule: FeeSchedule = "10000:0.002, 0:0.003,100000:0.001".parse().unwrap();
+
+        assert_eq!(schedule.rate_for(0.0), 0.003);
+        assert_eq!(schedule.rate_for(9999.0), 0.003);
+        assert_eq!(schedule.rate_for(10000.0), 0.002);
+        assert_eq!(schedule.rate_for(250000.0), 0.001);
+        assert_eq!("".parse::<FeeSchedule>().unwrap().rate_for(1e9), 0.0);
+        assert!("1000".parse::<FeeSchedule>().is_err());
+        assert!("0:1.5".parse::<FeeSchedule>().is_err());
+        assert!("0:0.001,10000:0.002".parse::<FeeSchedule>().is_err(), "a rate rising with volume");
+        assert!("0:0.002,10000:0.002".parse::<FeeSchedule>().is_ok(), "a flat rate");
+    }
+
+    #[test]
+    fn maker_rebates_are_capped_at_the_lowest_fee_rate() {
+        let schedule: FeeSchedule = "0:0.003,10000:0.001".parse().unwrap();
+
+        assert_eq!(capped_maker_rebate(None, &schedule), None);
+        assert_eq!(capped_maker_rebate(Some(0.0005), &schedule), Some(0.0005));
+        assert_eq!(capped_maker_rebate(Some(0.002), &schedule), Some(0.001));
+        assert_eq!(capped_maker_rebate(Some(-0.001), &schedule), None);
+        assert_eq!(capped_maker_rebate(Some(0.001), &FeeSchedule::default()), None, "no fees, so no room for a rebate");
+        assert_eq!(capped_maker_rebate(Some(0.001), &"100:0.003".parse().unwrap()), None, "new traders pay nothing");
+    }
+
+    #[test]
+    fn tolerances_must_be_finite_and_positive() {
+        assert_eq!(positive_or_default("EPSILON", 1e-6, EPSILON), 1e-6);
+        for bad in [0.0, -1e-6, f64::NAN, f64::INFINITY] {
+            assert_eq!(positive_or_default("EPSILON", bad, EPSILON), EPSILON, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn canonical_secret_name_takes_precedence() {
+        let required = RequiredEnv::from_lookup(lookup_in(&[("JWT_SECRET", "new"), ("JTW_SECRET", "old")])).unwrap();
+
+        assert_eq!(required.jwt_secrets, vec!["new".to_string()]);
+        assert!(required.deprecation_warnings.is_empty());
+    }
+
+    #[test]
+    fn runtime_settings_read_worker_threads_and_thread_name() {
+        assert_eq!(RuntimeSettings::from_lookup(lookup_in(&[])), RuntimeSettings::default());
+        let settings = RuntimeSettings::from_lookup(lookup_in(&[("WORKER_THREADS", " 3 "), ("RUNTIME_THREAD_NAME", "flvke-worker")]));
+        assert_eq!(settings, RuntimeSettings { worker_threads: Some(3), thread_name: Some("flvke-worker".to_string()) });
+        // Zero would panic in Tokio, so it falls back to the default like any bad value
+        assert_eq!(RuntimeSettings::from_lookup(lookup_in(&[("WORKER_THREADS", "0")])).worker_threads, None);
+    }
+
+    #[test]
+    fn server_boots_on_a_runtime_with_a_custom_worker_count() {
+        let settings = RuntimeSettings { worker_threads: Some(2), thread_name: Some("flvke-smoke".to_string()) };
+        let runtime = settings.build_runtime().expect("runtime");
+        assert_eq!(runtime.metrics().num_workers(), 2);
+
+        runtime.block_on(async {
+            let (addr, server) = warp::serve(crate::schema::schema_route()).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            let response = reqwest::get(format!("http://{}/schema", addr)).await.expect("server answers");
+            assert!(response.status().is_success());
+
+            let thread_name = tokio::spawn(async { std::thread::current().name().map(str::to_string) }).await.unwrap();
+            assert_eq!(thread_name.as_deref(), Some("flvke-smoke"));
+        });
+    }
+}
@@ -1,9 +1,30 @@
 // --- Constants ---
 
-// Small value to compare floating point numbers
+// Small value to compare floating point numbers (default for Config::epsilon)
 pub const EPSILON: f64 = 1e-9;
 
+// Version of the WebSocket message protocol, sent in Welcome; bumped on breaking changes
+pub const PROTOCOL_VERSION: u32 = 2;
+
 // Default starting balance for new users (temporary)
 pub const INITIAL_BALANCE: f64 = 1000.0; // Changed from previous value
 
-pub const BONDING_CURVE_EPSILON: f64 = 1e-9; // Epsilon specific to bonding curve calculations 
\ No newline at end of file
+pub const BONDING_CURVE_EPSILON: f64 = 1e-9; // Default for Config::bonding_curve_epsilon
+
+// Audience Supabase puts in user access tokens (default for Config::jwt_audiences)
+pub const DEFAULT_JWT_AUDIENCE: &str = "authenticated";
+
+// Page size of a GetPositions reply when the client doesn't pass a limit
+pub const DEFAULT_POSITIONS_PAGE_LIMIT: usize = 50;
+
+// Page size of a GetTradeHistory reply when the client doesn't pass a limit
+pub const DEFAULT_TRADE_HISTORY_LIMIT: usize = 50;
+
+// Longest idempotency key accepted on a Buy/Sell
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+// Posts whose thresholds a RecomputeThresholds for every post recomputes at once
+pub const MAX_CONCURRENT_THRESHOLD_RECOMPUTES: usize = 8;
+
+// Most legs accepted in one BatchTrade
+pub const MAX_BATCH_LEGS: usize = 20;
@@ -1,5 +1,9 @@
-use warp::{http::StatusCode, reject, Rejection, Reply};
+use schemars::JsonSchema;
+use serde::Serialize;
 use std::convert::Infallible;
+use std::fmt;
+use uuid::Uuid;
+use warp::{http::StatusCode, reject, Rejection, Reply};
 
 // Custom Auth Rejection
 #[derive(Debug)]
@@ -9,18 +13,106 @@ pub enum AuthError {
 
 impl reject::Reject for AuthError {}
 
+// Typed errors reported back to a client, serialized with a machine-readable `code`
+#[derive(Debug, Clone, Serialize, PartialEq, JsonSchema)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum TradeError {
+    // The message wasn't valid JSON or didn't match any ClientMessage
+    InvalidMessage { reason: String },
+    // A message field was missing or malformed
+    InvalidField { field: String, reason: String },
+    PostNotFound { post_id: Uuid },
+    // Only the post's creator may do this
+    NotPostCreator { post_id: Uuid },
+    // Creators may not trade their own post (when forbid_self_trade is on)
+    SelfTradeForbidden { post_id: Uuid },
+    // Operator request from a user not in Config::admin_users
+    AdminOnly,
+    // The post was settled and no longer trades
+    MarketClosed { post_id: Uuid },
+    // Strict mode: the authenticated user has no profile
+    UnknownUser { user_id: String },
+    // The creator already has a post with the same content (when unique_post_content is on)
+    DuplicatePost { existing_post_id: Uuid },
+    // The creator already has max_posts_per_user posts
+    PostLimitReached { limit: usize },
+    // The creator posted less than post_cooldown_secs ago
+    PostCooldown { retry_after_ms: u64 },
+    // The post failed content moderation (see moderation.rs)
+    ContentRejected { reason: String },
+    // The trade costs more than the user can spend; `available` already excludes the
+    // collateral reserve, and `shortfall` is `required - available`
+    InsufficientCollateral { required: f64, available: f64, shortfall: f64 },
+    // The fill's cost crossed the trade's limit: the client's max_cost / min_proceeds, or
+    // Config::default_slippage_tolerance from `quoted_cost`. Costs are signed as in
+    // TradeConfirmation (a sell's proceeds are negative).
+    SlippageExceeded { quoted_cost: f64, effective_cost: f64, limit: f64 },
+    // The trade would trigger more than Config::max_liquidations_per_trade liquidations
+    // (all of it under the reject policy, or already its first threshold)
+    LiquidationCapReached { cap: usize },
+    // Startup hasn't finished computing liquidation thresholds; retry shortly
+    WarmingUp,
+    // Pricing the trade produced a non-finite number; nothing was changed
+    CalculationFailed { reason: String },
+    // Any other refusal (market actor gone, shorting disabled, account being liquidated,
+    // idempotent trade still in flight)
+    Rejected { reason: String },
+}
+
+impl TradeError {
+    pub fn invalid_field(field: &str, reason: impl Into<String>) -> Self {
+        TradeError::InvalidField { field: field.to_string(), reason: reason.into() }
+    }
+
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        TradeError::Rejected { reason: reason.into() }
+    }
+}
+
+impl fmt::Display for TradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TradeError::InvalidMessage { reason } => write!(f, "{}", reason),
+            TradeError::InvalidField { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+            TradeError::PostNotFound { post_id } => write!(f, "Post {} not found", post_id),
+            TradeError::NotPostCreator { post_id } => write!(f, "Only the creator of post {} can do that", post_id),
+            TradeError::SelfTradeForbidden { post_id } => write!(f, "You can't trade post {}, which you created", post_id),
+            TradeError::AdminOnly => write!(f, "This request is restricted to administrators"),
+            TradeError::MarketClosed { post_id } => write!(f, "Post {} has been settled and no longer trades", post_id),
+            TradeError::UnknownUser { user_id } => write!(f, "Unknown user {}", user_id),
+            TradeError::DuplicatePost { existing_post_id } => write!(f, "You already posted this content (post {})", existing_post_id),
+            TradeError::PostLimitReached { limit } => write!(f, "Post limit reached ({} posts per user)", limit),
+            TradeError::PostCooldown { retry_after_ms } => write!(f, "You can create another post in {:.1}s", *retry_after_ms as f64 / 1000.0),
+            TradeError::ContentRejected { reason } => write!(f, "Post rejected by moderation: {}", reason),
+            TradeError::InsufficientCollateral { required, available, shortfall } => write!(
+                f, "Insufficient collateral: the trade needs {:.6} but only {:.6} is available ({:.6} short)", required, available, shortfall
+            ),
+            TradeError::SlippageExceeded { quoted_cost, effective_cost, limit } if *limit < 0.0 => write!(
+                f, "The sell would return {:.6}, below the minimum of {:.6} (quoted {:.6})", -effective_cost, -limit, -quoted_cost
+            ),
+            TradeError::SlippageExceeded { quoted_cost, effective_cost, limit } => write!(
+                f, "The buy would cost {:.6}, above the maximum of {:.6} (quoted {:.6})", effective_cost, limit, quoted_cost
+            ),
+            TradeError::LiquidationCapReached { cap } => write!(f, "The trade would trigger more than {} liquidations; trade a smaller quantity", cap),
+            TradeError::WarmingUp => write!(f, "Server is warming up, try again shortly"),
+            TradeError::CalculationFailed { reason } => write!(f, "Trade calculation failed: {}", reason),
+            TradeError::Rejected { reason } => write!(f, "{}", reason),
+        }
+    }
+}
+
 // Warp Rejection Handler
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     eprintln!("Handling rejection: {:?}", err);
 
     if err.is_not_found() {
         Ok(warp::reply::with_status("NOT_FOUND", StatusCode::NOT_FOUND))
-    } else if let Some(_) = err.find::<AuthError>() {
+    } else if err.find::<AuthError>().is_some() {
         Ok(warp::reply::with_status(
             "UNAUTHORIZED",
             StatusCode::UNAUTHORIZED,
         ))
-    } else if let Some(_) = err.find::<reject::MethodNotAllowed>() {
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
         Ok(warp::reply::with_status(
             "METHOD_NOT_ALLOWED",
             StatusCode::METHOD_NOT_ALLOWED,
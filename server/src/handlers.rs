@@ -1,28 +1,50 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
-use warp::filters::ws::Message;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use dashmap::mapref::entry::Entry;
+use std::cmp::Ordering;
+use std::future::Future;
 use ordered_float::OrderedFloat;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::time::Instant;
+use tracing::Instrument;
+use futures_util::{stream, StreamExt};
 
-use super::state::{AppState, UserPositions, UserBalances, UserRealizedPnl, Posts, LiquidationThresholds};
-use super::models::{ClientMessage, ServerMessage, Post, UserPositionDetail};
-use super::constants::{EPSILON, INITIAL_BALANCE};
+use super::state::{AppState, LiquidationEntry};
+use super::config::PrecisionPolicy;
+use super::models::{ClientMessage, ServerMessage, FeeTotals, Post, PostVisibility, PositionDetail, PositionSort, UserPositionDetail, TradeLeg, LegResult, LadderLevel, LadderEntry, TradeRecord, PositionImport};
+use super::constants::{INITIAL_BALANCE, DEFAULT_POSITIONS_PAGE_LIMIT, DEFAULT_TRADE_HISTORY_LIMIT, MAX_BATCH_LEGS, MAX_CONCURRENT_THRESHOLD_RECOMPUTES, MAX_IDEMPOTENCY_KEY_LEN};
+use super::idempotency::{Claim, TradeFingerprint};
 use super::bonding_curve::{get_price, calculate_smooth_cost};
 use super::calculations::{
-    calculate_average_price, calculate_unrealized_pnl, calculate_liquidation_price,
-    EffectiveTradeResult, calculate_effective_cost_and_final_supply
+    calculate_average_price, calculate_unrealized_pnl, calculate_liquidation_price, calculate_liquidation_supply, apply_fill,
+    calculate_effective_cost_and_final_supply, calculate_effective_cost_along_ladder, calculate_user_margin, EffectiveTradeResult
 };
-use super::websocket::{send_to_client, broadcast_message, broadcast_market_and_position_updates};
+use super::websocket::{send_to_client, send_to_user, build_initial_state, broadcast_message, broadcast_market_update, broadcast_new_post, send_post_trade_syncs};
+use super::market::{spawn_market, MarketHandle};
+use super::errors::TradeError;
+use super::webhooks::LiquidationWebhook;
+use super::moderation::moderate;
 
-// Helper function to initialize user state if it doesn't exist
-fn ensure_user_state_exists(user_id: &str, state: &AppState) {
+// Helper function to initialize user state if it doesn't exist. With
+// Config::strict_users the user must already have a profile (state.user_profiles);
+// otherwise any authenticated user gets a fresh account.
+pub fn ensure_user_state_exists(user_id: &str, state: &AppState) -> Result<(), TradeError> {
+    if state.config.strict_users && !state.user_profiles.contains(user_id) {
+        return Err(TradeError::UnknownUser { user_id: user_id.to_string() });
+    }
     // Use entry API to avoid multiple lookups and handle concurrent initialization safely
     state.user_balances.entry(user_id.to_string()).or_insert(INITIAL_BALANCE);
     state.user_realized_pnl.entry(user_id.to_string()).or_insert(0.0);
+    state.user_cash.entry(user_id.to_string()).or_insert(0.0);
     // Initialize stored exposure
     state.user_exposure.entry(user_id.to_string()).or_insert(0.0);
     // Ensure liquidation threshold map exists for posts (handled in update func)
+    Ok(())
 }
 
 // Helper function to calculate total unrealized PNL for a user
@@ -33,9 +55,7 @@ pub fn calculate_total_unrealized_pnl(user_id: &str, state: &AppState) -> f64 {
             let post_id = position_entry.key();
             let position = position_entry.value();
             if let Some(post) = state.posts.get(post_id) {
-                // Use the post's stored price if available, otherwise calculate
-                let current_price = post.price.unwrap_or_else(|| get_price(post.supply));
-                    total_urpnl += calculate_unrealized_pnl(position, current_price);
+                total_urpnl += calculate_unrealized_pnl(position, post.price, state.config.epsilon);
             }
         }
     }
@@ -56,194 +76,192 @@ pub fn calculate_total_exposure(user_id: &str, state: &AppState) -> f64 {
     total_exposure
 }
 
-// Helper function to send a comprehensive user state update
-pub async fn send_user_sync_update(user_id: &str, client_id: Uuid, state: &AppState) {
-    println!("--- Entering send_user_sync_update for User {} (Client {}) ---", user_id, client_id);
-    // Fetch potentially updated values
-    println!("send_user_sync_update: Fetching balance...");
+// Current price of every post the given users hold. Read once per trade and shared by
+// every UserSync built for it, so the per-user work is limited to that user's own
+// positions, and no more posts are read than the recipients can be sent.
+pub type PriceSnapshot = HashMap<Uuid, f64>;
+
+pub fn snapshot_prices(user_ids: &[&str], state: &AppState) -> PriceSnapshot {
+    let post_ids: HashSet<Uuid> = user_ids.iter()
+        .filter_map(|user_id| state.user_positions.get(*user_id))
+        .flat_map(|positions| positions.iter().map(|entry| *entry.key()).collect::<Vec<_>>())
+        .collect();
+    post_ids.into_iter()
+        .filter_map(|post_id| state.posts.get(&post_id).map(|post| (post_id, post.price)))
+        .collect()
+}
+
+// Builds a user's UserSync (balance, exposure, equity, PnL, positions), pricing their
+// positions from `prices` instead of re-reading each post
+pub fn build_user_sync(user_id: &str, prices: &PriceSnapshot, state: &AppState) -> ServerMessage {
     let balance = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |v| *v.value());
-    println!("send_user_sync_update: Fetching realized_pnl...");
     let realized_pnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
-    println!("send_user_sync_update: Fetching exposure...");
     let exposure = state.user_exposure.get(user_id).map_or(0.0, |v| *v.value());
-    println!("send_user_sync_update: Bal={:.4}, RPnl={:.4}, Exp={:.4}. Preparing to collect position data...", balance, realized_pnl, exposure);
-
-    // --- Collect position data safely --- 
-    let mut collected_positions = Vec::new();
-    let mut collection_error: Option<String> = None;
-
-    // Catch potential panics during position access
-    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-        println!("send_user_sync_update: Inside catch_unwind - Attempting state.user_positions.get()...");
-        let mut temp_positions = Vec::new();
-        if let Some(user_positions_map_ref) = state.user_positions.get(user_id) {
-            println!("send_user_sync_update: Inside catch_unwind - Got map reference. Iterating...");
-            for entry in user_positions_map_ref.iter() {
-                // Clone necessary data: post_id and UserPositionDetail
-                temp_positions.push((*entry.key(), entry.value().clone()));
-            }
-             println!("send_user_sync_update: Inside catch_unwind - Collected {} positions for user {}.", temp_positions.len(), user_id);
-        } else {
-             println!("send_user_sync_update: Inside catch_unwind - No positions map found for user {}.", user_id);
-        }
-        temp_positions // Return the collected data if successful
-    }));
-
-    match result {
-        Ok(positions) => {
-            println!("send_user_sync_update: catch_unwind succeeded. Using collected positions.");
-            collected_positions = positions;
-        }
-        Err(panic_payload) => {
-            // Log the panic
-            let panic_msg = if let Some(s) = panic_payload.downcast_ref::<&str>() {
-                *s
-            } else if let Some(s) = panic_payload.downcast_ref::<String>() {
-                s.as_str()
-            } else {
-                "Unknown panic type"
-            };
-            eprintln!("CRITICAL PANIC CAUGHT in send_user_sync_update while accessing user_positions for user {}: {}", user_id, panic_msg);
-            collection_error = Some(format!("Panic accessing position data: {}", panic_msg));
-            // collected_positions remains empty
-        }
-    }
-    
-    // If collection failed (panic or otherwise), we might still want to send a partial UserSync
-    if let Some(err_msg) = collection_error {
-       eprintln!("send_user_sync_update: Error occurred during position collection: {}", err_msg);
-       // Optionally, send an error message to the client or a UserSync with empty positions?
-       // For now, we continue and will send UserSync with empty positions.
-    }
-
-    // --- Process collected positions --- 
-    println!("send_user_sync_update: Processing collected positions (count: {})...", collected_positions.len());
-    let mut position_details = Vec::new();
-    let mut total_unrealized_pnl = 0.0;
-
-    // Need user's balance and rpnl for liquidation calc
-    let user_balance_for_liq = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |v| *v.value());
-    let user_rpnl_for_liq = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
-
-    for (post_id, position_value) in collected_positions {
-        println!("send_user_sync_update: Processing collected position for post {}", post_id);
-        let current_market_price = state.posts.get(&post_id)
-            .map_or(0.0, |p| p.value().price.unwrap_or(0.0));
-        println!("send_user_sync_update: Post {}, MarketPrice={:.4}, PosSize={:.4}. Calculating PnL...", post_id, current_market_price, position_value.size);
-
-        // Post existence check might be less critical now, but still good practice
-        if let Some(_post) = state.posts.get(&post_id) { 
-             let avg_price = calculate_average_price(&position_value);
-             if position_value.size.abs() > EPSILON {
-                let unrealized_pnl = calculate_unrealized_pnl(&position_value, current_market_price);
-                println!("send_user_sync_update: Post {}, AvgPrc={:.4}, uPnL={:.4}. Calculating liq price...", post_id, avg_price, unrealized_pnl);
-                    total_unrealized_pnl += unrealized_pnl;
-
-                // Calculate liquidation price for this position
-                let liquidation_price = calculate_liquidation_price(
-                    user_balance_for_liq, 
-                    user_rpnl_for_liq, 
-                    position_value.size, 
-                    avg_price
-                );
-                println!("send_user_sync_update: Post {}, LiqPrice={:?}. Adding detail.", post_id, liquidation_price);
-
-                    position_details.push(super::models::PositionDetail {
-                        post_id,
-                    size: position_value.size,
-                    average_price: avg_price,
-                        unrealized_pnl,
-                    liquidation_price, // Pass the calculated price
-                    });
-             } else {
-                println!("send_user_sync_update: Post {}, Size near zero, skipping detail.", post_id);
-                 }
-        } else {
-            eprintln!("Warning: Post {} disappeared while processing collected UserSync data for user {}", post_id, user_id);
-        }
+
+    let mut position_details = priced_positions(user_id, prices, balance, realized_pnl, false, state);
+    // Equity counts every position, including any the cap leaves out of the message
+    let total_unrealized_pnl: f64 = position_details.iter().map(|detail| detail.unrealized_pnl).sum();
+    let cap = state.config.user_sync_position_cap;
+    let has_more_positions = cap > 0 && position_details.len() > cap;
+    if has_more_positions {
+        position_details.truncate(cap);
     }
-    println!("send_user_sync_update: Finished processing collected positions. Total uPnL={:.4}", total_unrealized_pnl);
 
     let equity = balance + realized_pnl + total_unrealized_pnl;
-    println!("send_user_sync_update: Calculated Equity={:.4}. Constructing message...", equity);
-
-    let sync_msg = ServerMessage::UserSync {
+    ServerMessage::UserSync {
         balance,
         exposure,
         equity,
+        buying_power: (available_collateral(user_id, state) - collateral_reserve(balance, state)).max(0.0),
         positions: position_details,
+        has_more_positions,
         total_realized_pnl: realized_pnl,
-    };
-    println!("send_user_sync_update: Message constructed. Serializing...");
-
-    // Find the client sender to send the message
-    // Check if client is still connected before sending
-    if let Some(client_entry) = state.clients.get(&client_id) {
-        let client = client_entry.value();
-        if client.user_id == user_id { // Double check user_id match
-           println!("send_user_sync_update: Found client entry for {}. Serializing JSON...", client_id);
-           match serde_json::to_string(&sync_msg) {
-               Ok(msg_str) => {
-                   println!("send_user_sync_update: Serialized OK. Sending via MPSC channel...");
-                   if let Err(e) = client.sender.send(Ok(warp::filters::ws::Message::text(msg_str))) {
-                        eprintln!("Error sending UserSync to client {}: {}", client_id, e);
-                   }
-                   println!("send_user_sync_update: Sent via MPSC channel (or queued).");
-               },
-               Err(e) => {
-                    // Log serialization error - this could be a source of panic if unhandled floats exist
-                    eprintln!("Critical Error: Failed to serialize UserSync for user {}: {}. Message: {:?}", user_id, e, sync_msg);
-                    // Consider sending an error message instead? Or just logging.
-               }
-           }
-        } else {
+        // Same as calculate_margin_ratio, but from the equity priced above
+        margin_ratio: (exposure.abs() >= state.config.epsilon).then(|| equity / exposure),
+    }
+}
+
+// Details of a user's positions priced from `prices`, ordered by post id. Dust positions
+// (size within epsilon of zero) are only included with `include_dust`.
+fn priced_positions(
+    user_id: &str,
+    prices: &PriceSnapshot,
+    balance: f64,
+    realized_pnl: f64,
+    include_dust: bool,
+    state: &AppState,
+) -> Vec<PositionDetail> {
+    // Copy the positions out so no shard lock is held while computing
+    let mut positions: Vec<(Uuid, UserPositionDetail)> = state.user_positions.get(user_id)
+        .map(|positions| positions.iter().map(|entry| (*entry.key(), entry.value().clone())).collect())
+        .unwrap_or_default();
+    positions.sort_by_key(|(post_id, _)| *post_id);
+
+    let mut position_details = Vec::with_capacity(positions.len());
+    for (post_id, position) in positions {
+        if !include_dust && position.size.abs() <= state.config.epsilon {
+            continue;
+        }
+        let Some(&market_price) = prices.get(&post_id) else {
+            eprintln!("Warning: Post {} missing from price snapshot while pricing positions of user {}", post_id, user_id);
+            continue;
+        };
+        let avg_price = calculate_average_price(&position, state.config.epsilon);
+        position_details.push(PositionDetail {
+            post_id,
+            size: position.size,
+            average_price: avg_price,
+            unrealized_pnl: calculate_unrealized_pnl(&position, market_price, state.config.epsilon),
+            liquidation_price: calculate_liquidation_price(balance, realized_pnl, position.size, avg_price, state.config.epsilon, state.config.bonding_curve_epsilon),
+        });
+    }
+    position_details
+}
+
+// Reorders post-id-ordered details by `sort`; the sorts are stable, so ties keep post id order
+fn sort_positions(details: &mut [PositionDetail], sort: PositionSort) {
+    match sort {
+        PositionSort::PostId => {}
+        PositionSort::UnrealizedPnlDesc => details.sort_by(|a, b| b.unrealized_pnl.total_cmp(&a.unrealized_pnl)),
+        PositionSort::SizeDesc => details.sort_by(|a, b| b.size.abs().total_cmp(&a.size.abs())),
+    }
+}
+
+// Builds the user's PortfolioSummary from their stored ledgers and current post prices
+pub fn build_portfolio_summary(user_id: &str, state: &AppState) -> ServerMessage {
+    let balance = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |v| *v.value());
+    let realized_pnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
+    let unrealized_pnl = calculate_total_unrealized_pnl(user_id, state);
+
+    // Copy the positions out so no shard lock is held while reading posts
+    let open_positions: Vec<(Uuid, f64)> = state.user_positions.get(user_id)
+        .map(|positions| positions.iter()
+            .filter(|entry| entry.value().size.abs() > state.config.epsilon)
+            .map(|entry| (*entry.key(), entry.value().size))
+            .collect())
+        .unwrap_or_default();
+    let total_notional = open_positions.iter()
+        .filter_map(|(post_id, size)| state.posts.get(post_id).map(|post| {
+            size.abs() * post.price
+        }))
+        .sum();
+
+    ServerMessage::PortfolioSummary {
+        balance,
+        realized_pnl,
+        unrealized_pnl,
+        equity: balance + realized_pnl + unrealized_pnl,
+        exposure: calculate_total_exposure(user_id, state),
+        open_positions: open_positions.len(),
+        total_notional,
+    }
+}
+
+// Builds the user's RiskSnapshot. Each liquidation supply is computed from the stored
+// position, like the post's ladder, so thresholds omitted by the grace distance count too.
+pub fn build_risk_snapshot(user_id: &str, state: &AppState) -> ServerMessage {
+    let balance = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |v| *v.value());
+    let realized_pnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
+
+    // Copy the positions out so no shard lock is held while reading posts
+    let open_positions: Vec<(Uuid, UserPositionDetail)> = state.user_positions.get(user_id)
+        .map(|positions| positions.iter()
+            .filter(|entry| entry.value().size.abs() > state.config.epsilon)
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect())
+        .unwrap_or_default();
+    let liquidation_distance_min = open_positions.iter()
+        .filter_map(|(post_id, position)| {
+            let (supply, flat_width) = state.posts.get(post_id).map(|post| (post.supply, post.flat_width))?;
+            calculate_liquidation_supply(balance, realized_pnl, position.size, calculate_average_price(position, state.config.epsilon), flat_width, state.config.epsilon, state.config.bonding_curve_epsilon)
+                .map(|s_liq| (s_liq - supply).abs())
+        })
+        .min_by(f64::total_cmp);
+
+    ServerMessage::RiskSnapshot {
+        exposure: calculate_total_exposure(user_id, state),
+        margin: calculate_user_margin(user_id, state),
+        total_unrealized_pnl: calculate_total_unrealized_pnl(user_id, state),
+        liquidation_distance_min,
+    }
+}
+
+// Helper function to send a comprehensive user state update to one client
+pub async fn send_user_sync_update(user_id: &str, client_id: Uuid, state: &AppState) {
+    match state.clients.get(&client_id) {
+        Some(client) if client.user_id == user_id => {}
+        Some(_) => {
             eprintln!("Error: Client ID {} does not match User ID {} during UserSync send.", client_id, user_id);
+            return;
+        }
+        None => {
+            println!("send_user_sync_update: Client {} not found (offline?). Skipping send.", client_id);
+            return;
         }
-    } else {
-        println!("send_user_sync_update: Client {} not found (offline?). Skipping send.", client_id);
     }
-    println!("--- Exiting send_user_sync_update for User {} (Client {}) ---", user_id, client_id);
+    let sync_msg = build_user_sync(user_id, &snapshot_prices(&[user_id], state), state);
+    send_to_client(client_id, sync_msg, state).await;
 }
 
+// Transport side of a client message: runs it through process_client_message and
+// delivers the replies (or the error) to the requesting client. Returns the number of
+// trades it executed, for the connection's summary.
 pub async fn handle_client_message(
     client_id: Uuid,
     user_id: &str,
     msg: warp::filters::ws::Message,
     state: &AppState,
-) {
-    ensure_user_state_exists(user_id, state);
+) -> usize {
+    let mut trades_executed = 0;
     if let Ok(text) = msg.to_str() {
-        match serde_json::from_str::<ClientMessage>(text) {
-            Ok(client_msg) => {
-                println!("User {} ({}) request: {:?}", user_id, client_id, client_msg);
-                match client_msg {
-                    ClientMessage::CreatePost { content } => {
-                        println!("handle_client_message: Calling handle_create_post...");
-                        let new_post_id = handle_create_post(client_id, user_id, content, state).await;
-                        println!("handle_client_message: Returned from handle_create_post. Calling update_liquidation_thresholds...");
-                        update_liquidation_thresholds(new_post_id, state).await;
-                        println!("handle_client_message: Returned from update_liquidation_thresholds after CreatePost.");
-                    }
-                    ClientMessage::Buy { post_id, quantity } => {
-                        println!("handle_client_message: Calling handle_buy...");
-                        handle_buy(client_id, user_id, post_id, quantity, state).await;
-                        println!("handle_client_message: Returned from handle_buy. Calling update_liquidation_thresholds...");
-                        update_liquidation_thresholds(post_id, state).await;
-                        println!("handle_client_message: Returned from update_liquidation_thresholds after Buy.");
-                    }
-                     ClientMessage::Sell { post_id, quantity } => {
-                        println!("handle_client_message: Calling handle_sell...");
-                        handle_sell(client_id, user_id, post_id, quantity, state).await;
-                        println!("handle_client_message: Returned from handle_sell. Calling update_liquidation_thresholds...");
-                        update_liquidation_thresholds(post_id, state).await;
-                        println!("handle_client_message: Returned from update_liquidation_thresholds after Sell.");
-                    }
-                }
-            }
-            Err(e) => {
-                 // Also log deserialization errors
-                 eprintln!("Error deserializing client message from {}: {}. Raw text: '{}'", client_id, e, text);
-            }
+        let replies = process_client_message(client_id, user_id, text, state).await.unwrap_or_else(|e| vec![e.into()]);
+        for reply in replies {
+            trades_executed += match &reply {
+                ServerMessage::TradeConfirmation { .. } => 1,
+                ServerMessage::BatchResult { results, .. } => results.iter().filter(|leg| matches!(leg, LegResult::Filled { .. })).count(),
+                _ => 0,
+            };
+            send_to_client(client_id, reply, state).await;
         }
     } else if msg.is_ping() {
         // Ping/Pong handled automatically by Warp
@@ -252,192 +270,468 @@ pub async fn handle_client_message(
     } else {
         // Ignore binary messages etc.
     }
+    trades_executed
+}
+
+// Handles one client message and returns the replies addressed to the requesting
+// client, in order. Fan-out to other clients (NewPost, MarketUpdate, other users'
+// UserSyncs) still happens as a side effect while the message is processed.
+pub async fn process_client_message(
+    client_id: Uuid,
+    user_id: &str,
+    text: &str,
+    state: &AppState,
+) -> Result<Vec<ServerMessage>, TradeError> {
+    ensure_user_state_exists(user_id, state)?;
+    // Everything logged while handling this message, including the market actor's work
+    // on a trade, carries the same correlation_id
+    let correlation_id = Uuid::new_v4();
+    let span = tracing::info_span!("client_message", %correlation_id, %client_id, user_id);
+    async {
+        let client_msg = serde_json::from_str::<ClientMessage>(text).map_err(|e| {
+            // Also log deserialization errors
+            eprintln!("Error deserializing client message from {}: {}. Raw text: '{}'", client_id, e, text);
+            describe_invalid_message(text, &e)
+        })?;
+        println!("User {} ({}) request: {:?}", user_id, client_id, client_msg);
+        match client_msg {
+            ClientMessage::CreatePost { content, visibility, max_supply, flat_width, initial_supply, founder_position } => {
+                println!("process_client_message: Calling handle_create_post...");
+                let options = PostOptions { visibility, max_supply, flat_width, initial_supply, founder_position };
+                handle_create_post(client_id, user_id, content, options, state).await?;
+                // The creator learns of the post through the NewPost fan-out
+                Ok(Vec::new())
+            }
+            // Trades are routed to the post's market actor, which also
+            // recomputes the post's liquidation thresholds afterwards
+            ClientMessage::Buy { post_id, quantity, allow_flip, idempotency_key, max_cost } => {
+                println!("process_client_message: Calling handle_buy...");
+                let fingerprint = TradeFingerprint { post_id, quantity, allow_flip, limit: max_cost };
+                let trade = handle_buy(client_id, user_id, post_id, quantity, allow_flip, max_cost, state);
+                Ok(vec![execute_once(user_id, idempotency_key, fingerprint, trade, state).await?])
+            }
+            ClientMessage::Sell { post_id, quantity, allow_flip, idempotency_key, min_proceeds } => {
+                println!("process_client_message: Calling handle_sell...");
+                let fingerprint = TradeFingerprint { post_id, quantity: -quantity, allow_flip, limit: min_proceeds };
+                let trade = handle_sell(client_id, user_id, post_id, quantity, allow_flip, min_proceeds, state);
+                Ok(vec![execute_once(user_id, idempotency_key, fingerprint, trade, state).await?])
+            }
+            ClientMessage::BatchTrade { trades, all_or_nothing } => {
+                Ok(vec![handle_batch_trade(client_id, user_id, trades, all_or_nothing, state).await?])
+            }
+            ClientMessage::CloseOwnPost { post_id } => {
+                handle_close_own_post(client_id, user_id, post_id, state).await?;
+                Ok(Vec::new()) // The PostSettled broadcast reaches the creator too
+            }
+            ClientMessage::GetPost { post_id } => Ok(vec![handle_get_post(post_id, state)?]),
+            ClientMessage::GetLiquidationLadder { post_id } => Ok(vec![handle_get_liquidation_ladder(user_id, post_id, state)?]),
+            ClientMessage::SimulateCascade { post_id, target_supply } => {
+                Ok(vec![handle_simulate_cascade(user_id, post_id, target_supply, state)?])
+            }
+            ClientMessage::GetFeeTotals { post_id } => Ok(vec![handle_get_fee_totals(user_id, post_id, state)?]),
+            ClientMessage::RecomputeThresholds { post_id } => Ok(vec![handle_recompute_thresholds(user_id, post_id, state).await?]),
+            ClientMessage::AdjustBalance { user_id: target_user_id, amount } => {
+                Ok(vec![handle_adjust_balance(user_id, &target_user_id, amount, state).await?])
+            }
+            ClientMessage::ImportPositions { positions, adjust_supply } => {
+                Ok(vec![handle_import_positions(user_id, positions, adjust_supply, state).await?])
+            }
+            ClientMessage::GetPortfolioSummary => Ok(vec![build_portfolio_summary(user_id, state)]),
+            ClientMessage::GetRisk => Ok(vec![build_risk_snapshot(user_id, state)]),
+            ClientMessage::GetPositions { offset, limit, only_open, min_size, sort } => {
+                let filter = PositionFilter { offset, limit, only_open, min_size, sort };
+                Ok(vec![handle_get_positions(user_id, filter, state)?])
+            }
+            ClientMessage::GetPnlHistory { since } => Ok(vec![handle_get_pnl_history(user_id, since, state)]),
+            ClientMessage::GetTradeHistory { post_id, limit, before } => Ok(vec![handle_get_trade_history(user_id, post_id, limit, before, state)?]),
+            ClientMessage::Subscribe { post_id } => {
+                handle_subscribe(client_id, post_id, true, state)?;
+                Ok(Vec::new())
+            }
+            ClientMessage::Unsubscribe { post_id } => {
+                handle_subscribe(client_id, post_id, false, state)?;
+                Ok(Vec::new())
+            }
+            ClientMessage::SetAccountUpdates { enabled } => {
+                if let Some(mut client) = state.clients.get_mut(&client_id) {
+                    client.account_updates = enabled;
+                    println!("Client {} turned account updates {}", client_id, if enabled { "on" } else { "off" });
+                }
+                Ok(Vec::new())
+            }
+            ClientMessage::ResetClientState => {
+                if let Some(mut client) = state.clients.get_mut(&client_id) {
+                    client.reset();
+                }
+                // The same snapshots a new connection starts with
+                Ok(vec![build_initial_state(state), build_user_sync(user_id, &snapshot_prices(&[user_id], state), state)])
+            }
+            ClientMessage::Ack { id } => {
+                // Duplicate acks for a resent message are expected, so unknown ids are ignored
+                if let Some(client) = state.clients.get(&client_id) {
+                    if !client.acks.acknowledge(id) {
+                        println!("Client {} acknowledged unknown or settled ack_id {}", client_id, id);
+                    }
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+// Builds the error for a message that failed to deserialize, naming the offending
+// field where it can be identified (currently a malformed post_id)
+fn describe_invalid_message(text: &str, error: &serde_json::Error) -> TradeError {
+    let value = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => value,
+        Err(_) => return TradeError::InvalidMessage { reason: format!("Malformed message: {}", error) },
+    };
+    if let Some(raw_post_id) = value.get("post_id") {
+        let parsed = raw_post_id.as_str().map(Uuid::parse_str);
+        match parsed {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return TradeError::invalid_field("post_id", format!("'{}' is not a valid UUID ({})", raw_post_id.as_str().unwrap_or_default(), e)),
+            None => return TradeError::invalid_field("post_id", "expected a UUID string"),
+        }
+    }
+    TradeError::InvalidMessage { reason: format!("Invalid message: {}", error) }
+}
+
+// Market settings of a CreatePost request
+struct PostOptions {
+    visibility: PostVisibility,
+    max_supply: Option<f64>,
+    flat_width: f64,
+    initial_supply: f64,
+    founder_position: bool,
 }
 
 async fn handle_create_post(
     _client_id: Uuid,
     user_id: &str,
     content: String,
+    options: PostOptions,
     state: &AppState,
-) -> Uuid {
+) -> Result<Uuid, TradeError> {
+    let PostOptions { visibility, max_supply, flat_width, initial_supply, founder_position } = options;
     let new_post_id = Uuid::new_v4();
-    let initial_price = get_price(0.0);
+    if let Some(max_supply) = max_supply {
+        if !max_supply.is_finite() || max_supply <= state.config.epsilon {
+            return Err(TradeError::invalid_field("max_supply", format!("max_supply ({}) must be a positive number", max_supply)));
+        }
+    }
+    if !flat_width.is_finite() || flat_width < 0.0 {
+        return Err(TradeError::invalid_field("flat_width", format!("flat_width ({}) must be a non-negative number", flat_width)));
+    }
+    let founder_cost = validate_initial_supply(user_id, initial_supply, founder_position, max_supply, flat_width, state)?;
+    moderate(&content, &state.config, state.content_check.as_ref()).await?;
+
+    // Like the post slot below, the cooldown is claimed up front and handed back if
+    // the post is rejected
+    let previous_post_at = claim_post_cooldown(user_id, state)?;
+
+    // Reserve one of the creator's post slots up front so concurrent creates can't
+    // overshoot the cap; released again if the post is rejected below
+    {
+        let mut post_count = state.user_post_counts.entry(user_id.to_string()).or_insert(0);
+        let limit = state.config.max_posts_per_user;
+        if limit > 0 && *post_count >= limit {
+            drop(post_count);
+            release_post_cooldown(user_id, previous_post_at, state);
+            return Err(TradeError::PostLimitReached { limit });
+        }
+        *post_count += 1;
+    }
+
+    // Claim the (creator, content) slot atomically so two identical posts can't race in
+    let content_key = (user_id.to_string(), content_hash(&content));
+    match state.post_contents.entry(content_key) {
+        Entry::Occupied(existing) if state.config.unique_post_content => {
+            let existing_post_id = *existing.get();
+            drop(existing);
+            if let Some(mut post_count) = state.user_post_counts.get_mut(user_id) {
+                *post_count -= 1;
+            }
+            release_post_cooldown(user_id, previous_post_at, state);
+            return Err(TradeError::DuplicatePost { existing_post_id });
+        }
+        Entry::Occupied(_) => {} // Keep pointing at the first post with this content
+        Entry::Vacant(slot) => {
+            slot.insert(new_post_id);
+        }
+    }
+
+    let initial_price = get_price(initial_supply, flat_width, state.config.bonding_curve_epsilon);
     let new_post = Post {
         id: new_post_id,
         user_id: user_id.to_string(),
         content,
         timestamp: Utc::now(),
-        supply: 0.0,
-        price: Some(initial_price),
-        // Removed old fields
+        supply: initial_supply,
+        price: initial_price,
+        visibility,
+        settlement_price: None,
+        max_supply,
+        flat_width,
     };
     // Ensure threshold map exists for the new post, even if empty
     state.liquidation_thresholds.insert(new_post_id, BTreeMap::new());
     state.posts.insert(new_post_id, new_post.clone());
+    if let Some(cost) = founder_cost {
+        state.user_positions
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(new_post_id, UserPositionDetail { size: initial_supply, total_cost_basis: cost });
+        *state.user_cash.entry(user_id.to_string()).or_insert(0.0) -= cost;
+        state.user_exposure.insert(user_id.to_string(), calculate_total_exposure(user_id, state));
+        // Registered before the market starts, so no trade can cross it unregistered
+        update_liquidation_thresholds(new_post_id, state).await;
+    }
+    // Start the market actor that will serialize all trades on this post
+    state.markets.insert(new_post_id, spawn_market(new_post_id, state.clone()));
     println!(
-        "-> Post {} created (Price: {:.6}, Supply: 0.0)",
-        new_post_id, initial_price
+        "-> Post {} created (Price: {:.6}, Supply: {:.6})",
+        new_post_id, initial_price, initial_supply
     );
-    let broadcast_msg = ServerMessage::NewPost { post: new_post };
-    broadcast_message(broadcast_msg, state).await;
-    new_post_id
+    broadcast_new_post(new_post, state).await;
+    Ok(new_post_id)
 }
 
-async fn handle_buy(
-    client_id: Uuid,
-    trader_user_id: &str,
-    post_id: Uuid,
-    quantity: f64,
+// Checks a new post's starting supply against the supply bounds (the post's cap, or the
+// server's, and zero when shorts are disabled). Returns what the founder position costs
+// the creator, already checked against their collateral, when they asked for one.
+fn validate_initial_supply(
+    user_id: &str,
+    initial_supply: f64,
+    founder_position: bool,
+    max_supply: Option<f64>,
+    flat_width: f64,
     state: &AppState,
-) {
-     if quantity <= EPSILON {
-        send_to_client(client_id, ServerMessage::Error { message: format!("Buy quantity ({:.6}) must be positive", quantity) }, state).await;
-        return;
+) -> Result<Option<f64>, TradeError> {
+    let invalid = |reason: String| Err(TradeError::invalid_field("initial_supply", reason));
+    if !initial_supply.is_finite() {
+        return invalid(format!("initial_supply ({}) must be a finite number", initial_supply));
     }
-    ensure_user_state_exists(trader_user_id, state);
-
-    // --- Phase 1: Read Initial State & Calculate Effective Trade ---
-    let initial_supply = match state.posts.get(&post_id) {
-        Some(post_entry) => post_entry.supply,
-        None => { send_to_client(client_id, ServerMessage::Error { message: format!("Post {} not found", post_id) }, state).await; return; }
-    };
-
-    let trade_result = match calculate_effective_cost_and_final_supply(initial_supply, quantity, post_id, state) {
-        Ok(result) => result,
-        Err(e) => { send_to_client(client_id, ServerMessage::Error { message: format!("Trade calculation error: {}", e) }, state).await; return; }
-    };
+    let cap = max_supply.unwrap_or(state.config.max_supply);
+    if cap > 0.0 && initial_supply > cap + state.config.epsilon {
+        return invalid(format!("initial_supply ({}) is above the supply cap of {}", initial_supply, cap));
+    }
+    if !state.config.allow_shorts && initial_supply < 0.0 {
+        return invalid(format!("initial_supply ({}) can't be negative while shorting is disabled", initial_supply));
+    }
+    if initial_supply.abs() <= state.config.epsilon {
+        return Ok(None);
+    }
+    if !founder_position {
+        // Nobody would hold the supply, which the invariant check reports after every fill
+        if state.config.check_supply_invariant {
+            return Err(TradeError::invalid_field("founder_position", "required for a nonzero initial_supply while the supply invariant is checked"));
+        }
+        return Ok(None);
+    }
+    let cost = calculate_smooth_cost(0.0, initial_supply, flat_width, state.config.bonding_curve_epsilon);
+    check_collateral(user_id, cost, state)?;
+    Ok(Some(cost))
+}
 
-    // --- Phase 2: Collateral Check ---
-    let balance = state.user_balances.get(trader_user_id).map_or(INITIAL_BALANCE, |v| *v.value());
-    let realized_pnl = state.user_realized_pnl.get(trader_user_id).map_or(0.0, |v| *v.value());
-    let available_collateral = balance + realized_pnl;
+// Starts the creator's post cooldown, or refuses if the last one hasn't run out.
+// Returns when they last posted, for release_post_cooldown.
+fn claim_post_cooldown(user_id: &str, state: &AppState) -> Result<Option<Instant>, TradeError> {
+    if state.config.post_cooldown_secs == 0 {
+        return Ok(None);
+    }
+    let cooldown = Duration::from_secs(state.config.post_cooldown_secs);
+    let now = Instant::now();
+    match state.user_last_post_at.entry(user_id.to_string()) {
+        Entry::Occupied(mut last) => {
+            let elapsed = now.duration_since(*last.get());
+            if elapsed < cooldown {
+                let retry_after_ms = (cooldown - elapsed).as_millis().max(1) as u64;
+                return Err(TradeError::PostCooldown { retry_after_ms });
+            }
+            Ok(Some(last.insert(now)))
+        }
+        Entry::Vacant(slot) => {
+            slot.insert(now);
+            Ok(None)
+        }
+    }
+}
 
-    // Note: Simplified check
-    if trade_result.effective_cost > available_collateral + EPSILON {
-        send_to_client(client_id, ServerMessage::Error { message: format!("Insufficient collateral {:.6}. Available: {:.6}", trade_result.effective_cost, available_collateral) }, state).await;
+// Undoes claim_post_cooldown for a post that was rejected
+fn release_post_cooldown(user_id: &str, previous_post_at: Option<Instant>, state: &AppState) {
+    if state.config.post_cooldown_secs == 0 {
         return;
     }
-
-    // --- Phase 3: Atomic State Updates ---
-    let mut affected_user_ids = HashSet::new();
-    affected_user_ids.insert(trader_user_id.to_string());
-
-    let final_price;
-    let final_supply = trade_result.final_supply;
-
-    // --- Update Post --- 
-    match state.posts.get_mut(&post_id) {
-        Some(mut post_entry) => {
-            println!("    - Updating Post {}: Initial Supply = {:.6}, Calculated Final Supply = {:.6}", post_id, post_entry.supply, final_supply);
-            let supply_before_update = post_entry.supply; // Store pre-update value for logging
-            post_entry.supply = final_supply;
-            final_price = get_price(final_supply);
-            post_entry.price = Some(final_price);
-            println!("    - Post {} updated: Supply Before = {:.6}, Supply After = {:.6}, Final Price = {:.6}", post_id, supply_before_update, post_entry.supply, final_price);
-        },
-        None => { eprintln!("Critical Error: Post {} disappeared during trade processing.", post_id); return; }
+    match previous_post_at {
+        Some(previous) => { state.user_last_post_at.insert(user_id.to_string(), previous); }
+        None => { state.user_last_post_at.remove(user_id); }
     }
+}
 
-    // --- Update Trader State --- 
-    let trader_rpnl_change = -trade_result.effective_cost; 
-    println!("handle_buy: Updating trader state...");
-    { // Scope for user_positions access
-        let mut trader_pos_map = state.user_positions.entry(trader_user_id.to_string()).or_default();
-        let mut trader_pos = trader_pos_map.entry(post_id).or_default();
-
-        trader_pos.size += quantity;
-        trader_pos.total_cost_basis += trade_result.effective_cost; // Simplification
-        println!("handle_buy: Updated trader position: Size={:.4}, Basis={:.4}", trader_pos.size, trader_pos.total_cost_basis);
-
-        if trader_pos.size.abs() < EPSILON {
-            trader_pos.size = 0.0;
-            trader_pos.total_cost_basis = 0.0;
-            println!("handle_buy: Trader position size near zero, reset basis.");
-        }
-    } // Locks on user_positions released here
-    println!("handle_buy: Finished user_positions update scope.");
+// Key for the duplicate-content index: posts differing only in surrounding whitespace
+// count as the same content
+pub(crate) fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.trim().hash(&mut hasher);
+    hasher.finish()
+}
 
-    { // Scope for user_realized_pnl access
-         println!("handle_buy: Updating user_realized_pnl...");
-        state.user_realized_pnl.entry(trader_user_id.to_string())
-            .and_modify(|rpnl| *rpnl += trader_rpnl_change)
-            .or_insert(trader_rpnl_change);
-         println!("handle_buy: user_realized_pnl updated by {:.4}.", trader_rpnl_change);
-    } // Lock on user_realized_pnl released here
-     println!("handle_buy: Finished user_realized_pnl update scope.");
+// A single post's current market state
+fn handle_get_post(post_id: Uuid, state: &AppState) -> Result<ServerMessage, TradeError> {
+    let post = state.posts.get(&post_id)
+        .map(|post_entry| post_entry.value().clone())
+        .ok_or(TradeError::PostNotFound { post_id })?;
 
-    // Update Trader Exposure 
-    let new_total_exposure = calculate_total_exposure(trader_user_id, state);
-    state.user_exposure.insert(trader_user_id.to_string(), new_total_exposure);
-    println!("handle_buy: Updated trader exposure to {:.4}.", new_total_exposure);
+    let holders = state.holder_stats(post_id);
+    let detail = ServerMessage::PostDetail {
+        post,
+        volume: state.post_volumes.get(&post_id).map_or(0.0, |v| *v.value()),
+        open_interest: holders.open_interest,
+        holder_count: holders.holders,
+        top_holder_share: holders.top_holder_share,
+        liquidation_threshold_count: state.liquidation_thresholds.get(&post_id).map_or(0, |m| m.len()),
+    };
+    Ok(detail)
+}
 
-    println!("handle_buy: Updating liquidated users (if any)...");
-    // --- Update Liquidated Users --- 
-    for (liquidated_user_id, forced_trade_pnl) in &trade_result.liquidated_users {
-        println!("   - Processing state update for liquidated user: {}", liquidated_user_id);
-        affected_user_ids.insert(liquidated_user_id.clone());
-        let mut liq_pos_removed = false;
-
-        if let Some(mut liq_pos_map) = state.user_positions.get_mut(liquidated_user_id) {
-             if liq_pos_map.remove(&post_id).is_some() {
-                 liq_pos_removed = true;
-                 println!("     - Removed position for post {}", post_id);
-             } else {
-                 println!("     - Warning: Position for post {} not found for liquidated user {}.", post_id, liquidated_user_id);
-             }
-        } else {
-            println!("     - Warning: Position map not found for liquidated user {}.", liquidated_user_id);
-        }
+// A post's liquidation thresholds as computed by update_liquidation_thresholds, for
+// operators checking them against observed fills. Exposes other users' ids, hence
+// admin only.
+fn handle_get_liquidation_ladder(user_id: &str, post_id: Uuid, state: &AppState) -> Result<ServerMessage, TradeError> {
+    require_admin(user_id, state)?;
+    let (current_supply, flat_width) = state.posts.get(&post_id)
+        .map(|post| (post.supply, post.flat_width))
+        .ok_or(TradeError::PostNotFound { post_id })?;
+    // No entry means no open positions, so an empty ladder
+    let levels = state.liquidation_thresholds.get(&post_id).map_or_else(Vec::new, |ladder| {
+        ladder.iter()
+            .map(|(supply, entries)| LadderLevel {
+                supply: supply.0,
+                price: get_price(supply.0, flat_width, state.config.bonding_curve_epsilon),
+                entries: entries.iter()
+                    .map(|entry| LadderEntry { user_id: entry.user_id.clone(), cost_unwind: entry.cost_unwind, size_unwind: entry.size_unwind })
+                    .collect(),
+            })
+            .collect()
+    });
+    Ok(ServerMessage::LiquidationLadder { post_id, current_supply, levels })
+}
 
-        if liq_pos_removed { // Only update PnL if position was confirmed removed
-            state.user_realized_pnl.entry(liquidated_user_id.clone())
-                .and_modify(|rpnl| *rpnl += forced_trade_pnl)
-                .or_insert(*forced_trade_pnl);
-            println!("     - Updated RPnL by {:.4}", forced_trade_pnl);
-        } 
-        
-        // Reset exposure (simplistic)
-        state.user_exposure.entry(liquidated_user_id.clone()).and_modify(|exp| *exp = 0.0).or_insert(0.0);
-        println!("     - Reset exposure for user {}", liquidated_user_id);
+// Prices a trade like calculate_effective_cost_and_final_supply. When the post's ladder is
+// cut to a supply range (Config::threshold_grace_distance) and the trade would leave it,
+// the full ladder is registered first so no threshold on the way is missed; the next
+// recompute after the trade cuts it back. This writes the ladder, so only the post's
+// market actor may call it; everything else quotes with quote_trade.
+fn price_trade(
+    start_supply: f64,
+    trade_quantity: f64,
+    post_id: Uuid,
+    trader_user_id: Option<&str>,
+    liquidation_cap: usize, // See calculate_effective_cost_along_ladder
+    state: &AppState,
+) -> Result<EffectiveTradeResult, TradeError> {
+    let registered = || state.liquidation_thresholds.get(&post_id).map(|ladder| ladder.clone()).unwrap_or_default();
+    let result = calculate_effective_cost_along_ladder(start_supply, trade_quantity, post_id, trader_user_id, registered(), liquidation_cap, state)?;
+    if within_threshold_window(post_id, start_supply, &result, state) {
+        return Ok(result);
     }
-    println!("handle_buy: Finished updating liquidated users.");
+    tracing::info!(%post_id, start_supply, trade_quantity, "price_trade: trade leaves the registered threshold range, registering the full ladder");
+    register_liquidation_thresholds(post_id, None, state);
+    calculate_effective_cost_along_ladder(start_supply, trade_quantity, post_id, trader_user_id, registered(), liquidation_cap, state)
+}
 
-    // --- Phase 4: Post-Trade Updates & Broadcasts ---
-    // Thresholds update is now called from handle_client_message AFTER the handler returns
+// price_trade for callers outside the post's market actor (simulations, batch preflight).
+// A trade leaving the registered range is priced against a full ladder computed locally,
+// so a quote never overwrites the ladder the actor maintains.
+fn quote_trade(start_supply: f64, trade_quantity: f64, post_id: Uuid, trader_user_id: Option<&str>, state: &AppState) -> Result<EffectiveTradeResult, TradeError> {
+    let result = calculate_effective_cost_and_final_supply(start_supply, trade_quantity, post_id, trader_user_id, state)?;
+    if within_threshold_window(post_id, start_supply, &result, state) {
+        return Ok(result);
+    }
+    let full_ladder = compute_liquidation_thresholds(post_id, None, state).map(|computed| computed.ladder).unwrap_or_default();
+    let cap = state.config.max_liquidations_per_trade;
+    calculate_effective_cost_along_ladder(start_supply, trade_quantity, post_id, trader_user_id, full_ladder, cap, state)
+}
 
-    // Log Results
-            println!(
-        "-> Buy OK (Qty: {:.6}, EffCost: {:.6}): Post {} -> Supply: {:.6}, Prc: {:.6}. Liqs: {}",
-        quantity, trade_result.effective_cost, post_id, final_supply, final_price, trade_result.liquidated_users.len()
-    );
+// Whether a priced trade starts and ends inside the post's registered threshold range
+fn within_threshold_window(post_id: Uuid, start_supply: f64, result: &EffectiveTradeResult, state: &AppState) -> bool {
+    state.threshold_windows.get(&post_id).is_none_or(|window| {
+        let (low, high) = *window;
+        [start_supply, result.final_supply].iter().all(|supply| (low..=high).contains(supply))
+    })
+}
 
-    // Broadcast Market Updates 
-    println!("handle_buy: Broadcasting market updates...");
-    broadcast_market_and_position_updates(post_id, final_price, final_supply, client_id, state).await;
-    println!("handle_buy: Returned from market update broadcast.");
+// Stress test: prices a trade taking the post from its current supply to `target_supply`
+// with the same segmented walk a real trade uses, but applies nothing. A trade executed
+// right after, with no other trade in between, fills exactly as predicted.
+fn handle_simulate_cascade(user_id: &str, post_id: Uuid, target_supply: f64, state: &AppState) -> Result<ServerMessage, TradeError> {
+    require_admin(user_id, state)?;
+    if !target_supply.is_finite() {
+        return Err(TradeError::invalid_field("target_supply", "must be a finite number"));
+    }
+    let start_supply = state.posts.get(&post_id)
+        .map(|post| post.supply)
+        .ok_or(TradeError::PostNotFound { post_id })?;
+    let simulated = quote_trade(start_supply, target_supply - start_supply, post_id, None, state)?;
 
-    // Send UserSync Updates to all affected users
-    println!("handle_buy: Preparing UserSync client map...");
-    let client_map: HashMap<String, Uuid> = state.clients.iter()
-        .map(|entry| (entry.value().user_id.clone(), *entry.key()))
+    let liquidations: Vec<LadderEntry> = simulated.liquidated_users.iter()
+        .map(|l| LadderEntry { user_id: l.user_id.clone(), cost_unwind: l.cost_unwind, size_unwind: l.size_unwind })
         .collect();
-    println!("handle_buy: Client map created (size: {}). Sending UserSync updates...", client_map.len());
-
-    for user_id in affected_user_ids {
-        if let Some(affected_client_id) = client_map.get(&user_id) {
-            println!("   - Sending UserSync update to affected User {} (Client {})", user_id, affected_client_id);
-            let state_clone = state.clone(); 
-            let user_id_clone = user_id.clone();
-            let affected_client_id_clone = *affected_client_id;
-            // Add a small delay before accessing state again in send_user_sync_update
-            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
-            send_user_sync_update(&user_id_clone, affected_client_id_clone, &state_clone).await;
-             println!("   - Returned from send_user_sync_update for User {} (Client {})", user_id, affected_client_id);
+    Ok(ServerMessage::CascadeSimulation {
+        post_id,
+        start_supply,
+        final_supply: simulated.final_supply,
+        forced_trade_cost: liquidations.iter().map(|l| l.cost_unwind).sum(),
+        liquidations,
+        total_cost: simulated.effective_cost,
+    })
+}
+
+// Operator requests are limited to Config::admin_users
+fn require_admin(user_id: &str, state: &AppState) -> Result<(), TradeError> {
+    if state.config.admin_users.iter().any(|admin| admin == user_id) {
+        Ok(())
+    } else {
+        Err(TradeError::AdminOnly)
+    }
+}
+
+// Adds or removes a post from the client's MarketUpdate subscriptions
+fn handle_subscribe(client_id: Uuid, post_id: Uuid, subscribe: bool, state: &AppState) -> Result<(), TradeError> {
+    if subscribe && !state.posts.contains_key(&post_id) {
+        return Err(TradeError::PostNotFound { post_id });
+    }
+    if let Some(mut client) = state.clients.get_mut(&client_id) {
+        if subscribe {
+            client.subscriptions.insert(post_id);
         } else {
-            println!("   - Skipping UserSync update for User {} (offline?)", user_id);
+            client.subscriptions.remove(&post_id);
         }
+        println!("Client {} {} post {} ({} subscriptions)", client_id, if subscribe { "subscribed to" } else { "unsubscribed from" }, post_id, client.subscriptions.len());
+    }
+    Ok(())
+}
+
+async fn handle_buy(
+    client_id: Uuid,
+    trader_user_id: &str,
+    post_id: Uuid,
+    quantity: f64,
+    allow_flip: bool,
+    max_cost: Option<f64>,
+    state: &AppState,
+) -> Result<ServerMessage, TradeError> {
+    let quantity = normalize_precision("quantity", quantity, state)?;
+    if quantity <= state.config.epsilon {
+        return Err(TradeError::invalid_field("quantity", format!("Buy quantity ({:.6}) must be positive", quantity)));
+    }
+    if max_cost.is_some_and(|max_cost| !max_cost.is_finite()) {
+        return Err(TradeError::invalid_field("max_cost", "must be a finite number"));
     }
-    println!("handle_buy: Finished sending UserSync updates loop. Returning from handle_buy normally.");
+    submit_trade(client_id, trader_user_id, post_id, quantity, allow_flip, max_cost, state).await
 }
 
 async fn handle_sell(
@@ -445,228 +739,3764 @@ async fn handle_sell(
     trader_user_id: &str,
     post_id: Uuid,
     quantity: f64,
+    allow_flip: bool,
+    min_proceeds: Option<f64>,
     state: &AppState,
-) {
-    if quantity <= EPSILON { send_to_client(client_id, ServerMessage::Error { message: "Sell quantity must be positive".to_string() }, state).await; return; }
+) -> Result<ServerMessage, TradeError> {
+    let quantity = normalize_precision("quantity", quantity, state)?;
+    if quantity <= state.config.epsilon {
+        return Err(TradeError::invalid_field("quantity", "Sell quantity must be positive"));
+    }
+    if min_proceeds.is_some_and(|min_proceeds| !min_proceeds.is_finite()) {
+        return Err(TradeError::invalid_field("min_proceeds", "must be a finite number"));
+    }
     let trade_quantity = -quantity; // Internal representation
-    ensure_user_state_exists(trader_user_id, state);
-
-    // --- Phase 1: Read Initial State & Calculate Effective Trade ---
-    let initial_supply = match state.posts.get(&post_id) { 
-        Some(post_entry) => post_entry.supply, 
-        None => { send_to_client(client_id, ServerMessage::Error { message: format!("Post {} not found", post_id) }, state).await; return; }
-    };
-    let trade_result = match calculate_effective_cost_and_final_supply(initial_supply, trade_quantity, post_id, state) { 
-        Ok(result) => result, 
-        Err(e) => { send_to_client(client_id, ServerMessage::Error { message: format!("Trade calculation error: {}", e) }, state).await; return; }
-    };
-
-    // --- Phase 2: Collateral & Position Checks ---
-    let balance = state.user_balances.get(trader_user_id).map_or(INITIAL_BALANCE, |v| *v.value());
-    let realized_pnl = state.user_realized_pnl.get(trader_user_id).map_or(0.0, |v| *v.value());
-    let available_collateral = balance + realized_pnl;
+    // Proceeds are a negative cost, so the minimum proceeds are a maximum cost
+    submit_trade(client_id, trader_user_id, post_id, trade_quantity, allow_flip, min_proceeds.map(|min| -min), state).await
+}
 
-    if trade_result.effective_cost > available_collateral + EPSILON { 
-        send_to_client(client_id, ServerMessage::Error { message: format!("Insufficient collateral {:.6}. Available: {:.6}", trade_result.effective_cost, available_collateral) }, state).await; 
-        return;
+// Applies Config::quantity_decimals to an incoming quantity or amount: values within the
+// allowed precision pass through, others are rejected or rounded per quantity_precision
+fn normalize_precision(field: &str, value: f64, state: &AppState) -> Result<f64, TradeError> {
+    let Some(decimals) = state.config.quantity_decimals else { return Ok(value) };
+    if !value.is_finite() {
+        return Err(TradeError::invalid_field(field, "must be a finite number"));
     }
+    let scale = 10f64.powi(decimals as i32);
+    let scaled = value * scale;
+    // Tolerates the binary representation error of decimals like 0.1
+    if (scaled - scaled.round()).abs() <= 1e-9 * scaled.abs().max(1.0) {
+        return Ok(scaled.round() / scale);
+    }
+    match state.config.quantity_precision {
+        PrecisionPolicy::Reject => Err(TradeError::invalid_field(field, format!("{} has more than {} decimal places", value, decimals))),
+        PrecisionPolicy::Snap => Ok(scaled.round() / scale),
+    }
+}
 
-    // --- Phase 3: Atomic State Updates (similar scoping as handle_buy) --- 
-    let mut affected_user_ids = HashSet::new();
-    affected_user_ids.insert(trader_user_id.to_string());
-    let final_price;
-    let final_supply = trade_result.final_supply;
-
-    // Update Post (scope implicitly handled by get_mut)
-    match state.posts.get_mut(&post_id) { 
-         Some(mut post_entry) => {
-            println!("    - Updating Post {}: Initial Supply = {:.6}, Calculated Final Supply = {:.6}", post_id, post_entry.supply, final_supply);
-            let supply_before_update = post_entry.supply; 
-            post_entry.supply = final_supply;
-            final_price = get_price(final_supply);
-            post_entry.price = Some(final_price);
-            println!("    - Post {} updated: Supply Before = {:.6}, Supply After = {:.6}, Final Price = {:.6}", post_id, supply_before_update, post_entry.supply, final_price);
-        },
-        None => { eprintln!("Critical Error: Post {} disappeared during trade processing.", post_id); return; }
-    }; 
+// Runs `trade` unless the user already traded with this idempotency key, in which case
+// the original confirmation is returned (see idempotency.rs). Reusing a key for a trade
+// that doesn't match `fingerprint` is an error. Without a key the trade always runs.
+async fn execute_once(
+    user_id: &str,
+    idempotency_key: Option<String>,
+    fingerprint: TradeFingerprint,
+    trade: impl Future<Output = Result<ServerMessage, TradeError>>,
+    state: &AppState,
+) -> Result<ServerMessage, TradeError> {
+    let Some(key) = idempotency_key else { return trade.await };
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return Err(TradeError::invalid_field("idempotency_key", format!("must be 1 to {} bytes", MAX_IDEMPOTENCY_KEY_LEN)));
+    }
+    let keys = &state.trade_idempotency_keys;
+    let ttl = Duration::from_secs(state.config.idempotency_key_ttl_secs);
+    if let Claim::Replay(reply) = keys.claim(user_id, &key, fingerprint, ttl, state.config.idempotency_key_cap)? {
+        println!("execute_once: Replaying trade for user {} with idempotency key '{}'", user_id, key);
+        return Ok(reply);
+    }
+    let result = trade.await;
+    match &result {
+        Ok(reply) => keys.complete(user_id, &key, reply.clone()),
+        Err(_) => keys.release(user_id, &key),
+    }
+    result
+}
 
-    // Update Trader State with scopes
-    let trader_rpnl_change = -trade_result.effective_cost; // Proceeds = -Cost
-    println!("handle_sell: Updating trader state...");
-    {
-        let mut trader_pos_map = state.user_positions.entry(trader_user_id.to_string()).or_default();
-        let mut trader_pos = trader_pos_map.entry(post_id).or_default();
-        let old_size = trader_pos.size;
-        trader_pos.size += trade_quantity; // trade_quantity is negative for sell
-        trader_pos.total_cost_basis += trade_result.effective_cost;
-        println!("handle_sell: Updated trader position: OldSize={:.4}, NewSize={:.4}, BasisChange={:.4}", old_size, trader_pos.size, trade_result.effective_cost);
+// Hands a validated trade to the post's market actor and returns the trader's
+// TradeConfirmation.
+async fn submit_trade(
+    client_id: Uuid,
+    trader_user_id: &str,
+    post_id: Uuid,
+    trade_quantity: f64, // Positive for buy, negative for sell
+    allow_flip: bool,
+    max_cost: Option<f64>,
+    state: &AppState,
+) -> Result<ServerMessage, TradeError> {
+    let fill = fill_trade(client_id, trader_user_id, post_id, trade_quantity, allow_flip, max_cost, state).await?;
+    Ok(ServerMessage::TradeConfirmation {
+        post_id,
+        quantity: fill.quantity,
+        unfilled_quantity: trade_quantity - fill.quantity,
+        effective_cost: fill.effective_cost,
+        fee: fill.fee,
+        final_supply: fill.final_supply,
+        final_price: fill.final_price,
+        liquidations_triggered: fill.liquidations_triggered,
+        liquidation_notional: fill.liquidation_notional,
+        quoted_cost: fill.quoted_cost,
+        executed_cost: fill.effective_cost,
+        slippage: fill.effective_cost - fill.quoted_cost,
+    })
+}
 
-        // Check if position closed and reset basis
-        if trader_pos.size.abs() < EPSILON {
-            println!("handle_sell: Trader position size near zero ({:.6}), resetting basis.", trader_pos.size);
-            trader_pos.size = 0.0; 
-            trader_pos.total_cost_basis = 0.0; 
-        }
+// Waits for warmup to open trading, for at most Config::warmup_hold_ms, then refuses
+// with WarmingUp. A connection's messages are handled one at a time, so a held trade
+// also holds back everything the client sent after it and order is kept. Across
+// connections, held trades run in the order they arrived: each waits its turn on
+// state.warmup_queue and keeps it (the returned HeldTurn) until it has executed.
+async fn await_ready(state: &AppState) -> Result<Option<HeldTurn>, TradeError> {
+    if state.is_ready() {
+        return Ok(None);
+    }
+    let hold = Duration::from_millis(state.config.warmup_hold_ms);
+    if hold.is_zero() {
+        return Err(TradeError::WarmingUp);
     }
-    println!("handle_sell: Finished user_positions update scope.");
+    if state.held_trades.fetch_add(1, std::sync::atomic::Ordering::AcqRel) >= state.config.warmup_hold_cap {
+        state.held_trades.fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
+        return Err(TradeError::WarmingUp);
+    }
+    let deadline = Instant::now() + hold;
+    // Trades ahead in the queue leave by their own, earlier deadlines, so waiting for
+    // the turn needs no timeout of its own
+    let turn = HeldTurn { _turn: state.warmup_queue.clone().lock_owned().await, held_trades: state.held_trades.clone() };
+    if !state.is_ready() {
+        tokio::time::timeout_at(deadline, async {
+            let notified = state.ready_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable(); // Registered before the check, so mark_ready can't slip between
+            if !state.is_ready() {
+                notified.await;
+            }
+        }).await.map_err(|_| TradeError::WarmingUp)?;
+    }
+    Ok(Some(turn))
+}
 
-    {
-         println!("handle_sell: Updating user_realized_pnl...");
-        state.user_realized_pnl.entry(trader_user_id.to_string()) 
-            .and_modify(|rpnl| *rpnl += trader_rpnl_change)
-            .or_insert(trader_rpnl_change);
-        println!("handle_sell: user_realized_pnl updated by {:.4}.", trader_rpnl_change);
+// A held trade's place at the head of the warmup queue; the next held trade goes once
+// this is dropped
+struct HeldTurn {
+    _turn: tokio::sync::OwnedMutexGuard<()>,
+    held_trades: Arc<AtomicUsize>,
+}
+
+impl Drop for HeldTurn {
+    fn drop(&mut self) {
+        self.held_trades.fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
     }
-    println!("handle_sell: Finished user_realized_pnl update scope.");
+}
 
-    // Update Trader Exposure
-    let new_total_exposure = calculate_total_exposure(trader_user_id, state);
-    state.user_exposure.insert(trader_user_id.to_string(), new_total_exposure);
-    println!("handle_sell: Updated trader exposure to {:.4}.", new_total_exposure);
+// Runs a trade on the post's market actor and waits for its fill
+async fn fill_trade(
+    client_id: Uuid,
+    trader_user_id: &str,
+    post_id: Uuid,
+    trade_quantity: f64, // Positive for buy, negative for sell
+    allow_flip: bool,
+    max_cost: Option<f64>,
+    state: &AppState,
+) -> Result<TradeFill, TradeError> {
+    let _turn = await_ready(state).await?;
+    let market = market_handle(post_id, state)?;
+    // A post's creator never changes, so checking before the actor queues the trade is enough
+    check_self_trade(trader_user_id, post_id, state)?;
+    let start_time = Instant::now();
+    let result = market.trade(client_id, trader_user_id, trade_quantity, allow_flip, max_cost).await;
+    let duration = start_time.elapsed();
+    state.metrics.trade_duration.observe(duration);
+    println!("submit_trade: Trade on post {} took {:?}", post_id, duration);
 
-    println!("handle_sell: Updating liquidated users (if any)...");
-    for (liquidated_user_id, forced_trade_pnl) in &trade_result.liquidated_users {
-        affected_user_ids.insert(liquidated_user_id.clone());
-        let mut liq_pos_removed = false;
-        if let Some(mut liq_pos_map) = state.user_positions.get_mut(liquidated_user_id) {
-             if liq_pos_map.remove(&post_id).is_some() { liq_pos_removed = true; println!("     - Removed liq position for post {}", post_id); } 
-        } 
-        if liq_pos_removed { 
-            state.user_realized_pnl.entry(liquidated_user_id.clone())
-                .and_modify(|rpnl| *rpnl += forced_trade_pnl)
-                .or_insert(*forced_trade_pnl); 
-            println!("     - Updated liq RPnL by {:.4}", forced_trade_pnl);
-        } 
-        state.user_exposure.entry(liquidated_user_id.clone()).and_modify(|exp| *exp = 0.0).or_insert(0.0);
-        println!("     - Reset liq exposure for user {}", liquidated_user_id);
-    }
-    println!("handle_sell: Finished updating liquidated users.");
-
-    // --- Phase 4: Post-Trade Updates & Broadcasts --- 
-    println!(
-        "-> Sell OK (Qty: {:.6}, EffProceeds: {:.6}): Post {} -> Supply: {:.6}, Prc: {:.6}. Liqs: {}",
-        quantity, -trade_result.effective_cost, 
-        post_id, final_supply, final_price, trade_result.liquidated_users.len()
+    let fill = result?;
+    tracing::info!(
+        %post_id, user_id = trader_user_id, effective_cost = fill.effective_cost, final_supply = fill.final_supply,
+        final_price = fill.final_price, liquidations = fill.liquidations_triggered, "submit_trade: trade filled"
     );
+    Ok(fill)
+}
 
-    println!("handle_sell: Broadcasting market updates...");
-    broadcast_market_and_position_updates(post_id, final_price, final_supply, client_id, state).await;
-    println!("handle_sell: Returned from market update broadcast.");
+// Config::forbid_self_trade: creators may not trade their own posts
+fn check_self_trade(trader_user_id: &str, post_id: Uuid, state: &AppState) -> Result<(), TradeError> {
+    if state.config.forbid_self_trade && state.posts.get(&post_id).is_some_and(|post| post.user_id == trader_user_id) {
+        return Err(TradeError::SelfTradeForbidden { post_id });
+    }
+    Ok(())
+}
 
-    // Send UserSync Updates
-    println!("handle_sell: Preparing UserSync client map...");
-    let client_map: HashMap<String, Uuid> = state.clients.iter()
-        .map(|entry| (entry.value().user_id.clone(), *entry.key()))
-        .collect();
-    println!("handle_sell: Client map created (size: {}). Sending UserSync updates...", client_map.len());
-
-    for user_id in affected_user_ids {
-        if let Some(affected_client_id) = client_map.get(&user_id) {
-            println!("   - Sending UserSync update to affected User {} (Client {})", user_id, affected_client_id);
-            let state_clone = state.clone();
-            let user_id_clone = user_id.clone();
-            let affected_client_id_clone = *affected_client_id;
-            // Add a small delay before accessing state again in send_user_sync_update
-            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
-            send_user_sync_update(&user_id_clone, affected_client_id_clone, &state_clone).await;
-            println!("   - Returned from send_user_sync_update for User {} (Client {})", user_id, affected_client_id);
-        } else {
-             println!("   - Skipping UserSync update for User {} (offline?)", user_id);
-        }
+// The post's market actor. Clones the handle out so no map guard is held while
+// waiting on the actor.
+fn market_handle(post_id: Uuid, state: &AppState) -> Result<MarketHandle, TradeError> {
+    if let Some(handle) = state.markets.get(&post_id) {
+        return Ok(handle.value().clone());
+    }
+    match state.posts.get(&post_id) {
+        Some(post) if post.settlement_price.is_some() => Err(TradeError::MarketClosed { post_id }),
+        _ => Err(TradeError::PostNotFound { post_id }),
     }
-    println!("handle_sell: Finished sending UserSync updates loop. Returning from handle_sell normally.");
 }
 
-// Function to recalculate and update liquidation thresholds for a post
-async fn update_liquidation_thresholds(post_id: Uuid, state: &AppState) {
-    let start_time = Instant::now();
-    println!("--- Entering update_liquidation_thresholds for Post: {} ---", post_id);
+// Closes the creator's market through its actor, so the settlement can't interleave
+// with a trade on the post
+async fn handle_close_own_post(client_id: Uuid, user_id: &str, post_id: Uuid, state: &AppState) -> Result<(), TradeError> {
+    let _turn = await_ready(state).await?;
+    let market = market_handle(post_id, state)?;
+    let closed = market.settle(client_id, user_id).await?;
+    tracing::info!(%post_id, user_id, positions_closed = closed, "handle_close_own_post: market settled");
+    Ok(())
+}
 
-    // Temporary map to store user-specific thresholds before aggregating
-    // Key: s_liq (as OrderedFloat), Value: Vec<(cost_unwind, size_unwind, user_id)>
-    let mut aggregated_thresholds: BTreeMap<OrderedFloat<f64>, Vec<(f64, f64, String)>> = BTreeMap::new();
+// Executes a BatchTrade's legs in order, each succeeding or failing on its own unless the
+// batch is all_or_nothing (see execute_batch_exclusively)
+async fn handle_batch_trade(
+    client_id: Uuid,
+    user_id: &str,
+    legs: Vec<TradeLeg>,
+    all_or_nothing: bool,
+    state: &AppState,
+) -> Result<ServerMessage, TradeError> {
+    if legs.is_empty() {
+        return Err(TradeError::invalid_field("trades", "A batch needs at least one trade"));
+    }
+    if legs.len() > MAX_BATCH_LEGS {
+        return Err(TradeError::invalid_field("trades", format!("A batch holds at most {} trades, got {}", MAX_BATCH_LEGS, legs.len())));
+    }
+    let _turn = await_ready(state).await?;
+    let legs = legs.into_iter()
+        .map(|leg| Ok(TradeLeg { quantity: normalize_precision("quantity", leg.quantity, state)?, ..leg }))
+        .collect::<Result<Vec<TradeLeg>, TradeError>>()?;
 
-    println!("update_liquidation_thresholds: Starting Phase 1 - Iterating user positions...");
-    // --- Phase 1: Calculate individual user liquidation points & data ---
+    if all_or_nothing {
+        return Ok(execute_batch_exclusively(client_id, user_id, &legs, state).await);
+    }
+    let mut results = Vec::with_capacity(legs.len());
+    for leg in &legs {
+        results.push(match submit_leg(client_id, user_id, leg, state).await {
+            Ok(fill) => filled_leg(leg, &fill),
+            Err(error) => LegResult::Failed { error },
+        });
+    }
+    Ok(ServerMessage::BatchResult { results, compensated: false })
+}
+
+// Runs an all_or_nothing batch in one critical section. The trading gate is held for
+// writing, so no other trade, liquidation or settlement runs between the legs, and the
+// legs execute here rather than on their market actors (which wait on the gate). The
+// whole batch is priced first (see preflight_batch) and nothing is applied unless every
+// leg prices. A leg can still fail after that when an earlier leg's forced unwinds moved
+// a ladder the preflight priced against; the filled legs are then compensated with
+// opposite trades at the then current price. Compensation is not a rollback: it books
+// its own PnL and fees, and liquidations the filled legs triggered stay done.
+async fn execute_batch_exclusively(client_id: Uuid, user_id: &str, legs: &[TradeLeg], state: &AppState) -> ServerMessage {
+    let _gate = state.trading_gate.write().await;
+    if let Err((failed_index, error)) = preflight_batch(user_id, legs, state) {
+        println!("handle_batch_trade: Batch of {} for user {} refused at leg {}: {}", legs.len(), user_id, failed_index, error);
+        let results = (0..legs.len())
+            .map(|index| if index == failed_index { LegResult::Failed { error: error.clone() } } else { LegResult::NotExecuted })
+            .collect();
+        return ServerMessage::BatchResult { results, compensated: false };
+    }
+
+    let mut results = Vec::with_capacity(legs.len());
+    let mut fills = Vec::with_capacity(legs.len()); // (post, filled quantity) of each leg so far
+    for (index, leg) in legs.iter().enumerate() {
+        let executed = execute_trade_exclusively(client_id, user_id, leg.post_id, leg.signed_quantity(), leg.allow_flip, None, state).await;
+        // A leg the liquidation cap truncated fails the batch too; its partial fill is compensated
+        let executed = executed.and_then(|fill| {
+            let truncated = (fill.quantity - leg.signed_quantity()).abs() > state.config.epsilon;
+            fills.push((leg.post_id, fill.quantity));
+            if truncated { Err(TradeError::LiquidationCapReached { cap: state.config.max_liquidations_per_trade }) } else { Ok(fill) }
+        });
+        match executed {
+            Ok(fill) => results.push(filled_leg(leg, &fill)),
+            Err(error) => {
+                results.push(LegResult::Failed { error });
+                results.extend(legs[index + 1..].iter().map(|_| LegResult::NotExecuted));
+                let compensated = compensate_legs(client_id, user_id, &fills, &mut results, state).await;
+                return ServerMessage::BatchResult { results, compensated };
+            }
+        }
+    }
+    ServerMessage::BatchResult { results, compensated: false }
+}
+
+fn filled_leg(leg: &TradeLeg, fill: &TradeFill) -> LegResult {
+    LegResult::Filled {
+        post_id: leg.post_id,
+        quantity: fill.quantity,
+        effective_cost: fill.effective_cost,
+        fee: fill.fee,
+        final_supply: fill.final_supply,
+        final_price: fill.final_price,
+        liquidations_triggered: fill.liquidations_triggered,
+    }
+}
+
+async fn submit_leg(client_id: Uuid, user_id: &str, leg: &TradeLeg, state: &AppState) -> Result<TradeFill, TradeError> {
+    if leg.quantity <= state.config.epsilon {
+        return Err(TradeError::invalid_field("quantity", format!("Trade quantity ({:.6}) must be positive", leg.quantity)));
+    }
+    fill_trade(client_id, user_id, leg.post_id, leg.signed_quantity(), leg.allow_flip, None, state).await
+}
+
+// A trade executed in place instead of on the post's market actor, for a caller that
+// holds the trading gate for writing and so has every market to itself. Does what
+// fill_trade and the actor do around execute_trade_with_limit.
+async fn execute_trade_exclusively(
+    client_id: Uuid,
+    trader_user_id: &str,
+    post_id: Uuid,
+    trade_quantity: f64,
+    allow_flip: bool,
+    max_cost: Option<f64>,
+    state: &AppState,
+) -> Result<TradeFill, TradeError> {
+    market_handle(post_id, state)?; // Refused like a queued trade on a missing or closed market
+    check_self_trade(trader_user_id, post_id, state)?;
+    let start_time = Instant::now();
+    let result = execute_trade_with_limit(client_id, trader_user_id, post_id, trade_quantity, allow_flip, max_cost, state).await;
+    if result.is_ok() {
+        update_liquidation_thresholds(post_id, state).await;
+    }
+    state.metrics.trade_duration.observe(start_time.elapsed());
+    result
+}
+
+// Simulates an all_or_nothing batch against the current state without applying it:
+// each leg is checked and priced as if the legs before it had filled, and the collateral
+// check counts their costs. A leg the liquidation cap would truncate fails. Liquidations
+// an earlier leg would trigger are not simulated.
+// Returns the index and error of the first leg that would fail.
+fn preflight_batch(user_id: &str, legs: &[TradeLeg], state: &AppState) -> Result<(), (usize, TradeError)> {
+    let mut supplies: HashMap<Uuid, f64> = HashMap::new();
+    let mut sizes: HashMap<Uuid, f64> = HashMap::new();
+    let mut committed_cost = 0.0;
+
+    for (index, leg) in legs.iter().enumerate() {
+        let fail = |error: TradeError| (index, error);
+        if leg.quantity <= state.config.epsilon {
+            return Err(fail(TradeError::invalid_field("quantity", format!("Trade quantity ({:.6}) must be positive", leg.quantity))));
+        }
+        let post_id = leg.post_id;
+        let trade_quantity = leg.signed_quantity();
+        let supply = match supplies.get(&post_id) {
+            Some(supply) => *supply,
+            None => match state.posts.get(&post_id) {
+                Some(post) if state.markets.contains_key(&post_id) => post.supply,
+                _ => return Err(fail(TradeError::PostNotFound { post_id })),
+            },
+        };
+        let size = *sizes.entry(post_id).or_insert_with(|| position_size(user_id, post_id, state));
+
+        check_position_rules(size, trade_quantity, leg.allow_flip, state).map_err(fail)?;
+        check_supply_cap(post_id, Some(supply), trade_quantity, state).map_err(fail)?;
+        let trade_result = quote_trade(supply, trade_quantity, post_id, Some(user_id), state).map_err(fail)?;
+        // Under LiquidationCapPolicy::Truncate the leg would only partly fill, which an
+        // all_or_nothing batch can't accept
+        if trade_result.unfilled_quantity.abs() > state.config.epsilon {
+            return Err(fail(TradeError::LiquidationCapReached { cap: state.config.max_liquidations_per_trade }));
+        }
+        let cost = trade_result.effective_cost + trade_fee(user_id, supply, trade_quantity, fee_base(&trade_result, state), state);
+        check_collateral(user_id, committed_cost + cost, state).map_err(fail)?;
+
+        committed_cost += cost;
+        supplies.insert(post_id, trade_result.final_supply);
+        sizes.insert(post_id, size + trade_quantity);
+    }
+    Ok(())
+}
+
+// Reverses the filled legs of a failed all_or_nothing batch with opposite trades of the
+// quantity each one actually filled, last first, marking each one Compensated. Called
+// with the trading gate held for writing. Returns whether every one of them was reversed.
+async fn compensate_legs(client_id: Uuid, user_id: &str, fills: &[(Uuid, f64)], results: &mut [LegResult], state: &AppState) -> bool {
+    let mut all_compensated = true;
+    for (index, (post_id, filled_quantity)) in fills.iter().enumerate().rev() {
+        // Unlimited: compensation must go through whatever it costs
+        match execute_trade_exclusively(client_id, user_id, *post_id, -filled_quantity, true, Some(f64::INFINITY), state).await {
+            // A truncated leg keeps its Failed result; only whole fills read as Compensated
+            Ok(_) if matches!(results[index], LegResult::Filled { .. }) => results[index] = LegResult::Compensated,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("handle_batch_trade: Failed to compensate leg {} on post {} for user {}: {}", index, post_id, user_id, e);
+                all_compensated = false;
+            }
+        }
+    }
+    all_compensated
+}
+
+// Result of a trade executed by a market actor
+#[derive(Debug, Clone)]
+pub struct TradeFill {
+    pub effective_cost: f64,
+    pub fee: f64, // Trading fee charged on top of effective_cost; 0 for forced fills
+    pub final_supply: f64,
+    pub final_price: f64,
+    pub liquidations_triggered: usize, // Threshold liquidations the fill crossed
+    pub liquidation_notional: f64, // Summed absolute cost of those forced unwinds
+    pub quoted_cost: f64, // The trader's quantity alone along the smooth curve, no thresholds
+    pub quantity: f64, // Filled, signed; short of the request when the liquidation cap halted the trade
+}
+
+// Executes a trade against a post. Must only be called from that post's market actor,
+// which guarantees trades on the same post never interleave. A trade larger than the
+// trader's opposite position is rejected unless `allow_flip` is set.
+pub async fn execute_trade(
+    client_id: Uuid,
+    trader_user_id: &str,
+    post_id: Uuid,
+    trade_quantity: f64, // Positive for buy, negative for sell
+    allow_flip: bool,
+    state: &AppState,
+) -> Result<TradeFill, TradeError> {
+    // Checked here rather than in handle_buy/handle_sell so the position and supply can't
+    // change in between
+    execute_trade_with_limit(client_id, trader_user_id, post_id, trade_quantity, allow_flip, None, state).await
+}
+
+// execute_trade, rejecting the fill with SlippageExceeded if its effective cost (signed,
+// fees excluded) is above `max_cost`. Without a limit, Config::default_slippage_tolerance
+// sets one from the smooth-curve quote.
+pub async fn execute_trade_with_limit(
+    client_id: Uuid,
+    trader_user_id: &str,
+    post_id: Uuid,
+    trade_quantity: f64, // Positive for buy, negative for sell
+    allow_flip: bool,
+    max_cost: Option<f64>,
+    state: &AppState,
+) -> Result<TradeFill, TradeError> {
+    check_position_rules(position_size(trader_user_id, post_id, state), trade_quantity, allow_flip, state)?;
+    check_supply_cap(post_id, None, trade_quantity, state)?;
+    execute_fill(client_id, trader_user_id, post_id, trade_quantity, FillKind::Trade { max_cost }, state).await
+}
+
+// Rejects a fill whose effective cost is above the trade's limit (see execute_trade_with_limit)
+fn check_cost_limit(quoted_cost: f64, effective_cost: f64, max_cost: Option<f64>, state: &AppState) -> Result<(), TradeError> {
+    let limit = match (max_cost, state.config.default_slippage_tolerance) {
+        (Some(max_cost), _) => max_cost,
+        (None, Some(tolerance)) => quoted_cost + tolerance * quoted_cost.abs(),
+        (None, None) => return Ok(()),
+    };
+    if effective_cost > limit + state.config.epsilon {
+        return Err(TradeError::SlippageExceeded { quoted_cost, effective_cost, limit });
+    }
+    Ok(())
+}
+
+// Rejects a trade the trader's current position doesn't allow: a sell beyond the long
+// position while shorting is disabled, or a flip without `allow_flip`
+fn check_position_rules(position_size: f64, trade_quantity: f64, allow_flip: bool, state: &AppState) -> Result<(), TradeError> {
+    if !state.config.allow_shorts && trade_quantity < 0.0 && -trade_quantity > position_size.max(0.0) + state.config.epsilon {
+        return Err(TradeError::invalid_field(
+            "quantity",
+            format!("Shorting is disabled; you can sell at most {:.6}", position_size.max(0.0)),
+        ));
+    }
+    if !allow_flip && flip_closing_quantity(position_size, trade_quantity, state.config.epsilon).is_some() {
+        return Err(TradeError::invalid_field(
+            "quantity",
+            format!("Trade of {:.6} would flip the position of {:.6}; close it first or set allow_flip", trade_quantity, position_size),
+        ));
+    }
+    Ok(())
+}
+
+// Rejects a buy that would take the post's supply (its current supply unless
+// `from_supply` is given) above its cap. The error names the largest buy that fits.
+fn check_supply_cap(post_id: Uuid, from_supply: Option<f64>, trade_quantity: f64, state: &AppState) -> Result<(), TradeError> {
+    if trade_quantity <= 0.0 {
+        return Ok(());
+    }
+    let Some(post) = state.posts.get(&post_id) else { return Ok(()) }; // Reported by the fill
+    let cap = post.max_supply.unwrap_or(state.config.max_supply);
+    let supply = from_supply.unwrap_or(post.supply);
+    if cap > 0.0 && supply + trade_quantity > cap + state.config.epsilon {
+        return Err(TradeError::invalid_field(
+            "quantity",
+            format!("Supply is capped at {:.6}; you can buy at most {:.6}", cap, (cap - supply).max(0.0)),
+        ));
+    }
+    Ok(())
+}
+
+// Rejects a voluntary trade costing more than the trader's available collateral
+// (balance + trading cash), or any trade while they are pending liquidation. A trade
+// that spends cash must also leave the collateral reserve untouched.
+fn check_collateral(user_id: &str, cost: f64, state: &AppState) -> Result<(), TradeError> {
+    if state.under_margined.contains(user_id) {
+        return Err(TradeError::rejected("Account is under maintenance margin and being liquidated"));
+    }
+
+    let available_collateral = available_collateral(user_id, state);
+    // Trades bringing cash in (closing a long, opening a short) aren't held to the reserve
+    let reserve = if cost > 0.0 {
+        collateral_reserve(state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |v| *v.value()), state)
+    } else {
+        0.0
+    };
+
+    // Note: Simplified check
+    let spendable = available_collateral - reserve;
+    if cost > spendable + state.config.epsilon {
+        return Err(TradeError::InsufficientCollateral { required: cost, available: spendable, shortfall: cost - spendable });
+    }
+    Ok(())
+}
+
+// Credits a deposit (positive `amount`) or debits a withdrawal (negative) to the user's
+// balance, and returns the new balance. This is the only place state.user_balances
+// changes after account creation; each change pushes one BalanceUpdate to the user.
+// Withdrawals are held to the same collateral check as a buy of the same cost.
+pub async fn adjust_balance(user_id: &str, amount: f64, state: &AppState) -> Result<f64, TradeError> {
+    let amount = normalize_precision("amount", amount, state)?;
+    if !amount.is_finite() || amount.abs() <= state.config.epsilon {
+        return Err(TradeError::invalid_field("amount", format!("amount ({}) must be a non-zero number", amount)));
+    }
+    ensure_user_state_exists(user_id, state)?;
+    // Held like a fill, so a snapshot never sees the balance half way; released before the
+    // recompute below, which the market actors run under the gate themselves
+    let balance = {
+        let _gate = state.trading_gate.read().await;
+        if amount < 0.0 {
+            check_collateral(user_id, -amount, state)?;
+        }
+        let mut balance = state.user_balances.entry(user_id.to_string()).or_insert(INITIAL_BALANCE);
+        *balance += amount;
+        *balance
+    };
+    println!("adjust_balance: Balance of user {} moved by {:.6} to {:.6}", user_id, amount, balance);
+    send_to_user(user_id, ServerMessage::BalanceUpdate { balance }, state).await;
+
+    // Collateral moved, so the user's liquidation supplies did too. Each post's ladder is
+    // rewritten by its own market actor, never from here.
+    let markets: Vec<MarketHandle> = state.user_positions.get(user_id)
+        .map(|positions| positions.iter().filter_map(|entry| state.markets.get(entry.key()).map(|m| m.value().clone())).collect())
+        .unwrap_or_default();
+    for market in markets {
+        if let Err(e) = market.recompute_thresholds().await {
+            eprintln!("adjust_balance: A threshold recompute failed: {}", e);
+        }
+    }
+    Ok(balance)
+}
+
+// Admin only: credits a deposit to or debits a withdrawal from another user's balance,
+// e.g. when the payments backend confirms a transfer
+async fn handle_adjust_balance(user_id: &str, target_user_id: &str, amount: f64, state: &AppState) -> Result<ServerMessage, TradeError> {
+    require_admin(user_id, state)?;
+    let balance = adjust_balance(target_user_id, amount, state).await?;
+    tracing::info!(%user_id, %target_user_id, amount, balance, "balance adjusted by admin");
+    Ok(ServerMessage::BalanceAdjusted { user_id: target_user_id.to_string(), balance })
+}
+
+// Balance plus net trading cash
+fn available_collateral(user_id: &str, state: &AppState) -> f64 {
+    let balance = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |v| *v.value());
+    let cash = state.user_cash.get(user_id).map_or(0.0, |v| *v.value());
+    balance + cash
+}
+
+// Collateral a cash-spending trade must leave in place (Config::min_collateral_reserve)
+fn collateral_reserve(balance: f64, state: &AppState) -> f64 {
+    state.config.min_collateral_reserve.max(state.config.collateral_reserve_rate * balance)
+}
+
+// Fee on a voluntary trade of the given cost, at the rate of the trader's volume tier.
+// The volume doesn't include the trade itself, so crossing a tier lowers the next fee.
+// With Config::maker_rebate_rate, a trade leaving the post's supply closer to zero than
+// `start_supply` gets the rebate instead, as a negative fee.
+fn trade_fee(user_id: &str, start_supply: f64, trade_quantity: f64, effective_cost: f64, state: &AppState) -> f64 {
+    if let Some(rate) = state.config.maker_rebate_rate {
+        if (start_supply + trade_quantity).abs() < start_supply.abs() {
+            return -rate * effective_cost.abs();
+        }
+    }
+    let volume = state.user_volumes.get(user_id).map_or(0.0, |v| *v.value());
+    state.config.fee_tiers.rate_for(volume) * effective_cost.abs()
+}
+
+// The part of a fill's cost the trader's fee is charged on: all of it, unless forced
+// unwinds have their own fee (Config::liquidation_fee_rate), in which case only the
+// trader's own segments
+fn fee_base(trade_result: &EffectiveTradeResult, state: &AppState) -> f64 {
+    match state.config.liquidation_fee_rate {
+        Some(_) => trade_result.effective_cost - trade_result.liquidated_users.iter().map(|l| l.cost_unwind).sum::<f64>(),
+        None => trade_result.effective_cost,
+    }
+}
+
+// Fee on a forced unwind of the given absolute notional (Config::liquidation_fee_rate)
+fn liquidation_fee(notional: f64, state: &AppState) -> f64 {
+    state.config.liquidation_fee_rate.map_or(0.0, |rate| rate * notional)
+}
+
+// Splits a collected fee between the post's creator, its insurance fund and the
+// protocol (Config::creator_fee_share / protocol_fee_share) and records it in the fee
+// totals. The creator's share is paid to them as realized PnL; returns the creator when
+// they were paid.
+fn distribute_fee(post_id: Uuid, fee: f64, state: &AppState) -> Option<String> {
+    let creator_id = state.posts.get(&post_id).map(|post| post.user_id.clone());
+    let creator = match &creator_id {
+        Some(_) => fee * state.config.creator_fee_share,
+        None => 0.0,
+    };
+    let protocol = fee * state.config.protocol_fee_share;
+    let split = FeeTotals { total: fee, creator, insurance: fee - creator - protocol, protocol };
+
+    *state.insurance_fund.entry(post_id).or_insert(0.0) += split.insurance;
+    state.post_fee_totals.entry(post_id).or_default().add(&split);
+    state.metrics.fees_collected.add(split.total);
+    state.metrics.fees_to_creators.add(split.creator);
+    state.metrics.fees_to_insurance.add(split.insurance);
+    state.metrics.fees_to_protocol.add(split.protocol);
+
+    let creator_id = creator_id.filter(|_| creator > 0.0)?;
+    *state.user_cash.entry(creator_id.clone()).or_insert(0.0) += creator;
+    book_realized_pnl(&creator_id, creator, state);
+    Some(creator_id)
+}
+
+// Fees collected on a post, or summed over every post
+fn handle_get_fee_totals(user_id: &str, post_id: Option<Uuid>, state: &AppState) -> Result<ServerMessage, TradeError> {
+    require_admin(user_id, state)?;
+    let totals = match post_id {
+        Some(post_id) => {
+            if !state.posts.contains_key(&post_id) {
+                return Err(TradeError::PostNotFound { post_id });
+            }
+            state.post_fee_totals.get(&post_id).map(|totals| *totals).unwrap_or_default()
+        }
+        None => state.post_fee_totals.iter().fold(FeeTotals::default(), |mut sum, entry| {
+            sum.add(entry.value());
+            sum
+        }),
+    };
+    Ok(ServerMessage::FeeTotals { post_id, totals })
+}
+
+// Operational repair: reruns update_liquidation_thresholds for one post, or for every
+// open market with at most MAX_CONCURRENT_THRESHOLD_RECOMPUTES running at once. Each
+// recompute runs on the post's market actor, so it never races a trade on that post.
+async fn handle_recompute_thresholds(user_id: &str, post_id: Option<Uuid>, state: &AppState) -> Result<ServerMessage, TradeError> {
+    require_admin(user_id, state)?;
+    let markets: Vec<MarketHandle> = match post_id {
+        Some(post_id) => vec![market_handle(post_id, state)?],
+        None => state.markets.iter().map(|entry| entry.value().clone()).collect(),
+    };
+    let posts = markets.len();
+    let entries: usize = stream::iter(markets)
+        .map(|market| async move { market.recompute_thresholds().await })
+        .buffer_unordered(MAX_CONCURRENT_THRESHOLD_RECOMPUTES)
+        .map(|recomputed| recomputed.unwrap_or_else(|e| {
+            eprintln!("handle_recompute_thresholds: A recompute failed: {}", e);
+            0
+        }))
+        .fold(0, |total, count| async move { total + count })
+        .await;
+    tracing::info!(%user_id, ?post_id, posts, entries, "liquidation thresholds recomputed by admin");
+    Ok(ServerMessage::ThresholdsRecomputed { post_id, posts, entries })
+}
+
+// Migration tool: seeds positions directly, each paid for out of its holder's cash at
+// its cost basis as if bought, then refreshes exposure and the touched posts' thresholds.
+// Every entry is checked before anything is written, and trading is paused throughout.
+async fn handle_import_positions(
+    user_id: &str,
+    positions: Vec<PositionImport>,
+    adjust_supply: bool,
+    state: &AppState,
+) -> Result<ServerMessage, TradeError> {
+    require_admin(user_id, state)?;
+    let _pause = state.trading_gate.write().await;
+
+    // --- Validate ---
+    let epsilon = state.config.epsilon;
+    let mut seen = HashSet::new();
+    let mut net_positions: HashMap<Uuid, f64> = HashMap::new(); // Touched post -> net position after the import
+    for (i, import) in positions.iter().enumerate() {
+        let field = |name: &str| format!("positions[{}].{}", i, name);
+        if !import.size.is_finite() || import.size.abs() <= epsilon {
+            return Err(TradeError::invalid_field(&field("size"), "must be a nonzero finite number"));
+        }
+        if !import.cost_basis.is_finite() || import.cost_basis * import.size <= 0.0 {
+            return Err(TradeError::invalid_field(&field("cost_basis"), "must be finite and have the sign of size"));
+        }
+        match state.posts.get(&import.post_id) {
+            Some(post) if post.settlement_price.is_some() => return Err(TradeError::MarketClosed { post_id: import.post_id }),
+            Some(_) => {}
+            None => return Err(TradeError::PostNotFound { post_id: import.post_id }),
+        }
+        if state.config.strict_users && !state.user_profiles.contains(&import.user_id) {
+            return Err(TradeError::UnknownUser { user_id: import.user_id.clone() });
+        }
+        if position_size(&import.user_id, import.post_id, state).abs() > epsilon || !seen.insert((import.user_id.as_str(), import.post_id)) {
+            return Err(TradeError::invalid_field(
+                &field("user_id"),
+                format!("{} already holds a position on post {}", import.user_id, import.post_id),
+            ));
+        }
+        *net_positions.entry(import.post_id).or_insert_with(|| net_position(import.post_id, state)) += import.size;
+    }
+    if !adjust_supply {
+        for (post_id, net) in &net_positions {
+            let supply = state.posts.get(post_id).map_or(0.0, |post| post.supply);
+            if (supply - net).abs() > epsilon * supply.abs().max(1.0) {
+                return Err(TradeError::invalid_field("positions", format!(
+                    "Post {} would have a net position of {} but its supply is {}; pass adjust_supply to move its supply", post_id, net, supply
+                )));
+            }
+        }
+    }
+
+    // --- Apply ---
+    for import in &positions {
+        ensure_user_state_exists(&import.user_id, state)?; // Profiles were checked above
+        state.user_positions
+            .entry(import.user_id.clone())
+            .or_default()
+            .insert(import.post_id, UserPositionDetail { size: import.size, total_cost_basis: import.cost_basis });
+        *state.user_cash.entry(import.user_id.clone()).or_insert(0.0) -= import.cost_basis;
+        state.user_exposure.insert(import.user_id.clone(), calculate_total_exposure(&import.user_id, state));
+    }
+    for (&post_id, &net) in &net_positions {
+        state.holder_stats_cache.remove(&post_id);
+        if adjust_supply {
+            let moved = state.posts.get_mut(&post_id).and_then(|mut post| {
+                let moved = (post.supply - net).abs() > epsilon;
+                post.set_supply(net, state.config.bonding_curve_epsilon);
+                moved.then_some(post.price)
+            });
+            if let Some(price) = moved {
+                broadcast_market_update(post_id, price, net, state).await;
+            }
+        }
+        update_liquidation_thresholds(post_id, state).await;
+    }
+    println!("handle_import_positions: Imported {} positions on {} posts.", positions.len(), net_positions.len());
+    Ok(ServerMessage::PositionsImported { positions: positions.len(), posts: net_positions.len() })
+}
+
+// Summed signed size of every position on a post
+fn net_position(post_id: Uuid, state: &AppState) -> f64 {
+    state.user_positions.iter()
+        .filter_map(|entry| entry.value().get(&post_id).map(|position| position.size))
+        .sum()
+}
+
+// Signed size of a user's position on a post (0 when they have none)
+fn position_size(user_id: &str, post_id: Uuid, state: &AppState) -> f64 {
+    state.user_positions.get(user_id)
+        .and_then(|positions| positions.get(&post_id).map(|p| p.size))
+        .unwrap_or(0.0)
+}
+
+// If the trade crosses the position through zero, the part of it that closes the
+// position (the rest opens the other side)
+fn flip_closing_quantity(position_size: f64, trade_quantity: f64, epsilon: f64) -> Option<f64> {
+    let is_flip = position_size.abs() > epsilon
+        && position_size.signum() != trade_quantity.signum()
+        && trade_quantity.abs() > position_size.abs() + epsilon;
+    is_flip.then_some(-position_size)
+}
+
+// Closes a user's whole position on a post because their margin fell below maintenance
+// (see margin_sweep.rs). Same market actor requirement as execute_trade.
+pub async fn execute_margin_liquidation(user_id: &str, post_id: Uuid, state: &AppState) -> Result<TradeFill, TradeError> {
+    let size = position_size(user_id, post_id, state);
+    if size.abs() <= state.config.epsilon {
+        return Err(TradeError::rejected(format!("User {} has no position on post {} to liquidate", user_id, post_id)));
+    }
+    execute_fill(Uuid::nil(), user_id, post_id, -size, FillKind::MarginLiquidation, state).await
+}
+
+// Settles a post at its current curve price: every holder's position (the creator's
+// included) is closed at that price with the PnL booked, and the market stops trading.
+// Only the creator may do this. Same market actor requirement as execute_trade.
+// Returns the number of positions closed.
+pub async fn execute_settlement(client_id: Uuid, requester_user_id: &str, post_id: Uuid, state: &AppState) -> Result<usize, TradeError> {
+    let price = {
+        let mut post = state.posts.get_mut(&post_id).ok_or(TradeError::PostNotFound { post_id })?;
+        if post.user_id != requester_user_id {
+            return Err(TradeError::NotPostCreator { post_id });
+        }
+        if post.settlement_price.is_some() {
+            return Err(TradeError::MarketClosed { post_id });
+        }
+        let price = post.price;
+        post.settlement_price = Some(price);
+        post.set_supply(0.0, state.config.bonding_curve_epsilon); // Every position is closed below
+        price
+    };
+    state.markets.remove(&post_id); // New trades now fail with MarketClosed
+    state.liquidation_thresholds.remove(&post_id);
+    state.threshold_windows.remove(&post_id);
+    // A settled post no longer counts toward the creator's max_posts_per_user
+    if let Some(mut post_count) = state.user_post_counts.get_mut(requester_user_id) {
+        *post_count = post_count.saturating_sub(1);
+    }
+
+    // Collect first so no user_positions shard lock is held while booking
+    let holders: Vec<(String, UserPositionDetail)> = state.user_positions.iter()
+        .filter_map(|entry| entry.value().get(&post_id).map(|position| (entry.key().clone(), position.clone())))
+        .collect();
+    let mut affected_user_ids = HashSet::new();
+    for (holder_id, position) in &holders {
+        if let Some(positions) = state.user_positions.get(holder_id) {
+            positions.remove(&post_id);
+        }
+        remove_empty_position_map(holder_id, state);
+        // Longs are paid size * price, shorts buy back at it
+        let proceeds = position.size * price;
+        *state.user_cash.entry(holder_id.clone()).or_insert(0.0) += proceeds;
+        book_realized_pnl(holder_id, proceeds - position.total_cost_basis, state);
+        state.user_exposure.insert(holder_id.clone(), calculate_total_exposure(holder_id, state));
+        println!("execute_settlement: Closed {} on post {} ({:.6} @ {:.6}), PnL {:.6}", holder_id, post_id, position.size, price, proceeds - position.total_cost_basis);
+        affected_user_ids.insert(holder_id.clone());
+    }
+    state.holder_stats_cache.remove(&post_id);
+
+    if !state.config.dry_run || state.config.dry_run_broadcasts {
+        broadcast_message(ServerMessage::PostSettled { post_id, price, positions_closed: holders.len() }, state).await;
+        send_post_trade_syncs(post_id, client_id, &affected_user_ids, state).await;
+    }
+    Ok(holders.len())
+}
+
+// Why a fill is happening
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FillKind {
+    Trade { max_cost: Option<f64> }, // Voluntary, subject to the collateral check and its cost limit
+    MarginLiquidation, // Forced close of an under-margined position; penalized, never rejected
+}
+
+async fn execute_fill(
+    client_id: Uuid,
+    trader_user_id: &str,
+    post_id: Uuid,
+    trade_quantity: f64, // Positive for buy, negative for sell
+    kind: FillKind,
+    state: &AppState,
+) -> Result<TradeFill, TradeError> {
+    ensure_user_state_exists(trader_user_id, state)?;
+
+    // --- Phase 1: Read Initial State & Calculate Effective Trade ---
+    let (initial_supply, flat_width) = match state.posts.get(&post_id) {
+        // A trade queued before the settlement removed the market handle
+        Some(post_entry) if post_entry.settlement_price.is_some() => return Err(TradeError::MarketClosed { post_id }),
+        Some(post_entry) => (post_entry.supply, post_entry.flat_width),
+        None => return Err(TradeError::PostNotFound { post_id }),
+    };
+
+    // Forced fills are exempt from the liquidation cap: a margin liquidation is never refused
+    // or cut short
+    let liquidation_cap = match kind {
+        FillKind::Trade { .. } => state.config.max_liquidations_per_trade,
+        FillKind::MarginLiquidation => 0,
+    };
+    let trade_result = price_trade(initial_supply, trade_quantity, post_id, Some(trader_user_id), liquidation_cap, state)?;
+    // The liquidation cap may halt the trade early; from here on only the filled part counts
+    let trade_quantity = trade_quantity - trade_result.unfilled_quantity;
+    let quoted_cost = calculate_smooth_cost(initial_supply, initial_supply + trade_quantity, flat_width, state.config.bonding_curve_epsilon);
+    // The trader's own thresholds are skipped during pricing, so the fill below is the only
+    // change to their position; liquidating them here too would unwind it twice
+    debug_assert!(
+        trade_result.liquidated_users.iter().all(|l| l.user_id != trader_user_id),
+        "trader {} priced into their own liquidation on post {}", trader_user_id, post_id
+    );
+
+    // (quantity, cost) of the leg that closes the trader's position when the fill flips
+    // it. The path up to the crossing is a prefix of the full trade's path, so pricing it
+    // on its own gives exactly the cost of the first part of the fill.
+    let closing_leg = match flip_closing_quantity(position_size(trader_user_id, post_id, state), trade_quantity, state.config.epsilon) {
+        Some(closing_qty) => {
+            let closing = calculate_effective_cost_and_final_supply(initial_supply, closing_qty, post_id, Some(trader_user_id), state)?;
+            Some((closing_qty, closing.effective_cost))
+        }
+        None => None,
+    };
+
+    // --- Phase 2: Collateral Check ---
+    // The same fee is checked here and charged below
+    let fee = match kind {
+        FillKind::Trade { .. } => trade_fee(trader_user_id, initial_supply, trade_quantity, fee_base(&trade_result, state), state),
+        FillKind::MarginLiquidation => liquidation_fee(trade_result.effective_cost.abs(), state),
+    };
+    if let FillKind::Trade { max_cost } = kind {
+        check_cost_limit(quoted_cost, trade_result.effective_cost, max_cost, state)?;
+        check_collateral(trader_user_id, trade_result.effective_cost + fee, state)?;
+    }
+
+    // --- Phase 3: State Updates (serialized per post by the market actor) ---
+    let mut affected_user_ids = HashSet::new();
+    affected_user_ids.insert(trader_user_id.to_string());
+
+    let final_price;
+    let final_supply = trade_result.final_supply;
+
+    // --- Update Post ---
+    match state.posts.get_mut(&post_id) {
+        Some(mut post_entry) => {
+            println!("    - Updating Post {}: Initial Supply = {:.6}, Calculated Final Supply = {:.6}", post_id, post_entry.supply, final_supply);
+            let supply_before_update = post_entry.supply; // Store pre-update value for logging
+            post_entry.set_supply(final_supply, state.config.bonding_curve_epsilon);
+            final_price = post_entry.price;
+            println!("    - Post {} updated: Supply Before = {:.6}, Supply After = {:.6}, Final Price = {:.6}", post_id, supply_before_update, post_entry.supply, final_price);
+        },
+        None => {
+            eprintln!("Critical Error: Post {} disappeared during trade processing.", post_id);
+            return Err(TradeError::PostNotFound { post_id });
+        }
+    }
+
+    let traded_volume = trade_quantity.abs()
+        + trade_result.liquidated_users.iter().map(|l| l.size_unwind.abs()).sum::<f64>();
+    *state.post_volumes.entry(post_id).or_insert(0.0) += traded_volume;
+
+    // --- Update Trader State ---
+    // Cash moves by the full cost; realized PnL only by the part of the fill that closes.
+    // A fill through zero is split at the crossing: the closing leg is priced over the
+    // curve up to the crossing supply and realizes against the old basis, the opening
+    // leg pays the rest and becomes the new position's basis.
+    println!("execute_trade: Updating trader state...");
+    let trader_rpnl_change = { // Scope for user_positions access
+        let trader_pos_map = state.user_positions.entry(trader_user_id.to_string()).or_default();
+        let mut trader_pos = trader_pos_map.entry(post_id).or_default();
+        let old_size = trader_pos.size;
+        let realized = match closing_leg {
+            Some((closing_qty, closing_cost)) => {
+                let closed = apply_fill(&mut trader_pos, closing_qty, closing_cost, state.config.epsilon);
+                closed + apply_fill(&mut trader_pos, trade_quantity - closing_qty, trade_result.effective_cost - closing_cost, state.config.epsilon)
+            }
+            None => apply_fill(&mut trader_pos, trade_quantity, trade_result.effective_cost, state.config.epsilon),
+        };
+        println!("execute_trade: Updated trader position: OldSize={:.4}, NewSize={:.4}, Basis={:.4}", old_size, trader_pos.size, trader_pos.total_cost_basis);
+        realized
+    }; // Locks on user_positions released here
+
+    // The fee is a realized loss for the trader, split by distribute_fee
+    *state.user_cash.entry(trader_user_id.to_string()).or_insert(0.0) -= trade_result.effective_cost + fee;
+    book_realized_pnl(trader_user_id, trader_rpnl_change - fee, state);
+    record_trade(trader_user_id, TradeRecord {
+        seq: 0, // Assigned by record_trade
+        executed_at: Utc::now(),
+        post_id,
+        quantity: trade_quantity,
+        price: trade_result.effective_cost / trade_quantity,
+        cost: trade_result.effective_cost,
+        fee,
+        realized_pnl: trader_rpnl_change - fee,
+        forced: kind == FillKind::MarginLiquidation,
+    }, state);
+    println!("execute_trade: user_cash updated by {:.4}, user_realized_pnl by {:.4}.", -(trade_result.effective_cost + fee), trader_rpnl_change - fee);
+    if matches!(kind, FillKind::Trade { .. }) {
+        *state.user_volumes.entry(trader_user_id.to_string()).or_insert(0.0) += trade_quantity.abs();
+    }
+    if fee > 0.0 {
+        if let Some(creator_id) = distribute_fee(post_id, fee, state) {
+            affected_user_ids.insert(creator_id);
+        }
+    } else if fee < 0.0 {
+        state.metrics.rebates_paid.add(-fee); // A maker rebate, paid by the protocol
+    }
+
+    // Update Trader Exposure
+    let new_total_exposure = calculate_total_exposure(trader_user_id, state);
+    state.user_exposure.insert(trader_user_id.to_string(), new_total_exposure);
+    println!("execute_trade: Updated trader exposure to {:.4}.", new_total_exposure);
+
+    // --- Update Liquidated Users ---
+    let mut liquidation_events = Vec::new();
+    let mut socialized_losses: Vec<(String, f64)> = Vec::new();
+    if kind == FillKind::MarginLiquidation {
+        // The fill above already booked the forced close and zeroed the position
+        if let Some(positions) = state.user_positions.get(trader_user_id) {
+            positions.remove(&post_id);
+        }
+        remove_empty_position_map(trader_user_id, state);
+        // Charge the penalty on top
+        let (event, charges) = settle_liquidation(
+            trader_user_id, post_id, trade_quantity, trader_rpnl_change - fee, trade_result.effective_cost.abs(), final_price, state,
+        );
+        liquidation_events.push(event);
+        for (charged_user_id, amount) in charges {
+            affected_user_ids.insert(charged_user_id.clone());
+            socialized_losses.push((charged_user_id, amount));
+        }
+    }
+    for liquidation in &trade_result.liquidated_users {
+        let liquidated_user_id = &liquidation.user_id;
+        println!("   - Processing state update for liquidated user: {}", liquidated_user_id);
+        affected_user_ids.insert(liquidated_user_id.clone());
+        let mut liq_pos_unwound = false;
+
+        // Unwind exactly what the threshold registered, so the position moves by the same
+        // size as supply did; anything beyond the registered size stays open
+        if let Some(liq_pos_map) = state.user_positions.get(liquidated_user_id) {
+             let remaining_size = liq_pos_map.get_mut(&post_id).map(|mut position| {
+                 position.size += liquidation.size_unwind;
+                 position.total_cost_basis -= liquidation.cost_basis;
+                 position.size
+             });
+             match remaining_size {
+                 Some(size) if size.abs() < state.config.epsilon => {
+                     liq_pos_map.remove(&post_id);
+                     println!("     - Removed position for post {}", post_id);
+                 }
+                 Some(size) => println!("     - Unwound position for post {}, {:.4} left open", post_id, size),
+                 None => println!("     - Warning: Position for post {} not found for liquidated user {}.", post_id, liquidated_user_id),
+             }
+             liq_pos_unwound = remaining_size.is_some();
+        } else {
+            println!("     - Warning: Position map not found for liquidated user {}.", liquidated_user_id);
+        }
+        remove_empty_position_map(liquidated_user_id, state);
+
+        if liq_pos_unwound { // Only update PnL if the position was found and unwound
+            let unwind_fee = liquidation_fee(liquidation.notional(), state);
+            book_realized_pnl(liquidated_user_id, liquidation.forced_trade_pnl - unwind_fee, state);
+            record_trade(liquidated_user_id, TradeRecord {
+                seq: 0, // Assigned by record_trade
+                executed_at: Utc::now(),
+                post_id,
+                quantity: liquidation.size_unwind,
+                price: liquidation.cost_unwind / liquidation.size_unwind,
+                cost: liquidation.cost_unwind,
+                fee: unwind_fee,
+                realized_pnl: liquidation.forced_trade_pnl - unwind_fee,
+                forced: true,
+            }, state);
+            *state.user_cash.entry(liquidated_user_id.clone()).or_insert(0.0) -= liquidation.cost_unwind + unwind_fee;
+            if unwind_fee > 0.0 {
+                if let Some(creator_id) = distribute_fee(post_id, unwind_fee, state) {
+                    affected_user_ids.insert(creator_id);
+                }
+            }
+
+            let (event, charges) = settle_liquidation(
+                liquidated_user_id, post_id, liquidation.size_unwind, liquidation.forced_trade_pnl - unwind_fee, liquidation.notional(), final_price, state,
+            );
+            liquidation_events.push(event);
+            for (charged_user_id, amount) in charges {
+                affected_user_ids.insert(charged_user_id.clone());
+                socialized_losses.push((charged_user_id, amount));
+            }
+        }
+
+        let liq_exposure = calculate_total_exposure(liquidated_user_id, state);
+        state.user_exposure.insert(liquidated_user_id.clone(), liq_exposure);
+        println!("     - Updated exposure for user {} to {:.4}", liquidated_user_id, liq_exposure);
+    }
+
+    // Positions on this post changed; its holder stats are recomputed on next request
+    state.holder_stats_cache.remove(&post_id);
+    if state.config.check_supply_invariant {
+        enforce_supply_invariant(post_id, state);
+    }
+
+    // --- Phase 4: Post-Trade Updates & Broadcasts ---
+    // Thresholds are recomputed by the market actor once this returns.
+    //
+    // Message order is part of the protocol. Every client sees, in this order:
+    //   1. MarketUpdate (to everyone), so the new price arrives before anything derived from it
+    //   2. LiquidationEvent broadcasts, then SocializedLoss to charged users
+    //   3. UserSync / EquityUpdate for affected and holding clients
+    // Each client's messages go through one FIFO channel and this function sends them
+    // sequentially, so the order holds per client. Keep new sends within these steps.
+
+    println!(
+        "-> {} OK (Qty: {:.6}, EffCost: {:.6}): Post {} -> Supply: {:.6}, Prc: {:.6}. Liqs: {}",
+        if trade_quantity > 0.0 { "Buy" } else { "Sell" },
+        trade_quantity.abs(), trade_result.effective_cost, post_id, final_supply, final_price, trade_result.liquidated_users.len()
+    );
+
+    if state.config.dry_run && !state.config.dry_run_broadcasts {
+        println!("execute_trade: Dry run, skipping broadcasts.");
+    } else {
+        // Broadcast Market Updates
+        println!("execute_trade: Broadcasting market updates...");
+        broadcast_market_update(post_id, final_price, final_supply, state).await;
+        for event in liquidation_events {
+            broadcast_message(event, state).await;
+        }
+        // Tell counterparties why their realized PnL dropped; their UserSync follows below
+        for (charged_user_id, amount) in socialized_losses {
+            send_to_user(&charged_user_id, ServerMessage::SocializedLoss { post_id, amount }, state).await;
+        }
+
+        // One UserSync per affected or holding client, now that all state is final
+        send_post_trade_syncs(post_id, client_id, &affected_user_ids, state).await;
+    }
+
+    Ok(TradeFill {
+        effective_cost: trade_result.effective_cost,
+        fee,
+        final_supply,
+        final_price,
+        liquidations_triggered: trade_result.liquidated_users.len(),
+        liquidation_notional: trade_result.liquidated_users.iter().map(|l| l.notional()).sum(),
+        quoted_cost,
+        quantity: trade_quantity,
+    })
+}
+
+// Every fill moves a post's supply and its holders' positions by the same amounts, so
+// the supply must equal the net of all positions on it. Returns (supply, net position)
+// when they differ by more than epsilon (relative to the supply).
+pub fn check_supply_invariant(post_id: Uuid, state: &AppState) -> Result<(), (f64, f64)> {
+    let Some(supply) = state.posts.get(&post_id).map(|post| post.supply) else { return Ok(()) };
+    let net_position = net_position(post_id, state);
+    if (supply - net_position).abs() > state.config.epsilon * supply.abs().max(1.0) {
+        return Err((supply, net_position));
+    }
+    Ok(())
+}
+
+// Reports a broken supply invariant; fatal in tests so accounting bugs can't slip by
+fn enforce_supply_invariant(post_id: Uuid, state: &AppState) {
+    if let Err((supply, net_position)) = check_supply_invariant(post_id, state) {
+        eprintln!("INVARIANT VIOLATION: Post {} supply {:.12} != net position {:.12}", post_id, supply, net_position);
+        if cfg!(test) {
+            panic!("supply invariant violated on post {}: supply {} != net position {}", post_id, supply, net_position);
+        }
+    }
+}
+
+// Charges the liquidation penalty, notifies webhooks and covers any resulting bad debt.
+// The forced trade itself must already be booked to the user's ledgers. Returns the
+// LiquidationEvent to broadcast and the (user_id, amount) charged to counterparties.
+fn settle_liquidation(
+    user_id: &str,
+    post_id: Uuid,
+    size_unwind: f64,
+    forced_trade_pnl: f64,
+    notional: f64,
+    market_price: f64,
+    state: &AppState,
+) -> (ServerMessage, Vec<(String, f64)>) {
+    // Penalty on the unwound notional moves from the user to the post's insurance fund
+    let penalty = notional * state.config.liquidation_penalty_rate;
+    book_transfer(user_id, -penalty, state);
+    *state.insurance_fund.entry(post_id).or_insert(0.0) += penalty;
+    let booked_pnl = forced_trade_pnl - penalty;
+    println!("     - Liquidated {}: RPnL {:.4} (Penalty {:.4} credited to insurance fund)", user_id, booked_pnl, penalty);
+
+    if let Some(webhooks) = state.webhooks.as_ref().filter(|_| !state.config.dry_run) {
+        webhooks.notify_liquidation(LiquidationWebhook::new(post_id, user_id, size_unwind, booked_pnl));
+    }
+    let event = ServerMessage::LiquidationEvent {
+        post_id,
+        user_id: user_id.to_string(),
+        size: size_unwind,
+        realized_pnl: booked_pnl,
+        penalty,
+    };
+
+    // A loss beyond the user's collateral is bad debt that must be covered elsewhere
+    (event, cover_bad_debt(user_id, post_id, market_price, state))
+}
+
+// Covers a liquidated user's negative collateral (bad debt) so value is conserved.
+// The post's insurance fund pays first; any remainder is socialized across users with
+// unrealized profit on the same post, in proportion to that profit, by charging their
+// realized PnL and cash. Returns the (user_id, amount) charged to each counterparty.
+fn cover_bad_debt(
+    bankrupt_user_id: &str,
+    post_id: Uuid,
+    market_price: f64,
+    state: &AppState,
+) -> Vec<(String, f64)> {
+    let balance = state.user_balances.get(bankrupt_user_id).map_or(INITIAL_BALANCE, |v| *v.value());
+    let realized_pnl = state.user_realized_pnl.get(bankrupt_user_id).map_or(0.0, |v| *v.value());
+    let bad_debt = -(balance + realized_pnl);
+    if bad_debt <= state.config.epsilon {
+        return Vec::new();
+    }
+    println!("cover_bad_debt: User {} has bad debt {:.6} on post {}", bankrupt_user_id, bad_debt, post_id);
+
+    // 1. Insurance fund
+    let from_fund = {
+        let mut fund = state.insurance_fund.entry(post_id).or_insert(0.0);
+        let covered = fund.min(bad_debt).max(0.0);
+        *fund -= covered;
+        covered
+    };
+    let mut uncovered = bad_debt - from_fund;
+
+    // 2. Socialize the rest across profitable counterparties
+    let mut charges = Vec::new();
+    if uncovered > state.config.epsilon {
+        let profitable: Vec<(String, f64)> = state.user_positions.iter()
+            .filter(|entry| entry.key() != bankrupt_user_id)
+            .filter_map(|entry| {
+                entry.value().get(&post_id).and_then(|position| {
+                    let profit = calculate_unrealized_pnl(&position, market_price, state.config.epsilon);
+                    (profit > state.config.epsilon).then(|| (entry.key().clone(), profit))
+                })
+            })
+            .collect();
+        let total_profit: f64 = profitable.iter().map(|(_, profit)| profit).sum();
+
+        if total_profit > state.config.epsilon {
+            for (user_id, profit) in profitable {
+                let amount = uncovered * profit / total_profit;
+                book_transfer(&user_id, -amount, state);
+                println!("cover_bad_debt: Charged {:.6} to user {}", amount, user_id);
+                charges.push((user_id, amount));
+            }
+            uncovered = 0.0;
+        } else {
+            eprintln!("Critical Error: No profitable counterparties on post {} to absorb bad debt {:.6}", post_id, uncovered);
+        }
+    }
+
+    // Restore the bankrupt user's collateral by whatever was covered
+    let covered = bad_debt - uncovered;
+    book_transfer(bankrupt_user_id, covered, state);
+    println!("cover_bad_debt: Covered {:.6} (fund {:.6}, socialized {:.6}), uncovered {:.6}", covered, from_fund, covered - from_fund, uncovered);
+
+    charges
+}
+
+// Adds to a user's realized PnL and records the booking in their PnL history. Every
+// change to state.user_realized_pnl after account creation goes through here.
+fn book_realized_pnl(user_id: &str, delta: f64, state: &AppState) {
+    *state.user_realized_pnl.entry(user_id.to_string()).or_insert(0.0) += delta;
+    if delta.abs() <= state.config.epsilon || state.config.pnl_history_cap == 0 {
+        return; // Opening or adding to a position realizes nothing
+    }
+    let mut history = state.user_pnl_history.entry(user_id.to_string()).or_default();
+    if history.len() >= state.config.pnl_history_cap {
+        history.pop_front();
+    }
+    history.push_back((Utc::now(), delta));
+}
+
+// Appends a fill to the user's trade history under the user's next `seq`, dropping the
+// oldest past trade_history_cap
+fn record_trade(user_id: &str, record: TradeRecord, state: &AppState) {
+    if state.config.trade_history_cap == 0 {
+        return;
+    }
+    let mut history = state.user_trade_history.entry(user_id.to_string()).or_default();
+    let seq = history.back().map_or(1, |last| last.seq + 1);
+    if history.len() >= state.config.trade_history_cap {
+        history.pop_front();
+    }
+    history.push_back(TradeRecord { seq, ..record });
+}
+
+// Paging, filtering and ordering options of a GetPositions request
+struct PositionFilter {
+    offset: usize,
+    limit: Option<usize>,
+    only_open: bool,
+    min_size: f64,
+    sort: PositionSort,
+}
+
+// One page of the user's positions that pass the filters
+fn handle_get_positions(user_id: &str, filter: PositionFilter, state: &AppState) -> Result<ServerMessage, TradeError> {
+    let PositionFilter { offset, limit, only_open, min_size, sort } = filter;
+    let limit = limit.unwrap_or(DEFAULT_POSITIONS_PAGE_LIMIT);
+    if limit == 0 {
+        return Err(TradeError::invalid_field("limit", "must be positive"));
+    }
+    if min_size.is_nan() || min_size < 0.0 {
+        return Err(TradeError::invalid_field("min_size", "must be a non-negative number"));
+    }
+
+    let balance = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |v| *v.value());
+    let realized_pnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
+    let mut matching: Vec<PositionDetail> = priced_positions(user_id, &snapshot_prices(&[user_id], state), balance, realized_pnl, !only_open, state)
+        .into_iter()
+        .filter(|detail| detail.size.abs() >= min_size)
+        .collect();
+    sort_positions(&mut matching, sort);
+    let total = matching.len();
+    let positions: Vec<PositionDetail> = matching.into_iter().skip(offset).take(limit).collect();
+    let has_more = offset.saturating_add(positions.len()) < total;
+    Ok(ServerMessage::Positions { positions, offset, total, has_more })
+}
+
+// Replies with the user's realized-PnL bookings, optionally only those after `since`
+fn handle_get_pnl_history(user_id: &str, since: Option<DateTime<Utc>>, state: &AppState) -> ServerMessage {
+    let points = state.user_pnl_history.get(user_id)
+        .map(|history| history.iter()
+            .filter(|(timestamp, _)| since.is_none_or(|since| *timestamp > since))
+            .copied()
+            .collect())
+        .unwrap_or_default();
+    ServerMessage::PnlHistory { points }
+}
+
+// One page of the user's trades older than `before`, newest first
+fn handle_get_trade_history(
+    user_id: &str,
+    post_id: Option<Uuid>,
+    limit: Option<usize>,
+    before: Option<u64>,
+    state: &AppState,
+) -> Result<ServerMessage, TradeError> {
+    let limit = limit.unwrap_or(DEFAULT_TRADE_HISTORY_LIMIT);
+    if limit == 0 {
+        return Err(TradeError::invalid_field("limit", "must be positive"));
+    }
+    let Some(history) = state.user_trade_history.get(user_id) else {
+        return Ok(ServerMessage::TradeHistory { trades: Vec::new(), has_more: false });
+    };
+    let mut matching = history.iter().rev()
+        .filter(|trade| before.is_none_or(|before| trade.seq < before))
+        .filter(|trade| post_id.is_none_or(|post_id| trade.post_id == post_id));
+    let trades: Vec<TradeRecord> = matching.by_ref().take(limit).cloned().collect();
+    let has_more = matching.next().is_some();
+    Ok(ServerMessage::TradeHistory { trades, has_more })
+}
+
+// Drops a user's entry from state.user_positions once their last position is gone, so
+// liquidated users don't leave empty maps behind. Must not be called while holding a
+// guard into state.user_positions.
+fn remove_empty_position_map(user_id: &str, state: &AppState) {
+    if state.user_positions.remove_if(user_id, |_, positions| positions.is_empty()).is_some() {
+        println!("     - Removed empty position map for user {}", user_id);
+    }
+}
+
+// Books a transfer that is not a trade (e.g. a socialized loss) to both ledgers:
+// it is realized profit or loss and moves cash in the same amount.
+fn book_transfer(user_id: &str, amount: f64, state: &AppState) {
+    book_realized_pnl(user_id, amount, state);
+    *state.user_cash.entry(user_id.to_string()).or_insert(0.0) += amount;
+}
+
+// Function to recalculate and update liquidation thresholds for a post. Only thresholds
+// within Config::threshold_grace_distance of the current supply are registered; each
+// trade recomputes them, so the registered range follows the market.
+pub async fn update_liquidation_thresholds(post_id: Uuid, state: &AppState) {
+    let grace_distance = state.config.threshold_grace_distance;
+    register_liquidation_thresholds(post_id, (grace_distance > 0.0).then_some(grace_distance), state);
+}
+
+// A post's liquidation ladder as computed from the current positions, before it is
+// registered
+struct ComputedThresholds {
+    ladder: BTreeMap<OrderedFloat<f64>, Vec<LiquidationEntry>>,
+    window: Option<(f64, f64)>, // Registered supply range; None when every threshold is kept
+    holders: usize, // Users with an open position on the post
+    omitted: usize, // Thresholds left out for lying outside `window`
+}
+
+// Recomputes a post's thresholds, keeping only those within `grace_distance` of the
+// current supply (all of them for None), and records the registered range
+fn register_liquidation_thresholds(post_id: Uuid, grace_distance: Option<f64>, state: &AppState) {
+    let start_time = Instant::now();
+    let Some(ComputedThresholds { ladder, window, holders, omitted }) = compute_liquidation_thresholds(post_id, grace_distance, state) else {
+        return;
+    };
+    println!("update_liquidation_thresholds: Finished Phase 1. Starting Phase 2 - Updating state...");
+
+    // Nobody holds the post any more: drop its entry instead of keeping an empty map
+    if holders == 0 {
+        state.liquidation_thresholds.remove(&post_id);
+        state.threshold_windows.remove(&post_id);
+        state.metrics.threshold_recompute_duration.observe(start_time.elapsed());
+        tracing::info!(%post_id, "update_liquidation_thresholds: no open positions, removed the post's thresholds");
+        return;
+    }
+
+    // --- Phase 2: Update state --- 
+    // Insert the newly calculated map into the shared state
+    state.liquidation_thresholds.insert(post_id, ladder);
+    match window {
+        Some(window) => { state.threshold_windows.insert(post_id, window); }
+        None => { state.threshold_windows.remove(&post_id); }
+    }
+    println!("update_liquidation_thresholds: Inserted aggregated map into state.liquidation_thresholds.");
+
+    let duration = start_time.elapsed();
+    state.metrics.threshold_recompute_duration.observe(duration);
+    let thresholds = state.liquidation_thresholds.get(&post_id).map_or(0, |m| m.len());
+    if duration >= Duration::from_millis(state.config.slow_threshold_recompute_ms) {
+        tracing::warn!(%post_id, ?duration, holders, thresholds, omitted, "update_liquidation_thresholds: slow recompute");
+    } else {
+        tracing::info!(%post_id, ?duration, holders, thresholds, omitted, "update_liquidation_thresholds: thresholds recomputed");
+    }
+}
+
+// Computes a post's thresholds from the current positions and ledgers without
+// registering them; None when the post doesn't exist
+fn compute_liquidation_thresholds(post_id: Uuid, grace_distance: Option<f64>, state: &AppState) -> Option<ComputedThresholds> {
+    // Temporary map to store user-specific thresholds before aggregating
+    // Key: s_liq (as OrderedFloat), Value: Vec<(cost_unwind, size_unwind, cost_basis, user_id)>
+    let mut aggregated_thresholds: BTreeMap<OrderedFloat<f64>, Vec<LiquidationEntry>> = BTreeMap::new();
+    let mut holders = 0;
+    let Some((current_market_price, current_supply, flat_width)) = state.posts.get(&post_id).map(|post| (post.price, post.supply, post.flat_width)) else {
+        println!("update_liquidation_thresholds: Post {} not found, nothing to compute.", post_id);
+        return None;
+    };
+
+    let window = grace_distance.map(|distance| (current_supply - distance, current_supply + distance));
+    let mut omitted = 0;
+
+    println!("update_liquidation_thresholds: Starting Phase 1 - Iterating user positions...");
+    // --- Phase 1: Calculate individual user liquidation points & data ---
     for user_entry in state.user_positions.iter() {
         let user_id = user_entry.key();
         println!("update_liquidation_thresholds: Checking user: {}", user_id);
         if let Some(position) = user_entry.value().get(&post_id) {
             println!("update_liquidation_thresholds: Found position for user {} on post {}: Size={:.4}", user_id, post_id, position.size);
-            if position.size.abs() < EPSILON { continue; }
+            if position.size.abs() < state.config.epsilon { continue; }
+            holders += 1;
+
+            println!("update_liquidation_thresholds: Calculating for user {}: Getting balance/rpnl...", user_id);
+            let balance = state.user_balances.get(user_id).map_or(0.0, |v| *v.value());
+            let rpnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
+            println!("update_liquidation_thresholds: User {}: Bal={:.4}, RPnl={:.4}.", user_id, balance, rpnl);
+            println!("update_liquidation_thresholds: User {}: MarketPrice={:.4}. Calculating avg_price...", user_id, current_market_price);
+
+            let avg_price = calculate_average_price(&position, state.config.epsilon);
+            println!("update_liquidation_thresholds: User {}: AvgPrice={:.4}. Calculating uRPnL...", user_id, avg_price);
+            let total_unrealized_pnl = (current_market_price - avg_price) * position.size;
+            println!("update_liquidation_thresholds: User {}: uRPnL={:.4}. Calculating liquidation supply...", user_id, total_unrealized_pnl);
+
+            if let Some(s_liq) = calculate_liquidation_supply(balance, rpnl, position.size, avg_price, flat_width, state.config.epsilon, state.config.bonding_curve_epsilon) {
+                if window.is_some_and(|(low, high)| s_liq < low || s_liq > high) {
+                    omitted += 1;
+                    continue; // Registered once the market comes within the grace distance
+                }
+                println!("update_liquidation_thresholds: User {}: Calculated s_liq = {:.4}. Calculating unwind...", user_id, s_liq);
+                let forced_trade_size = -position.size;
+                let s_liq_after_unwind = s_liq + forced_trade_size;
+                let cost_unwind = calculate_smooth_cost(s_liq, s_liq_after_unwind, flat_width, state.config.bonding_curve_epsilon);
+                println!("update_liquidation_thresholds: User {}: ForcedSize={:.4}, s_liq_after={:.4}, CostUnwind={:.4}. Adding to map...", user_id, forced_trade_size, s_liq_after_unwind, cost_unwind);
+
+                // Add this user's liquidation data to the aggregation map
+                let supply_key = OrderedFloat(s_liq);
+                aggregated_thresholds.entry(supply_key)
+                    .or_default() // Get Vec or create new one
+                    .push(LiquidationEntry { cost_unwind, size_unwind: forced_trade_size, cost_basis: position.total_cost_basis, user_id: user_id.clone() });
+                println!("update_liquidation_thresholds: User {}: Added entry for s_liq = {:.4}.", user_id, s_liq);
+            } else {
+                println!("update_liquidation_thresholds: User {}: No liquidation supply calculated.", user_id);
+            }
+         } else {
+            println!("update_liquidation_thresholds: User {} has no position on post {}.", user_id, post_id);
+        }
+    }
+
+    // Remove thresholds where the net effect is negligible (optional optimization)
+     aggregated_thresholds.retain(|_, entries| {
+         entries.iter().any(|entry| entry.cost_unwind.abs() > state.config.epsilon || entry.size_unwind.abs() > state.config.epsilon)
+     });
+    println!("update_liquidation_thresholds: Retained {} aggregated thresholds.", aggregated_thresholds.len());
+
+    // Fix the processing order of users sharing a threshold. DashMap iteration order is
+    // arbitrary, and each unwind moves supply for the next one, so the order must be
+    // reproducible: largest position first, ties broken by user id.
+    for entries in aggregated_thresholds.values_mut() {
+        entries.sort_by(liquidation_priority);
+    }
+
+    Some(ComputedThresholds { ladder: aggregated_thresholds, window, holders, omitted })
+}
+
+// Cross-checks a post's registered ladder against the positions and ledgers it was
+// computed from: every entry must be a current holder's forced unwind at their
+// liquidation supply, in liquidation_priority order, and every holder whose liquidation
+// supply lies in the registered window must be on the ladder. Returns the first
+// mismatch found.
+pub fn ladder_inconsistency(post_id: Uuid, state: &AppState) -> Option<String> {
+    let flat_width = state.posts.get(&post_id)?.flat_width;
+    let ladder = state.liquidation_thresholds.get(&post_id).map(|ladder| ladder.clone()).unwrap_or_default();
+    let window = state.threshold_windows.get(&post_id).map(|window| *window);
+    let epsilon = state.config.bonding_curve_epsilon;
+
+    let mut registered = HashSet::new();
+    for (s_liq, entries) in &ladder {
+        if entries.windows(2).any(|pair| liquidation_priority(&pair[0], &pair[1]) == Ordering::Greater) {
+            return Some(format!("entries at supply {} are out of priority order", s_liq));
+        }
+        for LiquidationEntry { cost_unwind, size_unwind, cost_basis, user_id } in entries {
+            let Some(position) = state.user_positions.get(user_id).and_then(|positions| positions.get(&post_id).map(|p| p.clone())) else {
+                return Some(format!("{} is on the ladder without a position", user_id));
+            };
+            if *size_unwind != -position.size {
+                return Some(format!("{} unwinds {} but holds {}", user_id, size_unwind, position.size));
+            }
+            if *cost_basis != position.total_cost_basis {
+                return Some(format!("{} is registered with basis {} but holds basis {}", user_id, cost_basis, position.total_cost_basis));
+            }
+            let balance = state.user_balances.get(user_id).map_or(0.0, |v| *v.value());
+            let rpnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
+            let expected = calculate_liquidation_supply(balance, rpnl, position.size, calculate_average_price(&position, state.config.epsilon), flat_width, state.config.epsilon, state.config.bonding_curve_epsilon);
+            if expected != Some(s_liq.0) {
+                return Some(format!("{} is registered at supply {} but liquidates at {:?}", user_id, s_liq, expected));
+            }
+            if *cost_unwind != calculate_smooth_cost(s_liq.0, s_liq.0 + size_unwind, flat_width, epsilon) {
+                return Some(format!("{}'s unwind cost {} doesn't match the curve", user_id, cost_unwind));
+            }
+            registered.insert(user_id.clone());
+        }
+    }
+
+    for user_entry in state.user_positions.iter() {
+        let user_id = user_entry.key();
+        let Some(position) = user_entry.value().get(&post_id).map(|p| p.clone()) else { continue };
+        if position.size.abs() < state.config.epsilon || registered.contains(user_id) {
+            continue;
+        }
+        let balance = state.user_balances.get(user_id).map_or(0.0, |v| *v.value());
+        let rpnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
+        let Some(s_liq) = calculate_liquidation_supply(balance, rpnl, position.size, calculate_average_price(&position, state.config.epsilon), flat_width, state.config.epsilon, state.config.bonding_curve_epsilon) else { continue };
+        if window.is_none_or(|(low, high)| (low..=high).contains(&s_liq)) {
+            return Some(format!("{} liquidates at supply {} but is missing from the ladder", user_id, s_liq));
+        }
+    }
+    None
+}
+
+// Startup: computes every post's liquidation thresholds, then opens the server for
+// trading. Trades arriving before this finishes are refused with WarmingUp.
+pub async fn warm_up(state: &AppState) {
+    let post_ids: Vec<Uuid> = state.posts.iter().map(|entry| *entry.key()).collect();
+    for post_id in &post_ids {
+        update_liquidation_thresholds(*post_id, state).await;
+    }
+    state.mark_ready();
+    println!("Warmup complete: thresholds computed for {} posts, accepting trades.", post_ids.len());
+}
+
+// Ordering of liquidation entries at the same supply threshold: larger unwinds first,
+// then ascending user id so equal-size positions still have a stable order.
+pub fn liquidation_priority(a: &LiquidationEntry, b: &LiquidationEntry) -> Ordering {
+    b.size_unwind.abs()
+        .total_cmp(&a.size_unwind.abs())
+        .then_with(|| a.user_id.cmp(&b.user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BroadcastStrategy, Config, PrecisionPolicy};
+    use crate::models::Client;
+    use tokio::sync::mpsc;
+    use warp::filters::ws::Message;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    // Register a client for `user_id` and return its id and outgoing message queue
+    fn connect(user_id: &str, state: &AppState) -> (Uuid, mpsc::UnboundedReceiver<Result<Message, warp::Error>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let client_id = Uuid::new_v4();
+        state.clients.insert(client_id, Client::new(user_id, sender));
+        (client_id, receiver)
+    }
+
+    async fn request(client_id: Uuid, user_id: &str, json: serde_json::Value, state: &AppState) {
+        handle_client_message(client_id, user_id, Message::text(json.to_string()), state).await;
+    }
+
+    fn drain_json(receiver: &mut mpsc::UnboundedReceiver<Result<Message, warp::Error>>) -> Vec<serde_json::Value> {
+        let mut messages = Vec::new();
+        while let Ok(Ok(message)) = receiver.try_recv() {
+            messages.push(serde_json::from_str(message.to_str().expect("a text message")).expect("valid JSON"));
+        }
+        messages
+    }
+
+    fn count_of(messages: &[serde_json::Value], message_type: &str) -> usize {
+        messages.iter().filter(|m| m["type"] == message_type).count()
+    }
+
+    fn next_json(receiver: &mut mpsc::UnboundedReceiver<Result<Message, warp::Error>>) -> serde_json::Value {
+        let message = receiver.try_recv().expect("a queued message").expect("an Ok message");
+        serde_json::from_str(message.to_str().expect("a text message")).expect("valid JSON")
+    }
+
+    fn ledgers(user_id: &str, state: &AppState) -> (f64, f64) {
+        let realized_pnl = *state.user_realized_pnl.get(user_id).unwrap();
+        let cash = *state.user_cash.get(user_id).unwrap();
+        (realized_pnl, cash)
+    }
+
+    #[tokio::test]
+    async fn opening_and_holding_leaves_realized_pnl_at_zero() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+
+        let fill = execute_trade(Uuid::new_v4(), "alice", post_id, 4.0, false, &state).await.unwrap();
+
+        let (realized_pnl, cash) = ledgers("alice", &state);
+        assert_eq!(realized_pnl, 0.0);
+        assert!((cash + fill.effective_cost).abs() < TOLERANCE, "cash {} should be -{}", cash, fill.effective_cost);
+        assert!(cash < 0.0);
+    }
+
+    #[tokio::test]
+    async fn closing_books_realized_pnl_against_average_price() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+
+        let buy = execute_trade(Uuid::new_v4(), "alice", post_id, 4.0, false, &state).await.unwrap();
+        let sell = execute_trade(Uuid::new_v4(), "alice", post_id, -2.0, false, &state).await.unwrap();
+
+        // Proceeds of cost(4, 2) minus half the basis of cost(0, 4)
+        let expected_pnl = -sell.effective_cost - buy.effective_cost / 2.0;
+        let (realized_pnl, cash) = ledgers("alice", &state);
+        assert!((realized_pnl - expected_pnl).abs() < TOLERANCE, "realized {} != {}", realized_pnl, expected_pnl);
+        assert!((cash + buy.effective_cost + sell.effective_cost).abs() < TOLERANCE);
+        assert!((realized_pnl - 0.7810487200948625).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn selling_through_zero_splits_basis_at_the_crossing() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+        execute_trade(Uuid::new_v4(), "alice", post_id, 2.0, false, &state).await.unwrap(); // 0 -> 2
+        execute_trade(Uuid::new_v4(), "bob", post_id, 2.0, false, &state).await.unwrap(); // 2 -> 4
+
+        // 4 -> 2 closes alice's long, 2 -> -2 opens her short
+        execute_trade(Uuid::new_v4(), "alice", post_id, -6.0, true, &state).await.unwrap();
+
+        // Closed for the proceeds of cost(4, 2) against the cost(0, 2) basis
+        let (realized_pnl, _) = ledgers("alice", &state);
+        assert!((realized_pnl - 1.5620971670050783).abs() < TOLERANCE, "realized {}", realized_pnl);
+        // Short 4 opened for the proceeds of cost(2, -2) only
+        let short = state.user_positions.get("alice").unwrap().get(&post_id).unwrap().clone();
+        assert_eq!(short.size, -4.0);
+        assert!((calculate_average_price(&short, state.config.epsilon) - 1.2378245084678077).abs() < TOLERANCE, "average price {}", calculate_average_price(&short, state.config.epsilon));
+    }
+
+    #[tokio::test]
+    async fn short_gains_as_the_price_falls() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "carol", 0.0);
+        let fill = execute_trade(Uuid::new_v4(), "alice", post_id, -2.0, false, &state).await.unwrap(); // 0 -> -2
+        execute_trade(Uuid::new_v4(), "bob", post_id, -3.0, false, &state).await.unwrap(); // -2 -> -5
+
+        let ServerMessage::UserSync { positions, .. } = build_user_sync("alice", &snapshot_prices(&["alice"], &state), &state) else { unreachable!() };
+        let entry_price = -fill.effective_cost / 2.0;
+        let price = state.posts.get(&post_id).unwrap().price;
+        assert!(price < entry_price, "bob's sell pushed the price below alice's entry");
+        assert!((positions[0].average_price - entry_price).abs() < TOLERANCE);
+        assert!(positions[0].unrealized_pnl > 0.0);
+        assert!((positions[0].unrealized_pnl - (entry_price - price) * 2.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn price_snapshots_cover_only_the_recipients_posts() {
+        let (held, unheld) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(held, "bob", 2.0)
+            .with_post(unheld, "bob", 0.0)
+            .with_position("alice", held, 2.0, 3.0);
+
+        let prices = snapshot_prices(&["alice", "nobody"], &state);
+
+        assert_eq!(prices.keys().collect::<Vec<_>>(), vec![&held]);
+        assert_eq!(prices[&held], state.posts.get(&held).unwrap().price);
+    }
+
+    #[tokio::test]
+    async fn bad_post_ids_are_reported_as_such() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0).with_markets();
+        let buy = |post_id: serde_json::Value| serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }).to_string();
+
+        let malformed = process_client_message(Uuid::new_v4(), "alice", &buy("not-a-uuid".into()), &state).await;
+        assert!(matches!(malformed, Err(TradeError::InvalidField { ref field, ref reason }) if field == "post_id" && reason.contains("not-a-uuid")), "got {:?}", malformed);
+        let not_a_string = process_client_message(Uuid::new_v4(), "alice", &buy(7.into()), &state).await;
+        assert!(matches!(not_a_string, Err(TradeError::InvalidField { ref field, .. }) if field == "post_id"), "got {:?}", not_a_string);
+
+        let post_id = Uuid::new_v4();
+        let missing = process_client_message(Uuid::new_v4(), "alice", &buy(post_id.to_string().into()), &state).await;
+        assert_eq!(missing.unwrap_err(), TradeError::PostNotFound { post_id });
+
+        let unparseable = process_client_message(Uuid::new_v4(), "alice", "{ \"type\": \"buy\",", &state).await;
+        assert!(matches!(unparseable, Err(TradeError::InvalidMessage { ref reason }) if reason.starts_with("Malformed message")), "got {:?}", unparseable);
+    }
+
+    #[tokio::test]
+    async fn a_trade_records_a_latency_sample() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "bob", 0.0)
+            .with_markets();
+        assert_eq!(state.metrics.trade_duration.count(), 0);
+
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }).to_string();
+        process_client_message(Uuid::new_v4(), "alice", &buy, &state).await.unwrap();
+
+        assert_eq!(state.metrics.trade_duration.count(), 1);
+        assert!(state.metrics.threshold_recompute_duration.count() >= 1, "the recompute is timed separately");
+        assert!(state.metrics.render().contains("flvke_trade_duration_seconds_count 1\n"));
+    }
+
+    #[tokio::test]
+    async fn flip_without_allow_flip_is_rejected() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("alice", post_id, 2.0, 3.0);
+
+        let result = execute_trade(Uuid::new_v4(), "alice", post_id, -3.0, false, &state).await;
+
+        assert!(matches!(result, Err(TradeError::InvalidField { ref field, .. }) if field == "quantity"), "got {:?}", result);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 2.0);
+        execute_trade(Uuid::new_v4(), "alice", post_id, -2.0, false, &state).await.expect("an exact close is not a flip");
+    }
+
+    #[tokio::test]
+    async fn get_post_returns_market_detail() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+        execute_trade(Uuid::new_v4(), "alice", post_id, 4.0, false, &state).await.unwrap();
+        execute_trade(Uuid::new_v4(), "bob", post_id, -1.0, false, &state).await.unwrap();
+        let (client_id, mut receiver) = connect("alice", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "get_post", "post_id": post_id }), &state).await;
+
+        let reply = next_json(&mut receiver);
+        assert_eq!(reply["type"], "post_detail");
+        assert_eq!(reply["post"]["id"], post_id.to_string());
+        assert_eq!(reply["post"]["supply"], 3.0);
+        assert_eq!(reply["post"]["price"], get_price(3.0, 0.0, state.config.bonding_curve_epsilon));
+        assert_eq!(reply["volume"], 5.0);
+        assert_eq!(reply["open_interest"], 5.0);
+        assert_eq!(reply["liquidation_threshold_count"], 0);
+    }
+
+    #[tokio::test]
+    async fn get_post_unknown_id_is_post_not_found() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0);
+        let (client_id, mut receiver) = connect("alice", &state);
+        let missing = Uuid::new_v4();
+
+        request(client_id, "alice", serde_json::json!({ "type": "get_post", "post_id": missing }), &state).await;
+
+        let reply = next_json(&mut receiver);
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["error"]["code"], "post_not_found");
+        assert_eq!(reply["error"]["post_id"], missing.to_string());
+    }
+
+    #[tokio::test]
+    async fn each_affected_client_gets_exactly_one_user_sync_per_trade() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_user("carol", 1000.0)
+            .with_post(post_id, "alice", 3.0)
+            .with_position("alice", post_id, 2.0, 3.0)
+            .with_position("bob", post_id, 1.0, 2.0);
+        let (_, mut alice_phone) = connect("alice", &state);
+        let (_, mut alice_laptop) = connect("alice", &state);
+        let (bob_client, mut bob) = connect("bob", &state);
+        let (_, mut carol) = connect("carol", &state);
+
+        // bob is both the trader and a holder
+        execute_trade(bob_client, "bob", post_id, 1.0, false, &state).await.unwrap();
+
+        for (name, receiver) in [("alice phone", &mut alice_phone), ("alice laptop", &mut alice_laptop), ("bob", &mut bob)] {
+            let messages = drain_json(receiver);
+            assert_eq!(count_of(&messages, "user_sync"), 1, "{} got {:?}", name, messages);
+        }
+        let carol_messages = drain_json(&mut carol);
+        assert_eq!(count_of(&carol_messages, "user_sync"), 0);
+        assert_eq!(count_of(&carol_messages, "market_update"), 1);
+    }
+
+    #[tokio::test]
+    async fn market_update_precedes_user_sync() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("alice", post_id, 2.0, 3.0);
+        let (_, mut alice) = connect("alice", &state);
+        let (bob_client, mut bob) = connect("bob", &state);
+
+        execute_trade(bob_client, "bob", post_id, 1.0, false, &state).await.unwrap();
+
+        for (name, receiver) in [("holder", &mut alice), ("trader", &mut bob)] {
+            let types: Vec<String> = drain_json(receiver).iter().map(|m| m["type"].as_str().unwrap().to_string()).collect();
+            let market_update = types.iter().position(|t| t == "market_update").expect("a market_update");
+            let user_sync = types.iter().position(|t| t == "user_sync").expect("a user_sync");
+            assert!(market_update < user_sync, "{} received {:?}", name, types);
+            assert!(
+                types[..market_update].iter().all(|t| t != "user_sync" && t != "equity_update" && t != "position_update"),
+                "{} received {:?}", name, types
+            );
+        }
+    }
+
+    // Which of holder alice, subscriber carol and bystander dave see bob's trade
+    async fn market_update_recipients(strategy: BroadcastStrategy) -> Vec<&'static str> {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { broadcast_strategy: strategy, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("alice", post_id, 2.0, 3.0);
+        let (_, mut alice) = connect("alice", &state);
+        let (carol_client, mut carol) = connect("carol", &state);
+        let (_, mut dave) = connect("dave", &state);
+        let (bob_client, mut bob) = connect("bob", &state);
+        request(carol_client, "carol", serde_json::json!({ "type": "subscribe", "post_id": post_id }), &state).await;
+
+        execute_trade(bob_client, "bob", post_id, 1.0, false, &state).await.unwrap();
+
+        [("alice", &mut alice), ("carol", &mut carol), ("dave", &mut dave), ("bob", &mut bob)]
+            .into_iter()
+            .filter_map(|(name, receiver)| (count_of(&drain_json(receiver), "market_update") == 1).then_some(name))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn broadcast_strategy_selects_market_update_recipients() {
+        assert_eq!(market_update_recipients(BroadcastStrategy::All).await, ["alice", "carol", "dave", "bob"]);
+        // bob holds the post once his buy has filled
+        assert_eq!(market_update_recipients(BroadcastStrategy::HoldersOnly).await, ["alice", "bob"]);
+        assert_eq!(market_update_recipients(BroadcastStrategy::Subscribers).await, ["carol"]);
+    }
+
+    #[tokio::test]
+    async fn trade_confirmation_reports_triggered_liquidations() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_position("carol", post_id, -2.0, -3.0)
+            .with_position("dave", post_id, -1.0, -1.0)
+            .with_markets();
+        // carol buys back 4 -> 6, dave 7 -> 8
+        let mut ladder = BTreeMap::new();
+        ladder.insert(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind: 6.464625637799379, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }]);
+        ladder.insert(OrderedFloat(7.0), vec![LiquidationEntry { cost_unwind: 3.7381052136782564, size_unwind: 1.0, cost_basis: -1.0, user_id: "dave".to_string() }]);
+        state.liquidation_thresholds.insert(post_id, ladder);
+        let (client_id, mut receiver) = connect("alice", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 6.0 }), &state).await;
+
+        let messages = drain_json(&mut receiver);
+        let confirmation = messages.iter().find(|m| m["type"] == "trade_confirmation").expect("a trade_confirmation");
+        assert_eq!(confirmation["post_id"], post_id.to_string());
+        assert_eq!(confirmation["quantity"], 6.0);
+        assert_eq!(confirmation["final_supply"], 9.0);
+        assert_eq!(confirmation["liquidations_triggered"], 2);
+        let notional = confirmation["liquidation_notional"].as_f64().unwrap();
+        assert!((notional - 10.202730851477636).abs() < TOLERANCE, "notional {}", notional);
+    }
+
+    #[tokio::test]
+    async fn liquidating_sole_position_removes_user_positions_entry() {
+        let post_id = Uuid::new_v4();
+        let other_post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_post(other_post_id, "alice", 0.0)
+            .with_position("carol", post_id, -2.0, -3.0)
+            .with_position("dave", post_id, -1.0, -1.0)
+            .with_position("dave", other_post_id, 1.0, 1.0);
+        let mut ladder = BTreeMap::new();
+        ladder.insert(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind: 6.464625637799379, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }]);
+        ladder.insert(OrderedFloat(7.0), vec![LiquidationEntry { cost_unwind: 3.7381052136782564, size_unwind: 1.0, cost_basis: -1.0, user_id: "dave".to_string() }]);
+        state.liquidation_thresholds.insert(post_id, ladder);
+
+        execute_trade(Uuid::new_v4(), "alice", post_id, 6.0, false, &state).await.unwrap();
+
+        assert!(!state.user_positions.contains_key("carol"), "carol's only position was liquidated");
+        let dave = state.user_positions.get("dave").expect("dave still holds another post");
+        assert!(dave.get(&post_id).is_none());
+        assert!(dave.get(&other_post_id).is_some());
+        drop(dave);
+        update_liquidation_thresholds(post_id, &state).await; // Tolerates the missing entry
+    }
+
+    #[tokio::test]
+    async fn dry_run_executes_in_memory_without_broadcasts() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { dry_run: true, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_markets();
+        let (client_id, mut receiver) = connect("alice", &state);
+        let (_, mut watcher) = connect("bob", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 4.0 }), &state).await;
+
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 4.0);
+        let types: Vec<_> = drain_json(&mut receiver).iter().map(|m| m["type"].clone()).collect();
+        assert_eq!(types, ["trade_confirmation"]);
+        assert!(drain_json(&mut watcher).is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate_content_by_same_creator_is_rejected_when_enabled() {
+        let state = AppState::new_for_test()
+            .with_config(Config { unique_post_content: true, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0);
+        let (alice_client, mut alice) = connect("alice", &state);
+        let (bob_client, _bob) = connect("bob", &state);
+
+        request(alice_client, "alice", serde_json::json!({ "type": "create_post", "content": "gm" }), &state).await;
+        let first = drain_json(&mut alice);
+        request(alice_client, "alice", serde_json::json!({ "type": "create_post", "content": "  gm \n" }), &state).await;
+        request(bob_client, "bob", serde_json::json!({ "type": "create_post", "content": "gm" }), &state).await;
+
+        assert_eq!(state.posts.len(), 2, "bob may post the same content");
+        let first_post_id = first.iter().find(|m| m["type"] == "new_post").expect("a new_post")["post"]["id"].clone();
+        let reply = drain_json(&mut alice).into_iter().find(|m| m["type"] == "error").expect("an error");
+        assert_eq!(reply["error"]["code"], "duplicate_post");
+        assert_eq!(reply["error"]["existing_post_id"], first_post_id);
+    }
+
+    #[tokio::test]
+    async fn blocklisted_posts_are_rejected_before_they_go_live() {
+        struct NoShouting;
+        impl crate::moderation::ContentCheck for NoShouting {
+            fn check<'a>(&'a self, content: &'a str) -> std::pin::Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
+                Box::pin(async move {
+                    if content.chars().any(|c| c.is_lowercase()) { Ok(()) } else { Err("no shouting".to_string()) }
+                })
+            }
+        }
+        let state = AppState::new_for_test()
+            .with_config(Config { moderation_blocklist: vec!["scam".to_string(), "http://".to_string()], ..Config::default() })
+            .with_content_check(NoShouting)
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0);
+        let (_bob_client, mut bob) = connect("bob", &state);
+        drain_json(&mut bob);
+        let create = |content: &str| serde_json::json!({ "type": "create_post", "content": content }).to_string();
+
+        let clean = process_client_message(Uuid::new_v4(), "alice", &create("gm frens"), &state).await;
+        let blocked = process_client_message(Uuid::new_v4(), "alice", &create("Free SCAM at http://x.io"), &state).await;
+        let flagged = process_client_message(Uuid::new_v4(), "alice", &create("GM"), &state).await;
+
+        assert!(clean.is_ok(), "clean content passes: {:?}", clean);
+        assert_eq!(blocked.unwrap_err(), TradeError::ContentRejected { reason: "contains the blocked term \"scam\"".to_string() });
+        assert_eq!(flagged.unwrap_err(), TradeError::ContentRejected { reason: "no shouting".to_string() });
+        assert_eq!(state.posts.len(), 1);
+        assert!(state.posts.iter().all(|post| post.content == "gm frens"));
+        assert_eq!(count_of(&drain_json(&mut bob), "new_post"), 1, "only the clean post is broadcast");
+    }
+
+    #[tokio::test]
+    async fn unknown_user_is_rejected_in_strict_mode() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { strict_users: true, known_users: vec!["alice".to_string()], ..Config::default() })
+            .with_post(post_id, "alice", 0.0)
+            .with_markets();
+        let (mallory_client, mut mallory) = connect("mallory", &state);
+        let (alice_client, mut alice) = connect("alice", &state);
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 });
+
+        request(mallory_client, "mallory", buy.clone(), &state).await;
+        request(alice_client, "alice", buy, &state).await;
+
+        let reply = next_json(&mut mallory);
+        assert_eq!(reply["error"]["code"], "unknown_user");
+        assert_eq!(reply["error"]["user_id"], "mallory");
+        assert!(!state.user_balances.contains_key("mallory"), "no account minted");
+        assert_eq!(count_of(&drain_json(&mut alice), "trade_confirmation"), 1);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 1.0);
+    }
+
+    #[tokio::test]
+    async fn thresholds_removed_after_last_holder_closes() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+        execute_trade(Uuid::new_v4(), "alice", post_id, 2.0, false, &state).await.unwrap();
+        update_liquidation_thresholds(post_id, &state).await;
+        assert!(state.liquidation_thresholds.contains_key(&post_id));
+
+        execute_trade(Uuid::new_v4(), "alice", post_id, -2.0, false, &state).await.unwrap();
+        update_liquidation_thresholds(post_id, &state).await;
+
+        assert!(!state.liquidation_thresholds.contains_key(&post_id));
+        // Trading again works from the smooth curve and rebuilds the entry
+        execute_trade(Uuid::new_v4(), "alice", post_id, 1.0, false, &state).await.unwrap();
+        update_liquidation_thresholds(post_id, &state).await;
+        assert!(state.liquidation_thresholds.contains_key(&post_id));
+    }
+
+    #[tokio::test]
+    async fn pnl_history_records_each_booking() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { pnl_history_cap: 2, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+        let (client_id, mut receiver) = connect("alice", &state);
+
+        execute_trade(client_id, "alice", post_id, 6.0, false, &state).await.unwrap(); // Opens, books nothing
+        let mut bookings = Vec::new();
+        for _ in 0..3 {
+            let (before, _) = ledgers("alice", &state);
+            execute_trade(client_id, "alice", post_id, -1.0, false, &state).await.unwrap();
+            bookings.push(ledgers("alice", &state).0 - before);
+        }
+        drain_json(&mut receiver);
+        request(client_id, "alice", serde_json::json!({ "type": "get_pnl_history" }), &state).await;
+
+        let reply = next_json(&mut receiver);
+        assert_eq!(reply["type"], "pnl_history");
+        let points = reply["points"].as_array().unwrap();
+        assert_eq!(points.len(), 2, "capped at the two latest of three bookings");
+        for (point, expected) in points.iter().zip(&bookings[1..]) {
+            assert!((point[1].as_f64().unwrap() - expected).abs() < TOLERANCE, "{} != {}", point[1], expected);
+        }
+
+        let history = state.user_pnl_history.get("alice").unwrap();
+        let since = history[0].0;
+        drop(history);
+        request(client_id, "alice", serde_json::json!({ "type": "get_pnl_history", "since": since }), &state).await;
+        assert_eq!(next_json(&mut receiver)["points"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn post_id_round_trips_through_create_trade_and_query() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0);
+        let (client_id, mut receiver) = connect("alice", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "create_post", "content": "round trip" }), &state).await;
+        let created = next_json(&mut receiver);
+        let wire_id = created["post"]["id"].as_str().expect("post id is a string").to_string();
+        let post_id = Uuid::parse_str(&wire_id).expect("post id is a UUID");
+        assert!(state.posts.contains_key(&post_id) && state.markets.contains_key(&post_id));
+
+        request(client_id, "alice", serde_json::json!({ "type": "buy", "post_id": wire_id, "quantity": 2.0 }), &state).await;
+        let confirmation = drain_json(&mut receiver).into_iter().find(|m| m["type"] == "trade_confirmation").expect("a trade_confirmation");
+        assert_eq!(confirmation["post_id"], wire_id.as_str());
+
+        request(client_id, "alice", serde_json::json!({ "type": "get_post", "post_id": wire_id }), &state).await;
+        let detail = next_json(&mut receiver);
+        assert_eq!(detail["post"]["id"], wire_id.as_str());
+        assert_eq!(detail["post"]["supply"], 2.0);
+    }
+
+    #[tokio::test]
+    async fn posts_can_start_at_an_initial_supply_held_by_the_founder() {
+        let state = AppState::new_for_test()
+            .with_config(Config { max_supply: 100.0, check_supply_invariant: true, ..Config::default() })
+            .with_user("alice", 1000.0);
+        let create = |initial_supply: f64, founder_position: bool| serde_json::json!({
+            "type": "create_post", "content": "imported", "initial_supply": initial_supply, "founder_position": founder_position,
+        }).to_string();
+
+        process_client_message(Uuid::new_v4(), "alice", &create(4.0, true), &state).await.unwrap();
+        let over_cap = process_client_message(Uuid::new_v4(), "alice", &create(101.0, true), &state).await;
+        let unheld = process_client_message(Uuid::new_v4(), "alice", &create(4.0, false), &state).await;
+
+        let post = state.posts.iter().next().unwrap().clone();
+        assert_eq!(state.posts.len(), 1);
+        assert_eq!((post.supply, post.price), (4.0, 3.0));
+        let cost = calculate_smooth_cost(0.0, 4.0, 0.0, state.config.bonding_curve_epsilon);
+        let founder = state.user_positions.get("alice").unwrap().get(&post.id).unwrap().clone();
+        assert_eq!((founder.size, founder.total_cost_basis), (4.0, cost));
+        assert_eq!(ledgers("alice", &state).1, -cost, "paid for like a buy");
+        assert!(matches!(over_cap, Err(TradeError::InvalidField { ref field, .. }) if field == "initial_supply"), "got {:?}", over_cap);
+        assert!(matches!(unheld, Err(TradeError::InvalidField { ref field, .. }) if field == "founder_position"), "got {:?}", unheld);
+    }
+
+    #[tokio::test]
+    async fn a_founder_position_is_liquidated_like_any_other() {
+        // alice founds short 4 at I(-4) = -1.803; with a balance of 2 her equity runs out
+        // at price 0.95, just below supply 0
+        let state = AppState::new_for_test()
+            .with_config(Config { check_supply_invariant: true, ..Config::default() })
+            .with_user("alice", 2.0)
+            .with_user("bob", 1000.0);
+        let create = serde_json::json!({ "type": "create_post", "content": "short", "initial_supply": -4.0, "founder_position": true });
+        process_client_message(Uuid::new_v4(), "alice", &create.to_string(), &state).await.unwrap();
+        let post_id = *state.posts.iter().next().unwrap().key();
+        assert!(state.liquidation_thresholds.get(&post_id).unwrap().values().flatten().any(|entry| entry.user_id == "alice"));
+
+        let fill = execute_trade(Uuid::new_v4(), "bob", post_id, 5.0, false, &state).await.unwrap();
+
+        assert_eq!(fill.liquidations_triggered, 1);
+        assert!(state.user_positions.get("alice").is_none_or(|p| p.get(&post_id).is_none()), "the founder was unwound");
+    }
+
+    #[tokio::test]
+    async fn post_creation_stops_at_the_per_user_cap() {
+        let state = AppState::new_for_test()
+            .with_config(Config { max_posts_per_user: 2, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0);
+        let (alice_client, mut alice) = connect("alice", &state);
+        let (bob_client, mut bob) = connect("bob", &state);
+
+        for content in ["one", "two"] {
+            request(alice_client, "alice", serde_json::json!({ "type": "create_post", "content": content }), &state).await;
+        }
+        assert_eq!(count_of(&drain_json(&mut alice), "error"), 0);
+
+        request(alice_client, "alice", serde_json::json!({ "type": "create_post", "content": "three" }), &state).await;
+        request(bob_client, "bob", serde_json::json!({ "type": "create_post", "content": "one" }), &state).await;
+
+        assert_eq!(state.posts.len(), 3, "the cap is per creator");
+        assert_eq!(*state.user_post_counts.get("alice").unwrap(), 2);
+        let reply = drain_json(&mut alice).into_iter().find(|m| m["type"] == "error").expect("an error");
+        assert_eq!(reply["error"]["code"], "post_limit_reached");
+        assert_eq!(reply["error"]["limit"], 2);
+        assert_eq!(count_of(&drain_json(&mut bob), "error"), 0);
+
+        // Settling one of her posts frees its slot
+        let post_id = *state.posts.iter().find(|post| post.user_id == "alice").unwrap().key();
+        let close = serde_json::json!({ "type": "close_own_post", "post_id": post_id }).to_string();
+        process_client_message(alice_client, "alice", &close, &state).await.unwrap();
+        assert_eq!(*state.user_post_counts.get("alice").unwrap(), 1);
+        request(alice_client, "alice", serde_json::json!({ "type": "create_post", "content": "three" }), &state).await;
+        assert_eq!(count_of(&drain_json(&mut alice), "error"), 0);
+        assert_eq!(*state.user_post_counts.get("alice").unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn second_post_within_the_cooldown_is_rejected() {
+        let state = AppState::new_for_test()
+            .with_config(Config { post_cooldown_secs: 60, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0);
+        let create = |content: &str| serde_json::json!({ "type": "create_post", "content": content }).to_string();
+
+        process_client_message(Uuid::new_v4(), "alice", &create("one"), &state).await.unwrap();
+        let second = process_client_message(Uuid::new_v4(), "alice", &create("two"), &state).await;
+        let other_user = process_client_message(Uuid::new_v4(), "bob", &create("one"), &state).await;
+
+        let Err(TradeError::PostCooldown { retry_after_ms }) = second else { panic!("expected a cooldown, got {:?}", second) };
+        assert!(retry_after_ms > 0 && retry_after_ms <= 60_000, "retry after {}ms", retry_after_ms);
+        assert!(other_user.is_ok(), "the cooldown is per creator");
+        assert_eq!(state.posts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unlisted_post_is_not_broadcast_but_is_fetchable() {
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0);
+        let (alice_client, mut alice) = connect("alice", &state);
+        let (bob_client, mut bob) = connect("bob", &state);
+
+        request(alice_client, "alice", serde_json::json!({ "type": "create_post", "content": "draft", "visibility": "unlisted" }), &state).await;
+
+        assert_eq!(count_of(&drain_json(&mut bob), "new_post"), 0);
+        let announced = drain_json(&mut alice).into_iter().find(|m| m["type"] == "new_post").expect("creator's new_post");
+        assert_eq!(announced["post"]["visibility"], "unlisted");
+
+        request(bob_client, "bob", serde_json::json!({ "type": "get_post", "post_id": announced["post"]["id"] }), &state).await;
+        let detail = next_json(&mut bob);
+        assert_eq!(detail["type"], "post_detail");
+        assert_eq!(detail["post"]["content"], "draft");
+    }
+
+    #[tokio::test]
+    async fn public_new_post_follows_broadcast_strategy() {
+        for (strategy, bob_sees_it) in [(BroadcastStrategy::All, true), (BroadcastStrategy::Subscribers, false)] {
+            let state = AppState::new_for_test()
+                .with_config(Config { broadcast_strategy: strategy, ..Config::default() })
+                .with_user("alice", 1000.0)
+                .with_user("bob", 1000.0);
+            let (alice_client, mut alice) = connect("alice", &state);
+            let (_bob_client, mut bob) = connect("bob", &state);
+
+            request(alice_client, "alice", serde_json::json!({ "type": "create_post", "content": "gm" }), &state).await;
+
+            assert_eq!(count_of(&drain_json(&mut alice), "new_post"), 1, "{:?}", strategy);
+            assert_eq!(count_of(&drain_json(&mut bob), "new_post"), usize::from(bob_sees_it), "{:?}", strategy);
+        }
+    }
+
+    // Shared in-memory sink for a test's tracing output
+    #[derive(Clone, Default)]
+    struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn one_correlation_id_covers_a_trade_across_modules() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { broadcast_strategy: BroadcastStrategy::HoldersOnly, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_markets();
+        let (client_id, _alice) = connect("alice", &state);
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        request(client_id, "alice", serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 2.0 }), &state).await;
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        let ids: HashSet<&str> = lines.iter()
+            .filter_map(|line| line.split("correlation_id=").nth(1))
+            .map(|rest| &rest[..36])
+            .collect();
+        assert_eq!(ids.len(), 1, "one id per message:\n{}", output);
+        assert!(lines.iter().all(|line| line.contains("correlation_id=")), "every line in the span:\n{}", output);
+        for module in ["server::handlers", "server::calculations", "server::websocket"] {
+            assert!(lines.iter().any(|line| line.contains(module)), "no log from {}:\n{}", module, output);
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_threshold_recomputes_are_logged_at_warn() {
+        let post_id = Uuid::new_v4();
+        // 0 warns on every recompute, however fast
+        let mut state = AppState::new_for_test()
+            .with_config(Config { slow_threshold_recompute_ms: 0, ..Config::default() })
+            .with_post(post_id, "alice", 0.0);
+        for i in 0..3 {
+            state = state.with_user(&format!("holder-{}", i), 10.0).with_position(&format!("holder-{}", i), post_id, -1.0, -1.0);
+        }
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        update_liquidation_thresholds(post_id, &state).await;
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let warning = output.lines().find(|line| line.contains("slow recompute")).unwrap_or_else(|| panic!("no warning:\n{}", output));
+        assert!(warning.contains("WARN") && warning.contains("holders=3"), "{}", warning);
+    }
+
+    // alice holds 1..=count shares of `count` posts plus one dust position; returns the
+    // non-dust post ids in post id order and the dust post id
+    fn state_with_many_positions(count: usize) -> (AppState, Vec<Uuid>, Uuid) {
+        let mut state = AppState::new_for_test().with_user("alice", 1000.0);
+        let mut post_ids: Vec<Uuid> = (0..count).map(|_| Uuid::new_v4()).collect();
+        for (i, post_id) in post_ids.iter().enumerate() {
+            let size = (i + 1) as f64;
+            state = state.with_post(*post_id, "bob", size).with_position("alice", *post_id, size, size);
+        }
+        let dust_id = Uuid::new_v4();
+        state = state.with_post(dust_id, "bob", 0.0).with_position("alice", dust_id, 1e-12, 1e-12);
+        post_ids.sort();
+        (state, post_ids, dust_id)
+    }
+
+    #[tokio::test]
+    async fn get_positions_pages_through_a_large_portfolio() {
+        let (state, post_ids, _) = state_with_many_positions(25);
+        let (client_id, mut alice) = connect("alice", &state);
+        let page = |offset: usize| serde_json::json!({ "type": "get_positions", "offset": offset, "limit": 10, "only_open": true });
+
+        let mut seen = Vec::new();
+        for (offset, expected_len, expected_more) in [(0, 10, true), (10, 10, true), (20, 5, false), (25, 0, false)] {
+            request(client_id, "alice", page(offset), &state).await;
+            let reply = next_json(&mut alice);
+            assert_eq!(reply["type"], "positions");
+            assert_eq!(reply["total"], 25);
+            assert_eq!(reply["offset"], offset);
+            assert_eq!(reply["has_more"], expected_more, "offset {}", offset);
+            let positions = reply["positions"].as_array().unwrap();
+            assert_eq!(positions.len(), expected_len, "offset {}", offset);
+            seen.extend(positions.iter().map(|p| p["post_id"].as_str().unwrap().to_string()));
+        }
+
+        let expected: Vec<String> = post_ids.iter().map(Uuid::to_string).collect();
+        assert_eq!(seen, expected, "pages cover every position once, in post id order");
+    }
+
+    #[tokio::test]
+    async fn get_positions_filters_dust_and_small_positions() {
+        let (state, _, dust_id) = state_with_many_positions(5);
+        let (client_id, mut alice) = connect("alice", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "get_positions" }), &state).await;
+        let everything = next_json(&mut alice);
+        request(client_id, "alice", serde_json::json!({ "type": "get_positions", "only_open": true }), &state).await;
+        let open = next_json(&mut alice);
+        request(client_id, "alice", serde_json::json!({ "type": "get_positions", "only_open": true, "min_size": 3.0 }), &state).await;
+        let large = next_json(&mut alice);
+
+        assert_eq!(everything["total"], 6);
+        assert!(everything["positions"].as_array().unwrap().iter().any(|p| p["post_id"] == dust_id.to_string()));
+        assert_eq!(open["total"], 5);
+        assert!(open["positions"].as_array().unwrap().iter().all(|p| p["post_id"] != dust_id.to_string()));
+        assert_eq!(large["total"], 3); // sizes 3, 4 and 5
+    }
+
+    #[test]
+    fn user_sync_caps_positions_and_flags_the_rest() {
+        let (state, post_ids, _) = state_with_many_positions(5);
+        let state = state.with_config(Config { user_sync_position_cap: 3, ..Config::default() });
+
+        let ServerMessage::UserSync { positions, has_more_positions, equity, .. } = build_user_sync("alice", &snapshot_prices(&["alice"], &state), &state) else {
+            panic!("expected a UserSync");
+        };
+
+        assert!(has_more_positions);
+        assert_eq!(positions.iter().map(|p| p.post_id).collect::<Vec<_>>(), post_ids[..3]);
+        // Equity still prices all five positions
+        assert!((equity - calculate_user_margin("alice", &state)).abs() < TOLERANCE);
+    }
 
-            println!("update_liquidation_thresholds: Calculating for user {}: Getting balance/rpnl...", user_id);
-            let balance = state.user_balances.get(user_id).map_or(0.0, |v| *v.value());
-            let rpnl = state.user_realized_pnl.get(user_id).map_or(0.0, |v| *v.value());
-            println!("update_liquidation_thresholds: User {}: Bal={:.4}, RPnl={:.4}. Getting market price...", user_id, balance, rpnl);
-            let current_market_price = state.posts.get(&post_id)
-                .map_or(0.0, |p| p.value().price.unwrap_or(0.0)); // Fixed: Use price, unwrap Option
-            println!("update_liquidation_thresholds: User {}: MarketPrice={:.4}. Calculating avg_price...", user_id, current_market_price);
+    #[test]
+    fn position_order_is_stable_between_syncs() {
+        let (state, post_ids, _) = state_with_many_positions(20);
+        let post_order = |sync: ServerMessage| match sync {
+            ServerMessage::UserSync { positions, .. } => positions.into_iter().map(|p| p.post_id).collect::<Vec<_>>(),
+            other => panic!("expected a UserSync, got {:?}", other),
+        };
 
-            let avg_price = calculate_average_price(&position);
-            println!("update_liquidation_thresholds: User {}: AvgPrice={:.4}. Calculating uRPnL...", user_id, avg_price);
-            let total_unrealized_pnl = (current_market_price - avg_price) * position.size;
-            println!("update_liquidation_thresholds: User {}: uRPnL={:.4}. Calculating liquidation price...", user_id, total_unrealized_pnl);
+        let first = post_order(build_user_sync("alice", &snapshot_prices(&["alice"], &state), &state));
+        let second = post_order(build_user_sync("alice", &snapshot_prices(&["alice"], &state), &state));
 
-            if let Some(s_liq) = calculate_liquidation_price(balance, rpnl, position.size, avg_price) {
-                println!("update_liquidation_thresholds: User {}: Calculated s_liq = {:.4}. Calculating unwind...", user_id, s_liq);
-                let forced_trade_size = -position.size;
-                let s_liq_after_unwind = s_liq + forced_trade_size;
-                let cost_unwind = calculate_smooth_cost(s_liq, s_liq_after_unwind);
-                println!("update_liquidation_thresholds: User {}: ForcedSize={:.4}, s_liq_after={:.4}, CostUnwind={:.4}. Adding to map...", user_id, forced_trade_size, s_liq_after_unwind, cost_unwind);
+        assert_eq!(first, second);
+        assert_eq!(first, post_ids);
+    }
 
-                // Add this user's liquidation data to the aggregation map
-                let supply_key = OrderedFloat(s_liq);
-                aggregated_thresholds.entry(supply_key)
-                    .or_default() // Get Vec or create new one
-                    .push((cost_unwind, forced_trade_size, user_id.clone()));
-                println!("update_liquidation_thresholds: User {}: Added entry for s_liq = {:.4}.", user_id, s_liq);
+    #[tokio::test]
+    async fn get_positions_sorts_by_requested_key() {
+        let (state, _, _) = state_with_many_positions(5);
+        let (client_id, mut alice) = connect("alice", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "get_positions", "only_open": true, "sort": "size_desc" }), &state).await;
+        let reply = next_json(&mut alice);
+
+        let sizes: Vec<f64> = reply["positions"].as_array().unwrap().iter().map(|p| p["size"].as_f64().unwrap()).collect();
+        assert_eq!(sizes, [5.0, 4.0, 3.0, 2.0, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn portfolio_summary_equity_adds_up() {
+        let (long_post, short_post) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 800.0)
+            .with_post(long_post, "bob", 9.0) // price 4
+            .with_post(short_post, "bob", -4.0) // price 1/3
+            .with_position("alice", long_post, 3.0, 6.0) // +6 unrealized
+            .with_position("alice", short_post, -2.0, -2.0); // +4/3 unrealized
+        state.user_realized_pnl.insert("alice".to_string(), 25.0);
+        let (client_id, mut alice) = connect("alice", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "get_portfolio_summary" }), &state).await;
+        let summary = next_json(&mut alice);
+
+        assert_eq!(summary["type"], "portfolio_summary");
+        let field = |name: &str| summary[name].as_f64().unwrap();
+        assert!((field("unrealized_pnl") - (6.0 + 4.0 / 3.0)).abs() < TOLERANCE);
+        assert!((field("equity") - (field("balance") + field("realized_pnl") + field("unrealized_pnl"))).abs() < TOLERANCE);
+        assert_eq!(field("balance"), 800.0);
+        assert_eq!(field("realized_pnl"), 25.0);
+        assert_eq!(field("exposure"), 8.0);
+        assert_eq!(summary["open_positions"], 2);
+        assert!((field("total_notional") - (3.0 * 4.0 + 2.0 / 3.0)).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn risk_snapshot_reuses_the_exposure_and_margin_helpers() {
+        let (long_post, short_post) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 5.0)
+            .with_post(long_post, "bob", 9.0) // price 4
+            .with_post(short_post, "bob", -4.0) // price 1/3
+            .with_position("alice", long_post, 3.0, 6.0)
+            .with_position("alice", short_post, -2.0, -2.0);
+        let (client_id, mut alice) = connect("alice", &state);
+
+        request(client_id, "alice", serde_json::json!({ "type": "get_risk" }), &state).await;
+        let risk = next_json(&mut alice);
+
+        assert_eq!(risk["type"], "risk_snapshot");
+        let field = |name: &str| risk[name].as_f64().unwrap();
+        assert_eq!(field("exposure"), calculate_total_exposure("alice", &state));
+        assert_eq!(field("margin"), calculate_user_margin("alice", &state));
+        assert!((field("total_unrealized_pnl") - (6.0 + 4.0 / 3.0)).abs() < TOLERANCE);
+        let long_distance = calculate_liquidation_supply(5.0, 0.0, 3.0, 2.0, 0.0, state.config.epsilon, state.config.bonding_curve_epsilon).unwrap() - 9.0;
+        let short_distance = calculate_liquidation_supply(5.0, 0.0, -2.0, 1.0, 0.0, state.config.epsilon, state.config.bonding_curve_epsilon).unwrap() + 4.0;
+        let nearest = long_distance.abs().min(short_distance.abs());
+        assert!((field("liquidation_distance_min") - nearest).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn portfolio_summary_is_pushed_to_the_trader_only() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("bob", post_id, 2.0, 3.0)
+            .with_markets();
+        let (alice_client, mut alice) = connect("alice", &state);
+        let (_bob_client, mut bob) = connect("bob", &state);
+
+        request(alice_client, "alice", serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }), &state).await;
+
+        assert_eq!(count_of(&drain_json(&mut alice), "portfolio_summary"), 1);
+        assert_eq!(count_of(&drain_json(&mut bob), "portfolio_summary"), 0, "a price move alone isn't pushed");
+    }
+
+    #[tokio::test]
+    async fn process_client_message_returns_the_replies() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "bob", 0.0)
+            .with_markets();
+        let client_id = Uuid::new_v4(); // No connection needed
+
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 2.0 }).to_string();
+        let replies = process_client_message(client_id, "alice", &buy, &state).await.unwrap();
+        let detail = serde_json::json!({ "type": "get_post", "post_id": post_id }).to_string();
+        let detail_replies = process_client_message(client_id, "alice", &detail, &state).await.unwrap();
+
+        assert!(matches!(replies.as_slice(), [ServerMessage::TradeConfirmation { quantity, .. }] if *quantity == 2.0));
+        assert!(matches!(detail_replies.as_slice(), [ServerMessage::PostDetail { post, .. }] if post.supply == 2.0));
+    }
+
+    #[tokio::test]
+    async fn process_client_message_returns_typed_errors() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0);
+        let client_id = Uuid::new_v4();
+        let missing = Uuid::new_v4();
+
+        let not_found = process_client_message(client_id, "alice", &serde_json::json!({ "type": "get_post", "post_id": missing }).to_string(), &state).await;
+        let bad_quantity = process_client_message(client_id, "alice", &serde_json::json!({ "type": "sell", "post_id": missing, "quantity": -1.0 }).to_string(), &state).await;
+        let malformed = process_client_message(client_id, "alice", "{not json", &state).await;
+
+        assert_eq!(not_found.unwrap_err(), TradeError::PostNotFound { post_id: missing });
+        assert!(matches!(bad_quantity.unwrap_err(), TradeError::InvalidField { field, .. } if field == "quantity"));
+        assert!(matches!(malformed.unwrap_err(), TradeError::InvalidMessage { reason } if reason.starts_with("Malformed message")));
+    }
+
+    #[tokio::test]
+    async fn trades_are_refused_until_warmup_completes() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "bob", 2.0)
+            .with_position("bob", post_id, 2.0, 3.0)
+            .with_markets();
+        state.ready.store(false, std::sync::atomic::Ordering::SeqCst);
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }).to_string();
+
+        let refused = process_client_message(Uuid::new_v4(), "alice", &buy, &state).await;
+        assert_eq!(refused.unwrap_err(), TradeError::WarmingUp);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 2.0);
+
+        warm_up(&state).await;
+
+        assert!(state.liquidation_thresholds.contains_key(&post_id), "bob's threshold computed during warmup");
+        let replies = process_client_message(Uuid::new_v4(), "alice", &buy, &state).await.unwrap();
+        assert!(matches!(replies.as_slice(), [ServerMessage::TradeConfirmation { .. }]));
+    }
+
+    #[tokio::test]
+    async fn trades_held_during_warmup_fill_once_trading_opens() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { warmup_hold_ms: 5_000, warmup_hold_cap: 1, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("carol", 1000.0)
+            .with_post(post_id, "bob", 2.0)
+            .with_markets();
+        state.ready.store(false, std::sync::atomic::Ordering::SeqCst);
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }).to_string();
+
+        let held = tokio::spawn({
+            let (state, buy) = (state.clone(), buy.clone());
+            async move { process_client_message(Uuid::new_v4(), "alice", &buy, &state).await }
+        });
+        while state.held_trades.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 2.0, "nothing fills while warming up");
+        let over_cap = process_client_message(Uuid::new_v4(), "carol", &buy, &state).await;
+        assert_eq!(over_cap.unwrap_err(), TradeError::WarmingUp, "the hold queue is full");
+
+        state.mark_ready();
+
+        let replies = held.await.unwrap().unwrap();
+        assert!(matches!(replies.as_slice(), [ServerMessage::TradeConfirmation { .. }]));
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 3.0);
+        assert_eq!(state.held_trades.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn held_warmup_trades_fill_in_arrival_order() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { warmup_hold_ms: 5_000, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("carol", 1000.0)
+            .with_post(post_id, "bob", 0.0)
+            .with_markets();
+        state.ready.store(false, std::sync::atomic::Ordering::SeqCst);
+        let hold = |user_id: &'static str, quantity: f64| {
+            let state = state.clone();
+            let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": quantity }).to_string();
+            tokio::spawn(async move { process_client_message(Uuid::new_v4(), user_id, &buy, &state).await })
+        };
+        let held_count = |count: usize| {
+            let held_trades = state.held_trades.clone();
+            async move {
+                while held_trades.load(std::sync::atomic::Ordering::SeqCst) < count {
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+
+        let first = hold("alice", 1.0);
+        held_count(1).await;
+        let second = hold("carol", 2.0);
+        held_count(2).await;
+        state.mark_ready();
+
+        let final_supply = |replies: Vec<ServerMessage>| match replies.as_slice() {
+            [ServerMessage::TradeConfirmation { final_supply, .. }] => *final_supply,
+            other => panic!("expected a confirmation, got {:?}", other),
+        };
+        assert_eq!(final_supply(first.await.unwrap().unwrap()), 1.0, "alice arrived first, so fills first");
+        assert_eq!(final_supply(second.await.unwrap().unwrap()), 3.0);
+        assert_eq!(state.held_trades.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn held_warmup_trades_are_refused_after_the_hold() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { warmup_hold_ms: 20, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "bob", 2.0)
+            .with_markets();
+        state.ready.store(false, std::sync::atomic::Ordering::SeqCst);
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }).to_string();
+
+        let refused = process_client_message(Uuid::new_v4(), "alice", &buy, &state).await;
+        assert_eq!(refused.unwrap_err(), TradeError::WarmingUp);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 2.0);
+        assert_eq!(state.held_trades.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn sells_are_capped_at_the_long_position_when_shorts_are_disabled() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { allow_shorts: false, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("alice", post_id, 2.0, 3.0)
+            .with_markets();
+        let sell = |quantity: f64| serde_json::json!({ "type": "sell", "post_id": post_id, "quantity": quantity, "allow_flip": true }).to_string();
+
+        let oversell = process_client_message(Uuid::new_v4(), "alice", &sell(3.0), &state).await;
+        let naked_short = process_client_message(Uuid::new_v4(), "bob", &sell(0.5), &state).await;
+        let full_close = process_client_message(Uuid::new_v4(), "alice", &sell(2.0), &state).await;
+
+        assert!(matches!(oversell.unwrap_err(), TradeError::InvalidField { field, .. } if field == "quantity"));
+        assert!(matches!(naked_short.unwrap_err(), TradeError::InvalidField { field, .. } if field == "quantity"));
+        assert!(full_close.is_ok());
+        assert!(state.posts.get(&post_id).unwrap().supply.abs() < TOLERANCE);
+        let any_short = state.user_positions.iter()
+            .any(|positions| positions.value().iter().any(|p| p.size < -TOLERANCE));
+        assert!(!any_short, "no position went negative");
+    }
+
+    fn batch(legs: serde_json::Value, all_or_nothing: bool) -> String {
+        serde_json::json!({ "type": "batch_trade", "trades": legs, "all_or_nothing": all_or_nothing }).to_string()
+    }
+
+    fn leg_statuses(reply: &ServerMessage) -> Vec<String> {
+        let json = serde_json::to_value(reply).unwrap();
+        json["results"].as_array().unwrap().iter().map(|r| r["status"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn batch_fills_every_leg_in_order() {
+        let (post_a, post_b) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_a, "alice", 0.0)
+            .with_post(post_b, "alice", 2.0)
+            .with_position("alice", post_b, 2.0, 3.0)
+            .with_markets();
+        let legs = serde_json::json!([
+            { "post_id": post_a, "side": "buy", "quantity": 3.0 },
+            { "post_id": post_b, "side": "sell", "quantity": 1.0 },
+            { "post_id": post_a, "side": "sell", "quantity": 1.0 },
+        ]);
+
+        let replies = process_client_message(Uuid::new_v4(), "alice", &batch(legs, true), &state).await.unwrap();
+
+        assert_eq!(leg_statuses(&replies[0]), ["filled", "filled", "filled"]);
+        assert!(matches!(replies[0], ServerMessage::BatchResult { compensated: false, .. }));
+        let positions = state.user_positions.get("alice").unwrap();
+        assert!((positions.get(&post_a).unwrap().size - 2.0).abs() < TOLERANCE);
+        assert!((positions.get(&post_b).unwrap().size - 1.0).abs() < TOLERANCE);
+        assert!((state.posts.get(&post_a).unwrap().supply - 2.0).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn no_trade_runs_between_the_legs_of_an_all_or_nothing_batch() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "carol", 0.0)
+            .with_markets();
+        let legs = serde_json::json!([
+            { "post_id": post_id, "side": "buy", "quantity": 3.0 },
+            { "post_id": post_id, "side": "sell", "quantity": 1.0 },
+        ]);
+
+        // Hold the gate like an executing trade, queue the batch, then bob's trade behind it
+        let in_flight = state.trading_gate.read().await;
+        let batch_state = state.clone();
+        let batch = tokio::spawn(async move {
+            process_client_message(Uuid::new_v4(), "alice", &batch(legs, true), &batch_state).await.unwrap()
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let bob_state = state.clone();
+        let bob = tokio::spawn(async move { fill_trade(Uuid::new_v4(), "bob", post_id, 1.0, false, None, &bob_state).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(in_flight);
+
+        let replies = batch.await.unwrap();
+        let bob_fill = tokio::time::timeout(Duration::from_secs(5), bob).await.unwrap().unwrap().unwrap();
+        let json = serde_json::to_value(&replies[0]).unwrap();
+        let supplies: Vec<f64> = json["results"].as_array().unwrap().iter().map(|r| r["final_supply"].as_f64().unwrap()).collect();
+        assert_eq!(supplies, [3.0, 2.0], "the legs ran back to back");
+        assert_eq!(bob_fill.final_supply, 3.0, "bob's trade ran after the batch");
+    }
+
+    #[tokio::test]
+    async fn compensation_reverses_the_filled_quantity_not_the_requested_one() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "carol", 0.0)
+            .with_markets();
+        // A leg that asked for 5 but was truncated to 2
+        execute_trade(Uuid::new_v4(), "alice", post_id, 2.0, false, &state).await.unwrap();
+        let mut results = vec![
+            LegResult::Filled { post_id, quantity: 2.0, effective_cost: 0.0, fee: 0.0, final_supply: 2.0, final_price: 0.0, liquidations_triggered: 0 },
+            LegResult::Failed { error: TradeError::LiquidationCapReached { cap: 1 } },
+        ];
+
+        let compensated = compensate_legs(Uuid::new_v4(), "alice", &[(post_id, 2.0)], &mut results, &state).await;
+
+        assert!(compensated);
+        assert!(matches!(results[0], LegResult::Compensated));
+        assert_eq!(position_size("alice", post_id, &state), 0.0, "no opposite position opened");
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 0.0);
+    }
+
+    #[tokio::test]
+    async fn all_or_nothing_batch_with_a_failing_leg_changes_nothing() {
+        let (post_a, post_b) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 10.0)
+            .with_post(post_a, "alice", 0.0)
+            .with_post(post_b, "alice", 0.0)
+            .with_markets();
+        // The first leg is affordable on its own; together they exceed alice's collateral
+        let legs = serde_json::json!([
+            { "post_id": post_a, "side": "buy", "quantity": 3.0 },
+            { "post_id": post_b, "side": "buy", "quantity": 5.0 },
+        ]);
+
+        let replies = process_client_message(Uuid::new_v4(), "alice", &batch(legs.clone(), true), &state).await.unwrap();
+
+        assert_eq!(leg_statuses(&replies[0]), ["not_executed", "failed"]);
+        assert!(matches!(replies[0], ServerMessage::BatchResult { compensated: false, .. }));
+        assert_eq!(state.posts.get(&post_a).unwrap().supply, 0.0);
+        assert!(state.user_positions.get("alice").is_none());
+        assert_eq!(ledgers("alice", &state), (0.0, 0.0));
+
+        // Without all_or_nothing the affordable leg goes through alone
+        let replies = process_client_message(Uuid::new_v4(), "alice", &batch(legs, false), &state).await.unwrap();
+        assert_eq!(leg_statuses(&replies[0]), ["filled", "failed"]);
+        assert!((state.posts.get(&post_a).unwrap().supply - 3.0).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn empty_batch_is_rejected() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0);
+
+        let result = process_client_message(Uuid::new_v4(), "alice", &batch(serde_json::json!([]), false), &state).await;
+
+        assert!(matches!(result, Err(TradeError::InvalidField { ref field, .. }) if field == "trades"), "got {:?}", result);
+    }
+
+    #[tokio::test]
+    async fn post_detail_reports_holder_count_and_top_share() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_user("carol", 1000.0)
+            .with_user("dave", 1000.0)
+            .with_post(post_id, "alice", 5.0)
+            .with_position("alice", post_id, 6.0, 10.0)
+            .with_position("bob", post_id, 3.0, 5.0)
+            .with_position("carol", post_id, -4.0, -6.0)
+            .with_position("dave", post_id, 0.0, 0.0) // Fully closed, not a holder
+            .with_markets();
+        let get_post = serde_json::json!({ "type": "get_post", "post_id": post_id }).to_string();
+
+        let replies = process_client_message(Uuid::new_v4(), "bob", &get_post, &state).await.unwrap();
+        let ServerMessage::PostDetail { holder_count, top_holder_share, open_interest, .. } = replies[0] else { panic!("expected PostDetail") };
+        assert_eq!(holder_count, 3);
+        assert!((open_interest - 13.0).abs() < TOLERANCE);
+        assert!((top_holder_share.unwrap() - 6.0 / 13.0).abs() < TOLERANCE);
+
+        // A fill drops the cached stats, so the next request sees dave as a holder
+        execute_trade(Uuid::new_v4(), "dave", post_id, 10.0, false, &state).await.unwrap();
+        let replies = process_client_message(Uuid::new_v4(), "bob", &get_post, &state).await.unwrap();
+        let ServerMessage::PostDetail { holder_count, top_holder_share, .. } = replies[0] else { panic!("expected PostDetail") };
+        assert_eq!(holder_count, 4);
+        assert!((top_holder_share.unwrap() - 10.0 / 23.0).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn crossing_a_volume_tier_lowers_the_next_fee() {
+        let post_id = Uuid::new_v4();
+        let config = Config { fee_tiers: "0:0.01,5:0.005".parse().unwrap(), ..Config::default() };
+        let state = AppState::new_for_test()
+            .with_config(config)
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+
+        let first = execute_trade(Uuid::new_v4(), "alice", post_id, 4.0, false, &state).await.unwrap();
+        let crossing = execute_trade(Uuid::new_v4(), "alice", post_id, 2.0, false, &state).await.unwrap(); // Volume 4 -> 6
+        let next = execute_trade(Uuid::new_v4(), "alice", post_id, -1.0, false, &state).await.unwrap();
+
+        assert!((first.fee - 0.01 * first.effective_cost).abs() < TOLERANCE);
+        assert!((crossing.fee - 0.01 * crossing.effective_cost).abs() < TOLERANCE, "volume before the trade picks the tier");
+        assert!((next.fee - 0.005 * next.effective_cost.abs()).abs() < TOLERANCE);
+        let fees = first.fee + crossing.fee + next.fee;
+        let (_, cash) = ledgers("alice", &state);
+        let costs = first.effective_cost + crossing.effective_cost + next.effective_cost;
+        assert!((cash + costs + fees).abs() < TOLERANCE, "fees are deducted from cash");
+        assert!((*state.insurance_fund.get(&post_id).unwrap() - fees).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn skew_reducing_trades_earn_the_maker_rebate_instead_of_the_fee() {
+        let post_id = Uuid::new_v4();
+        let config = Config { fee_tiers: "0:0.01".parse().unwrap(), maker_rebate_rate: Some(0.002), ..Config::default() };
+        let state = AppState::new_for_test()
+            .with_config(config)
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "bob", 4.0)
+            .with_position("bob", post_id, 4.0, 9.333333333333334);
+
+        let taker = execute_trade(Uuid::new_v4(), "alice", post_id, 1.0, false, &state).await.unwrap(); // 4 -> 5, away from zero
+        let maker = execute_trade(Uuid::new_v4(), "alice", post_id, -1.0, false, &state).await.unwrap(); // 5 -> 4, toward zero
+
+        assert!((taker.fee - 0.01 * taker.effective_cost).abs() < TOLERANCE);
+        assert!((maker.fee + 0.002 * maker.effective_cost.abs()).abs() < TOLERANCE, "a rebate is a negative fee");
+        assert!((maker.effective_cost + taker.effective_cost).abs() < TOLERANCE, "same size, same stretch of the curve");
+        let (realized_pnl, cash) = ledgers("alice", &state);
+        assert!((cash + taker.effective_cost + maker.effective_cost + taker.fee + maker.fee).abs() < TOLERANCE, "the rebate is credited");
+        assert!((realized_pnl + taker.fee + maker.fee).abs() < TOLERANCE);
+        assert!((*state.insurance_fund.get(&post_id).unwrap() - taker.fee).abs() < TOLERANCE, "only the fee is distributed");
+        assert!((state.metrics.rebates_paid.get() + maker.fee).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn a_round_trip_never_profits_at_the_highest_allowed_rebate() {
+        let fee_tiers: crate::config::FeeSchedule = "0:0.01,5:0.004".parse().unwrap();
+        let config = Config { maker_rebate_rate: Some(fee_tiers.min_rate()), fee_tiers, ..Config::default() };
+        // Long skew: the buy pays the fee and the sell earns the rebate; short skew: the reverse
+        for skew in [4.0, -4.0] {
+            let post_id = Uuid::new_v4();
+            let state = AppState::new_for_test()
+                .with_config(config.clone())
+                .with_user("alice", 1000.0)
+                .with_post(post_id, "bob", skew)
+                .with_position("bob", post_id, skew, skew.abs().powf(1.5) * skew.signum());
+            // Enough round trips to cross into the cheaper tier
+            for _ in 0..4 {
+                execute_trade(Uuid::new_v4(), "alice", post_id, 2.0, false, &state).await.unwrap();
+                execute_trade(Uuid::new_v4(), "alice", post_id, -2.0, false, &state).await.unwrap();
+                let (realized_pnl, cash) = ledgers("alice", &state);
+                assert!(cash <= 0.0 && realized_pnl <= 0.0, "skew {}: cash {}, realized {}", skew, cash, realized_pnl);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn liquidation_cap_halts_a_cascade_through_stacked_thresholds() {
+        use crate::config::LiquidationCapPolicy;
+        let stacked = |policy: LiquidationCapPolicy| {
+            let post_id = Uuid::new_v4();
+            let mut state = AppState::new_for_test()
+                .with_config(Config { max_liquidations_per_trade: 3, liquidation_cap_policy: policy, ..Config::default() })
+                .with_user("alice", 1_000_000.0)
+                .with_post(post_id, "alice", 0.0);
+            // Shorts of 1 at price 1 with balances 1..=8 liquidate at prices 2..=9: supplies 1, 4, 9, ..., 64
+            for i in 1..=8 {
+                let user_id = format!("short{}", i);
+                state = state.with_user(&user_id, i as f64).with_position(&user_id, post_id, -1.0, -1.0);
+            }
+            (post_id, state.with_markets())
+        };
+
+        let (post_id, state) = stacked(LiquidationCapPolicy::Truncate);
+        update_liquidation_thresholds(post_id, &state).await;
+        assert_eq!(state.liquidation_thresholds.get(&post_id).unwrap().len(), 8);
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 100.0 }).to_string();
+        let replies = process_client_message(Uuid::new_v4(), "alice", &buy, &state).await.unwrap();
+
+        let [ServerMessage::TradeConfirmation { quantity, unfilled_quantity, liquidations_triggered, final_supply, .. }] = replies.as_slice() else {
+            panic!("expected a TradeConfirmation, got {:?}", replies)
+        };
+        assert_eq!(*liquidations_triggered, 3);
+        // Segments 0->1, 2->4 and 5->9, halted after the third unwind, short of the threshold at 16
+        assert!((quantity - 7.0).abs() < 1e-6, "filled {}", quantity);
+        assert!((unfilled_quantity - 93.0).abs() < 1e-6);
+        assert!((final_supply - 10.0).abs() < 1e-6);
+        let open: Vec<bool> = (1..=8).map(|i| position_size(&format!("short{}", i), post_id, &state) != 0.0).collect();
+        assert_eq!(open, [false, false, false, true, true, true, true, true]);
+
+        let (post_id, state) = stacked(LiquidationCapPolicy::Reject);
+        update_liquidation_thresholds(post_id, &state).await;
+        let rejected = execute_trade(Uuid::new_v4(), "alice", post_id, 100.0, false, &state).await;
+        assert_eq!(rejected.unwrap_err(), TradeError::LiquidationCapReached { cap: 3 });
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 0.0);
+        assert_eq!(position_size("alice", post_id, &state), 0.0);
+
+        // An all_or_nothing batch refuses a leg the cap would truncate, before anything fills
+        let (post_id, state) = stacked(LiquidationCapPolicy::Truncate);
+        update_liquidation_thresholds(post_id, &state).await;
+        let legs = serde_json::json!([{ "post_id": post_id, "side": "buy", "quantity": 100.0 }]);
+        let replies = process_client_message(Uuid::new_v4(), "alice", &batch(legs, true), &state).await.unwrap();
+        let ServerMessage::BatchResult { results, .. } = &replies[0] else { panic!("expected BatchResult") };
+        assert!(matches!(&results[0], LegResult::Failed { error: TradeError::LiquidationCapReached { cap: 3 } }));
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 0.0);
+
+        // A margin liquidation crossing the same thresholds is never capped
+        let (post_id, state) = stacked(LiquidationCapPolicy::Reject);
+        let state = state.with_user("whale", 1.0).with_position("whale", post_id, -100.0, -100.0);
+        update_liquidation_thresholds(post_id, &state).await;
+        let forced = execute_margin_liquidation("whale", post_id, &state).await.unwrap();
+        assert_eq!(forced.liquidations_triggered, 8);
+        assert_eq!(position_size("whale", post_id, &state), 0.0, "closed in full");
+    }
+
+    #[tokio::test]
+    async fn collateral_check_includes_the_fee() {
+        let post_id = Uuid::new_v4();
+        let config = Config { fee_tiers: "0:0.1".parse().unwrap(), ..Config::default() };
+        let state = AppState::new_for_test()
+            .with_config(config)
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+        // cost(0, 4) is 3 + 2/3 * 4^1.5 = 9.333..., so 10 covers the cost but not the 10% fee
+        state.user_balances.insert("alice".to_string(), 10.0);
+
+        let result = execute_trade(Uuid::new_v4(), "alice", post_id, 4.0, false, &state).await;
+
+        assert!(matches!(result, Err(TradeError::InsufficientCollateral { .. })), "got {:?}", result);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 0.0);
+    }
+
+    #[tokio::test]
+    async fn supply_invariant_holds_across_trades_and_detects_corruption() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { check_supply_invariant: true, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+        execute_trade(Uuid::new_v4(), "alice", post_id, 3.0, false, &state).await.unwrap();
+        execute_trade(Uuid::new_v4(), "bob", post_id, -5.0, true, &state).await.unwrap();
+        execute_trade(Uuid::new_v4(), "alice", post_id, -4.0, true, &state).await.unwrap();
+        assert_eq!(check_supply_invariant(post_id, &state), Ok(()));
+
+        state.user_positions.get("bob").unwrap().get_mut(&post_id).unwrap().size += 0.25;
+
+        let (supply, net_position) = check_supply_invariant(post_id, &state).unwrap_err();
+        assert!((net_position - supply - 0.25).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "supply invariant violated")]
+    async fn corrupted_position_fails_the_post_trade_invariant_check() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { check_supply_invariant: true, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("alice", post_id, 1.0, 1.5); // Supply says 2
+
+        let _ = execute_trade(Uuid::new_v4(), "alice", post_id, 1.0, false, &state).await;
+    }
+
+    #[tokio::test]
+    async fn creator_closing_a_market_pays_out_longs_and_shorts_at_the_current_price() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_user("carol", 1000.0)
+            .with_post(post_id, "alice", 4.0) // Price 1 + sqrt(4) = 3
+            .with_position("alice", post_id, 2.0, 3.0)
+            .with_position("bob", post_id, 3.0, 5.0)
+            .with_position("carol", post_id, -1.0, -1.5)
+            .with_markets();
+        let (bob_client, mut bob) = connect("bob", &state);
+        let close = serde_json::json!({ "type": "close_own_post", "post_id": post_id }).to_string();
+
+        let by_holder = process_client_message(bob_client, "bob", &close, &state).await;
+        assert!(matches!(by_holder, Err(TradeError::NotPostCreator { .. })), "got {:?}", by_holder);
+
+        process_client_message(Uuid::new_v4(), "alice", &close, &state).await.unwrap();
+
+        // PnL = size * 3 - basis; cash had paid the basis and now receives size * 3
+        assert_eq!(ledgers("alice", &state), (3.0, 3.0));
+        assert_eq!(ledgers("bob", &state), (4.0, 4.0));
+        assert_eq!(ledgers("carol", &state), (-1.5, -1.5));
+        assert!(state.user_positions.is_empty());
+        assert_eq!(*state.user_exposure.get("bob").unwrap(), 0.0);
+        let post = state.posts.get(&post_id).unwrap().clone();
+        assert_eq!((post.supply, post.settlement_price), (0.0, Some(3.0)));
+
+        let messages = drain_json(&mut bob);
+        let settled = messages.iter().find(|m| m["type"] == "post_settled").expect("a post_settled broadcast");
+        assert_eq!(settled["price"], 3.0);
+        assert_eq!(settled["positions_closed"], 3);
+        assert_eq!(count_of(&messages, "user_sync"), 1);
+
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }).to_string();
+        let after = process_client_message(bob_client, "bob", &buy, &state).await;
+        assert!(matches!(after, Err(TradeError::MarketClosed { .. })), "got {:?}", after);
+    }
+
+    #[tokio::test]
+    async fn admins_can_read_the_liquidation_ladder() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { admin_users: vec!["ops".to_string()], ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0);
+        let mut ladder = BTreeMap::new();
+        ladder.insert(OrderedFloat(9.0), vec![LiquidationEntry { cost_unwind: 3.5, size_unwind: 1.0, cost_basis: -1.0, user_id: "dave".to_string() }]);
+        ladder.insert(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind: 6.25, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }, LiquidationEntry { cost_unwind: 1.5, size_unwind: 0.5, cost_basis: -0.5, user_id: "erin".to_string() }]);
+        state.liquidation_thresholds.insert(post_id, ladder);
+        let get_ladder = serde_json::json!({ "type": "get_liquidation_ladder", "post_id": post_id }).to_string();
+
+        let by_user = process_client_message(Uuid::new_v4(), "alice", &get_ladder, &state).await;
+        let replies = process_client_message(Uuid::new_v4(), "ops", &get_ladder, &state).await.unwrap();
+
+        assert!(matches!(by_user, Err(TradeError::AdminOnly)), "got {:?}", by_user);
+        let ServerMessage::LiquidationLadder { current_supply, levels, .. } = &replies[0] else { panic!("expected LiquidationLadder") };
+        assert_eq!(*current_supply, 0.0);
+        let entry = |user_id: &str, cost_unwind, size_unwind| LadderEntry { user_id: user_id.to_string(), cost_unwind, size_unwind };
+        assert_eq!(levels, &vec![
+            LadderLevel { supply: 4.0, price: 3.0, entries: vec![entry("carol", 6.25, 2.0), entry("erin", 1.5, 0.5)] },
+            LadderLevel { supply: 9.0, price: 4.0, entries: vec![entry("dave", 3.5, 1.0)] },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn buys_stop_at_the_post_supply_cap() {
+        let (capped, default_capped) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_config(Config { max_supply: 100.0, ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(capped, "alice", 1.0)
+            .with_post(default_capped, "alice", 99.0);
+        state.posts.get_mut(&capped).unwrap().max_supply = Some(4.0); // Overrides the server cap
+
+        let over = execute_trade(Uuid::new_v4(), "alice", capped, 3.5, false, &state).await;
+        let to_cap = execute_trade(Uuid::new_v4(), "alice", capped, 3.0, false, &state).await.unwrap();
+        let over_default = execute_trade(Uuid::new_v4(), "alice", default_capped, 2.0, false, &state).await;
+        let sell_at_cap = execute_trade(Uuid::new_v4(), "alice", capped, -1.0, false, &state).await;
+
+        let Err(TradeError::InvalidField { field, reason }) = over else { panic!("expected a cap rejection, got {:?}", over) };
+        assert_eq!(field, "quantity");
+        assert!(reason.contains("at most 3.000000"), "{}", reason);
+        // The buy up to the cap pays the plain curve integral from 1 to 4
+        assert!((to_cap.effective_cost - calculate_smooth_cost(1.0, 4.0, 0.0, state.config.bonding_curve_epsilon)).abs() < TOLERANCE);
+        assert_eq!(to_cap.final_supply, 4.0);
+        assert!(matches!(over_default, Err(TradeError::InvalidField { .. })));
+        assert!(sell_at_cap.is_ok(), "sells are never capped");
+    }
+
+    #[tokio::test]
+    async fn simulated_cascade_matches_the_real_trade() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { admin_users: vec!["ops".to_string()], ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_position("carol", post_id, -2.0, -3.0)
+            .with_position("dave", post_id, -1.0, -1.0);
+        let mut ladder = BTreeMap::new();
+        ladder.insert(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind: 6.464625637799379, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }]);
+        ladder.insert(OrderedFloat(7.0), vec![LiquidationEntry { cost_unwind: 3.7381052136782564, size_unwind: 1.0, cost_basis: -1.0, user_id: "dave".to_string() }]);
+        state.liquidation_thresholds.insert(post_id, ladder);
+        let simulate = serde_json::json!({ "type": "simulate_cascade", "post_id": post_id, "target_supply": 6.0 }).to_string();
+
+        let replies = process_client_message(Uuid::new_v4(), "ops", &simulate, &state).await.unwrap();
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 0.0, "simulation changes nothing");
+        let fill = execute_trade(Uuid::new_v4(), "alice", post_id, 6.0, false, &state).await.unwrap();
+
+        let ServerMessage::CascadeSimulation { final_supply, liquidations, forced_trade_cost, total_cost, .. } = &replies[0] else {
+            panic!("expected CascadeSimulation")
+        };
+        let users: Vec<&str> = liquidations.iter().map(|l| l.user_id.as_str()).collect();
+        assert_eq!(users, ["carol", "dave"]);
+        assert_eq!(*final_supply, fill.final_supply);
+        assert!((total_cost - fill.effective_cost).abs() < TOLERANCE);
+        assert!((forced_trade_cost - fill.liquidation_notional).abs() < TOLERANCE);
+        let by_user = process_client_message(Uuid::new_v4(), "alice", &simulate, &state).await;
+        assert!(matches!(by_user, Err(TradeError::AdminOnly)));
+    }
+
+    #[tokio::test]
+    async fn buys_cannot_spend_the_collateral_reserve() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { min_collateral_reserve: 1.0, collateral_reserve_rate: 0.1, ..Config::default() })
+            .with_user("alice", 20.0)
+            .with_post(post_id, "alice", 0.0);
+        // cost(0, 4) = 9.33 leaves 10.67 of the 20 free
+        let ServerMessage::UserSync { buying_power, .. } = build_user_sync("alice", &snapshot_prices(&["alice"], &state), &state) else { unreachable!() };
+        assert_eq!(buying_power, 18.0, "20 less the larger of 1 and 10% of 20");
+
+        execute_trade(Uuid::new_v4(), "alice", post_id, 4.0, false, &state).await.unwrap();
+        // cost(4, 6.8) = 9.29 fits in the raw 10.67 left but not in the 8.67 above the reserve
+        let into_reserve = execute_trade(Uuid::new_v4(), "alice", post_id, 2.8, false, &state).await;
+        let closing = execute_trade(Uuid::new_v4(), "alice", post_id, -4.0, false, &state).await;
+
+        let Err(TradeError::InsufficientCollateral { available, .. }) = into_reserve else { panic!("expected a rejection, got {:?}", into_reserve) };
+        assert!((available - (20.0 - 9.333333 - 2.0)).abs() < 1e-5, "the reserve isn't spendable, got {}", available);
+        assert!(closing.is_ok(), "closing is never held back by the reserve");
+    }
+
+    #[tokio::test]
+    async fn post_price_tracks_supply_through_every_kind_of_fill() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_position("carol", post_id, -2.0, -3.0)
+            .with_markets();
+        state.liquidation_thresholds.insert(post_id, BTreeMap::from([(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind: 6.464625637799379, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }])]));
+        let price_matches_supply = |state: &AppState| state.posts.iter().all(|post| {
+            post.price > 0.0 && (post.price - get_price(post.supply, post.flat_width, state.config.bonding_curve_epsilon)).abs() < TOLERANCE
+        });
+
+        let created = process_client_message(Uuid::new_v4(), "bob", &serde_json::json!({ "type": "create_post", "content": "x" }).to_string(), &state).await;
+        assert!(created.is_ok() && price_matches_supply(&state), "new post");
+        execute_trade(Uuid::new_v4(), "alice", post_id, 6.0, false, &state).await.unwrap(); // Crosses carol's threshold
+        assert!(price_matches_supply(&state), "after a trade with a liquidation");
+        execute_margin_liquidation("alice", post_id, &state).await.unwrap();
+        assert!(price_matches_supply(&state), "after a margin liquidation");
+        execute_trade(Uuid::new_v4(), "bob", post_id, 3.0, false, &state).await.unwrap();
+        execute_settlement(Uuid::new_v4(), "alice", post_id, &state).await.unwrap();
+        assert!(price_matches_supply(&state), "after settlement");
+    }
+
+    #[tokio::test]
+    async fn retried_buy_with_the_same_idempotency_key_executes_once() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_markets();
+        let buy = |key: &str| serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 2.0, "idempotency_key": key }).to_string();
+
+        let first = process_client_message(Uuid::new_v4(), "alice", &buy("order-1"), &state).await.unwrap();
+        let retry = process_client_message(Uuid::new_v4(), "alice", &buy("order-1"), &state).await.unwrap();
+
+        assert_eq!(serde_json::to_value(&first[0]).unwrap(), serde_json::to_value(&retry[0]).unwrap());
+        assert_eq!(state.user_positions.get("alice").unwrap().get(&post_id).unwrap().size, 2.0);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 2.0);
+
+        process_client_message(Uuid::new_v4(), "alice", &buy("order-2"), &state).await.unwrap();
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 4.0, "a new key trades again");
+
+        let sell = serde_json::json!({ "type": "sell", "post_id": post_id, "quantity": 2.0, "idempotency_key": "order-1" }).to_string();
+        let reused = process_client_message(Uuid::new_v4(), "alice", &sell, &state).await;
+        assert!(matches!(reused, Err(TradeError::InvalidField { ref field, .. }) if field == "idempotency_key"), "{:?}", reused);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 4.0, "a reused key neither replays nor trades");
+    }
+
+    #[tokio::test]
+    async fn insufficient_collateral_reports_a_consistent_shortfall() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 5.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_markets();
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 4.0 }).to_string();
+
+        let result = process_client_message(Uuid::new_v4(), "alice", &buy, &state).await;
+
+        let Err(error) = result else { panic!("expected a rejection") };
+        let TradeError::InsufficientCollateral { required, available, shortfall } = error.clone() else { panic!("got {:?}", error) };
+        // cost(0, 4) = 4 + 2/3 * 4^1.5 = 9.333...
+        assert!((required - (4.0 + 16.0 / 3.0)).abs() < TOLERANCE);
+        assert_eq!(available, 5.0);
+        assert_eq!(shortfall, required - available);
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["code"], "insufficient_collateral");
+        assert_eq!(json["shortfall"], shortfall);
+    }
+
+    #[tokio::test]
+    async fn trade_history_pages_back_from_the_newest_trade() {
+        let (post_a, post_b) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_a, "alice", 0.0)
+            .with_post(post_b, "alice", 0.0)
+            .with_markets();
+        for (post_id, quantity) in [(post_a, 3.0_f64), (post_b, 1.0), (post_a, -1.0), (post_a, 2.0)] {
+            let side = if quantity > 0.0 { "buy" } else { "sell" };
+            let trade = serde_json::json!({ "type": side, "post_id": post_id, "quantity": quantity.abs() }).to_string();
+            process_client_message(Uuid::new_v4(), "alice", &trade, &state).await.unwrap();
+        }
+        let history = |request: serde_json::Value| {
+            let state = state.clone();
+            async move {
+                let mut reply = process_client_message(Uuid::new_v4(), "alice", &request.to_string(), &state).await.unwrap();
+                let Some(ServerMessage::TradeHistory { trades, has_more }) = reply.pop() else { panic!("expected a trade history") };
+                (trades, has_more)
+            }
+        };
+
+        let (first_page, has_more) = history(serde_json::json!({ "type": "get_trade_history", "limit": 3 })).await;
+        let quantities: Vec<f64> = first_page.iter().map(|t| t.quantity).collect();
+        assert_eq!(quantities, [2.0, -1.0, 1.0], "newest first");
+        assert!(has_more);
+        assert!(first_page.windows(2).all(|pair| pair[0].seq == pair[1].seq + 1));
+        assert!(first_page.windows(2).all(|pair| pair[0].executed_at >= pair[1].executed_at));
+        let sale = &first_page[1];
+        assert!((sale.price * sale.quantity - sale.cost).abs() < TOLERANCE);
+        assert!(sale.realized_pnl != 0.0 && !sale.forced);
+
+        let before = first_page.last().unwrap().seq;
+        let (second_page, has_more) = history(serde_json::json!({ "type": "get_trade_history", "limit": 3, "before": before })).await;
+        assert_eq!(second_page.len(), 1);
+        assert_eq!(second_page[0].quantity, 3.0);
+        assert!(!has_more);
+
+        let (on_b, _) = history(serde_json::json!({ "type": "get_trade_history", "post_id": post_b })).await;
+        assert_eq!(on_b.len(), 1);
+        assert_eq!(on_b[0].post_id, post_b);
+    }
+
+    #[test]
+    fn trade_history_pages_through_trades_sharing_a_timestamp() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0);
+        let executed_at = Utc::now();
+        for quantity in [1.0, 2.0, 3.0] {
+            let record = TradeRecord { seq: 0, executed_at, post_id: Uuid::nil(), quantity, price: 1.0, cost: quantity, fee: 0.0, realized_pnl: 0.0, forced: false };
+            record_trade("alice", record, &state);
+        }
+        let page = |before| match handle_get_trade_history("alice", None, Some(2), before, &state).unwrap() {
+            ServerMessage::TradeHistory { trades, has_more } => (trades.iter().map(|t| t.quantity).collect::<Vec<_>>(), has_more),
+            other => panic!("expected a trade history, got {:?}", other),
+        };
+
+        assert_eq!(page(None), (vec![3.0, 2.0], true));
+        assert_eq!(page(Some(2)), (vec![1.0], false), "the cursor doesn't skip trades from the same instant");
+    }
+
+    #[tokio::test]
+    async fn a_deposit_pushes_exactly_one_balance_update() {
+        let state = AppState::new_for_test()
+            .with_config(Config { admin_users: vec!["ops".to_string()], ..Config::default() })
+            .with_user("alice", 100.0);
+        let (_client_id, mut receiver) = connect("alice", &state);
+        let adjust = |user_id: &str, amount: f64| {
+            let text = serde_json::json!({ "type": "adjust_balance", "user_id": "alice", "amount": amount }).to_string();
+            let user_id = user_id.to_string();
+            let state = state.clone();
+            async move { process_client_message(Uuid::new_v4(), &user_id, &text, &state).await }
+        };
+
+        let replies = adjust("ops", 25.0).await.unwrap();
+
+        let messages = drain_json(&mut receiver);
+        assert!(matches!(&replies[..], [ServerMessage::BalanceAdjusted { balance, .. }] if *balance == 125.0), "{:?}", replies);
+        assert_eq!(count_of(&messages, "balance_update"), 1, "{:?}", messages);
+        assert_eq!(messages[0]["balance"], 125.0);
+        assert!(adjust("ops", -1000.0).await.is_err(), "can't withdraw more than the collateral");
+        assert!(matches!(adjust("alice", 1000.0).await, Err(TradeError::AdminOnly)), "only admins adjust balances");
+        assert!(drain_json(&mut receiver).is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_sharing_a_threshold_unwind_largest_first_then_by_user_id() {
+        // Shorts at price 1 with a balance of one per unit all liquidate at the same supply
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("whale", 1_000_000.0)
+            .with_post(post_id, "whale", -5.0)
+            .with_user("carol", 1.0)
+            .with_position("carol", post_id, -1.0, -1.0)
+            .with_user("bob", 2.0)
+            .with_position("bob", post_id, -2.0, -2.0)
+            .with_user("alice", 2.0)
+            .with_position("alice", post_id, -2.0, -2.0)
+            .with_markets();
+        update_liquidation_thresholds(post_id, &state).await;
+
+        let ladder = state.liquidation_thresholds.get(&post_id).unwrap().clone();
+        assert_eq!(ladder.len(), 1, "one shared threshold: {:?}", ladder);
+        let queued: Vec<&str> = ladder.values().next().unwrap().iter().map(|entry| entry.user_id.as_str()).collect();
+        assert_eq!(queued, ["alice", "bob", "carol"]);
+
+        let result = calculate_effective_cost_and_final_supply(-5.0, 100.0, post_id, Some("whale"), &state).unwrap();
+        let unwound: Vec<&str> = result.liquidated_users.iter().map(|fill| fill.user_id.as_str()).collect();
+        assert_eq!(unwound, ["alice", "bob", "carol"]);
+    }
+
+    #[tokio::test]
+    async fn the_liquidation_penalty_moves_from_the_user_to_the_insurance_fund() {
+        // Well collateralized, so the close leaves no bad debt for the fund to cover
+        let liquidate = |liquidation_penalty_rate| async move {
+            let post_id = Uuid::new_v4();
+            let state = AppState::new_for_test()
+                .with_config(Config { liquidation_penalty_rate, ..Config::default() })
+                .with_user("alice", 100.0)
+                .with_post(post_id, "bob", 4.0)
+                .with_position("alice", post_id, 4.0, 6.0)
+                .with_markets();
+            let fill = execute_margin_liquidation("alice", post_id, &state).await.unwrap();
+            let fund = state.insurance_fund.get(&post_id).map_or(0.0, |fund| *fund);
+            (fill, ledgers("alice", &state), fund)
+        };
+
+        let (free, (free_pnl, free_cash), free_fund) = liquidate(0.0).await;
+        let (charged, (charged_pnl, charged_cash), charged_fund) = liquidate(0.1).await;
+
+        let penalty = 0.1 * charged.effective_cost.abs();
+        assert!(penalty > 0.0);
+        assert!((charged.effective_cost - free.effective_cost).abs() < TOLERANCE, "same forced close");
+        assert!((free_pnl - charged_pnl - penalty).abs() < TOLERANCE, "PnL {} -> {}", free_pnl, charged_pnl);
+        assert!((free_cash - charged_cash - penalty).abs() < TOLERANCE);
+        assert_eq!(free_fund, 0.0);
+        assert!((charged_fund - penalty).abs() < TOLERANCE, "fund {} != penalty {}", charged_fund, penalty);
+    }
+
+    #[test]
+    fn bad_debt_is_covered_by_the_fund_first_then_by_profitable_holders() {
+        // bankrupt: balance 1, realized PnL -3, so 2 of bad debt
+        let post_id = Uuid::new_v4();
+        let seeded = |fund: f64| {
+            let state = AppState::new_for_test()
+                .with_user("bankrupt", 1.0)
+                .with_user("carol", 10.0)
+                .with_user("dave", 10.0)
+                .with_user("erin", 10.0)
+                .with_post(post_id, "bob", 4.0)
+                .with_position("carol", post_id, 2.0, 3.0) // Up 3 at price 3
+                .with_position("dave", post_id, 1.0, 2.0) // Up 1
+                .with_position("erin", post_id, 1.0, 5.0); // Down 2, never charged
+            state.user_realized_pnl.insert("bankrupt".to_string(), -3.0);
+            state.insurance_fund.insert(post_id, fund);
+            state
+        };
+        let total_value = |state: &AppState| {
+            let collateral: f64 = ["bankrupt", "carol", "dave", "erin"].iter().map(|user_id| {
+                *state.user_balances.get(*user_id).unwrap() + ledgers(user_id, state).0
+            }).sum();
+            collateral + *state.insurance_fund.get(&post_id).unwrap()
+        };
+        let equity = |user_id: &str, state: &AppState| *state.user_balances.get(user_id).unwrap() + ledgers(user_id, state).0;
+
+        // A fund large enough covers it all
+        let state = seeded(5.0);
+        let before = total_value(&state);
+        assert!(cover_bad_debt("bankrupt", post_id, 3.0, &state).is_empty());
+        assert!((*state.insurance_fund.get(&post_id).unwrap() - 3.0).abs() < TOLERANCE);
+        assert!(equity("bankrupt", &state).abs() < TOLERANCE);
+        assert!((total_value(&state) - before).abs() < TOLERANCE);
+
+        // An empty fund leaves it all to the profitable holders, pro rata to their profit
+        let state = seeded(0.0);
+        let before = total_value(&state);
+        let mut charges = cover_bad_debt("bankrupt", post_id, 3.0, &state);
+        charges.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(charges.len(), 2, "{:?}", charges);
+        assert_eq!((charges[0].0.as_str(), charges[1].0.as_str()), ("carol", "dave"));
+        assert!((charges[0].1 - 1.5).abs() < TOLERANCE && (charges[1].1 - 0.5).abs() < TOLERANCE, "{:?}", charges);
+        assert!((equity("carol", &state) - 8.5).abs() < TOLERANCE);
+        assert!((equity("erin", &state) - 10.0).abs() < TOLERANCE);
+        assert!(equity("bankrupt", &state).abs() < TOLERANCE);
+        assert_eq!(*state.insurance_fund.get(&post_id).unwrap(), 0.0);
+        assert!((total_value(&state) - before).abs() < TOLERANCE);
+
+        // A fund too small to cover it pays what it holds; the holders pay the rest
+        let state = seeded(1.0);
+        let before = total_value(&state);
+        let charged: f64 = cover_bad_debt("bankrupt", post_id, 3.0, &state).iter().map(|(_, amount)| amount).sum();
+        assert!((charged - 1.0).abs() < TOLERANCE);
+        assert_eq!(*state.insurance_fund.get(&post_id).unwrap(), 0.0);
+        assert!((total_value(&state) - before).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn a_withdrawal_moves_thresholds_through_the_market_actor() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("short", 10.0)
+            .with_post(post_id, "bob", -1.0)
+            .with_position("short", post_id, -1.0, -1.0)
+            .with_markets();
+        update_liquidation_thresholds(post_id, &state).await;
+        let threshold = || state.liquidation_thresholds.get(&post_id).and_then(|ladder| ladder.keys().next().map(|supply| supply.0));
+        let before = threshold().expect("the short is liquidatable");
+
+        adjust_balance("short", -5.0, &state).await.unwrap();
+
+        let after = threshold().expect("the short is still liquidatable");
+        assert!(after < before, "less collateral liquidates the short sooner: {} -> {}", before, after);
+    }
+
+    #[tokio::test]
+    async fn creators_trade_their_own_posts_only_when_self_trade_is_allowed() {
+        for forbid_self_trade in [false, true] {
+            let post_id = Uuid::new_v4();
+            let state = AppState::new_for_test()
+                .with_config(Config { forbid_self_trade, ..Config::default() })
+                .with_user("alice", 1000.0)
+                .with_user("bob", 1000.0)
+                .with_post(post_id, "alice", 0.0)
+                .with_markets();
+            let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 1.0 }).to_string();
+
+            let by_creator = process_client_message(Uuid::new_v4(), "alice", &buy, &state).await;
+            let by_other = process_client_message(Uuid::new_v4(), "bob", &buy, &state).await;
+
+            assert!(by_other.is_ok());
+            if forbid_self_trade {
+                assert_eq!(by_creator.unwrap_err(), TradeError::SelfTradeForbidden { post_id });
+                assert!(state.user_positions.get("alice").is_none_or(|positions| positions.get(&post_id).is_none()));
+                assert_eq!(state.posts.get(&post_id).unwrap().supply, 1.0, "only bob's buy");
             } else {
-                println!("update_liquidation_thresholds: User {}: No liquidation price calculated.", user_id);
+                assert!(by_creator.is_ok());
+                assert_eq!(state.posts.get(&post_id).unwrap().supply, 2.0);
             }
-         } else {
-            println!("update_liquidation_thresholds: User {} has no position on post {}.", user_id, post_id);
         }
     }
-    println!("update_liquidation_thresholds: Finished Phase 1. Starting Phase 2 - Updating state...");
 
-    // --- Phase 2: Update state --- 
-    // Remove thresholds where the net effect is negligible (optional optimization)
-     aggregated_thresholds.retain(|_, entries| {
-         entries.iter().any(|(cost, size, _)| cost.abs() > EPSILON || size.abs() > EPSILON)
-     });
-    println!("update_liquidation_thresholds: Retained {} aggregated thresholds.", aggregated_thresholds.len());
+    #[tokio::test]
+    async fn a_non_finite_threshold_rejects_the_trade_without_touching_state() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_position("alice", post_id, 2.0, 3.0);
+        // A corrupted threshold in bob's path
+        state.liquidation_thresholds.get_mut(&post_id).unwrap()
+            .insert(OrderedFloat(1.0), vec![LiquidationEntry { cost_unwind: f64::NAN, size_unwind: -2.0, cost_basis: 3.0, user_id: "alice".to_string() }]);
+        let before = (state.posts.get(&post_id).unwrap().supply, ledgers("bob", &state), ledgers("alice", &state));
 
-    // Insert the newly calculated map into the shared state
-    state.liquidation_thresholds.insert(post_id, aggregated_thresholds);
-    println!("update_liquidation_thresholds: Inserted aggregated map into state.liquidation_thresholds.");
+        let result = execute_trade(Uuid::new_v4(), "bob", post_id, 3.0, false, &state).await;
 
-    let duration = start_time.elapsed();
-     println!("--- Finished Updating Liquidation Thresholds for Post: {}. Took {:?}. Entries: {} ---", post_id, duration, state.liquidation_thresholds.get(&post_id).map_or(0, |m| m.len()));
-} 
+        assert!(matches!(result, Err(TradeError::CalculationFailed { .. })), "got {:?}", result);
+        assert_eq!((state.posts.get(&post_id).unwrap().supply, ledgers("bob", &state), ledgers("alice", &state)), before);
+        assert_eq!(state.user_positions.get("alice").unwrap().get(&post_id).unwrap().size, 2.0);
+        assert!(state.user_positions.get("bob").is_none_or(|positions| positions.is_empty()));
+    }
 
-// --- Margin Calculation Helper ---
+    #[tokio::test]
+    async fn clients_opted_out_of_account_updates_still_see_market_updates() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("alice", post_id, 2.0, 3.0)
+            .with_markets();
+        let (watcher, mut prices_only) = connect("alice", &state);
+        let (_, mut full) = connect("alice", &state);
+        request(watcher, "alice", serde_json::json!({ "type": "set_account_updates", "enabled": false }), &state).await;
 
-pub fn calculate_user_margin(user_id: &str, state: &AppState) -> f64 {
-    let balance = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |b| *b.value());
-    let mut total_unrealized_pnl = 0.0;
+        execute_trade(Uuid::new_v4(), "bob", post_id, 1.0, false, &state).await.unwrap();
 
-    if let Some(user_positions_map) = state.user_positions.get(user_id) {
-        for position_entry in user_positions_map.iter() {
-            let post_id = *position_entry.key();
-            let position = position_entry.value();
+        let (watched, synced) = (drain_json(&mut prices_only), drain_json(&mut full));
+        assert_eq!(count_of(&watched, "market_update"), 1);
+        assert_eq!(watched.len(), 1, "only the market update: {:?}", watched);
+        assert_eq!(count_of(&synced, "market_update"), 1);
+        assert_eq!(count_of(&synced, "user_sync"), 1, "the other connection is unaffected");
+
+        adjust_balance("alice", 10.0, &state).await.unwrap();
+        assert!(drain_json(&mut prices_only).is_empty(), "no BalanceUpdate either");
+        assert_eq!(count_of(&drain_json(&mut full), "balance_update"), 1);
+    }
+
+    #[tokio::test]
+    async fn fee_totals_reconcile_with_their_splits() {
+        let post_id = Uuid::new_v4();
+        let config = Config {
+            fee_tiers: "0:0.1".parse().unwrap(),
+            creator_fee_share: 0.2,
+            protocol_fee_share: 0.3,
+            admin_users: vec!["admin".to_string()],
+            ..Config::default()
+        };
+        let state = AppState::new_for_test()
+            .with_config(config)
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_user("carol", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_markets();
+
+        let mut fees = 0.0;
+        for (user_id, quantity) in [("bob", 3.0), ("carol", 2.0), ("bob", -1.5), ("carol", -2.0)] {
+            fees += execute_trade(Uuid::new_v4(), user_id, post_id, quantity, false, &state).await.unwrap().fee;
+        }
+        let get_totals = serde_json::json!({ "type": "get_fee_totals", "post_id": post_id }).to_string();
+        let replies = process_client_message(Uuid::new_v4(), "admin", &get_totals, &state).await.unwrap();
+
+        let ServerMessage::FeeTotals { totals, .. } = replies[0] else { panic!("expected FeeTotals") };
+        assert!((totals.total - fees).abs() < TOLERANCE);
+        assert!((totals.total - (totals.creator + totals.insurance + totals.protocol)).abs() < TOLERANCE);
+        assert!((totals.creator - 0.2 * fees).abs() < TOLERANCE);
+        assert!((totals.protocol - 0.3 * fees).abs() < TOLERANCE);
+        assert!((*state.insurance_fund.get(&post_id).unwrap() - totals.insurance).abs() < TOLERANCE);
+        assert!((*state.user_realized_pnl.get("alice").unwrap() - totals.creator).abs() < TOLERANCE, "the creator is paid their share");
+        assert!((state.metrics.fees_collected.get() - fees).abs() < TOLERANCE);
+        assert!(state.metrics.render().contains("flvke_fees_collected_total"));
+        let by_user = process_client_message(Uuid::new_v4(), "bob", &get_totals, &state).await;
+        assert!(matches!(by_user, Err(TradeError::AdminOnly)));
+    }
+
+    #[tokio::test]
+    async fn reset_client_state_restores_defaults_and_resends_snapshots() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(first, "bob", 0.0)
+            .with_post(second, "bob", 0.0);
+        let (client_id, mut receiver) = connect("alice", &state);
+        for post_id in [first, second] {
+            request(client_id, "alice", serde_json::json!({ "type": "subscribe", "post_id": post_id }), &state).await;
+        }
+        request(client_id, "alice", serde_json::json!({ "type": "set_account_updates", "enabled": false }), &state).await;
+        assert_eq!(state.clients.get(&client_id).unwrap().subscriptions.len(), 2);
+        drain_json(&mut receiver);
 
-            if position.size.abs() > EPSILON {
-                if let Some(market_post) = state.posts.get(&post_id) {
-                    let current_market_price = market_post.price.unwrap_or_else(|| get_price(market_post.supply));
-                    total_unrealized_pnl += calculate_unrealized_pnl(position, current_market_price);
-                } else {
-                    eprintln!("Warning: Post {} not found while calculating margin for user {}", post_id, user_id);
+        request(client_id, "alice", serde_json::json!({ "type": "reset_client_state" }), &state).await;
+
+        let client = state.clients.get(&client_id).unwrap();
+        assert!(client.subscriptions.is_empty());
+        assert!(client.account_updates);
+        let replies = drain_json(&mut receiver);
+        let types: Vec<&str> = replies.iter().map(|m| m["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["initial_state", "user_sync"]);
+        assert_eq!(replies[0]["posts"].as_array().unwrap().len(), 2);
+        assert_eq!(replies[1]["balance"], 1000.0);
+    }
+
+    #[tokio::test]
+    async fn over_precise_quantities_are_rejected_or_snapped_per_config() {
+        for policy in [PrecisionPolicy::Reject, PrecisionPolicy::Snap] {
+            let post_id = Uuid::new_v4();
+            let state = AppState::new_for_test()
+                .with_config(Config { quantity_decimals: Some(4), quantity_precision: policy, ..Config::default() })
+                .with_user("alice", 1000.0)
+                .with_post(post_id, "bob", 0.0)
+                .with_markets();
+            let buy = |quantity: f64| serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": quantity }).to_string();
+
+            process_client_message(Uuid::new_v4(), "alice", &buy(0.1), &state).await.expect("0.1 is within 4 places");
+            let over_precise = process_client_message(Uuid::new_v4(), "alice", &buy(0.123456789012345), &state).await;
+
+            let supply = state.posts.get(&post_id).unwrap().supply;
+            match policy {
+                PrecisionPolicy::Reject => {
+                    assert!(matches!(over_precise, Err(TradeError::InvalidField { ref field, .. }) if field == "quantity"), "got {:?}", over_precise);
+                    assert_eq!(supply, 0.1);
+                }
+                PrecisionPolicy::Snap => {
+                    let Ok(replies) = over_precise else { panic!("expected a fill, got {:?}", over_precise) };
+                    assert!(matches!(replies[0], ServerMessage::TradeConfirmation { quantity, .. } if quantity == 0.1235));
+                    assert!((supply - 0.2235).abs() < TOLERANCE);
                 }
             }
         }
     }
-    balance + total_unrealized_pnl
-} 
 
+    #[tokio::test]
+    async fn selling_through_your_own_threshold_is_a_plain_sell() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "bob", 6.0)
+            .with_position("alice", post_id, 6.0, 20.0)
+            .with_position("carol", post_id, 1.0, 2.0);
+        // Stale thresholds for alice and carol on the way down: carol's is crossed and
+        // unwound, alice's is the position she's selling and must not unwind it again
+        state.liquidation_thresholds.insert(post_id, BTreeMap::from([
+            (OrderedFloat(3.5), vec![LiquidationEntry { cost_unwind: -12.0, size_unwind: -6.0, cost_basis: 20.0, user_id: "alice".to_string() }]),
+            (OrderedFloat(5.0), vec![LiquidationEntry { cost_unwind: -2.5, size_unwind: -1.0, cost_basis: 2.0, user_id: "carol".to_string() }]),
+        ]));
+
+        let fill = execute_trade(Uuid::new_v4(), "alice", post_id, -2.0, false, &state).await.unwrap();
+
+        assert_eq!(fill.liquidations_triggered, 1, "only carol is liquidated");
+        assert!((fill.final_supply - 3.0).abs() < TOLERANCE, "6 - 2 sold - 1 unwound, got {}", fill.final_supply);
+        let positions = state.user_positions.get("alice").unwrap();
+        assert_eq!(positions.get(&post_id).unwrap().size, 4.0);
+        assert!(state.user_positions.get("carol").is_none_or(|p| p.get(&post_id).is_none()));
+        let expected = calculate_smooth_cost(6.0, 5.0, 0.0, state.config.bonding_curve_epsilon) - 2.5
+            + calculate_smooth_cost(4.0, 3.0, 0.0, state.config.bonding_curve_epsilon);
+        assert!((fill.effective_cost - expected).abs() < TOLERANCE, "{} vs {}", fill.effective_cost, expected);
+    }
+
+    #[tokio::test]
+    async fn far_thresholds_are_registered_as_the_market_nears_them() {
+        // Shorts of 1 at price 1: carol's equity runs out at price 3 (supply 4), dave's
+        // at price 9 (supply 64)
+        let post_id = Uuid::new_v4();
+        let seeded = || {
+            let state = AppState::new_for_test()
+                .with_config(Config { threshold_grace_distance: 10.0, ..Config::default() })
+                .with_user("bob", 10_000.0)
+                .with_user("carol", 2.0)
+                .with_user("dave", 8.0)
+                .with_post(post_id, "bob", 0.0)
+                .with_position("carol", post_id, -1.0, -1.0)
+                .with_position("dave", post_id, -1.0, -1.0);
+            state.user_cash.insert("carol".to_string(), 0.0);
+            state.user_cash.insert("dave".to_string(), 0.0);
+            state
+        };
+        let registered = |state: &AppState| -> Vec<f64> {
+            state.liquidation_thresholds.get(&post_id).map_or_else(Vec::new, |ladder| ladder.keys().map(|s| s.into_inner()).collect())
+        };
+
+        let state = seeded();
+        update_liquidation_thresholds(post_id, &state).await;
+        assert_eq!(registered(&state), vec![4.0], "dave's threshold is beyond the grace distance");
+        // A buy to 59 crosses carol's threshold and stops short of dave's
+        let fill = execute_trade(Uuid::new_v4(), "bob", post_id, 58.0, false, &state).await.unwrap();
+        assert_eq!(fill.liquidations_triggered, 1);
+        update_liquidation_thresholds(post_id, &state).await;
+        assert_eq!(registered(&state), vec![64.0], "within 10 of supply 59 now");
+
+        // One large buy from 0 runs past both: dave's threshold is added back before pricing
+        let state = seeded();
+        update_liquidation_thresholds(post_id, &state).await;
+        let fill = execute_trade(Uuid::new_v4(), "bob", post_id, 70.0, false, &state).await.unwrap();
+        assert_eq!(fill.liquidations_triggered, 2);
+        assert!((fill.final_supply - 72.0).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn simulating_past_the_registered_range_leaves_the_ladder_alone() {
+        // As in far_thresholds_are_registered_as_the_market_nears_them: only carol's
+        // threshold (supply 4) is within 10 of supply 0, dave's is at 64
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { threshold_grace_distance: 10.0, admin_users: vec!["ops".to_string()], ..Config::default() })
+            .with_user("carol", 2.0)
+            .with_user("dave", 8.0)
+            .with_post(post_id, "ops", 0.0)
+            .with_position("carol", post_id, -1.0, -1.0)
+            .with_position("dave", post_id, -1.0, -1.0);
+        update_liquidation_thresholds(post_id, &state).await;
+        let ladder = state.liquidation_thresholds.get(&post_id).unwrap().clone();
+        let simulate = serde_json::json!({ "type": "simulate_cascade", "post_id": post_id, "target_supply": 70.0 }).to_string();
+
+        let replies = process_client_message(Uuid::new_v4(), "ops", &simulate, &state).await.unwrap();
+
+        let ServerMessage::CascadeSimulation { liquidations, .. } = &replies[0] else { panic!("expected CascadeSimulation") };
+        let users: Vec<&str> = liquidations.iter().map(|l| l.user_id.as_str()).collect();
+        assert_eq!(users, ["carol", "dave"], "priced against the full ladder");
+        assert_eq!(*state.liquidation_thresholds.get(&post_id).unwrap(), ladder, "registered ladder untouched");
+        assert_eq!(state.threshold_windows.get(&post_id).map(|window| *window), Some((-10.0, 10.0)));
+    }
+
+    #[tokio::test]
+    async fn recompute_thresholds_restores_a_corrupted_ladder() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_config(Config { admin_users: vec!["ops".to_string()], ..Config::default() })
+            .with_user("carol", 2.0)
+            .with_user("dave", 8.0)
+            .with_post(first, "carol", 0.0)
+            .with_post(second, "dave", 0.0)
+            .with_position("carol", first, -1.0, -1.0)
+            .with_position("dave", first, -1.0, -1.0)
+            .with_position("dave", second, -2.0, -2.0)
+            .with_markets();
+        update_liquidation_thresholds(first, &state).await;
+        update_liquidation_thresholds(second, &state).await;
+        let ladder = |post_id| state.liquidation_thresholds.get(&post_id).map(|ladder| ladder.clone());
+        let (good_first, good_second) = (ladder(first), ladder(second));
+        state.liquidation_thresholds.insert(first, BTreeMap::from([(OrderedFloat(1.0), vec![LiquidationEntry { cost_unwind: 9.0, size_unwind: 9.0, cost_basis: -9.0, user_id: "mallory".to_string() }])]));
+        state.liquidation_thresholds.remove(&second);
+        let recompute = |post_id: Option<Uuid>| serde_json::json!({ "type": "recompute_thresholds", "post_id": post_id }).to_string();
+
+        let by_user = process_client_message(Uuid::new_v4(), "carol", &recompute(None), &state).await;
+        let replies = process_client_message(Uuid::new_v4(), "ops", &recompute(None), &state).await.unwrap();
+
+        assert!(matches!(by_user, Err(TradeError::AdminOnly)), "got {:?}", by_user);
+        let ServerMessage::ThresholdsRecomputed { post_id: None, posts, entries } = replies[0] else { panic!("expected ThresholdsRecomputed") };
+        assert_eq!((posts, entries), (2, 3));
+        assert_eq!(ladder(first), good_first);
+        assert_eq!(ladder(second), good_second);
+        let one = process_client_message(Uuid::new_v4(), "ops", &recompute(Some(second)), &state).await.unwrap();
+        assert!(matches!(one[0], ServerMessage::ThresholdsRecomputed { posts: 1, entries: 1, .. }));
+    }
+
+    #[tokio::test]
+    async fn confirmation_reports_slippage_from_crossed_thresholds() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_position("carol", post_id, -2.0, -3.0)
+            .with_markets();
+        state.liquidation_thresholds.insert(post_id, BTreeMap::from([(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind: 6.464625637799379, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }])]));
+        let buy = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 6.0 }).to_string();
+
+        let replies = process_client_message(Uuid::new_v4(), "alice", &buy, &state).await.unwrap();
+
+        let ServerMessage::TradeConfirmation { quoted_cost, executed_cost, slippage, effective_cost, .. } = replies[0] else {
+            panic!("expected TradeConfirmation")
+        };
+        assert!((quoted_cost - calculate_smooth_cost(0.0, 6.0, 0.0, state.config.bonding_curve_epsilon)).abs() < TOLERANCE);
+        assert_eq!(executed_cost, effective_cost);
+        assert!(slippage > 0.0, "carol's forced buy pushed the rest of the fill up the curve");
+        assert!((slippage - (executed_cost - quoted_cost)).abs() < TOLERANCE);
+    }
+
+    #[tokio::test]
+    async fn default_slippage_tolerance_applies_only_without_an_explicit_limit() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { default_slippage_tolerance: Some(0.01), ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "alice", 0.0)
+            .with_position("carol", post_id, -2.0, -3.0)
+            .with_markets();
+        state.liquidation_thresholds.insert(post_id, BTreeMap::from([(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind: 6.464625637799379, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }])]));
+        let buy = |extra: serde_json::Value| {
+            let mut message = serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 6.0 });
+            message.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            message.to_string()
+        };
+
+        let naive = process_client_message(Uuid::new_v4(), "alice", &buy(serde_json::json!({})), &state).await;
+        let Err(TradeError::SlippageExceeded { quoted_cost, effective_cost, limit }) = naive else {
+            panic!("expected SlippageExceeded, got {:?}", naive)
+        };
+        assert!((limit - quoted_cost * 1.01).abs() < TOLERANCE);
+        assert!(effective_cost > limit, "carol's forced buy costs alice more than 1%");
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 0.0, "nothing filled");
+
+        let too_tight = process_client_message(Uuid::new_v4(), "alice", &buy(serde_json::json!({ "max_cost": quoted_cost })), &state).await;
+        assert!(matches!(too_tight, Err(TradeError::SlippageExceeded { limit, .. }) if limit == quoted_cost));
+        let explicit = process_client_message(Uuid::new_v4(), "alice", &buy(serde_json::json!({ "max_cost": effective_cost + 1.0 })), &state).await;
+        assert!(explicit.is_ok(), "an explicit limit overrides the default: {:?}", explicit);
+
+        // No thresholds left to cross: the sell fills at its quote, well within 1%
+        let sell = serde_json::json!({ "type": "sell", "post_id": post_id, "quantity": 1.0 }).to_string();
+        let replies = process_client_message(Uuid::new_v4(), "alice", &sell, &state).await.unwrap();
+        assert!(matches!(replies.as_slice(), [ServerMessage::TradeConfirmation { slippage, .. }] if slippage.abs() < TOLERANCE));
+        let greedy = serde_json::json!({ "type": "sell", "post_id": post_id, "quantity": 1.0, "min_proceeds": 1000.0 }).to_string();
+        let refused = process_client_message(Uuid::new_v4(), "alice", &greedy, &state).await;
+        assert!(matches!(refused, Err(TradeError::SlippageExceeded { limit, .. }) if limit == -1000.0));
+    }
+
+    #[tokio::test]
+    async fn imported_positions_keep_supply_thresholds_and_pnl_consistent() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { admin_users: vec!["ops".to_string()], check_supply_invariant: true, ..Config::default() })
+            .with_user("alice", 5.0)
+            .with_user("bob", 10.0)
+            .with_post(post_id, "carol", 3.0)
+            .with_markets();
+        let import = |positions: serde_json::Value| serde_json::json!({ "type": "import_positions", "positions": positions }).to_string();
+        let alice = serde_json::json!({ "user_id": "alice", "post_id": post_id, "size": 5.0, "cost_basis": 8.0 });
+        let bob = serde_json::json!({ "user_id": "bob", "post_id": post_id, "size": -2.0, "cost_basis": -3.0 });
+
+        let unbalanced = process_client_message(Uuid::new_v4(), "ops", &import(serde_json::json!([alice])), &state).await;
+        assert!(matches!(unbalanced, Err(TradeError::InvalidField { ref field, .. }) if field == "positions"), "got {:?}", unbalanced);
+        assert!(state.user_positions.get("alice").is_none(), "a rejected import writes nothing");
+
+        let replies = process_client_message(Uuid::new_v4(), "ops", &import(serde_json::json!([alice, bob])), &state).await.unwrap();
+
+        assert!(matches!(replies[0], ServerMessage::PositionsImported { positions: 2, posts: 1 }));
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 3.0);
+        assert!(check_supply_invariant(post_id, &state).is_ok());
+        let ladder = state.liquidation_thresholds.get(&post_id).unwrap().clone();
+        let mut liquidatable: Vec<String> = ladder.values().flatten().map(|entry| entry.user_id.clone()).collect();
+        liquidatable.sort();
+        assert_eq!(liquidatable, ["alice", "bob"]);
+        let price = state.posts.get(&post_id).unwrap().price;
+        let ServerMessage::UserSync { positions, .. } = build_user_sync("alice", &snapshot_prices(&["alice"], &state), &state) else { unreachable!() };
+        assert!((positions[0].average_price - 1.6).abs() < TOLERANCE);
+        assert!((positions[0].unrealized_pnl - (price - 1.6) * 5.0).abs() < TOLERANCE);
+        assert_eq!(ledgers("alice", &state).1, -8.0, "paid for at its cost basis");
+        assert_eq!(*state.user_exposure.get("bob").unwrap(), 3.0);
+
+        // Trading carries on from the imported book
+        execute_trade(Uuid::new_v4(), "bob", post_id, 1.0, false, &state).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn forced_unwinds_pay_the_liquidation_fee_rate_not_the_trading_rate() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_config(Config { fee_tiers: "0:0.05".parse().unwrap(), liquidation_fee_rate: Some(0.01), ..Config::default() })
+            .with_user("alice", 1000.0)
+            .with_user("carol", 100.0)
+            .with_post(post_id, "bob", 0.0)
+            .with_position("carol", post_id, -2.0, -3.0);
+        let cost_unwind = 6.464625637799379;
+        state.liquidation_thresholds.insert(post_id, BTreeMap::from([(OrderedFloat(4.0), vec![LiquidationEntry { cost_unwind, size_unwind: 2.0, cost_basis: -3.0, user_id: "carol".to_string() }])]));
+        let carol_cash = ledgers("carol", &state).1;
+
+        let fill = execute_trade(Uuid::new_v4(), "alice", post_id, 6.0, false, &state).await.unwrap();
 
+        let own_cost = fill.effective_cost - cost_unwind;
+        assert!((fill.fee - 0.05 * own_cost).abs() < TOLERANCE, "alice pays 5% of her own segments only, got {}", fill.fee);
+        let unwind = state.user_trade_history.get("carol").unwrap().back().unwrap().clone();
+        assert!(unwind.forced);
+        assert!((unwind.fee - 0.01 * cost_unwind).abs() < TOLERANCE, "carol pays 1% of the unwind, got {}", unwind.fee);
+        assert!((ledgers("carol", &state).1 - (carol_cash - cost_unwind - unwind.fee)).abs() < TOLERANCE);
+        let totals = *state.post_fee_totals.get(&post_id).unwrap();
+        assert!((totals.total - (fill.fee + unwind.fee)).abs() < TOLERANCE);
+    }
+}
@@ -0,0 +1,51 @@
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::state::AppState;
+
+// --- Health Probes ---
+//
+// Liveness and readiness are separate so an orchestrator can tell "restart me" from
+// "send me no traffic": the process is live as soon as it serves HTTP, but ready only
+// once warmup has opened trading and until shutdown begins draining it.
+
+// GET /health/live: OK whenever the process can answer
+// GET /health/ready (and the older /health): OK while the server accepts trades and
+// isn't shutting down, 503 otherwise
+pub fn health_routes(state: AppState) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let live = warp::path!("health" / "live").map(|| StatusCode::OK);
+    let ready = warp::path!("health" / "ready").or(warp::path!("health")).unify()
+        .map(move || if is_ready_for_traffic(&state) { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE });
+    warp::get().and(live.or(ready))
+}
+
+fn is_ready_for_traffic(state: &AppState) -> bool {
+    state.is_ready() && !state.is_draining()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn status(path: &str, state: &AppState) -> StatusCode {
+        warp::test::request().path(path).reply(&health_routes(state.clone())).await.status()
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_warmup_and_shutdown_while_liveness_stays_ok() {
+        let state = AppState::new_for_test();
+        state.ready.store(false, std::sync::atomic::Ordering::Release); // Still warming up
+
+        assert_eq!(status("/health/live", &state).await, StatusCode::OK);
+        assert_eq!(status("/health/ready", &state).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("/health", &state).await, StatusCode::SERVICE_UNAVAILABLE);
+
+        state.mark_ready();
+        assert_eq!(status("/health/live", &state).await, StatusCode::OK);
+        assert_eq!(status("/health/ready", &state).await, StatusCode::OK);
+        assert_eq!(status("/health", &state).await, StatusCode::OK);
+
+        state.begin_draining();
+        assert_eq!(status("/health/live", &state).await, StatusCode::OK);
+        assert_eq!(status("/health/ready", &state).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
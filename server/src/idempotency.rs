@@ -0,0 +1,169 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::errors::TradeError;
+use super::models::ServerMessage;
+
+// --- Trade Idempotency Keys ---
+//
+// A client that loses its connection after sending a Buy/Sell can't tell whether the
+// trade ran. Retrying with the same `idempotency_key` is safe: the first request with a
+// key claims it, and any later request from the same user with that key gets the
+// original TradeConfirmation back instead of trading again. Only successful trades are
+// remembered; a failed trade released its key, so retrying it trades for real. A key
+// reused for a different trade (see TradeFingerprint) is rejected rather than replayed.
+//
+// Keys expire after Config::idempotency_key_ttl_secs, and at most
+// Config::idempotency_key_cap are kept (the oldest are evicted first).
+
+// What a key was claimed for. A retry must repeat the trade exactly; the same key on a
+// different post, quantity, side or limit is a client bug, not a retry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TradeFingerprint {
+    pub post_id: Uuid,
+    pub quantity: f64, // Signed: positive for a buy, negative for a sell
+    pub allow_flip: bool,
+    pub limit: Option<f64>, // max_cost of a buy, min_proceeds of a sell
+}
+
+#[derive(Debug, Clone)]
+enum KeyState {
+    InFlight, // Claimed by a trade that hasn't finished
+    Done(ServerMessage), // The trade's reply, returned to retries
+}
+
+// Outcome of claiming a key
+#[derive(Debug)]
+pub enum Claim {
+    New, // Execute the trade, then complete or release the key
+    Replay(ServerMessage), // Already executed; reply with this
+}
+
+// A claimed key: when, for what trade, and how far that trade got
+#[derive(Debug, Clone)]
+struct KeyEntry {
+    claimed_at: Instant,
+    fingerprint: TradeFingerprint,
+    state: KeyState,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IdempotencyKeys {
+    // (UserID, key) -> claim
+    entries: Arc<DashMap<(String, String), KeyEntry>>,
+}
+
+impl IdempotencyKeys {
+    pub fn claim(&self, user_id: &str, key: &str, fingerprint: TradeFingerprint, ttl: Duration, cap: usize) -> Result<Claim, TradeError> {
+        let now = Instant::now();
+        if self.entries.len() >= cap {
+            self.evict(now, ttl, cap);
+        }
+        match self.entries.entry((user_id.to_string(), key.to_string())) {
+            Entry::Occupied(mut existing) => {
+                let entry = existing.get();
+                if now.duration_since(entry.claimed_at) >= ttl {
+                    existing.insert(KeyEntry { claimed_at: now, fingerprint, state: KeyState::InFlight });
+                    return Ok(Claim::New);
+                }
+                if entry.fingerprint != fingerprint {
+                    return Err(TradeError::invalid_field("idempotency_key", format!("'{}' was already used for a different trade", key)));
+                }
+                match &entry.state {
+                    KeyState::Done(reply) => Ok(Claim::Replay(reply.clone())),
+                    KeyState::InFlight => Err(TradeError::rejected(format!("A trade with idempotency key '{}' is still being executed", key))),
+                }
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(KeyEntry { claimed_at: now, fingerprint, state: KeyState::InFlight });
+                Ok(Claim::New)
+            }
+        }
+    }
+
+    // Remember the trade's reply for retries
+    pub fn complete(&self, user_id: &str, key: &str, reply: ServerMessage) {
+        if let Some(mut entry) = self.entries.get_mut(&(user_id.to_string(), key.to_string())) {
+            entry.state = KeyState::Done(reply);
+        }
+    }
+
+    // The trade failed without executing; let a retry run it
+    pub fn release(&self, user_id: &str, key: &str) {
+        self.entries.remove(&(user_id.to_string(), key.to_string()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Drop expired keys, then the oldest until there is room for one more
+    fn evict(&self, now: Instant, ttl: Duration, cap: usize) {
+        self.entries.retain(|_, entry| now.duration_since(entry.claimed_at) < ttl);
+        if self.entries.len() < cap {
+            return;
+        }
+        let mut by_age: Vec<((String, String), Instant)> = self.entries.iter()
+            .filter(|entry| matches!(entry.value().state, KeyState::Done(_)))
+            .map(|entry| (entry.key().clone(), entry.value().claimed_at))
+            .collect();
+        by_age.sort_by_key(|(_, claimed_at)| *claimed_at);
+        let excess = self.entries.len() + 1 - cap;
+        for (key, _) in by_age.into_iter().take(excess) {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn trade() -> TradeFingerprint {
+        TradeFingerprint { post_id: Uuid::nil(), quantity: 1.0, allow_flip: false, limit: None }
+    }
+
+    #[test]
+    fn keys_are_scoped_per_user_and_evicted_oldest_first() {
+        let keys = IdempotencyKeys::default();
+        let reply = ServerMessage::EquityUpdate { equity: 1.0 };
+
+        assert!(matches!(keys.claim("alice", "k1", trade(), TTL, 2), Ok(Claim::New)));
+        assert!(keys.claim("alice", "k1", trade(), TTL, 2).is_err(), "in flight");
+        keys.complete("alice", "k1", reply);
+        assert!(matches!(keys.claim("alice", "k1", trade(), TTL, 2), Ok(Claim::Replay(ServerMessage::EquityUpdate { .. }))));
+        assert!(matches!(keys.claim("bob", "k1", trade(), TTL, 2), Ok(Claim::New)));
+        keys.release("bob", "k1");
+        assert!(matches!(keys.claim("bob", "k1", trade(), TTL, 2), Ok(Claim::New)));
+        keys.complete("bob", "k1", ServerMessage::EquityUpdate { equity: 2.0 });
+
+        // At the cap, alice's older key makes room for carol's
+        assert!(matches!(keys.claim("carol", "k1", trade(), TTL, 2), Ok(Claim::New)));
+        assert_eq!(keys.len(), 2);
+        assert!(matches!(keys.claim("alice", "k1", trade(), TTL, 3), Ok(Claim::New)));
+    }
+
+    #[test]
+    fn a_key_reused_for_a_different_trade_is_rejected() {
+        let keys = IdempotencyKeys::default();
+        assert!(matches!(keys.claim("alice", "k1", trade(), TTL, 10), Ok(Claim::New)));
+        keys.complete("alice", "k1", ServerMessage::EquityUpdate { equity: 1.0 });
+
+        let sell = TradeFingerprint { quantity: -1.0, ..trade() };
+        let capped = TradeFingerprint { limit: Some(5.0), ..trade() };
+        for different in [sell, capped] {
+            assert!(matches!(keys.claim("alice", "k1", different, TTL, 10), Err(TradeError::InvalidField { .. })));
+        }
+        assert!(matches!(keys.claim("alice", "k1", trade(), TTL, 10), Ok(Claim::Replay(_))));
+    }
+}
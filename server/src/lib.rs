@@ -0,0 +1,27 @@
+// Declare modules (shared by the server binary and the benchmarks)
+pub mod acks;
+pub mod auth;
+pub mod backlog_sampler;
+pub mod bonding_curve;
+pub mod calculations;
+pub mod config;
+pub mod constants;
+pub mod errors;
+pub mod handlers;
+pub mod health;
+pub mod idempotency;
+pub mod margin_sweep;
+pub mod market;
+pub mod metrics;
+pub mod moderation;
+pub mod models;
+pub mod schema;
+pub mod snapshot;
+pub mod sse;
+pub mod state;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_transport;
+pub mod threshold_gc;
+pub mod webhooks;
+pub mod websocket;
+pub mod wire;
@@ -1,69 +1,114 @@
-// Declare modules
-mod auth;
-mod bonding_curve;
-mod calculations;
-mod constants;
-mod errors;
-mod handlers;
-mod models;
-mod state;
-mod websocket;
-
-use dotenvy::dotenv;
-use std::{env, sync::Arc};
-use warp::{
-    http::StatusCode,
-    Filter,
-};
-
-// Use items from modules
-use auth::with_auth;
-use errors::handle_rejection;
-use state::{AppState, Clients, Posts, UserBalances, UserPositions, UserRealizedPnl, UserExposure, LiquidationThresholds};
-use websocket::handle_connection;
-
-#[tokio::main]
-async fn main() {
-     if dotenvy::from_filename("../.env").is_err() {
-         if dotenv().is_err() {
-             eprintln!("Warning: .env file not found.");
-         }
-     }
-
-    let jwt_secret = env::var("JTW_SECRET").expect("JTW_SECRET must be set in .env file");
+use std::env;
+use std::time::Duration;
+use warp::Filter;
+
+// Use items from the library crate
+use server::auth::with_auth;
+use server::errors::handle_rejection;
+use server::state::AppState;
+use server::config::{load_env_files, Config, RuntimeSettings};
+use server::models::Claims;
+use server::margin_sweep::spawn_margin_sweep;
+use server::threshold_gc::spawn_threshold_gc;
+use server::backlog_sampler::spawn_backlog_sampler;
+use server::schema::schema_route;
+use server::sse::stream_route;
+use server::handlers::warm_up;
+use server::health::health_routes;
+use server::websocket::{handle_connection, disconnect_all_clients, CLOSE_NORMAL};
+
+// The env files are loaded before the runtime is built, since they can size it
+fn main() {
+    let env_files = load_env_files(&["../.env", ".env"]);
+    if env_files.is_empty() {
+        eprintln!("Warning: .env file not found.");
+    } else {
+        println!("Loaded env files: {}", env_files.join(", "));
+    }
+
+    let runtime_settings = RuntimeSettings::from_env();
+    let runtime = runtime_settings.build_runtime().unwrap_or_else(|e| {
+        eprintln!("Error: Failed to start the async runtime: {}", e);
+        std::process::exit(1);
+    });
+    match runtime_settings.worker_threads {
+        Some(worker_threads) => println!("Runtime started with {} worker threads.", worker_threads),
+        None => println!("Runtime started with one worker thread per CPU."),
+    }
+    runtime.block_on(run());
+}
+
+async fn run() {
+    // Structured logs (spans carry the per-message correlation_id); the remaining
+    // println! diagnostics still go straight to stdout
+    tracing_subscriber::fmt().init();
+
+    // Fail fast, naming every missing variable at once
+    let mut config = Config::from_env().unwrap_or_else(|missing| {
+        eprintln!("Error: {}", missing);
+        std::process::exit(1);
+    });
+    if config.jwt_secrets.len() > 1 {
+        println!("Previous JWT secret accepted for rotation.");
+    }
+    if env::args().any(|arg| arg == "--dry-run") {
+        config.dry_run = true;
+    }
+    if config.dry_run {
+        println!("Dry-run mode: trades execute in memory only, webhooks{} are skipped.", if config.dry_run_broadcasts { "" } else { " and broadcasts" });
+    }
 
     // Initialize shared state using types defined in state.rs
-    let app_state = AppState {
-        clients: Clients::default(),
-        posts: Posts::default(),
-        user_balances: UserBalances::default(),
-        user_positions: UserPositions::default(),
-        user_realized_pnl: UserRealizedPnl::default(),
-        user_exposure: UserExposure::default(),
-        jwt_secret: Arc::new(jwt_secret),
-        liquidation_thresholds: LiquidationThresholds::default(),
-    };
+    let app_state = AppState::new(config);
 
     println!("JWT Secret loaded.");
 
+    spawn_margin_sweep(app_state.clone());
+    spawn_threshold_gc(app_state.clone());
+    spawn_backlog_sampler(app_state.clone());
+    let warmup_state = app_state.clone();
+    tokio::spawn(async move { warm_up(&warmup_state).await });
+
     // Define routes using functions from modules
+    let shutdown_state = app_state.clone();
     let ws_route = warp::path("ws")
         .and(warp::ws())
         .and(with_auth(app_state.clone())) // from auth.rs
         .and(warp::any().map(move || app_state.clone()))
-        .map(|ws: warp::ws::Ws, user_id: String, state: AppState| {
-            ws.on_upgrade(move |websocket| handle_connection(websocket, user_id, state)) // from websocket.rs
+        .map(|ws: warp::ws::Ws, claims: Claims, state: AppState| {
+            ws.on_upgrade(move |websocket| handle_connection(websocket, claims.sub, claims.exp, state)) // from websocket.rs
         });
 
-    let health_route = warp::path!("health").map(|| StatusCode::OK);
+    // Not ready (503) until warmup has opened trading, and again once shutdown begins
+    let health_route = health_routes(shutdown_state.clone());
+
+    let metrics_state = shutdown_state.clone();
+    let metrics_route = warp::path!("metrics").map(move || metrics_state.metrics.render());
+
+    let sse_route = stream_route(shutdown_state.clone()); // from sse.rs, read-only
 
-    let routes = health_route.or(ws_route).recover(handle_rejection); // from errors.rs
+    let routes = health_route.or(metrics_route).or(schema_route()).or(ws_route).or(sse_route).recover(handle_rejection); // from errors.rs
 
     let addr = "127.0.0.1:8080";
     println!("Server starting on {}", addr);
 
-    warp::serve(routes)
-        .run(addr.parse::<std::net::SocketAddr>().unwrap())
-        .await;
+    // On Ctrl-C, fail readiness, give load balancers shutdown_drain_secs to notice, then
+    // tell every client we're going away before the listener stops
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(
+        addr.parse::<std::net::SocketAddr>().unwrap(),
+        async move {
+            tokio::signal::ctrl_c().await.ok();
+            shutdown_state.begin_draining();
+            let drain = Duration::from_secs(shutdown_state.config.shutdown_drain_secs);
+            if !drain.is_zero() {
+                println!("Shutdown signal received, draining for {}s...", drain.as_secs());
+                tokio::time::sleep(drain).await;
+            }
+            println!("Shutdown signal received, closing client connections...");
+            disconnect_all_clients(CLOSE_NORMAL, "Server shutting down", &shutdown_state);
+            shutdown_state.sse_clients.clear(); // Dropping the senders ends the SSE streams
+        },
+    );
+    server.await;
 }
 
@@ -0,0 +1,157 @@
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::calculations::calculate_user_margin;
+use super::state::AppState;
+
+// --- Margin Sweep ---
+//
+// Liquidation thresholds only fire when a trade crosses them, so a user pushed under
+// water by a price move can stay insolvent until someone trades their post again. The
+// sweep periodically checks every user with exposure and, when their margin is below
+// maintenance, flags them (new trades are rejected) and closes their positions through
+// each post's market actor, one post at a time, until they are healthy again. A user
+// whose liquidation failed stays flagged until a later sweep finds them healthy.
+
+// Start the periodic sweep if an interval is configured
+pub fn spawn_margin_sweep(state: AppState) {
+    let interval_secs = state.config.margin_sweep_interval_secs;
+    if interval_secs == 0 {
+        println!("Margin sweep disabled.");
+        return;
+    }
+    tokio::spawn(async move {
+        println!("Margin sweep running every {}s.", interval_secs);
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            let liquidated = sweep_once(&state).await;
+            if liquidated > 0 {
+                println!("Margin sweep: liquidated {} position(s).", liquidated);
+            }
+        }
+    });
+}
+
+// Below maintenance when margin < maintenance_margin_ratio * exposure
+fn is_under_margined(user_id: &str, state: &AppState) -> bool {
+    let exposure = state.user_exposure.get(user_id).map_or(0.0, |v| *v.value());
+    exposure > state.config.epsilon
+        && calculate_user_margin(user_id, state) < state.config.maintenance_margin_ratio * exposure
+}
+
+// One pass over all users; returns the number of positions liquidated. Does nothing
+// until warmup has computed the thresholds the liquidations would trade through.
+pub async fn sweep_once(state: &AppState) -> usize {
+    if !state.is_ready() {
+        return 0;
+    }
+    let user_ids: Vec<String> = state.user_positions.iter().map(|entry| entry.key().clone()).collect();
+    let mut liquidated = 0;
+
+    for user_id in user_ids {
+        if !is_under_margined(&user_id, state) {
+            state.under_margined.remove(&user_id); // Healthy again after an earlier failed sweep
+            continue;
+        }
+        println!("Margin sweep: user {} is below maintenance margin, liquidating.", user_id);
+        state.under_margined.insert(user_id.clone());
+
+        let post_ids: Vec<Uuid> = state.user_positions.get(&user_id)
+            .map(|positions| positions.iter()
+                .filter(|p| p.size.abs() > state.config.epsilon)
+                .map(|p| *p.key())
+                .collect())
+            .unwrap_or_default();
+        let mut failed = false;
+        for post_id in post_ids {
+            let Some(market) = state.markets.get(&post_id).map(|m| m.value().clone()) else {
+                eprintln!("Margin sweep: no market for post {}, cannot liquidate user {}.", post_id, user_id);
+                failed = true;
+                continue;
+            };
+            match market.liquidate(&user_id).await {
+                Ok(_) => liquidated += 1,
+                Err(e) => {
+                    eprintln!("Margin sweep: liquidating user {} on post {} failed: {}", user_id, post_id, e);
+                    failed = true;
+                }
+            }
+            if !is_under_margined(&user_id, state) {
+                break;
+            }
+        }
+
+        if !failed || !is_under_margined(&user_id, state) {
+            state.under_margined.remove(&user_id);
+        }
+    }
+    liquidated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::execute_trade;
+
+    // alice is short 2 at avg 1.5 with only 2.0 of balance behind it
+    fn thinly_margined_short(post_id: Uuid) -> AppState {
+        AppState::new_for_test()
+            .with_user("alice", 2.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", -2.0)
+            .with_position("alice", post_id, -2.0, -3.0)
+            .with_markets()
+    }
+
+    #[tokio::test]
+    async fn sweep_liquidates_user_pushed_under_by_price_move() {
+        let post_id = Uuid::new_v4();
+        let state = thinly_margined_short(post_id);
+        assert_eq!(sweep_once(&state).await, 0, "healthy before the price move");
+
+        // bob's buy moves the price from 1/(1+sqrt 2) to 1+sqrt 8; no thresholds are set,
+        // so nothing liquidates alice on the trade itself
+        execute_trade(Uuid::new_v4(), "bob", post_id, 10.0, false, &state).await.unwrap();
+        assert!(calculate_user_margin("alice", &state) < 0.0);
+
+        state.ready.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(sweep_once(&state).await, 0, "skipped while warming up");
+        assert!(state.under_margined.is_empty());
+        state.mark_ready();
+        assert_eq!(sweep_once(&state).await, 1);
+
+        let alice_size = state.user_positions.get("alice").and_then(|p| p.get(&post_id).map(|p| p.size)).unwrap_or(0.0);
+        assert_eq!(alice_size, 0.0);
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 10.0);
+        assert!(state.under_margined.is_empty());
+        assert_eq!(sweep_once(&state).await, 0, "nothing left to liquidate");
+    }
+
+    #[tokio::test]
+    async fn a_failed_liquidation_keeps_the_user_flagged() {
+        let post_id = Uuid::new_v4();
+        let state = thinly_margined_short(post_id);
+        execute_trade(Uuid::new_v4(), "bob", post_id, 10.0, false, &state).await.unwrap();
+        let market = state.markets.remove(&post_id).unwrap().1; // Nothing to liquidate through
+
+        assert_eq!(sweep_once(&state).await, 0);
+        assert!(state.under_margined.contains("alice"), "still insolvent, so still barred from trading");
+
+        state.markets.insert(post_id, market);
+        assert_eq!(sweep_once(&state).await, 1);
+        assert!(state.under_margined.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flagged_user_cannot_trade() {
+        let post_id = Uuid::new_v4();
+        let state = thinly_margined_short(post_id);
+        state.under_margined.insert("alice".to_string());
+
+        let result = execute_trade(Uuid::new_v4(), "alice", post_id, -1.0, false, &state).await;
+
+        assert!(result.is_err());
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, -2.0);
+    }
+}
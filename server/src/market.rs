@@ -0,0 +1,211 @@
+use tokio::sync::{mpsc, oneshot};
+use tracing::{Instrument, Span};
+use uuid::Uuid;
+
+use super::state::AppState;
+use super::errors::TradeError;
+use super::handlers::{execute_trade_with_limit, execute_margin_liquidation, execute_settlement, update_liquidation_thresholds, TradeFill};
+
+// --- Per-Post Market Actor ---
+//
+// Every post gets one task that owns the write path for its supply and liquidation
+// thresholds. Trades arrive as commands over a channel and are executed one at a time,
+// so the read-calculate-write phases of two trades on the same post can never
+// interleave. Different posts still trade in parallel on their own tasks.
+
+// Commands accepted by a market actor
+#[derive(Debug)]
+pub enum MarketCommand {
+    Trade {
+        client_id: Uuid,
+        user_id: String,
+        quantity: f64, // Positive for buy, negative for sell
+        allow_flip: bool, // May close the opposite position and open this side
+        max_cost: Option<f64>, // Signed cost limit of the fill, see execute_trade_with_limit
+        reply: oneshot::Sender<Result<TradeFill, TradeError>>,
+        span: Span, // The requester's span, entered while executing so logs keep its correlation id
+    },
+    // Force-close a user's position (issued by the margin sweep)
+    Liquidate {
+        user_id: String,
+        reply: oneshot::Sender<Result<TradeFill, TradeError>>,
+        span: Span,
+    },
+    // Recompute the post's liquidation thresholds (operator repair, balance changes)
+    RecomputeThresholds {
+        reply: oneshot::Sender<usize>, // Threshold entries now registered
+        span: Span,
+    },
+    // Close the market at the current price (issued by the post's creator)
+    Settle {
+        client_id: Uuid,
+        user_id: String,
+        reply: oneshot::Sender<Result<usize, TradeError>>, // Positions closed
+        span: Span,
+    },
+}
+
+// Cheap, cloneable handle used by the handlers to talk to a market actor.
+// The actor stops once every handle for its post has been dropped.
+#[derive(Debug, Clone)]
+pub struct MarketHandle {
+    sender: mpsc::UnboundedSender<MarketCommand>,
+}
+
+impl MarketHandle {
+    // Queue a trade on this market and wait for the actor to execute it
+    pub async fn trade(&self, client_id: Uuid, user_id: &str, quantity: f64, allow_flip: bool, max_cost: Option<f64>) -> Result<TradeFill, TradeError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(MarketCommand::Trade { client_id, user_id: user_id.to_string(), quantity, allow_flip, max_cost, reply, span: Span::current() })
+            .map_err(|_| TradeError::rejected("Market is closed"))?;
+        response
+            .await
+            .map_err(|_| TradeError::rejected("Market stopped before completing the trade"))?
+    }
+
+    // Queue a forced close of the user's position and wait for it
+    pub async fn liquidate(&self, user_id: &str) -> Result<TradeFill, TradeError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(MarketCommand::Liquidate { user_id: user_id.to_string(), reply, span: Span::current() })
+            .map_err(|_| TradeError::rejected("Market is closed"))?;
+        response
+            .await
+            .map_err(|_| TradeError::rejected("Market stopped before completing the liquidation"))?
+    }
+
+    // Queue a threshold recompute and wait for it; returns the entries now registered
+    pub async fn recompute_thresholds(&self) -> Result<usize, TradeError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(MarketCommand::RecomputeThresholds { reply, span: Span::current() })
+            .map_err(|_| TradeError::rejected("Market is closed"))?;
+        response
+            .await
+            .map_err(|_| TradeError::rejected("Market stopped before completing the recompute"))
+    }
+
+    // Queue the market's settlement and wait for it; returns the positions closed
+    pub async fn settle(&self, client_id: Uuid, user_id: &str) -> Result<usize, TradeError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(MarketCommand::Settle { client_id, user_id: user_id.to_string(), reply, span: Span::current() })
+            .map_err(|_| TradeError::rejected("Market is closed"))?;
+        response
+            .await
+            .map_err(|_| TradeError::rejected("Market stopped before completing the settlement"))?
+    }
+}
+
+// Spawn the actor task for a post and return a handle to it
+pub fn spawn_market(post_id: Uuid, state: AppState) -> MarketHandle {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<MarketCommand>();
+
+    tokio::spawn(async move {
+        println!("Market actor started for post {}", post_id);
+        while let Some(command) = receiver.recv().await {
+            match command {
+                MarketCommand::Trade { client_id, user_id, quantity, allow_flip, max_cost, reply, span } => {
+                    let result = async {
+                        let _gate = state.trading_gate.read().await;
+                        let result = execute_trade_with_limit(client_id, &user_id, post_id, quantity, allow_flip, max_cost, &state).await;
+                        if result.is_ok() {
+                            // Recompute before taking the next command so it sees fresh thresholds
+                            update_liquidation_thresholds(post_id, &state).await;
+                        }
+                        result
+                    }
+                    .instrument(span)
+                    .await;
+                    if reply.send(result).is_err() {
+                        println!("Market {}: trade requester went away before the reply.", post_id);
+                    }
+                }
+                MarketCommand::Liquidate { user_id, reply, span } => {
+                    let result = async {
+                        let _gate = state.trading_gate.read().await;
+                        let result = execute_margin_liquidation(&user_id, post_id, &state).await;
+                        if result.is_ok() {
+                            update_liquidation_thresholds(post_id, &state).await;
+                        }
+                        result
+                    }
+                    .instrument(span)
+                    .await;
+                    if reply.send(result).is_err() {
+                        println!("Market {}: liquidation requester went away before the reply.", post_id);
+                    }
+                }
+                MarketCommand::RecomputeThresholds { reply, span } => {
+                    let entries = async {
+                        let _gate = state.trading_gate.read().await;
+                        update_liquidation_thresholds(post_id, &state).await;
+                        state.liquidation_thresholds.get(&post_id).map_or(0, |ladder| ladder.values().map(Vec::len).sum::<usize>())
+                    }
+                    .instrument(span)
+                    .await;
+                    if reply.send(entries).is_err() {
+                        println!("Market {}: recompute requester went away before the reply.", post_id);
+                    }
+                }
+                MarketCommand::Settle { client_id, user_id, reply, span } => {
+                    let result = async {
+                        let _gate = state.trading_gate.read().await;
+                        execute_settlement(client_id, &user_id, post_id, &state).await
+                    }
+                    .instrument(span)
+                    .await;
+                    if reply.send(result).is_err() {
+                        println!("Market {}: settlement requester went away before the reply.", post_id);
+                    }
+                }
+            }
+        }
+        println!("Market actor stopped for post {}", post_id);
+    });
+
+    MarketHandle { sender }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::handlers::check_supply_invariant;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_trades_through_the_actor_keep_supply_equal_to_positions() {
+        const TRADERS: usize = 16;
+        let post_id = Uuid::new_v4();
+        let mut state = AppState::new_for_test()
+            .with_config(Config { check_supply_invariant: true, ..Config::default() })
+            .with_post(post_id, "creator", 0.0);
+        for i in 0..TRADERS {
+            state = state.with_user(&format!("trader{}", i), 1000.0);
+        }
+        let state = state.with_markets();
+        let market = state.markets.get(&post_id).unwrap().clone();
+
+        // Each trader buys 3 and sells 1, interleaved with every other trader's trades
+        let traders = (0..TRADERS).map(|i| {
+            let market = market.clone();
+            tokio::spawn(async move {
+                let user_id = format!("trader{}", i);
+                let quantity = if i % 2 == 0 { 1.0 } else { -1.0 }; // Half go long, half short
+                market.trade(Uuid::new_v4(), &user_id, 3.0 * quantity, false, None).await.unwrap();
+                market.trade(Uuid::new_v4(), &user_id, -quantity, false, None).await.unwrap();
+            })
+        });
+        for trader in traders.collect::<Vec<_>>() {
+            trader.await.unwrap();
+        }
+
+        assert_eq!(check_supply_invariant(post_id, &state), Ok(()));
+        assert!(state.posts.get(&post_id).unwrap().supply.abs() < 1e-9, "as many longs of 2 as shorts of 2");
+        for i in 0..TRADERS {
+            let size = state.user_positions.get(&format!("trader{}", i)).unwrap().get(&post_id).unwrap().size;
+            assert_eq!(size, if i % 2 == 0 { 2.0 } else { -2.0 });
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+// --- Metrics ---
+//
+// Lock-free counters rendered in the Prometheus text format by the /metrics route.
+
+// Latency buckets in seconds, from 100µs up to 5s
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+// Connection lifetimes in seconds, from a second up to a day
+const CONNECTION_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 300.0, 900.0, 3600.0, 14400.0, 86400.0];
+
+// Fixed-bucket histogram of durations
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>, // Non-cumulative count per bound, plus one overflow bucket
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let index = self.bounds.iter().position(|bound| seconds <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+// Monotonic f64 counter (the value's bits in an AtomicU64)
+#[derive(Debug, Default)]
+pub struct FloatCounter(AtomicU64);
+
+impl FloatCounter {
+    pub fn add(&self, amount: f64) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| Some((f64::from_bits(bits) + amount).to_bits()));
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+// All server metrics, shared through AppState
+#[derive(Debug)]
+pub struct Metrics {
+    // End-to-end trade time: queueing on the market actor, execution and threshold recompute
+    pub trade_duration: Histogram,
+    // Time spent in update_liquidation_thresholds alone
+    pub threshold_recompute_duration: Histogram,
+    // Open WebSocket connections (incremented on connect, decremented on every exit path)
+    pub active_connections: AtomicUsize,
+    // Closed connections: how long they lasted, and their session totals summed
+    pub connection_duration: Histogram,
+    pub connection_messages_received: AtomicU64,
+    pub connection_trades_executed: AtomicU64,
+    pub connection_bytes_sent: AtomicU64,
+    // Outbound queue depths at the last backlog sample: the deepest client and the sum
+    pub max_client_backlog: AtomicUsize,
+    pub total_client_backlog: AtomicUsize,
+    // Clients at or over Config::client_backlog_warn_threshold at the last sample
+    pub backlogged_clients: AtomicUsize,
+    // Trading fees collected on every post, and their split (see Config::creator_fee_share)
+    pub fees_collected: FloatCounter,
+    pub fees_to_creators: FloatCounter,
+    pub fees_to_insurance: FloatCounter,
+    pub fees_to_protocol: FloatCounter,
+    // Maker rebates paid to skew-reducing trades (see Config::maker_rebate_rate)
+    pub rebates_paid: FloatCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            trade_duration: Histogram::new(LATENCY_BUCKETS),
+            threshold_recompute_duration: Histogram::new(LATENCY_BUCKETS),
+            active_connections: AtomicUsize::new(0),
+            connection_duration: Histogram::new(CONNECTION_BUCKETS),
+            connection_messages_received: AtomicU64::new(0),
+            connection_trades_executed: AtomicU64::new(0),
+            connection_bytes_sent: AtomicU64::new(0),
+            max_client_backlog: AtomicUsize::new(0),
+            total_client_backlog: AtomicUsize::new(0),
+            backlogged_clients: AtomicUsize::new(0),
+            fees_collected: FloatCounter::default(),
+            fees_to_creators: FloatCounter::default(),
+            fees_to_insurance: FloatCounter::default(),
+            fees_to_protocol: FloatCounter::default(),
+            rebates_paid: FloatCounter::default(),
+        }
+    }
+}
+
+impl Metrics {
+    // Prometheus text exposition of every metric
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.trade_duration.render(
+            "flvke_trade_duration_seconds",
+            "End-to-end time to execute a trade, including liquidation threshold recompute.",
+            &mut out,
+        );
+        self.threshold_recompute_duration.render(
+            "flvke_threshold_recompute_duration_seconds",
+            "Time spent recomputing a post's liquidation thresholds.",
+            &mut out,
+        );
+        let _ = writeln!(out, "# HELP flvke_active_connections Open WebSocket connections.");
+        let _ = writeln!(out, "# TYPE flvke_active_connections gauge");
+        let _ = writeln!(out, "flvke_active_connections {}", self.active_connections.load(Ordering::Relaxed));
+        self.connection_duration.render("flvke_connection_duration_seconds", "Lifetime of closed WebSocket connections.", &mut out);
+        let session_counters = [
+            ("flvke_connection_messages_received_total", "Messages received on closed connections.", &self.connection_messages_received),
+            ("flvke_connection_trades_executed_total", "Trades executed on closed connections.", &self.connection_trades_executed),
+            ("flvke_connection_bytes_sent_total", "Bytes sent on closed connections.", &self.connection_bytes_sent),
+        ];
+        for (name, help, counter) in session_counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        let gauges = [
+            ("flvke_client_backlog_max", "Deepest client outbound queue at the last sample.", &self.max_client_backlog),
+            ("flvke_client_backlog_total", "Messages queued for all clients at the last sample.", &self.total_client_backlog),
+            ("flvke_backlogged_clients", "Clients over the backlog warning threshold at the last sample.", &self.backlogged_clients),
+        ];
+        for (name, help, gauge) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, gauge.load(Ordering::Relaxed));
+        }
+        let fee_counters = [
+            ("flvke_fees_collected_total", "Trading fees collected.", &self.fees_collected),
+            ("flvke_fees_to_creators_total", "Share of trading fees paid to post creators.", &self.fees_to_creators),
+            ("flvke_fees_to_insurance_total", "Share of trading fees added to insurance funds.", &self.fees_to_insurance),
+            ("flvke_fees_to_protocol_total", "Share of trading fees kept by the protocol.", &self.fees_to_protocol),
+            ("flvke_maker_rebates_paid_total", "Maker rebates paid to trades that reduce a post's skew.", &self.rebates_paid),
+        ];
+        for (name, help, counter) in fee_counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.get());
+        }
+        out
+    }
+}
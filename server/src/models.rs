@@ -1,15 +1,25 @@
 use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::UnboundedSender;
 use uuid::Uuid;
 use warp::filters::ws::Message;
 
+use super::acks::PendingAcks;
+use super::bonding_curve::get_price;
+use super::errors::TradeError;
+
 // --- JWT & Auth Types ---
 
 // Represents the claims expected in the Supabase JWT
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // Subject (user ID)
+    #[serde(default)] // Only required when audience validation is on
     pub aud: String, // Audience
     pub exp: usize,  // Expiration time
 }
@@ -22,16 +32,40 @@ pub struct AuthQuery {
 
 // --- Core Data Models ---
 
+// Who can discover a post. Unlisted posts are left out of NewPost broadcasts and
+// InitialState but stay reachable (GetPost, trading) by id.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PostVisibility {
+    #[default]
+    Public,
+    Unlisted,
+}
+
 // Represents a post in the timeline
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Post {
+    // Post ids are UUIDs everywhere: map keys in AppState, message payloads and the wire
+    // format (a hyphenated UUID string). Anything persisting posts must store them as such.
     pub id: Uuid,
     pub user_id: String,
     pub content: String,
     pub timestamp: DateTime<Utc>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub price: Option<f64>,
+    // Always get_price(supply, flat_width): change both through set_supply, never one alone
+    pub price: f64,
     pub supply: f64,
+    #[serde(default)]
+    pub visibility: PostVisibility,
+    // Set once the creator closed the market (CloseOwnPost): every position was paid out
+    // at this price and the post no longer trades
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settlement_price: Option<f64>,
+    // Overrides Config::max_supply for this post
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_supply: Option<f64>,
+    // Half-width of the flat zone around s = 0 where the price stays at 1 (see bonding_curve.rs)
+    #[serde(default)]
+    pub flat_width: f64,
 }
 
 // Ensure Default implementation reflects the current fields
@@ -42,12 +76,24 @@ impl Default for Post {
             user_id: String::new(),
             content: String::new(),
             timestamp: Utc::now(),
-            price: Some(1.0), // Default price for supply 0
+            price: 1.0, // get_price(0.0)
             supply: 0.0,
+            visibility: PostVisibility::default(),
+            settlement_price: None,
+            max_supply: None,
+            flat_width: 0.0,
         }
     }
 }
 
+impl Post {
+    // Moves the post to a new supply and reprices it, keeping `price` authoritative
+    pub fn set_supply(&mut self, supply: f64, bonding_curve_epsilon: f64) {
+        self.supply = supply;
+        self.price = get_price(supply, self.flat_width, bonding_curve_epsilon);
+    }
+}
+
 // Holds the details of a user's position in a specific post
 #[derive(Debug, Clone, Default)]
 pub struct UserPositionDetail {
@@ -59,45 +105,359 @@ pub struct UserPositionDetail {
 #[derive(Debug)]
 pub struct Client {
     pub user_id: String,
-    pub sender: UnboundedSender<Result<Message, warp::Error>>,
+    pub sender: ClientSender,
+    pub subscriptions: HashSet<Uuid>, // Posts whose MarketUpdates this client asked for
+    pub account_updates: bool, // Receives account pushes (see websocket::is_account_update)
+    pub acks: PendingAcks, // Critical messages sent but not yet acknowledged (see acks.rs)
+}
+
+impl Client {
+    pub fn new(user_id: impl Into<String>, sender: UnboundedSender<Result<Message, warp::Error>>) -> Self {
+        Client { user_id: user_id.into(), sender: ClientSender::new(sender), subscriptions: HashSet::new(), account_updates: true, acks: PendingAcks::default() }
+    }
+
+    // Drop every per-connection setting back to what Client::new starts with
+    pub fn reset(&mut self) {
+        self.subscriptions.clear();
+        self.account_updates = true;
+    }
+}
+
+// Messages queued for a client but not yet taken off the channel by its forwarder.
+// Shared between the ClientSender and the forwarder without keeping the channel open.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // Called by the forwarder for every message it takes off the queue
+    pub fn dequeued(&self) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| depth.checked_sub(1));
+    }
+}
+
+// A client's outbound channel. UnboundedSender doesn't expose its length, so sends are
+// counted here and the forwarder counts them back down (see QueueDepth). The forwarder
+// also adds up the bytes it writes to the socket, for the connection's summary.
+#[derive(Debug, Clone)]
+pub struct ClientSender {
+    inner: UnboundedSender<Result<Message, warp::Error>>,
+    depth: QueueDepth,
+    bytes_sent: Arc<AtomicU64>,
+}
+
+impl ClientSender {
+    pub fn new(inner: UnboundedSender<Result<Message, warp::Error>>) -> Self {
+        ClientSender { inner, depth: QueueDepth::default(), bytes_sent: Arc::default() }
+    }
+
+    pub fn send(&self, message: Result<Message, warp::Error>) -> Result<(), SendError<Result<Message, warp::Error>>> {
+        // Count first so a forwarder dequeuing right away never sees a negative depth
+        self.depth.0.fetch_add(1, Ordering::Relaxed);
+        self.inner.send(message).inspect_err(|_| self.depth.dequeued())
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        self.depth.clone()
+    }
+
+    pub fn bytes_sent(&self) -> Arc<AtomicU64> {
+        self.bytes_sent.clone()
+    }
 }
 
 // --- WebSocket Message Types ---
 
 // Represents incoming messages from the client
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
-    CreatePost { content: String },
-    Buy { post_id: Uuid, quantity: f64 },
-    Sell { post_id: Uuid, quantity: f64 },
+    // `max_supply` caps how far buys can take the post's supply (instead of the server
+    // default); `flat_width` keeps the price at 1 while |supply| stays within it.
+    // `initial_supply` starts the market part-way along the curve (e.g. when importing
+    // it); with `founder_position` the creator holds that supply, bought at curve cost.
+    CreatePost {
+        content: String,
+        #[serde(default)]
+        visibility: PostVisibility,
+        #[serde(default)]
+        max_supply: Option<f64>,
+        #[serde(default)]
+        flat_width: f64,
+        #[serde(default)]
+        initial_supply: f64,
+        #[serde(default)]
+        founder_position: bool,
+    },
+    // `allow_flip` lets a trade larger than the opposite position close it and open the
+    // other side; without it such a trade is rejected. Resending a trade with the same
+    // `idempotency_key` returns the first one's confirmation instead of trading again;
+    // the key can't be reused for a different trade.
+    // `max_cost` (buy) and `min_proceeds` (sell) bound the fill, fees excluded; without
+    // one, Config::default_slippage_tolerance applies
+    Buy {
+        post_id: Uuid,
+        quantity: f64,
+        #[serde(default)]
+        allow_flip: bool,
+        #[serde(default)]
+        idempotency_key: Option<String>,
+        #[serde(default)]
+        max_cost: Option<f64>,
+    },
+    Sell {
+        post_id: Uuid,
+        quantity: f64,
+        #[serde(default)]
+        allow_flip: bool,
+        #[serde(default)]
+        idempotency_key: Option<String>,
+        #[serde(default)]
+        min_proceeds: Option<f64>,
+    },
+    // Several trades executed in order. With `all_or_nothing` the batch runs with no other
+    // trade in between and is priced as a whole first, so nothing is applied unless every
+    // leg can fill; otherwise each leg succeeds or fails on its own.
+    BatchTrade {
+        trades: Vec<TradeLeg>, // At most MAX_BATCH_LEGS
+        #[serde(default)]
+        all_or_nothing: bool,
+    },
+    // Creator only: close the market, settling every position at the current price
+    CloseOwnPost { post_id: Uuid },
+    GetPost { post_id: Uuid },
+    // Admin only: the post's liquidation thresholds
+    GetLiquidationLadder { post_id: Uuid },
+    // Admin only: who a trade moving the post's supply to `target_supply` would
+    // liquidate, without executing it
+    SimulateCascade { post_id: Uuid, target_supply: f64 },
+    // Admin only: fees collected on one post, or on every post when omitted
+    GetFeeTotals {
+        #[serde(default)]
+        post_id: Option<Uuid>,
+    },
+    // Admin only: recompute the liquidation thresholds of one post, or of every post when
+    // omitted, e.g. after a fix or data import left them stale
+    RecomputeThresholds {
+        #[serde(default)]
+        post_id: Option<Uuid>,
+    },
+    // Admin only: deposit (positive `amount`) to or withdraw (negative) from a user's
+    // balance. The user is sent a BalanceUpdate.
+    AdjustBalance { user_id: String, amount: f64 },
+    // Admin only, for migrations: seed positions directly. Each touched post's supply
+    // must then equal its holders' net position, or is moved to it with `adjust_supply`.
+    ImportPositions {
+        positions: Vec<PositionImport>,
+        #[serde(default)]
+        adjust_supply: bool,
+    },
+    GetPortfolioSummary,
+    GetRisk,
+    // One page of the user's positions in `sort` order. `only_open` drops dust
+    // positions; `min_size` drops positions smaller than it in absolute size.
+    GetPositions {
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>, // DEFAULT_POSITIONS_PAGE_LIMIT when omitted
+        #[serde(default)]
+        only_open: bool,
+        #[serde(default)]
+        min_size: f64,
+        #[serde(default)]
+        sort: PositionSort,
+    },
+    // Realized-PnL bookings, oldest first; all retained ones when `since` is omitted
+    GetPnlHistory {
+        #[serde(default)]
+        since: Option<DateTime<Utc>>,
+    },
+    // The user's executed trades, newest first, optionally on one post only. Pass the
+    // oldest `seq` of a page as `before` to fetch the next one.
+    GetTradeHistory {
+        #[serde(default)]
+        post_id: Option<Uuid>,
+        #[serde(default)]
+        limit: Option<usize>, // DEFAULT_TRADE_HISTORY_LIMIT when omitted
+        #[serde(default)]
+        before: Option<u64>,
+    },
+    // Receive MarketUpdates for a post under the `subscribers` broadcast strategy
+    Subscribe { post_id: Uuid },
+    Unsubscribe { post_id: Uuid },
+    // Turn this connection's account pushes (UserSync, PortfolioSummary, EquityUpdate,
+    // BalanceUpdate, SocializedLoss) off or back on, e.g. for a client that only watches
+    // prices. Market broadcasts and replies to its own requests are unaffected.
+    SetAccountUpdates { enabled: bool },
+    // Return this connection to its just-connected state: no subscriptions, account
+    // updates on, and a fresh InitialState + UserSync in reply
+    ResetClientState,
+    // Acknowledge the critical message carrying this `ack_id` (see acks.rs)
+    Ack { id: u64 },
+}
+
+// One position within an ImportPositions; size and cost_basis share a sign (see
+// calculate_average_price)
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct PositionImport {
+    pub user_id: String,
+    pub post_id: Uuid,
+    pub size: f64,
+    pub cost_basis: f64,
+}
+
+// One trade within a BatchTrade
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+pub struct TradeLeg {
+    pub post_id: Uuid,
+    pub side: TradeSide,
+    pub quantity: f64, // Positive; `side` gives the direction
+    #[serde(default)]
+    pub allow_flip: bool,
+}
+
+impl TradeLeg {
+    // Positive for buy, negative for sell, as the market actor expects
+    pub fn signed_quantity(&self) -> f64 {
+        match self.side {
+            TradeSide::Buy => self.quantity,
+            TradeSide::Sell => -self.quantity,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TradeSide {
+    Buy,
+    Sell,
+}
+
+// Outcome of one leg of a BatchTrade, in the order the legs were sent
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LegResult {
+    Filled {
+        post_id: Uuid,
+        quantity: f64, // Positive for buy, negative for sell
+        effective_cost: f64,
+        fee: f64,
+        final_supply: f64,
+        final_price: f64,
+        liquidations_triggered: usize,
+    },
+    Failed { error: TradeError },
+    // An all_or_nothing batch stopped before this leg ran
+    NotExecuted,
+    // Filled, then reversed by an opposite trade at the then current price because a later
+    // leg failed. The reversal books its own PnL and fees; it is not a rollback.
+    Compensated,
+}
+
+// One supply level of a post's liquidation ladder: the positions force-closed when a
+// trade moves supply across it
+#[derive(Serialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct LadderLevel {
+    pub supply: f64,
+    pub price: f64, // Curve price at `supply`
+    pub entries: Vec<LadderEntry>,
+}
+
+// Trading fees collected and where they went: total = creator + insurance + protocol
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default, JsonSchema)]
+pub struct FeeTotals {
+    pub total: f64,
+    pub creator: f64,
+    pub insurance: f64,
+    pub protocol: f64,
+}
+
+impl FeeTotals {
+    pub fn add(&mut self, other: &FeeTotals) {
+        self.total += other.total;
+        self.creator += other.creator;
+        self.insurance += other.insurance;
+        self.protocol += other.protocol;
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct LadderEntry {
+    pub user_id: String,
+    pub cost_unwind: f64, // Cost of the forced trade
+    pub size_unwind: f64, // Forced trade size (opposite sign of the position)
+}
+
+// Order of a position list. Every key falls back to post id, so the order is stable
+// between replies for unchanged state.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionSort {
+    #[default]
+    PostId,
+    UnrealizedPnlDesc, // Biggest winners first
+    SizeDesc, // Largest absolute size first
+}
+
+// One fill of a user's position, as kept for GetTradeHistory
+#[derive(Serialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct TradeRecord {
+    // Per user, increasing with each trade. Unlike `executed_at`, never shared by two of
+    // the user's trades, so it is the GetTradeHistory paging cursor.
+    pub seq: u64,
+    pub executed_at: DateTime<Utc>,
+    pub post_id: Uuid,
+    pub quantity: f64, // Signed: negative for sells
+    pub price: f64, // Average fill price, cost / quantity
+    pub cost: f64, // Cash paid (negative when received), fee excluded
+    pub fee: f64,
+    pub realized_pnl: f64, // Realized by this fill, net of the fee
+    pub forced: bool, // A liquidation rather than the user's own trade
 }
 
 // Used within UserSync to send position details
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, JsonSchema)]
 pub struct PositionDetail {
     pub post_id: Uuid,
-    pub size: f64,
-    pub average_price: f64,
+    pub size: f64, // Signed: negative for shorts
+    pub average_price: f64, // Average entry price per share; positive for shorts too (see calculate_average_price)
     pub unrealized_pnl: f64,
+    // Market price at which this position is liquidated (the curve price at its
+    // liquidation supply); omitted when equity can't reach zero
     #[serde(skip_serializing_if = "Option::is_none")]
     pub liquidation_price: Option<f64>,
 }
 
 // Represents messages sent from the server to the client
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
+    // First message on an accepted connection: what this server runs and which optional
+    // features its config enables (see websocket::enabled_features)
+    Welcome { server_version: String, protocol_version: u32, features: Vec<String> },
     InitialState { posts: Vec<Post> },
     UserSync {
         balance: f64,
         exposure: f64,
         equity: f64,
-        positions: Vec<PositionDetail>,
+        // Most a new buy can cost: collateral left after the reserve (see check_collateral)
+        buying_power: f64,
+        positions: Vec<PositionDetail>, // Ordered by post id, at most config.user_sync_position_cap
+        has_more_positions: bool, // Positions were left out by the cap; fetch them with GetPositions
         total_realized_pnl: f64,
+        // Margin / exposure (see calculate_margin_ratio); omitted with no open positions
+        #[serde(skip_serializing_if = "Option::is_none")]
+        margin_ratio: Option<f64>,
     },
     NewPost { post: Post },
     MarketUpdate { post_id: Uuid, price: f64, supply: f64 },
+    // The user's balance changed (see adjust_balance). Balance is lifetime deposits minus
+    // withdrawals only: trading cash, realized PnL and fees are reported separately and
+    // never fold into it, so equity is balance + realized PnL + unrealized PnL.
     BalanceUpdate { balance: f64 },
     PositionUpdate {
         post_id: Uuid,
@@ -108,5 +468,116 @@ pub enum ServerMessage {
     RealizedPnlUpdate { total_realized_pnl: f64 },
     ExposureUpdate { exposure: f64 },
     EquityUpdate { equity: f64 },
-    Error { message: String },
+    LiquidationEvent {
+        post_id: Uuid,
+        user_id: String,
+        size: f64, // Forced trade size (opposite sign of the closed position)
+        realized_pnl: f64, // PnL booked on the forced trade, after the penalty
+        penalty: f64, // Amount credited to the post's insurance fund
+    },
+    // Reply to GetPost with the post's current market state
+    PostDetail {
+        post: Post,
+        volume: f64, // Cumulative absolute quantity traded, including forced unwinds
+        open_interest: f64, // Summed absolute size of all open positions
+        holder_count: usize, // Users with an open position
+        // Largest holder's share of open_interest (0..=1); omitted with no holders
+        #[serde(skip_serializing_if = "Option::is_none")]
+        top_holder_share: Option<f64>,
+        liquidation_threshold_count: usize,
+    },
+    // Reply to GetLiquidationLadder, levels in ascending supply
+    LiquidationLadder { post_id: Uuid, current_supply: f64, levels: Vec<LadderLevel> },
+    // Reply to SimulateCascade: a trade of (target_supply - start_supply) from the current
+    // state. `final_supply` includes the forced unwinds, so it can overshoot the target.
+    CascadeSimulation {
+        post_id: Uuid,
+        start_supply: f64,
+        final_supply: f64,
+        liquidations: Vec<LadderEntry>, // In the order they would fire
+        forced_trade_cost: f64, // Summed cost_unwind of the liquidations
+        total_cost: f64, // What the trade would cost, forced unwinds included
+    },
+    // Reply to GetFeeTotals; `post_id` is None for the system-wide totals
+    FeeTotals { post_id: Option<Uuid>, totals: FeeTotals },
+    // Reply to RecomputeThresholds: how many posts were recomputed and how many threshold
+    // entries (one per liquidatable user) they now hold
+    ThresholdsRecomputed { post_id: Option<Uuid>, posts: usize, entries: usize },
+    // Reply to AdjustBalance: the user's new balance
+    BalanceAdjusted { user_id: String, balance: f64 },
+    // Reply to ImportPositions
+    PositionsImported { positions: usize, posts: usize },
+    // Reply to the trader once their Buy/Sell has filled
+    TradeConfirmation {
+        post_id: Uuid,
+        quantity: f64, // Filled; positive for buy, negative for sell
+        // Requested but rejected because the trade reached Config::max_liquidations_per_trade
+        unfilled_quantity: f64,
+        effective_cost: f64, // Includes the cost of any forced unwinds the fill crossed
+        fee: f64, // Charged on top of effective_cost (see Config::fee_tiers); negative for a maker rebate
+        final_supply: f64,
+        final_price: f64,
+        liquidations_triggered: usize,
+        liquidation_notional: f64, // Summed absolute cost of the triggered forced unwinds
+        // What the quantity alone costs along the smooth curve from the starting supply,
+        // what the fill actually cost (effective_cost), and executed minus quoted: the
+        // extra paid (or proceeds lost) to forced unwinds the fill crossed
+        quoted_cost: f64,
+        executed_cost: f64,
+        slippage: f64,
+    },
+    // Reply to BatchTrade, one result per leg. `compensated` is set when a leg of an
+    // all_or_nothing batch failed after earlier legs had filled and every one of those
+    // was reversed (see LegResult::Compensated). A batch refused before any leg ran
+    // reports its failed leg with the rest NotExecuted and `compensated` unset.
+    BatchResult { results: Vec<LegResult>, compensated: bool },
+    // The user's whole account in one snapshot: equity = balance + realized_pnl +
+    // unrealized_pnl. Reply to GetPortfolioSummary, and pushed after a trade changes the account.
+    PortfolioSummary {
+        balance: f64,
+        realized_pnl: f64,
+        unrealized_pnl: f64,
+        equity: f64,
+        exposure: f64,
+        open_positions: usize,
+        total_notional: f64, // Summed |size| * current price of the open positions
+    },
+    // The user's risk in one snapshot, beside PortfolioSummary's money view. Reply to GetRisk.
+    RiskSnapshot {
+        exposure: f64, // Summed |cost basis| of the open positions
+        margin: f64, // Equity backing them: balance + realized PnL + unrealized PnL
+        total_unrealized_pnl: f64,
+        // Smallest distance, in supply, from a post's current supply to the user's
+        // liquidation supply on it; None when no position can be liquidated
+        liquidation_distance_min: Option<f64>,
+    },
+    // Reply to GetPositions. `total` counts every position matching the filters.
+    Positions { positions: Vec<PositionDetail>, offset: usize, total: usize, has_more: bool },
+    // Reply to GetPnlHistory: (booked at, realized PnL delta) pairs, oldest first
+    PnlHistory { points: Vec<(DateTime<Utc>, f64)> },
+    // Reply to GetTradeHistory. `has_more` is set when older matching trades remain.
+    TradeHistory { trades: Vec<TradeRecord>, has_more: bool },
+    // Broadcast when a creator closes their market; holders' UserSyncs follow
+    PostSettled { post_id: Uuid, price: f64, positions_closed: usize },
+    // Sent to a counterparty whose realized PnL was reduced to cover bad debt
+    SocializedLoss { post_id: Uuid, amount: f64 },
+    Error {
+        message: String,
+        // Structured detail for errors the client can act on
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<TradeError>,
+    },
+}
+
+impl ServerMessage {
+    // Plain error with only a human-readable message
+    pub fn error(message: impl Into<String>) -> Self {
+        ServerMessage::Error { message: message.into(), error: None }
+    }
+}
+
+impl From<TradeError> for ServerMessage {
+    fn from(error: TradeError) -> Self {
+        ServerMessage::Error { message: error.to_string(), error: Some(error) }
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,82 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::config::Config;
+use super::errors::TradeError;
+
+// --- Content Moderation ---
+//
+// New posts are screened before they are stored or broadcast: first against the
+// configured blocklist, then by an optional external check (e.g. a moderation API).
+// The external check is bounded by Config::moderation_timeout_ms; a check that doesn't
+// answer in time rejects the post, so unscreened content never goes live.
+
+// Pluggable asynchronous screening of a post's content. Err carries the reason
+// reported to the creator.
+pub trait ContentCheck: Send + Sync {
+    fn check<'a>(&'a self, content: &'a str) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+}
+
+pub type ContentChecker = Arc<dyn ContentCheck>;
+
+// The first blocklisted term the content contains. Terms match case-insensitively
+// anywhere in the text, so a term like "http://" also catches links.
+pub fn blocklisted_term<'a>(content: &str, blocklist: &'a [String]) -> Option<&'a str> {
+    let content = content.to_lowercase();
+    blocklist.iter()
+        .filter(|term| !term.is_empty())
+        .find(|term| content.contains(&term.to_lowercase()))
+        .map(String::as_str)
+}
+
+// Screen a new post's content, returning ContentRejected for a blocklisted term, a
+// failed external check or one that timed out
+pub async fn moderate(content: &str, config: &Config, check: Option<&ContentChecker>) -> Result<(), TradeError> {
+    if let Some(term) = blocklisted_term(content, &config.moderation_blocklist) {
+        return Err(TradeError::ContentRejected { reason: format!("contains the blocked term \"{}\"", term) });
+    }
+    let Some(check) = check else { return Ok(()) };
+    let timeout = Duration::from_millis(config.moderation_timeout_ms);
+    match tokio::time::timeout(timeout, check.check(content)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(reason)) => Err(TradeError::ContentRejected { reason }),
+        Err(_) => {
+            tracing::warn!(timeout_ms = config.moderation_timeout_ms, "moderate: external content check timed out");
+            Err(TradeError::ContentRejected { reason: "the moderation check timed out, try again later".to_string() })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Stalls;
+
+    impl ContentCheck for Stalls {
+        fn check<'a>(&'a self, _content: &'a str) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[test]
+    fn blocklist_matches_case_insensitively_inside_words_and_links() {
+        let blocklist = vec!["".to_string(), "Spam".to_string(), "http://".to_string()];
+
+        assert_eq!(blocklisted_term("gm frens", &blocklist), None);
+        assert_eq!(blocklisted_term("buy my SPAMcoin", &blocklist), Some("Spam"));
+        assert_eq!(blocklisted_term("see HTTP://example.com", &blocklist), Some("http://"));
+    }
+
+    #[tokio::test]
+    async fn a_stalled_external_check_rejects_after_the_timeout() {
+        let config = Config { moderation_timeout_ms: 10, ..Config::default() };
+        let check: ContentChecker = Arc::new(Stalls);
+
+        let result = moderate("gm", &config, Some(&check)).await;
+
+        assert!(matches!(result, Err(TradeError::ContentRejected { .. })), "got {:?}", result);
+    }
+}
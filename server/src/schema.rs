@@ -0,0 +1,61 @@
+use schemars::schema_for;
+use serde_json::{json, Value};
+use warp::{Filter, Rejection, Reply};
+
+use super::models::{ClientMessage, ServerMessage};
+
+// --- Protocol Schema ---
+//
+// JSON Schema for every WebSocket message, derived from the enums in models.rs so it
+// can't drift from the wire format. Both enums are internally tagged: each message is
+// an object whose `type` field holds the snake_case variant name.
+
+pub fn protocol_schema() -> Value {
+    json!({
+        "client_message": schema_for!(ClientMessage),
+        "server_message": schema_for!(ServerMessage),
+    })
+}
+
+// GET /schema
+pub fn schema_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("schema")
+        .and(warp::get())
+        .map(|| warp::reply::json(&protocol_schema()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The `type` tag of every variant in one enum's schema
+    fn variant_tags(schema: &Value) -> Vec<String> {
+        schema["oneOf"]
+            .as_array()
+            .expect("tagged enum schema is a oneOf")
+            .iter()
+            .map(|variant| variant["properties"]["type"]["enum"][0].as_str().expect("type tag").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn schema_includes_every_variant() {
+        let schema = protocol_schema();
+
+        assert_eq!(variant_tags(&schema["client_message"]), ["create_post", "buy", "sell", "batch_trade", "close_own_post", "get_post", "get_liquidation_ladder", "simulate_cascade", "get_fee_totals", "recompute_thresholds", "adjust_balance", "import_positions", "get_portfolio_summary", "get_risk", "get_positions", "get_pnl_history", "get_trade_history", "subscribe", "unsubscribe", "set_account_updates", "reset_client_state", "ack"]);
+        assert_eq!(variant_tags(&schema["server_message"]), [
+            "welcome", "initial_state", "user_sync", "new_post", "market_update", "balance_update",
+            "position_update", "realized_pnl_update", "exposure_update", "equity_update",
+            "liquidation_event", "post_detail", "liquidation_ladder", "cascade_simulation", "fee_totals", "thresholds_recomputed", "balance_adjusted", "positions_imported", "trade_confirmation", "batch_result", "portfolio_summary", "risk_snapshot", "positions", "pnl_history", "trade_history", "post_settled", "socialized_loss", "error",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn schema_route_serves_json() {
+        let response = warp::test::request().path("/schema").reply(&schema_route()).await;
+
+        assert_eq!(response.status(), 200);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, protocol_schema());
+    }
+}
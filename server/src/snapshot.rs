@@ -0,0 +1,230 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::handlers::{content_hash, ladder_inconsistency, update_liquidation_thresholds};
+use super::market::spawn_market;
+use super::models::{Post, UserPositionDetail};
+use super::state::AppState;
+
+// --- Consistent State Snapshots ---
+//
+// Trades update posts, positions and ledgers one map at a time, so copying the maps
+// while markets are trading can capture a post's supply from after a fill and its
+// holders' positions from before it. A snapshot instead takes the trading gate
+// (AppState::trading_gate) for writing: every market actor holds it for reading while
+// it executes a command, and adjust_balance while it moves a balance, so the copy starts
+// once in-flight fills finish and no new fill starts until it is done.
+//
+// Trading on every post pauses for the duration of the copy, which is linear in the
+// number of posts and positions. Take snapshots for exports and audits, not on a hot
+// path or a short timer.
+//
+// Liquidation thresholds are not part of a snapshot: they are derived data, and
+// load_state recomputes them from the restored positions and ledgers instead, so a
+// restored state liquidates exactly where its accounts say it should.
+//
+// The server keeps no state across restarts and nothing calls load_state at startup:
+// snapshots are for exports and audits, and load_state for tests and for tooling that
+// rebuilds a state from an exported snapshot.
+
+// Point-in-time copy of the market and every account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub taken_at: DateTime<Utc>,
+    pub posts: Vec<Post>, // Ordered by post id
+    pub accounts: BTreeMap<String, AccountSnapshot>,
+    pub insurance_fund: BTreeMap<Uuid, f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AccountSnapshot {
+    pub balance: f64,
+    pub cash: f64,
+    pub realized_pnl: f64,
+    pub positions: BTreeMap<Uuid, PositionSnapshot>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PositionSnapshot {
+    pub size: f64,
+    pub total_cost_basis: f64,
+}
+
+impl StateSnapshot {
+    // Net position size per post across all accounts
+    pub fn net_positions(&self) -> BTreeMap<Uuid, f64> {
+        let mut net = BTreeMap::new();
+        for account in self.accounts.values() {
+            for (post_id, position) in &account.positions {
+                *net.entry(*post_id).or_insert(0.0) += position.size;
+            }
+        }
+        net
+    }
+}
+
+// Copy the state while trading is paused (see above)
+pub async fn take_snapshot(state: &AppState) -> StateSnapshot {
+    let _gate = state.trading_gate.write().await;
+    let started = Instant::now();
+
+    let mut posts: Vec<Post> = state.posts.iter().map(|entry| entry.value().clone()).collect();
+    posts.sort_by_key(|post| post.id);
+
+    let mut accounts: BTreeMap<String, AccountSnapshot> = BTreeMap::new();
+    for entry in state.user_balances.iter() {
+        accounts.entry(entry.key().clone()).or_default().balance = *entry.value();
+    }
+    for entry in state.user_cash.iter() {
+        accounts.entry(entry.key().clone()).or_default().cash = *entry.value();
+    }
+    for entry in state.user_realized_pnl.iter() {
+        accounts.entry(entry.key().clone()).or_default().realized_pnl = *entry.value();
+    }
+    for entry in state.user_positions.iter() {
+        let positions = &mut accounts.entry(entry.key().clone()).or_default().positions;
+        for position in entry.value().iter() {
+            positions.insert(*position.key(), PositionSnapshot { size: position.size, total_cost_basis: position.total_cost_basis });
+        }
+    }
+    let insurance_fund = state.insurance_fund.iter().map(|entry| (*entry.key(), *entry.value())).collect();
+
+    println!("take_snapshot: Copied {} posts and {} accounts with trading paused for {:?}", posts.len(), accounts.len(), started.elapsed());
+    StateSnapshot { taken_at: Utc::now(), posts, accounts, insurance_fund }
+}
+
+// Restore a snapshot into a fresh state (before it accepts connections), start the
+// markets of unsettled posts and recompute every post's liquidation thresholds, in post
+// id order. Debug builds then cross-check each ladder against the restored accounts.
+pub async fn load_state(snapshot: &StateSnapshot, state: &AppState) {
+    let started = Instant::now();
+    for post in &snapshot.posts {
+        if post.settlement_price.is_none() {
+            *state.user_post_counts.entry(post.user_id.clone()).or_insert(0) += 1;
+        }
+        state.post_contents.entry((post.user_id.clone(), content_hash(&post.content))).or_insert(post.id);
+        state.posts.insert(post.id, post.clone());
+    }
+    for (user_id, account) in &snapshot.accounts {
+        state.user_balances.insert(user_id.clone(), account.balance);
+        state.user_cash.insert(user_id.clone(), account.cash);
+        state.user_realized_pnl.insert(user_id.clone(), account.realized_pnl);
+        let positions = state.user_positions.entry(user_id.clone()).or_default();
+        for (post_id, position) in &account.positions {
+            positions.insert(*post_id, UserPositionDetail { size: position.size, total_cost_basis: position.total_cost_basis });
+        }
+        let exposure = account.positions.values().map(|position| position.total_cost_basis.abs()).sum();
+        state.user_exposure.insert(user_id.clone(), exposure);
+    }
+    for (post_id, fund) in &snapshot.insurance_fund {
+        state.insurance_fund.insert(*post_id, *fund);
+    }
+
+    for post in snapshot.posts.iter().filter(|post| post.settlement_price.is_none()) {
+        state.markets.insert(post.id, spawn_market(post.id, state.clone()));
+    }
+    for post in &snapshot.posts {
+        update_liquidation_thresholds(post.id, state).await;
+        if cfg!(debug_assertions) {
+            if let Some(problem) = ladder_inconsistency(post.id, state) {
+                panic!("load_state: post {} has an inconsistent liquidation ladder after restore: {}", post.id, problem);
+            }
+        }
+    }
+    println!("load_state: Restored {} posts and {} accounts taken at {} in {:?}", snapshot.posts.len(), snapshot.accounts.len(), snapshot.taken_at, started.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Supply of every post equals the net of its holders' positions
+    fn assert_supply_matches_positions(snapshot: &StateSnapshot) {
+        let net = snapshot.net_positions();
+        for post in &snapshot.posts {
+            let held = net.get(&post.id).copied().unwrap_or(0.0);
+            assert!((post.supply - held).abs() < 1e-6, "post {} supply {} but holders net {}", post.id, post.supply, held);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn snapshots_taken_during_trading_are_consistent() {
+        let post_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        let users = ["u0", "u1", "u2", "u3"];
+        let mut state = AppState::new_for_test();
+        for user_id in users {
+            state = state.with_user(user_id, 1_000_000.0);
+        }
+        for post_id in post_ids {
+            state = state.with_post(post_id, "u0", 0.0);
+        }
+        let state = state.with_markets();
+
+        let traders: Vec<_> = users.iter().enumerate().map(|(i, user_id)| {
+            let state = state.clone();
+            let user_id = user_id.to_string();
+            tokio::spawn(async move {
+                for round in 0..50 {
+                    let post_id = post_ids[(i + round) % post_ids.len()];
+                    let quantity = if round % 3 == 2 { -0.5 } else { 1.0 };
+                    let market = state.markets.get(&post_id).unwrap().value().clone();
+                    market.trade(Uuid::new_v4(), &user_id, quantity, true, None).await.unwrap();
+                }
+            })
+        }).collect();
+
+        let mut snapshots = 0;
+        while traders.iter().any(|trader| !trader.is_finished()) {
+            assert_supply_matches_positions(&take_snapshot(&state).await);
+            snapshots += 1;
+            tokio::task::yield_now().await;
+        }
+        for trader in traders {
+            trader.await.unwrap();
+        }
+
+        let last = take_snapshot(&state).await;
+        assert_supply_matches_positions(&last);
+        assert!(snapshots > 0);
+        assert_eq!(last.accounts.len(), users.len());
+    }
+
+    #[tokio::test]
+    async fn restored_state_recomputes_the_same_liquidation_ladders() {
+        let (long_post, short_post) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_user("alice", 5.0)
+            .with_user("bob", 5.0)
+            .with_user("carol", 4.0)
+            .with_post(long_post, "bob", 10.0)
+            .with_post(short_post, "bob", -3.0)
+            .with_position("alice", long_post, 3.0, 6.0)
+            .with_position("bob", long_post, 3.0, 6.0)
+            .with_position("carol", long_post, 4.0, 7.0)
+            .with_position("alice", short_post, -1.0, -1.0)
+            .with_position("carol", short_post, -2.0, -2.0);
+        for post_id in [long_post, short_post] {
+            update_liquidation_thresholds(post_id, &state).await;
+        }
+        let ladders = |state: &AppState| [long_post, short_post].map(|post_id| state.liquidation_thresholds.get(&post_id).unwrap().clone());
+        let original = ladders(&state);
+        assert!(original.iter().all(|ladder| !ladder.is_empty()), "both posts have active thresholds");
+        assert_eq!(original[0].values().map(Vec::len).sum::<usize>(), 3, "alice and bob share a threshold, carol's is separate");
+
+        // Through JSON, as a snapshot persisted to disk would be
+        let json = serde_json::to_string(&take_snapshot(&state).await).unwrap();
+        let restored = AppState::new_for_test();
+        load_state(&serde_json::from_str(&json).unwrap(), &restored).await;
+
+        assert_eq!(ladders(&restored), original);
+        assert!(restored.markets.contains_key(&long_post) && restored.markets.contains_key(&short_post));
+        assert_eq!(restored.user_exposure.get("carol").map(|v| *v), Some(9.0));
+
+        assert_eq!(ladder_inconsistency(long_post, &restored), None);
+        restored.user_balances.insert("carol".to_string(), 6.0);
+        assert!(ladder_inconsistency(long_post, &restored).is_some(), "a ladder computed from other ledgers is caught");
+    }
+}
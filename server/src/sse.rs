@@ -0,0 +1,144 @@
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+use warp::sse::Event;
+use warp::{Filter, Rejection, Reply};
+
+use super::auth::with_auth;
+use super::models::{Claims, ServerMessage};
+use super::state::AppState;
+use super::websocket::message_type_for_debug;
+
+// --- Server-Sent Events ---
+//
+// Read-only alternative to the WebSocket for clients that can't hold one open (simple
+// dashboards, restrictive proxies). Subscribers receive the public broadcast stream and
+// cannot trade. Each subscriber is registered in state.sse_clients next to the
+// WebSocket clients and is dropped on the first failed send after it disconnects.
+
+// Broadcasts that are forwarded to SSE subscribers
+pub fn is_public_event(message: &ServerMessage) -> bool {
+    matches!(
+        message,
+        ServerMessage::MarketUpdate { .. } | ServerMessage::NewPost { .. } | ServerMessage::LiquidationEvent { .. }
+    )
+}
+
+// Queue a broadcast for every SSE subscriber, pruning ones that have gone away
+pub fn forward_to_sse_clients(message: &ServerMessage, state: &AppState) {
+    if !is_public_event(message) || state.sse_clients.is_empty() {
+        return;
+    }
+    state.sse_clients.retain(|subscriber_id, sender| {
+        let delivered = sender.send(message.clone()).is_ok();
+        if !delivered {
+            println!("SSE subscriber {} disconnected, removing.", subscriber_id);
+        }
+        delivered
+    });
+}
+
+// Register a subscriber and return its event stream
+fn subscribe(user_id: String, state: AppState) -> impl Stream<Item = Result<Event, Infallible>> {
+    let subscriber_id = Uuid::new_v4();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    state.sse_clients.insert(subscriber_id, sender);
+    println!("New SSE subscriber: subscriber_id={}, user_id={}", subscriber_id, user_id);
+
+    UnboundedReceiverStream::new(receiver).map(|message| {
+        let event = Event::default().event(sse_event_name(&message));
+        Ok(event.json_data(&message).unwrap_or_else(|e| {
+            eprintln!("Failed to serialize SSE event {:?}: {}", message, e);
+            Event::default().comment("serialization error")
+        }))
+    })
+}
+
+// SSE event name, matching the `type` tag of the WebSocket messages
+fn sse_event_name(message: &ServerMessage) -> &'static str {
+    match message {
+        ServerMessage::MarketUpdate { .. } => "market_update",
+        ServerMessage::NewPost { .. } => "new_post",
+        ServerMessage::LiquidationEvent { .. } => "liquidation_event",
+        other => message_type_for_debug(other),
+    }
+}
+
+// GET /stream?token=<jwt>
+pub fn stream_route(state: AppState) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("stream")
+        .and(warp::get())
+        .and(with_auth(state.clone()))
+        .map(move |claims: Claims| {
+            let events = subscribe(claims.sub, state.clone());
+            warp::sse::reply(warp::sse::keep_alive().stream(events))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::handle_client_message;
+    use crate::models::Client;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::Duration;
+    use warp::filters::ws::Message;
+
+    fn token_for(user_id: &str) -> String {
+        let claims = Claims { sub: user_id.to_string(), aud: "authenticated".to_string(), exp: 4_000_000_000 };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(AppState::TEST_JWT_SECRET.as_ref())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscriber_receives_new_post() {
+        let state = AppState::new_for_test();
+        let (addr, server) = warp::serve(stream_route(state.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut response = reqwest::get(format!("http://{}/stream?token={}", addr, token_for("viewer")))
+            .await
+            .expect("SSE request");
+        assert!(response.status().is_success());
+        while state.sse_clients.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Another user creates a post over their WebSocket connection
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let client_id = Uuid::new_v4();
+        state.clients.insert(client_id, Client::new("alice", sender));
+        let create = serde_json::json!({ "type": "create_post", "content": "hello sse" });
+        handle_client_message(client_id, "alice", Message::text(create.to_string()), &state).await;
+
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), response.chunk())
+                .await
+                .expect("event before timeout")
+                .expect("readable stream")
+                .expect("stream still open");
+            received.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        assert!(received.contains("event:new_post"), "got {:?}", received);
+        let data = received.lines().find_map(|line| line.strip_prefix("data:")).expect("data line");
+        let message: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(message["type"], "new_post");
+        assert_eq!(message["post"]["content"], "hello sse");
+        assert_eq!(message["post"]["user_id"], "alice");
+    }
+
+    #[test]
+    fn only_public_broadcasts_are_forwarded() {
+        let state = AppState::new_for_test();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        state.sse_clients.insert(Uuid::new_v4(), sender);
+
+        forward_to_sse_clients(&ServerMessage::BalanceUpdate { balance: 1.0 }, &state);
+        forward_to_sse_clients(&ServerMessage::MarketUpdate { post_id: Uuid::new_v4(), price: 2.0, supply: 1.0 }, &state);
+
+        assert!(matches!(receiver.try_recv(), Ok(ServerMessage::MarketUpdate { .. })));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -1,39 +1,308 @@
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use tokio::sync::{Mutex, Notify, RwLock};
+use tokio::time::Instant;
+use tokio::sync::mpsc::UnboundedSender;
 use uuid::Uuid;
 // use tokio::sync::Mutex; // Removed Mutex import unless needed elsewhere
-use std::collections::{VecDeque, BTreeMap};
+use std::collections::{BTreeMap, VecDeque};
+use chrono::{DateTime, Utc};
 use ordered_float::OrderedFloat; // For sorting f64 keys
 
-use super::models::{Client, Post, UserPositionDetail};
+use super::models::{Client, FeeTotals, Post, ServerMessage, TradeRecord, UserPositionDetail};
+use super::calculations::{calculate_holder_stats, HolderStats};
+use super::market::MarketHandle;
+use super::config::Config;
+use super::metrics::Metrics;
+use super::webhooks::WebhookNotifier;
+use super::moderation::ContentChecker;
+use super::idempotency::IdempotencyKeys;
+#[cfg(any(test, feature = "test-utils"))]
+use super::{bonding_curve::get_price, market::spawn_market};
 
 // Type aliases for shared state
 pub type Clients = Arc<DashMap<Uuid, Client>>;         // ClientID -> Client
+pub type SseClients = Arc<DashMap<Uuid, UnboundedSender<ServerMessage>>>; // SubscriberID -> Read-only SSE event queue
 pub type Posts = Arc<DashMap<Uuid, Post>>;             // PostID -> Post
 pub type UserBalances = Arc<DashMap<String, f64>>;   // UserID -> Lifetime Balance (Deposits - Withdrawals)
 pub type UserPositions = Arc<DashMap<String, DashMap<Uuid, UserPositionDetail>>>; // UserID -> PostID -> UserPositionDetail
-pub type UserRealizedPnl = Arc<DashMap<String, f64>>; // UserID -> Total Realized PNL
+pub type UserRealizedPnl = Arc<DashMap<String, f64>>; // UserID -> Total Realized PNL (closed positions only)
+pub type UserPnlHistory = Arc<DashMap<String, VecDeque<(DateTime<Utc>, f64)>>>; // UserID -> Recent realized PnL bookings (bounded)
+pub type UserTradeHistory = Arc<DashMap<String, VecDeque<TradeRecord>>>; // UserID -> Recent executed trades, oldest first (bounded)
+pub type UserCash = Arc<DashMap<String, f64>>;        // UserID -> Net trading cash flow (proceeds - costs)
 pub type UserExposure = Arc<DashMap<String, f64>>;   // UserID -> Cumulative Abs Cost of Open Positions
+pub type PostVolumes = Arc<DashMap<Uuid, f64>>;     // PostID -> Cumulative absolute quantity traded
+pub type UserVolumes = Arc<DashMap<String, f64>>;   // UserID -> Cumulative absolute quantity traded voluntarily (fee tier)
+pub type Markets = Arc<DashMap<Uuid, MarketHandle>>; // PostID -> Market actor handle
 // pub type LiquidationQueue = Arc<Mutex<VecDeque<String>>>; // Removed
 
-// Map: PostID -> SortedMap[SupplyThreshold -> Vec<(ForcedTradeCost, ForcedTradeSize, UserId)>]
+// One user's forced unwind, registered at the supply where they liquidate
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiquidationEntry {
+    pub cost_unwind: f64, // Cost of the forced trade along the curve
+    pub size_unwind: f64, // Supply the forced trade moves, i.e. -position.size at registration
+    pub cost_basis: f64,  // The position's total_cost_basis at registration
+    pub user_id: String,
+}
+// Map: PostID -> SortedMap[SupplyThreshold -> Vec<LiquidationEntry>]
 // Use Vec to handle multiple users liquidating at the exact same supply threshold.
-pub type LiquidationThresholds = Arc<DashMap<Uuid, BTreeMap<OrderedFloat<f64>, Vec<(f64, f64, String)>>>>;
+pub type LiquidationThresholds = Arc<DashMap<Uuid, BTreeMap<OrderedFloat<f64>, Vec<LiquidationEntry>>>>;
+// PostID -> (low, high) supply range whose thresholds are registered, when the ladder was
+// cut to Config::threshold_grace_distance; no entry means every threshold is registered
+pub type ThresholdWindows = Arc<DashMap<Uuid, (f64, f64)>>;
 
-// pub type InsuranceFund = Arc<DashMap<Uuid, Mutex<f64>>>; // Removed
+pub type InsuranceFund = Arc<DashMap<Uuid, f64>>; // PostID -> Accumulated liquidation penalties and insurance fee shares
+pub type UnderMargined = Arc<DashSet<String>>; // UserIDs flagged by the margin sweep, pending liquidation
+pub type UserProfiles = Arc<DashSet<String>>; // UserIDs with a registered profile (checked in strict mode)
+pub type PostContents = Arc<DashMap<(String, u64), Uuid>>; // (Creator UserID, trimmed content hash) -> First PostID
+pub type UserPostCounts = Arc<DashMap<String, usize>>; // Creator UserID -> Unsettled posts (for max_posts_per_user)
+pub type UserLastPostAt = Arc<DashMap<String, Instant>>; // Creator UserID -> When they last created a post (for post_cooldown_secs)
+pub type PostFeeTotals = Arc<DashMap<Uuid, FeeTotals>>; // PostID -> Trading fees collected and their split
+pub type HolderStatsCache = Arc<DashMap<Uuid, HolderStats>>; // PostID -> Holder stats, dropped whenever the post fills
 
 
 // Combined Application State
 #[derive(Clone)]
 pub struct AppState {
     pub clients: Clients,
+    pub sse_clients: SseClients,
     pub posts: Posts,
     pub user_balances: UserBalances,
     pub user_positions: UserPositions,
     pub user_realized_pnl: UserRealizedPnl,
+    pub user_pnl_history: UserPnlHistory,
+    pub user_trade_history: UserTradeHistory,
+    pub user_cash: UserCash,
     pub user_exposure: UserExposure,
-    pub jwt_secret: Arc<String>,
+    pub post_volumes: PostVolumes,
+    pub user_volumes: UserVolumes,
+    pub jwt_secrets: Arc<Vec<String>>, // Accepted JWT secrets, primary first
     // pub liquidation_queue: LiquidationQueue, // Removed
     pub liquidation_thresholds: LiquidationThresholds, 
-    // pub insurance_fund: InsuranceFund, // Removed
-} 
\ No newline at end of file
+    pub threshold_windows: ThresholdWindows,
+    pub markets: Markets,
+    pub insurance_fund: InsuranceFund,
+    pub under_margined: UnderMargined,
+    pub post_contents: PostContents,
+    pub user_post_counts: UserPostCounts,
+    pub user_last_post_at: UserLastPostAt,
+    pub holder_stats_cache: HolderStatsCache,
+    pub post_fee_totals: PostFeeTotals,
+    pub trade_idempotency_keys: IdempotencyKeys,
+    pub user_profiles: UserProfiles,
+    pub config: Arc<Config>,
+    pub metrics: Arc<Metrics>,
+    pub webhooks: Option<WebhookNotifier>, // None when no webhook URL is configured
+    pub content_check: Option<ContentChecker>, // External screening of new posts; None runs the blocklist only
+    // Set once startup has computed every post's liquidation thresholds; trades are
+    // refused until then (see mark_ready)
+    pub ready: Arc<AtomicBool>,
+    // Wakes the trades held during warmup (Config::warmup_hold_ms) once `ready` is set,
+    // and counts them against Config::warmup_hold_cap. Held trades queue on
+    // `warmup_queue` (a fair, FIFO lock), so they run one at a time in arrival order.
+    pub ready_notify: Arc<Notify>,
+    pub held_trades: Arc<AtomicUsize>,
+    pub warmup_queue: Arc<Mutex<()>>,
+    // Set when shutdown begins, so readiness probes fail and load balancers drain the
+    // instance before it stops (see health.rs)
+    pub draining: Arc<AtomicBool>,
+    // Held for reading by a market actor while it executes a command, and for writing
+    // by take_snapshot to pause all trading during the copy (see snapshot.rs)
+    pub trading_gate: Arc<RwLock<()>>,
+}
+
+impl AppState {
+    // Fresh, empty state with the given config, which must hold a JWT secret
+    pub fn new(config: Config) -> Self {
+        assert!(!config.jwt_secrets.is_empty(), "At least one JWT secret is required");
+        AppState {
+            clients: Clients::default(),
+            sse_clients: SseClients::default(),
+            posts: Posts::default(),
+            user_balances: UserBalances::default(),
+            user_positions: UserPositions::default(),
+            user_realized_pnl: UserRealizedPnl::default(),
+            user_pnl_history: UserPnlHistory::default(),
+            user_trade_history: UserTradeHistory::default(),
+            user_cash: UserCash::default(),
+            user_exposure: UserExposure::default(),
+            post_volumes: PostVolumes::default(),
+            user_volumes: UserVolumes::default(),
+            jwt_secrets: Arc::new(config.jwt_secrets.clone()),
+            liquidation_thresholds: LiquidationThresholds::default(),
+            threshold_windows: ThresholdWindows::default(),
+            markets: Markets::default(),
+            insurance_fund: InsuranceFund::default(),
+            under_margined: UnderMargined::default(),
+            post_contents: PostContents::default(),
+            user_post_counts: UserPostCounts::default(),
+            user_last_post_at: UserLastPostAt::default(),
+            holder_stats_cache: HolderStatsCache::default(),
+            post_fee_totals: PostFeeTotals::default(),
+            trade_idempotency_keys: IdempotencyKeys::default(),
+            user_profiles: Arc::new(config.known_users.iter().cloned().collect()),
+            webhooks: WebhookNotifier::from_config(&config),
+            content_check: None,
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::default()),
+            ready: Arc::new(AtomicBool::new(false)),
+            ready_notify: Arc::new(Notify::new()),
+            held_trades: Arc::new(AtomicUsize::new(0)),
+            warmup_queue: Arc::new(Mutex::new(())),
+            draining: Arc::new(AtomicBool::new(false)),
+            trading_gate: Arc::new(RwLock::new(())),
+        }
+    }
+
+    // Open the server for trading, once every post's liquidation thresholds are computed
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+        self.ready_notify.notify_waiters();
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    // Report not-ready from now on; trading itself is unaffected
+    pub fn begin_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    // A post's holder stats, computed on the first request after the post last filled
+    pub fn holder_stats(&self, post_id: Uuid) -> HolderStats {
+        *self.holder_stats_cache
+            .entry(post_id)
+            .or_insert_with(|| calculate_holder_stats(post_id, self))
+    }
+}
+
+// --- Test Helpers ---
+//
+// One way for tests to build a correctly-shaped AppState and seed it, e.g. two users
+// holding one post:
+//
+//     let state = AppState::new_for_test()
+//         .with_user("alice", 1000.0)
+//         .with_user("bob", 500.0)
+//         .with_post(post_id, "alice", 10.0)
+//         .with_position("alice", post_id, 6.0, 20.0)
+//         .with_position("bob", post_id, 4.0, 15.0);
+//
+// Seeding writes the maps directly and does not recompute liquidation thresholds or
+// start market actors; call `update_liquidation_thresholds` / `with_markets` as needed.
+#[cfg(any(test, feature = "test-utils"))]
+#[allow(dead_code)]
+impl AppState {
+    pub const TEST_JWT_SECRET: &'static str = "test-secret";
+
+    // Ready for trading; clear `ready` to test the warmup window
+    pub fn new_for_test() -> Self {
+        let state = AppState::new(Config { jwt_secrets: vec![Self::TEST_JWT_SECRET.to_string()], ..Config::default() });
+        state.mark_ready();
+        state
+    }
+
+    // Replace the config (call before sharing the state)
+    pub fn with_config(mut self, config: Config) -> Self {
+        for user_id in &config.known_users {
+            self.user_profiles.insert(user_id.clone());
+        }
+        self.webhooks = WebhookNotifier::from_config(&config);
+        self.config = Arc::new(config);
+        self
+    }
+
+    // Register a user (profile included) with a starting balance and zeroed PnL/cash/exposure
+    pub fn with_user(self, user_id: &str, balance: f64) -> Self {
+        self.user_profiles.insert(user_id.to_string());
+        self.user_balances.insert(user_id.to_string(), balance);
+        self.user_realized_pnl.insert(user_id.to_string(), 0.0);
+        self.user_cash.insert(user_id.to_string(), 0.0);
+        self.user_exposure.insert(user_id.to_string(), 0.0);
+        self
+    }
+
+    // Add a post at the given supply with a consistent price and an empty threshold map
+    pub fn with_post(self, post_id: Uuid, creator: &str, supply: f64) -> Self {
+        let post = Post {
+            id: post_id,
+            user_id: creator.to_string(),
+            content: format!("test post {}", post_id),
+            supply,
+            price: get_price(supply, 0.0, self.config.bonding_curve_epsilon),
+            ..Post::default()
+        };
+        self.posts.insert(post_id, post);
+        *self.user_post_counts.entry(creator.to_string()).or_insert(0) += 1;
+        self.liquidation_thresholds.insert(post_id, BTreeMap::new());
+        self
+    }
+
+    // Set a user's position on a post, pay its cost basis out of their cash and refresh
+    // their stored exposure
+    pub fn with_position(self, user_id: &str, post_id: Uuid, size: f64, total_cost_basis: f64) -> Self {
+        *self.user_cash.entry(user_id.to_string()).or_insert(0.0) -= total_cost_basis;
+        self.user_positions
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(post_id, UserPositionDetail { size, total_cost_basis });
+        self.holder_stats_cache.remove(&post_id);
+        let exposure: f64 = self.user_positions
+            .get(user_id)
+            .map_or(0.0, |positions| positions.iter().map(|p| p.total_cost_basis.abs()).sum());
+        self.user_exposure.insert(user_id.to_string(), exposure);
+        self
+    }
+
+    // Screen new posts with an external content check
+    pub fn with_content_check(mut self, check: impl super::moderation::ContentCheck + 'static) -> Self {
+        self.content_check = Some(Arc::new(check));
+        self
+    }
+
+    // Start market actors for every seeded post (requires a Tokio runtime)
+    pub fn with_markets(self) -> Self {
+        let post_ids: Vec<Uuid> = self.posts.iter().map(|entry| *entry.key()).collect();
+        for post_id in post_ids {
+            self.markets.insert(post_id, spawn_market(post_id, self.clone()));
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::check_supply_invariant;
+
+    #[tokio::test]
+    async fn builders_seed_a_two_user_one_post_market() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 500.0)
+            .with_post(post_id, "alice", 2.0)
+            .with_position("alice", post_id, 2.0, 3.0)
+            .with_markets();
+
+        assert_eq!(check_supply_invariant(post_id, &state), Ok(()));
+        assert_eq!(*state.user_post_counts.get("alice").unwrap(), 1);
+        assert_eq!(*state.user_cash.get("alice").unwrap(), -3.0, "the seeded cost basis is paid out of cash");
+        assert_eq!(*state.user_exposure.get("alice").unwrap(), 3.0);
+        assert_eq!(state.posts.get(&post_id).unwrap().price, get_price(2.0, 0.0, state.config.bonding_curve_epsilon));
+
+        // The seeded market trades like any other
+        let market = state.markets.get(&post_id).unwrap().clone();
+        let fill = market.trade(Uuid::new_v4(), "bob", 1.0, false, None).await.unwrap();
+        assert_eq!(state.posts.get(&post_id).unwrap().supply, 3.0);
+        assert_eq!(state.user_positions.get("bob").unwrap().get(&post_id).unwrap().size, 1.0);
+        assert!((*state.user_cash.get("bob").unwrap() + fill.effective_cost + fill.fee).abs() < 1e-9);
+        assert_eq!(check_supply_invariant(post_id, &state), Ok(()));
+    }
+}
@@ -0,0 +1,162 @@
+use futures_util::{Sink, Stream};
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use warp::filters::ws::Message;
+
+use super::state::AppState;
+use super::websocket::handle_connection;
+
+// --- In-Memory WebSocket Transport (tests only) ---
+//
+// Drives the real handle_connection loop without binding a socket or minting a JWT: the
+// server half is a stream/sink of frames backed by channels, and the client half sends
+// ClientMessages and reads back the ServerMessages as JSON. Everything between the two
+// (initial snapshot, MPSC forwarder, serialization, handler dispatch) runs as in production.
+
+// Far-future expiry so the token timer never fires during a test
+pub(crate) const TOKEN_EXP: usize = 4_000_000_000;
+
+// How long recv waits for the server before failing the test
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The client went away; returned by sends on the server half
+#[derive(Debug)]
+pub struct TransportClosed;
+
+impl fmt::Display for TransportClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in-memory transport closed")
+    }
+}
+
+// Server half, handed to handle_connection in place of a warp WebSocket
+pub struct InMemorySocket {
+    incoming: mpsc::UnboundedReceiver<Message>,
+    outgoing: mpsc::UnboundedSender<Message>,
+}
+
+impl Stream for InMemorySocket {
+    type Item = Result<Message, warp::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx).map(|message| message.map(Ok))
+    }
+}
+
+impl Sink<Message> for InMemorySocket {
+    type Error = TransportClosed;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(())) // Unbounded
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+        self.outgoing.send(message).map_err(|_| TransportClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Client half. Dropping it ends the connection like a closed socket.
+pub struct TestClient {
+    to_server: mpsc::UnboundedSender<Message>,
+    from_server: mpsc::UnboundedReceiver<Message>,
+    pub bytes_received: u64, // Payload bytes of every message read so far
+}
+
+impl TestClient {
+    // Connect `user_id` as if their token had just been verified
+    pub fn connect(user_id: &str, state: &AppState) -> Self {
+        Self::connect_until(user_id, TOKEN_EXP, state)
+    }
+
+    // Same as connect with a token expiring at `token_exp` (seconds since the epoch)
+    pub fn connect_until(user_id: &str, token_exp: usize, state: &AppState) -> Self {
+        let (to_server, incoming) = mpsc::unbounded_channel();
+        let (outgoing, from_server) = mpsc::unbounded_channel();
+        tokio::spawn(handle_connection(InMemorySocket { incoming, outgoing }, user_id.to_string(), token_exp, state.clone()));
+        TestClient { to_server, from_server, bytes_received: 0 }
+    }
+
+    // Send a ClientMessage, given as its JSON form
+    pub fn send(&self, message: serde_json::Value) {
+        self.to_server.send(Message::text(message.to_string())).expect("server end is gone");
+    }
+
+    // Next ServerMessage as JSON; panics on timeout, a closed connection or a close frame
+    pub async fn recv(&mut self) -> serde_json::Value {
+        let message = tokio::time::timeout(RECV_TIMEOUT, self.from_server.recv())
+            .await
+            .expect("timed out waiting for the server")
+            .expect("connection closed");
+        self.bytes_received += message.as_bytes().len() as u64;
+        serde_json::from_str(message.to_str().expect("a text message")).expect("valid JSON")
+    }
+
+    // Skip ahead to the close frame and return its code and reason
+    pub async fn recv_close(&mut self) -> (u16, String) {
+        loop {
+            let message = tokio::time::timeout(RECV_TIMEOUT, self.from_server.recv())
+                .await
+                .expect("timed out waiting for the close frame")
+                .expect("connection closed without a close frame");
+            if let Some((code, reason)) = message.close_frame() {
+                return (code, reason.to_string());
+            }
+        }
+    }
+
+    // Skip ahead to the next message of the given `type`
+    pub async fn recv_type(&mut self, message_type: &str) -> serde_json::Value {
+        loop {
+            let message = self.recv().await;
+            if message["type"] == message_type {
+                return message;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn create_post_and_buy_over_the_full_connection_loop() {
+        let state = AppState::new_for_test();
+        let mut alice = TestClient::connect("alice", &state);
+        assert_eq!(alice.recv().await["type"], "welcome");
+        assert_eq!(alice.recv().await["type"], "initial_state");
+        assert_eq!(alice.recv().await["type"], "user_sync");
+
+        alice.send(serde_json::json!({ "type": "create_post", "content": "hello" }));
+        let post = alice.recv_type("new_post").await["post"].clone();
+        alice.send(serde_json::json!({ "type": "buy", "post_id": post["id"], "quantity": 4.0 }));
+
+        // The new price reaches everyone before the buyer's sync, and the reply comes last
+        let mut sequence = Vec::new();
+        while sequence.last().map(String::as_str) != Some("trade_confirmation") {
+            let message = alice.recv().await;
+            if message["type"] == "market_update" {
+                assert_eq!(message["post_id"], post["id"]);
+                assert_eq!(message["supply"], 4.0);
+                assert_eq!(message["price"], 3.0);
+            }
+            if message["type"] == "user_sync" {
+                assert_eq!(message["positions"][0]["size"], 4.0);
+            }
+            sequence.push(message["type"].as_str().unwrap().to_string());
+        }
+        let position = |message_type: &str| sequence.iter().position(|t| t == message_type).unwrap_or_else(|| panic!("no {} in {:?}", message_type, sequence));
+        assert!(position("market_update") < position("user_sync"), "{:?}", sequence);
+    }
+}
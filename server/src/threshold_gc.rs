@@ -0,0 +1,115 @@
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::state::AppState;
+
+// --- Liquidation Threshold GC ---
+//
+// update_liquidation_thresholds drops a post's entry once its last holder exits, but
+// only when a trade on that post runs it. Entries for markets nobody trades again (or
+// that were created and never traded) would otherwise live forever. This task
+// periodically removes the entries of posts at zero supply with no open positions.
+
+// Start the periodic GC if an interval is configured
+pub fn spawn_threshold_gc(state: AppState) {
+    let interval_secs = state.config.threshold_gc_interval_secs;
+    if interval_secs == 0 {
+        println!("Liquidation threshold GC disabled.");
+        return;
+    }
+    tokio::spawn(async move {
+        println!("Liquidation threshold GC running every {}s.", interval_secs);
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            let removed = prune_once(&state).await;
+            if removed > 0 {
+                println!("Liquidation threshold GC: removed {} entries.", removed);
+            }
+        }
+    });
+}
+
+// Any user with a non-dust position on the post
+fn has_open_positions(post_id: Uuid, state: &AppState) -> bool {
+    state.user_positions.iter().any(|entry| {
+        entry.value().get(&post_id).is_some_and(|position| position.size.abs() > state.config.epsilon)
+    })
+}
+
+// A post at zero supply (or deleted) that nobody holds
+fn is_dead(post_id: Uuid, state: &AppState) -> bool {
+    let at_zero_supply = state.posts.get(&post_id)
+        .is_none_or(|post| post.supply.abs() <= state.config.epsilon); // Deleted posts count as dead
+    at_zero_supply && !has_open_positions(post_id, state)
+}
+
+// One pass over the threshold map; returns the number of entries removed. Candidates
+// are found without blocking trading, then checked again and removed with the trading
+// gate held for writing, so a trade landing between the check and the removal can't
+// lose the ladder it just registered. Does nothing until warmup has computed every
+// post's ladder.
+pub async fn prune_once(state: &AppState) -> usize {
+    if !state.is_ready() {
+        return 0;
+    }
+    // Collect first so no threshold shard lock is held while scanning positions
+    let post_ids: Vec<Uuid> = state.liquidation_thresholds.iter().map(|entry| *entry.key()).collect();
+    let candidates: Vec<Uuid> = post_ids.into_iter().filter(|post_id| is_dead(*post_id, state)).collect();
+    if candidates.is_empty() {
+        return 0;
+    }
+    let _gate = state.trading_gate.write().await;
+    let mut removed = 0;
+    for post_id in candidates {
+        if is_dead(post_id, state) && state.liquidation_thresholds.remove(&post_id).is_some() {
+            state.threshold_windows.remove(&post_id);
+            removed += 1;
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prunes_only_dead_markets() {
+        let dead = Uuid::new_v4();
+        let held = Uuid::new_v4();
+        let moved = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_post(dead, "alice", 0.0)
+            .with_post(held, "alice", 0.0)
+            .with_post(moved, "alice", 2.0)
+            .with_position("bob", held, 1.0, 1.0);
+
+        state.ready.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(prune_once(&state).await, 0, "skipped while warming up");
+        assert!(state.liquidation_thresholds.contains_key(&dead));
+        state.mark_ready();
+        assert_eq!(prune_once(&state).await, 1);
+
+        assert!(!state.liquidation_thresholds.contains_key(&dead));
+        assert!(state.liquidation_thresholds.contains_key(&held));
+        assert!(state.liquidation_thresholds.contains_key(&moved));
+    }
+
+    #[tokio::test]
+    async fn a_trade_in_flight_keeps_its_ladder() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test().with_post(post_id, "alice", 0.0);
+
+        // A market actor mid-trade: the post looks dead until the fill lands
+        let in_flight = state.trading_gate.read().await;
+        let gc_state = state.clone();
+        let prune = tokio::spawn(async move { prune_once(&gc_state).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        state.posts.get_mut(&post_id).unwrap().supply = 1.0;
+        drop(in_flight);
+
+        assert_eq!(prune.await.unwrap(), 0);
+        assert!(state.liquidation_thresholds.contains_key(&post_id));
+    }
+}
@@ -0,0 +1,178 @@
+use chrono::{DateTime, Utc};
+use ring::hmac;
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::config::Config;
+
+// --- Outgoing Webhooks ---
+//
+// External systems (e.g. risk monitoring) can be notified of events by HTTP POST.
+// Delivery runs on a background task so a slow or unreachable endpoint never blocks
+// the trade path; failed attempts are retried with exponential backoff, then dropped.
+
+pub const SIGNATURE_HEADER: &str = "X-FLVKE-Signature";
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Payload POSTed for every forced unwind
+#[derive(Serialize, Debug, Clone)]
+pub struct LiquidationWebhook {
+    pub event: &'static str, // Always "liquidation"
+    pub post_id: Uuid,
+    pub user_id: String,
+    pub size: f64, // Forced trade size (opposite sign of the closed position)
+    pub realized_pnl: f64, // PnL booked on the forced trade, after the penalty
+    pub timestamp: DateTime<Utc>,
+}
+
+impl LiquidationWebhook {
+    pub fn new(post_id: Uuid, user_id: &str, size: f64, realized_pnl: f64) -> Self {
+        LiquidationWebhook {
+            event: "liquidation",
+            post_id,
+            user_id: user_id.to_string(),
+            size,
+            realized_pnl,
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+    signing_key: Option<hmac::Key>,
+}
+
+impl WebhookNotifier {
+    // None when no webhook URL is configured
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let url = config.webhook_url.clone()?;
+        let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Error: Could not build webhook HTTP client, webhooks disabled: {}", e);
+                return None;
+            }
+        };
+        let signing_key = config.webhook_secret.as_ref()
+            .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()));
+        Some(WebhookNotifier { client, url, signing_key })
+    }
+
+    // Queue a liquidation notification; returns immediately
+    pub fn notify_liquidation(&self, payload: LiquidationWebhook) {
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Failed to serialize liquidation webhook for user {}: {}", payload.user_id, e);
+                return;
+            }
+        };
+        let notifier = self.clone();
+        tokio::spawn(async move { notifier.deliver(body).await });
+    }
+
+    // POST the body, retrying failures and non-2xx responses with exponential backoff
+    async fn deliver(&self, body: Vec<u8>) {
+        let signature = self.signing_key.as_ref().map(|key| sign(key, &body));
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut request = self.client.post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => return,
+                Ok(response) => eprintln!("Webhook attempt {}/{} to {} got status {}", attempt, MAX_ATTEMPTS, self.url, response.status()),
+                Err(e) => eprintln!("Webhook attempt {}/{} to {} failed: {}", attempt, MAX_ATTEMPTS, self.url, e),
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        eprintln!("Error: Giving up on webhook to {} after {} attempts", self.url, MAX_ATTEMPTS);
+    }
+}
+
+// "sha256=<hex HMAC of the body>", verifiable by the receiver with the shared secret
+pub fn sign(key: &hmac::Key, body: &[u8]) -> String {
+    let tag = hmac::sign(key, body);
+    let hex: String = tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    type Received = mpsc::UnboundedReceiver<(Option<String>, Vec<u8>)>; // (signature header, raw body)
+
+    // Local HTTP server that fails the first `failures` requests with a 500, then
+    // accepts and forwards (signature header, raw body) of each request to the channel
+    fn mock_receiver(failures: usize) -> (String, Received) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let route = warp::post()
+            .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature: Option<String>, body: warp::hyper::body::Bytes| {
+                if seen.fetch_add(1, Ordering::SeqCst) < failures {
+                    return warp::http::StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                let _ = sender.send((signature, body.to_vec()));
+                warp::http::StatusCode::OK
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/hooks/liquidation", addr), receiver)
+    }
+
+    #[tokio::test]
+    async fn liquidation_webhook_is_signed_and_retried() {
+        let (url, mut received) = mock_receiver(1);
+        let config = Config {
+            webhook_url: Some(url),
+            webhook_secret: Some("hook-secret".to_string()),
+            ..Config::default()
+        };
+        let notifier = WebhookNotifier::from_config(&config).expect("webhooks enabled");
+        let post_id = Uuid::new_v4();
+
+        notifier.notify_liquidation(LiquidationWebhook::new(post_id, "carol", 2.0, -3.5));
+
+        let (signature, raw_body) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("webhook delivered after a retry")
+            .expect("mock server still running");
+        let body: serde_json::Value = serde_json::from_slice(&raw_body).unwrap();
+        assert_eq!(body["event"], "liquidation");
+        assert_eq!(body["post_id"], post_id.to_string());
+        assert_eq!(body["user_id"], "carol");
+        assert_eq!(body["size"], 2.0);
+        assert_eq!(body["realized_pnl"], -3.5);
+        assert!(body["timestamp"].is_string());
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"hook-secret");
+        let expected = sign(&key, &raw_body);
+        assert_eq!(signature.as_deref(), Some(expected.as_str()));
+    }
+
+    #[test]
+    fn no_url_disables_webhooks() {
+        assert!(WebhookNotifier::from_config(&Config::default()).is_none());
+    }
+}
@@ -1,21 +1,29 @@
-use futures_util::{StreamExt, SinkExt};
-use tokio::sync::mpsc;
+use futures_util::{Sink, Stream, StreamExt, SinkExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
-use warp::filters::ws::{Message, WebSocket};
+use warp::filters::ws::Message;
 
+use super::acks::queue_message;
 use super::state::AppState;
-use super::models::{Client, ServerMessage, PositionDetail};
-use super::constants::{INITIAL_BALANCE, EPSILON};
-use super::bonding_curve::get_price;
-use super::calculations::{calculate_average_price, calculate_unrealized_pnl, calculate_liquidation_price};
-use super::handlers::{calculate_total_unrealized_pnl, handle_client_message, send_user_sync_update};
+use super::config::{BroadcastStrategy, Config};
+use super::constants::PROTOCOL_VERSION;
+use super::models::{Client, Post, PostVisibility, ServerMessage};
+use super::sse::forward_to_sse_clients;
+use super::wire;
+use super::handlers::{handle_client_message, build_user_sync, build_portfolio_summary, snapshot_prices, ensure_user_state_exists};
 
 // --- WebSocket Handling ---
 
 // Helper to get simple message type string for logging
 pub fn message_type_for_debug(msg: &ServerMessage) -> &'static str {
     match msg {
+       ServerMessage::Welcome { .. } => "Welcome",
        ServerMessage::InitialState { .. } => "InitialState",
        ServerMessage::UserSync { .. } => "UserSync",
        ServerMessage::NewPost { .. } => "NewPost",
@@ -25,30 +33,55 @@ pub fn message_type_for_debug(msg: &ServerMessage) -> &'static str {
        ServerMessage::RealizedPnlUpdate { .. } => "RealizedPnlUpdate",
        ServerMessage::ExposureUpdate { .. } => "ExposureUpdate",
        ServerMessage::EquityUpdate { .. } => "EquityUpdate",
+       ServerMessage::LiquidationEvent { .. } => "LiquidationEvent",
+       ServerMessage::PostDetail { .. } => "PostDetail",
+       ServerMessage::LiquidationLadder { .. } => "LiquidationLadder",
+       ServerMessage::CascadeSimulation { .. } => "CascadeSimulation",
+       ServerMessage::FeeTotals { .. } => "FeeTotals",
+       ServerMessage::ThresholdsRecomputed { .. } => "ThresholdsRecomputed",
+       ServerMessage::BalanceAdjusted { .. } => "BalanceAdjusted",
+       ServerMessage::PositionsImported { .. } => "PositionsImported",
+       ServerMessage::TradeConfirmation { .. } => "TradeConfirmation",
+       ServerMessage::BatchResult { .. } => "BatchResult",
+       ServerMessage::PortfolioSummary { .. } => "PortfolioSummary",
+       ServerMessage::RiskSnapshot { .. } => "RiskSnapshot",
+       ServerMessage::Positions { .. } => "Positions",
+       ServerMessage::PnlHistory { .. } => "PnlHistory",
+       ServerMessage::TradeHistory { .. } => "TradeHistory",
+       ServerMessage::PostSettled { .. } => "PostSettled",
+       ServerMessage::SocializedLoss { .. } => "SocializedLoss",
        ServerMessage::Error { .. } => "Error",
    }
 }
 
+// Sent in place of a message that can't be encoded (see wire.rs), so the client hears
+// back instead of getting nothing
+pub const STATE_UNAVAILABLE: &str = "state temporarily unavailable";
+
+// A message and its wire encoding, or the STATE_UNAVAILABLE error and its encoding when
+// the message can't be encoded
+pub fn encode_or_fallback(message: ServerMessage) -> (ServerMessage, String) {
+    match wire::encode(&message) {
+        Ok(json_msg) => (message, json_msg),
+        Err(e) => {
+            tracing::error!(message_type = message_type_for_debug(&message), error = %e, "failed to encode message, sending the fallback error");
+            let fallback = ServerMessage::error(STATE_UNAVAILABLE);
+            let json_msg = serde_json::to_string(&fallback).expect("a plain error message always serializes");
+            (fallback, json_msg)
+        }
+    }
+}
+
 // Helper to send a message to a specific client
 pub async fn send_to_client(client_id: Uuid, message: ServerMessage, state: &AppState) {
     if let Some(client) = state.clients.get(&client_id) {
-        match serde_json::to_string(&message) {
-            Ok(json_msg) => {
-                if client.sender.send(Ok(Message::text(json_msg))).is_err() {
-                    eprintln!(
-                        "Error queueing message type '{}' for client_id={}",
-                        message_type_for_debug(&message),
-                        client_id
-                    );
-                }
-            }
-            Err(e) => {
-                eprintln!(
-                    "Failed to serialize direct message '{}' for client_id={}: {}",
-                     message_type_for_debug(&message),
-                     client_id, e
-                );
-            }
+        let (message, json_msg) = encode_or_fallback(message);
+        if queue_message(client_id, &client.sender, &client.acks, &message, &json_msg, state).is_err() {
+            eprintln!(
+                "Error queueing message type '{}' for client_id={}",
+                message_type_for_debug(&message),
+                client_id
+            );
         }
     } else {
          eprintln!(
@@ -59,19 +92,95 @@ pub async fn send_to_client(client_id: Uuid, message: ServerMessage, state: &App
     }
 }
 
+// Pushes about the user's own account, which a connection can opt out of with
+// SetAccountUpdates
+fn is_account_update(message: &ServerMessage) -> bool {
+    matches!(message,
+        ServerMessage::UserSync { .. } | ServerMessage::PortfolioSummary { .. } | ServerMessage::EquityUpdate { .. }
+        | ServerMessage::BalanceUpdate { .. } | ServerMessage::SocializedLoss { .. })
+}
+
+// Send a message to every connection of a user, except account updates to connections
+// that opted out of them
+pub async fn send_to_user(user_id: &str, message: ServerMessage, state: &AppState) {
+    let account_update = is_account_update(&message);
+    let client_ids: Vec<Uuid> = state.clients.iter()
+        .filter(|entry| entry.value().user_id == user_id)
+        .filter(|entry| entry.value().account_updates || !account_update)
+        .map(|entry| *entry.key())
+        .collect();
+    for client_id in client_ids {
+        send_to_client(client_id, message.clone(), state).await;
+    }
+}
+
+// --- Server-Initiated Disconnects ---
+
+// Standard close codes (RFC 6455, section 7.4.1)
+pub const CLOSE_NORMAL: u16 = 1000; // Graceful shutdown
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008; // Auth problems, e.g. an expired token, and rate limiting
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011; // The server hit an error it can't recover from
+
+// Queue a close frame for a client and drop it from state.clients. The forwarder task
+// stops after delivering the frame; any later messages for this client are discarded.
+pub fn disconnect_client(client_id: Uuid, code: u16, reason: &str, state: &AppState) {
+    if let Some((_, client)) = state.clients.remove(&client_id) {
+        println!("Disconnecting client_id={} (user_id={}): {} {}", client_id, client.user_id, code, reason);
+        if client.sender.send(Ok(Message::close_with(code, reason.to_string()))).is_err() {
+            eprintln!("Could not queue close frame for client_id={}. Channel likely closed.", client_id);
+        }
+    }
+}
+
+// Disconnect every client with the same code, e.g. on shutdown
+pub fn disconnect_all_clients(code: u16, reason: &str, state: &AppState) {
+    let client_ids: Vec<Uuid> = state.clients.iter().map(|entry| *entry.key()).collect();
+    for client_id in client_ids {
+        disconnect_client(client_id, code, reason, state);
+    }
+}
+
+// Config::max_messages_per_sec, counted over fixed one-second windows
+struct RateLimit {
+    limit: u32, // 0 doesn't limit
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimit {
+    fn new(limit: u32) -> Self {
+        RateLimit { limit, window_start: Instant::now(), count: 0 }
+    }
+
+    // Counts one message; false once the current window is over the limit
+    fn allow(&mut self) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= self.limit
+    }
+}
+
+// Time left before a JWT `exp` (seconds since the epoch) passes
+fn time_until_expiry(token_exp: usize) -> Duration {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Duration::from_secs(token_exp as u64).saturating_sub(now)
+}
+
 // Original broadcast function (used for NewPost and MarketUpdate inside broadcast_market_and_position_updates)
 pub async fn broadcast_message(message: ServerMessage, state: &AppState) {
+    forward_to_sse_clients(&message, state);
      if state.clients.is_empty() {
         println!("No clients connected, skipping broadcast.");
         return;
     }
-    let serialized_message = match serde_json::to_string(&message) {
-        Ok(s) => s,
-        Err(e) => {
-             eprintln!("Failed to serialize broadcast message: {:?}, error: {}", message, e);
-            return;
-        }
-    };
+    let (message, serialized_message) = encode_or_fallback(message);
     println!(
         "Broadcasting message type: {} to {} clients",
         message_type_for_debug(&message),
@@ -80,13 +189,13 @@ pub async fn broadcast_message(message: ServerMessage, state: &AppState) {
     for client_entry in state.clients.iter() {
         let client_id = client_entry.key();
         let client = client_entry.value();
-        if client.sender.send(Ok(Message::text(serialized_message.clone()))).is_err() {
+        if queue_message(*client_id, &client.sender, &client.acks, &message, &serialized_message, state).is_err() {
             eprintln!("Failed to send broadcast message to client_id={}, user_id={}. Channel likely closed.", client_id, client.user_id);
         }
     }
 }
 
-// Function to broadcast market update and then send individual position/margin updates to OTHER clients
+// Function to broadcast market update and then send individual position/margin updates
 pub async fn broadcast_market_and_position_updates(
     post_id: Uuid,
     new_price: f64,
@@ -94,84 +203,264 @@ pub async fn broadcast_market_and_position_updates(
     trading_client_id: Uuid, // ID of the client who made the trade
     state: &AppState,
 ) {
-    // 1. Broadcast the general market update to everyone
-    println!("broadcast_market_and_position_updates: Broadcasting MarketUpdate...");
+    broadcast_market_update(post_id, new_price, new_supply, state).await;
+    send_post_trade_syncs(post_id, trading_client_id, &HashSet::new(), state).await;
+}
+
+// Broadcast the general market update to the clients chosen by the configured
+// BroadcastStrategy (SSE subscribers always receive it)
+pub async fn broadcast_market_update(post_id: Uuid, new_price: f64, new_supply: f64, state: &AppState) {
+    tracing::info!(%post_id, price = new_price, supply = new_supply, "broadcast_market_update: broadcasting MarketUpdate");
     let market_update_msg = ServerMessage::MarketUpdate {
         post_id,
         price: new_price,
         supply: new_supply,
     };
-    broadcast_message(market_update_msg, state).await;
-    println!("broadcast_market_and_position_updates: Finished MarketUpdate broadcast. Iterating clients for PnL/Equity...");
+    let strategy = state.config.broadcast_strategy;
+    if strategy == BroadcastStrategy::All {
+        broadcast_message(market_update_msg, state).await;
+        return;
+    }
 
-    // 2. Iterate through all ACTIVE clients to potentially send PNL and Equity updates
-    for client_entry in state.clients.iter() {
-        let current_client_id = *client_entry.key();
-        let client_info = client_entry.value();
-        let user_id = &client_info.user_id;
-
-        println!("broadcast_market_and_position_updates: Checking client {} (User {})", current_client_id, user_id);
-        // Skip the client who initiated this trade
-        if current_client_id == trading_client_id {
-             println!("broadcast_market_and_position_updates: Skipping trading client {}", current_client_id);
+    forward_to_sse_clients(&market_update_msg, state);
+    let recipients: Vec<Uuid> = state.clients.iter()
+        .filter(|entry| match strategy {
+            BroadcastStrategy::HoldersOnly => state.user_positions.get(&entry.value().user_id)
+                .and_then(|positions| positions.get(&post_id).map(|p| p.size.abs() > state.config.epsilon))
+                .unwrap_or(false),
+            BroadcastStrategy::Subscribers => entry.value().subscriptions.contains(&post_id),
+            BroadcastStrategy::All => true,
+        })
+        .map(|entry| *entry.key())
+        .collect();
+    tracing::info!(?strategy, recipients = recipients.len(), clients = state.clients.len(), "broadcast_market_update: filtered fan-out");
+    send_to_clients(&recipients, &market_update_msg, state);
+}
+
+// Announces a new post. Unlisted posts only go to their creator's clients. Public posts
+// go to everyone under the All strategy; under the filtered strategies nobody holds or
+// subscribes to a brand-new post yet, so only the SSE feed and the creator get it and
+// other clients discover it through InitialState or GetPost.
+pub async fn broadcast_new_post(post: Post, state: &AppState) {
+    let creator = post.user_id.clone();
+    let visibility = post.visibility;
+    let new_post_msg = ServerMessage::NewPost { post };
+    if visibility == PostVisibility::Unlisted {
+        println!("broadcast_new_post: Post is unlisted, sending NewPost to creator {} only", creator);
+        send_to_user(&creator, new_post_msg, state).await;
+        return;
+    }
+    if state.config.broadcast_strategy == BroadcastStrategy::All {
+        broadcast_message(new_post_msg, state).await;
+        return;
+    }
+    forward_to_sse_clients(&new_post_msg, state);
+    send_to_user(&creator, new_post_msg, state).await;
+}
+
+// Serialize a message once and queue the same text for each of the given clients
+pub fn send_to_clients(client_ids: &[Uuid], message: &ServerMessage, state: &AppState) {
+    let (message, json_msg) = encode_or_fallback(message.clone());
+    for client_id in client_ids {
+        if let Some(client) = state.clients.get(client_id) {
+            if queue_message(*client_id, &client.sender, &client.acks, &message, &json_msg, state).is_err() {
+                eprintln!("Error queueing message type '{}' for client_id={}", message_type_for_debug(&message), client_id);
+            }
+        }
+    }
+}
+
+// Sends each client at most one UserSync after a trade on `post_id`: clients of users
+// in `affected_user_ids` (trader, liquidated users, charged counterparties) and of users
+// holding the post. Affected users also get a PortfolioSummary. Call once, after all of
+// the trade's state updates are final and after the MarketUpdate has been broadcast (see
+// the ordering note in execute_trade).
+//
+// Post prices are snapshotted once for the whole fan-out, and each user's UserSync is
+// built and serialized once and shared by all of that user's connections. Nothing here
+// awaits per recipient: messages are only queued on each client's channel (the
+// forwarders write them out concurrently), so a trade liquidating many users doesn't
+// pay a round trip or a lock wait per user.
+pub async fn send_post_trade_syncs(
+    post_id: Uuid,
+    trading_client_id: Uuid, // ID of the client who made the trade
+    affected_user_ids: &HashSet<String>,
+    state: &AppState,
+) {
+    // Snapshot the recipients first so no DashMap shard lock is held across an await.
+    // Clients that opted out of account updates are left out altogether.
+    let recipients: Vec<(Uuid, String)> = state.clients.iter()
+        .filter(|entry| entry.value().account_updates)
+        .map(|entry| (*entry.key(), entry.value().user_id.clone()))
+        .collect();
+    println!("send_post_trade_syncs: Checking {} clients for post {}...", recipients.len(), post_id);
+
+    // UserID -> (holds the post, that user's connected clients)
+    let mut clients_by_user: HashMap<String, (bool, Vec<Uuid>)> = HashMap::new();
+    for (client_id, user_id) in recipients {
+        if let Some((_, client_ids)) = clients_by_user.get_mut(&user_id) {
+            client_ids.push(client_id);
             continue;
         }
+        let holds_post = state.user_positions.get(&user_id)
+            .and_then(|positions| positions.get(&post_id).map(|p| p.size.abs() > state.config.epsilon))
+            .unwrap_or(false);
+        if holds_post || affected_user_ids.contains(&user_id) {
+            clients_by_user.insert(user_id, (holds_post, vec![client_id]));
+        }
+    }
+    if clients_by_user.is_empty() {
+        println!("send_post_trade_syncs: Finished, no recipients.");
+        return;
+    }
 
-        let mut affected_by_price_change = false;
-
-        // Check if this *other* user has a position in the updated post
-         if let Some(user_positions_map) = state.user_positions.get(user_id) {
-             if let Some(position) = user_positions_map.get(&post_id) {
-                 println!("broadcast_market_and_position_updates: User {} has position in post {}. Size={:.4}", user_id, post_id, position.size);
-                 // Only process if position exists and is non-zero (PnL change matters)
-                 if position.size.abs() > EPSILON { 
-                     println!("broadcast_market_and_position_updates: Position size non-zero. Calculating updates for user {}", user_id);
-                     // We need to send the full UserSync to include the updated liq price
-                     // (since PositionUpdate doesn't currently support it)
-                     // The UserSync calculation automatically handles getting the latest data
-                     println!(
-                         "   -> Sending UserSync instead of PositionUpdate for Post {} to OTHER User {} ({}).",
-                         post_id, user_id, current_client_id
-                     );
-                     // Call send_user_sync_update directly
-                     send_user_sync_update(user_id, current_client_id, state).await;
-                     println!("   -> Returned from send_user_sync_update call.");
-                     affected_by_price_change = true; // Mark that PnL potentially changed
-                 } else {
-                     println!("broadcast_market_and_position_updates: Position size near zero for user {}, skipping PnL update.", user_id);
-                 }
-             } else {
-                 println!("broadcast_market_and_position_updates: User {} has no position in post {}, skipping PnL update.", user_id, post_id);
-             }
-         } else {
-              println!("broadcast_market_and_position_updates: User {} has no position map, skipping PnL update.", user_id);
-         }
-
-        // If the user's PNL for this post changed, their overall Equity also changed.
-        // Send EquityUpdate. Exposure doesn't change from market moves, only trades.
-        // This might be redundant if we send UserSync above, but let's keep it for now
-        // as UserSync primarily updates the *target* user of the sync.
-        if affected_by_price_change {
-            println!("broadcast_market_and_position_updates: User {} was affected by price change, sending EquityUpdate.", user_id);
-            // Recalculate total equity for this user
-            let balance = state.user_balances.get(user_id).map_or(INITIAL_BALANCE, |b| *b.value());
-            let realized_pnl = state.user_realized_pnl.get(user_id).map_or(0.0, |pnl| *pnl.value());
-            let total_unrealized_pnl = calculate_total_unrealized_pnl(user_id, state);
-            let equity = balance + realized_pnl + total_unrealized_pnl;
-
-            println!(
-                "   -> Sending Equity update to OTHER User {} ({}): {:.4}",
-                 user_id, current_client_id, equity
-            );
-            send_to_client(current_client_id, ServerMessage::EquityUpdate { equity }, state).await;
+    let user_ids: Vec<&str> = clients_by_user.keys().map(String::as_str).collect();
+    let prices = snapshot_prices(&user_ids, state);
+    for (user_id, (holds_post, client_ids)) in clients_by_user {
+        println!("send_post_trade_syncs: Sending UserSync to User {} ({} clients)", user_id, client_ids.len());
+        let sync_msg = build_user_sync(&user_id, &prices, state);
+        send_to_clients(&client_ids, &sync_msg, state);
+        // The trade changed this user's own account, not just a price they hold
+        if affected_user_ids.contains(&user_id) {
+            send_to_clients(&client_ids, &build_portfolio_summary(&user_id, state), state);
+        }
+
+        // Other holders also get the lighter EquityUpdate for the price move
+        if let (true, ServerMessage::UserSync { equity, .. }) = (holds_post, &sync_msg) {
+            let other_clients: Vec<Uuid> = client_ids.into_iter().filter(|id| *id != trading_client_id).collect();
+            if !other_clients.is_empty() {
+                send_to_clients(&other_clients, &ServerMessage::EquityUpdate { equity: *equity }, state);
+            }
+        }
+    }
+    println!("send_post_trade_syncs: Finished.");
+}
+
+// Why a connection's forwarder task stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwarderExit {
+    ChannelClosed, // Every sender for the client is gone
+    CloseSent, // Delivered a server-initiated close frame
+    SendFailed, // The socket reported an error
+    SendTimedOut, // A send didn't complete within Config::ws_send_timeout_ms
+}
+
+// Forwards a client's queued messages to its socket. A send that doesn't complete in
+// time means the socket is wedged (e.g. the peer vanished without a TCP reset): the
+// client is removed from state.clients so broadcasts stop piling up in its channel.
+pub async fn run_forwarder<S>(
+    client_id: Uuid,
+    mut sink: S,
+    mut messages: UnboundedReceiverStream<Result<Message, warp::Error>>,
+    state: &AppState,
+) -> ForwarderExit
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    let send_timeout = Duration::from_millis(state.config.ws_send_timeout_ms);
+    let (depth, bytes_sent) = state.clients.get(&client_id)
+        .map(|client| (client.sender.depth(), client.sender.bytes_sent()))
+        .unzip();
+    let exit = loop {
+        let next = messages.next().await;
+        if let (Some(_), Some(depth)) = (&next, &depth) {
+            depth.dequeued();
+        }
+        let msg = match next {
+            Some(Ok(msg)) => msg,
+            Some(Err(e)) => {
+                eprintln!("Error receiving message in MPSC->WS forwarder task for client {}: {}", client_id, e);
+                continue;
+            }
+            None => break ForwarderExit::ChannelClosed,
+        };
+        let is_close = msg.is_close();
+        let len = msg.as_bytes().len() as u64;
+        let sent = if send_timeout.is_zero() {
+            Ok(sink.send(msg).await)
         } else {
-             println!("broadcast_market_and_position_updates: User {} not affected by price change, skipping EquityUpdate.", user_id);
+            tokio::time::timeout(send_timeout, sink.send(msg)).await
+        };
+        match sent {
+            Ok(Ok(())) if is_close => break ForwarderExit::CloseSent, // Nothing more to send
+            Ok(Ok(())) => {
+                if let Some(bytes_sent) = &bytes_sent {
+                    bytes_sent.fetch_add(len, Ordering::Relaxed);
+                }
+            }
+            Ok(Err(e)) => {
+                eprintln!("Error sending message via MPSC->WS forwarder task for client {}: {}", client_id, e);
+                break ForwarderExit::SendFailed;
+            }
+            Err(_) => {
+                eprintln!("Send to client {} timed out after {:?}, dropping the client", client_id, send_timeout);
+                state.clients.remove(&client_id);
+                break ForwarderExit::SendTimedOut;
+            }
         }
+    };
+    println!("MPSC->WS forwarder task finished for client {}: {:?}", client_id, exit);
+    exit
+}
+
+// A connection's activity, reported once when it ends. The report runs on drop, so
+// every exit path of handle_connection (including the early rejections) emits it.
+struct SessionStats {
+    client_id: Uuid,
+    user_id: String,
+    started: Instant,
+    messages_received: u64,
+    trades_executed: u64,
+    bytes_sent: Arc<AtomicU64>, // Added to by the forwarder
+    state: AppState,
+}
+
+impl Drop for SessionStats {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed();
+        let bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        let metrics = &self.state.metrics;
+        metrics.connection_duration.observe(duration);
+        metrics.connection_messages_received.fetch_add(self.messages_received, Ordering::Relaxed);
+        metrics.connection_trades_executed.fetch_add(self.trades_executed, Ordering::Relaxed);
+        metrics.connection_bytes_sent.fetch_add(bytes_sent, Ordering::Relaxed);
+        tracing::info!(
+            client_id = %self.client_id, user_id = %self.user_id, ?duration, messages_received = self.messages_received,
+            trades_executed = self.trades_executed, bytes_sent, "handle_connection: session closed"
+        );
     }
-     println!("broadcast_market_and_position_updates: Finished iterating clients.");
 }
 
-pub async fn handle_connection(ws: WebSocket, user_id: String, state: AppState) {
+// Optional features the config turns on, for clients to adapt to
+pub fn enabled_features(config: &Config) -> Vec<String> {
+    let features = [
+        ("shorts", config.allow_shorts),
+        ("subscriptions", config.broadcast_strategy == BroadcastStrategy::Subscribers),
+        ("pnl_history", config.pnl_history_cap > 0),
+        ("trade_history", config.trade_history_cap > 0),
+    ];
+    features.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| name.to_string()).collect()
+}
+
+// Every public post, as sent to a client when it connects
+pub fn build_initial_state(state: &AppState) -> ServerMessage {
+    let posts = state
+        .posts
+        .iter()
+        .filter(|entry| entry.value().visibility == PostVisibility::Public)
+        .map(|entry| entry.value().clone())
+        .collect();
+    ServerMessage::InitialState { posts }
+}
+
+// Serves one client over `ws`: a warp WebSocket in production, or any other stream/sink
+// of frames (see test_transport.rs for the in-memory one tests drive it with)
+pub async fn handle_connection<W>(ws: W, user_id: String, token_exp: usize, state: AppState)
+where
+    W: Stream<Item = Result<Message, warp::Error>> + Sink<Message> + Send + 'static,
+    <W as Sink<Message>>::Error: std::fmt::Display,
+{
     let client_id = Uuid::new_v4();
     println!(
         "New WebSocket connection: client_id={}, user_id={}",
@@ -181,132 +470,98 @@ pub async fn handle_connection(ws: WebSocket, user_id: String, state: AppState)
     let (client_sender, client_rcv) = mpsc::unbounded_channel();
     let client_rcv_stream = UnboundedReceiverStream::new(client_rcv);
 
-    state.user_balances.entry(user_id.clone()).or_insert(INITIAL_BALANCE);
-    state.user_realized_pnl.entry(user_id.clone()).or_insert(0.0);
-    state.user_exposure.entry(user_id.clone()).or_insert(0.0);
+    let account = ensure_user_state_exists(&user_id, &state);
 
-    state.clients.insert(
+    let client = Client::new(user_id.clone(), client_sender);
+    let client_sender = client.sender.clone(); // Counted, like every other send to the client
+    let mut stats = SessionStats {
         client_id,
-        Client {
-            user_id: user_id.clone(),
-            sender: client_sender.clone(),
-        },
-    );
+        user_id: user_id.clone(),
+        started: Instant::now(),
+        messages_received: 0,
+        trades_executed: 0,
+        bytes_sent: client.sender.bytes_sent(),
+        state: state.clone(),
+    };
+    state.clients.insert(client_id, client);
+    state.metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+
+    // --- WebSocket Task Setup ---
+    // Started before the initial snapshot so close frames queued on failure are delivered
+    let (ws_sender, mut ws_receiver) = ws.split();
+
+    // Task to forward messages from MPSC channel to WebSocket sink. If the socket wedges,
+    // it tells the main loop below to stop reading from it.
+    let (wedged_sender, mut wedged) = oneshot::channel::<()>();
+    let forwarder_state = state.clone();
+    tokio::spawn(async move {
+        if run_forwarder(client_id, ws_sender, client_rcv_stream, &forwarder_state).await == ForwarderExit::SendTimedOut {
+            let _ = wedged_sender.send(());
+        }
+    });
+
+    // Strict mode: a valid token alone doesn't create an account
+    if let Err(e) = account {
+        eprintln!("Rejecting connection client_id={}: {}", client_id, e);
+        send_to_client(client_id, e.into(), &state).await;
+        disconnect_client(client_id, CLOSE_POLICY_VIOLATION, "Unknown user", &state);
+        state.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+        return;
+    }
+
+    // --- Send Welcome (Server Capabilities) ---
+    let welcome = ServerMessage::Welcome {
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        features: enabled_features(&state.config),
+    };
+    send_to_client(client_id, welcome, &state).await;
 
     // --- Send InitialState (Global Posts) --- 
-    let current_posts = state
-        .posts
-        .iter()
-        .map(|entry| {
-            let mut post = entry.value().clone();
-            post.price = Some(get_price(post.supply)); // Ensure price is current
-            post
-        })
-        .collect();
-    let initial_state_msg = ServerMessage::InitialState { posts: current_posts };
-    if client_sender.send(Ok(Message::text(serde_json::to_string(&initial_state_msg).unwrap()))).is_err() {
+    let initial_state_msg = build_initial_state(&state);
+    let (_, initial_state_json) = encode_or_fallback(initial_state_msg);
+    if client_sender.send(Ok(Message::text(initial_state_json))).is_err() {
          eprintln!("Failed initial send (InitialState) to client_id={}", client_id);
          state.clients.remove(&client_id);
+         state.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
          return;
     }
     println!("Sent InitialState to client_id={}", client_id);
 
     // --- Send UserSync (Balance, Exposure, Equity, PnL, Positions) ---
-    let user_balance = *state.user_balances.get(&user_id).unwrap().value();
-    let total_realized_pnl = *state.user_realized_pnl.get(&user_id).unwrap().value();
-    let user_exposure = *state.user_exposure.get(&user_id).unwrap().value();
-    let total_unrealized_pnl = calculate_total_unrealized_pnl(&user_id, &state);
-    let user_equity = user_balance + total_realized_pnl + total_unrealized_pnl;
-
-    let user_positions_detail: Vec<PositionDetail> = state
-        .user_positions
-        .get(&user_id)
-        .map(|positions_map| {
-            positions_map
-                .iter()
-                .filter_map(|pos_entry| {
-                    let post_id = *pos_entry.key();
-                    let position = pos_entry.value();
-
-                    if position.size.abs() <= EPSILON {
-                         return None; 
-                    }
-
-                    state.posts.get(&post_id).map(|market_post| {
-                        let current_market_price = market_post.price.unwrap_or_else(|| get_price(market_post.supply));
-                        let avg_price = calculate_average_price(position);
-                        let unrealized_pnl = calculate_unrealized_pnl(position, current_market_price);
-                        
-                        // Calculate liquidation price here too
-                        let liquidation_price = calculate_liquidation_price(
-                            user_balance, // Use fetched balance
-                            total_realized_pnl, // Use fetched rpnl
-                            position.size, 
-                            avg_price
-                        );
-
-                        PositionDetail {
-                            post_id,
-                            size: position.size,
-                            average_price: avg_price.abs(),
-                            unrealized_pnl: unrealized_pnl,
-                            liquidation_price, // Add field
-                        }
-                    })
-                })
-                .collect()
-        })
-        .unwrap_or_else(Vec::new);
-
-    let user_sync_msg = ServerMessage::UserSync {
-        balance: user_balance,
-        exposure: user_exposure,
-        equity: user_equity,
-        positions: user_positions_detail,
-        total_realized_pnl,
-    };
-     if client_sender.send(Ok(Message::text(serde_json::to_string(&user_sync_msg).unwrap()))).is_err() {
+    // Built the same way as the post-trade syncs so both report positions identically
+    let user_sync_msg = build_user_sync(&user_id, &snapshot_prices(&[&user_id], &state), &state);
+    let (_, user_sync_json) = encode_or_fallback(user_sync_msg);
+     if client_sender.send(Ok(Message::text(user_sync_json))).is_err() {
          eprintln!("Failed initial send (UserSync) to client_id={}", client_id);
          state.clients.remove(&client_id);
+         state.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
          return;
     }
-     println!("Sent UserSync to client_id={} (Bal: {:.4}, RPnl: {:.4}, Exp: {:.4}, Equity: {:.4})",
-        client_id, user_balance, total_realized_pnl, user_exposure, user_equity);
-
-    // --- WebSocket Task Setup ---
-    let (ws_sender, mut ws_receiver) = ws.split();
-
-    // Task to forward messages from MPSC channel to WebSocket sink
-    tokio::spawn(async move {
-       let task_client_id = client_id;
-       let mut ws_sender = ws_sender;
-       let mut client_rcv_stream = client_rcv_stream;
-       while let Some(message_result) = client_rcv_stream.next().await {
-            match message_result {
-                Ok(msg) => {
-                    if ws_sender.send(msg).await.is_err() {
-                        eprintln!(
-                            "Error sending message via MPSC->WS forwarder task for client {}",
-                            task_client_id
-                        );
-                        break; // Exit loop on send error
-                    }
-                }
-                Err(e) => {
-                    eprintln!(
-                        "Error receiving message in MPSC->WS forwarder task for client {}: {}",
-                        task_client_id, e
-                    );
-                    // Optionally break or continue depending on desired error handling
-                }
-            }
-        }
-       
-        println!("MPSC->WS forwarder task finished for client {}", task_client_id);
-    });
+     println!("Sent UserSync to client_id={}", client_id);
 
     // --- Main Message Loop ---
-    while let Some(result) = ws_receiver.next().await {
+    // Runs until the client goes away, its token expires mid-session or it sends faster
+    // than the rate limit
+    let mut rate_limit = RateLimit::new(state.config.max_messages_per_sec);
+    let token_expiry = tokio::time::sleep(time_until_expiry(token_exp));
+    tokio::pin!(token_expiry);
+    loop {
+        let result = tokio::select! {
+            next = ws_receiver.next() => match next {
+                Some(result) => result,
+                None => break,
+            },
+            Ok(()) = &mut wedged => {
+                println!("Socket wedged for client_id={}, user_id={}", client_id, &user_id);
+                break;
+            }
+            _ = &mut token_expiry => {
+                println!("Token expired for client_id={}, user_id={}", client_id, &user_id);
+                disconnect_client(client_id, CLOSE_POLICY_VIOLATION, "Token expired", &state);
+                break;
+            }
+        };
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
@@ -318,11 +573,17 @@ pub async fn handle_connection(ws: WebSocket, user_id: String, state: AppState)
             }
         };
 
+        stats.messages_received += 1;
+        if !rate_limit.allow() {
+            println!("Rate limit exceeded for client_id={}, user_id={}", client_id, &user_id);
+            disconnect_client(client_id, CLOSE_POLICY_VIOLATION, "Rate limit exceeded", &state);
+            break;
+        }
         println!(
             "handle_connection for client_id={}: Received msg: {:?}. Calling handle_client_message...",
             client_id, msg
         );
-        handle_client_message(client_id, &user_id, msg, &state).await;
+        stats.trades_executed += handle_client_message(client_id, &user_id, msg, &state).await as u64;
         println!(
             "handle_connection for client_id={}: Returned from handle_client_message.",
             client_id
@@ -335,4 +596,304 @@ pub async fn handle_connection(ws: WebSocket, user_id: String, state: AppState)
         client_id, &user_id
     );
     state.clients.remove(&client_id);
-} 
\ No newline at end of file
+    state.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::handlers::execute_trade;
+    use crate::test_transport::TOKEN_EXP;
+    use warp::Filter;
+
+    fn active_connections(state: &AppState) -> usize {
+        state.metrics.active_connections.load(Ordering::Relaxed)
+    }
+
+    // Wait for handle_connection tasks to notice dropped sockets
+    async fn wait_for_active_connections(state: &AppState, expected: usize) {
+        for _ in 0..200 {
+            if active_connections(state) == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("active_connections stuck at {}, expected {}", active_connections(state), expected);
+    }
+
+    // WebSocket route that connects every handshake as `user_id`
+    fn ws_route(user_id: &'static str, state: &AppState) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let route_state = state.clone();
+        warp::ws().map(move |ws: warp::ws::Ws| {
+            let state = route_state.clone();
+            ws.on_upgrade(move |websocket| handle_connection(websocket, user_id.to_string(), TOKEN_EXP, state))
+        })
+    }
+
+    async fn recv_json(client: &mut warp::test::WsClient) -> serde_json::Value {
+        let message = client.recv().await.expect("a message");
+        serde_json::from_str(message.to_str().expect("a text message")).expect("valid JSON")
+    }
+
+    #[tokio::test]
+    async fn active_connections_returns_to_zero_after_clients_drop() {
+        let state = AppState::new_for_test();
+        let route = ws_route("alice", &state);
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = warp::test::ws().handshake(route.clone()).await.expect("handshake");
+            client.recv().await.expect("welcome");
+            client.recv().await.expect("initial state");
+            client.recv().await.expect("user sync");
+            clients.push(client);
+        }
+        assert_eq!(active_connections(&state), 3);
+        assert_eq!(state.clients.len(), 3);
+
+        clients.pop();
+        wait_for_active_connections(&state, 2).await;
+
+        clients.clear();
+        wait_for_active_connections(&state, 0).await;
+        assert!(state.clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn short_average_price_agrees_between_connect_and_trade_syncs() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_user("bob", 1000.0)
+            .with_post(post_id, "alice", -2.0)
+            .with_position("alice", post_id, -2.0, -3.0); // Short 2 at 1.5
+        let mut client = warp::test::ws().handshake(ws_route("alice", &state)).await.expect("handshake");
+        recv_json(&mut client).await; // Welcome
+        recv_json(&mut client).await; // InitialState
+        let connect_sync = recv_json(&mut client).await;
+
+        execute_trade(Uuid::new_v4(), "bob", post_id, 1.0, false, &state).await.unwrap();
+        let mut trade_sync = recv_json(&mut client).await;
+        while trade_sync["type"] != "user_sync" {
+            trade_sync = recv_json(&mut client).await;
+        }
+
+        assert_eq!(connect_sync["type"], "user_sync");
+        assert_eq!(connect_sync["positions"][0]["average_price"], 1.5);
+        assert_eq!(trade_sync["positions"][0]["average_price"], connect_sync["positions"][0]["average_price"]);
+        assert_eq!(trade_sync["positions"][0]["size"], -2.0);
+    }
+
+    #[tokio::test]
+    async fn a_nan_position_gets_the_fallback_error_instead_of_a_user_sync() {
+        let post_id = Uuid::new_v4();
+        let state = AppState::new_for_test()
+            .with_user("alice", 1000.0)
+            .with_post(post_id, "bob", 0.0)
+            .with_position("alice", post_id, 2.0, f64::NAN);
+        let mut client = warp::test::ws().handshake(ws_route("alice", &state)).await.expect("handshake");
+        recv_json(&mut client).await; // Welcome
+        recv_json(&mut client).await; // InitialState
+
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["message"], STATE_UNAVAILABLE);
+
+        // The same goes for replies to requests
+        client.send(Message::text(serde_json::json!({ "type": "get_positions" }).to_string())).await;
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["message"], STATE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn initial_state_leaves_out_unlisted_posts() {
+        let (public_id, unlisted_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = AppState::new_for_test()
+            .with_post(public_id, "alice", 0.0)
+            .with_post(unlisted_id, "alice", 0.0);
+        state.posts.get_mut(&unlisted_id).unwrap().visibility = PostVisibility::Unlisted;
+
+        let mut client = warp::test::ws().handshake(ws_route("bob", &state)).await.expect("handshake");
+        recv_json(&mut client).await; // Welcome
+        let initial_state = recv_json(&mut client).await;
+
+        assert_eq!(initial_state["type"], "initial_state");
+        let posts = initial_state["posts"].as_array().unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0]["id"], public_id.to_string());
+    }
+
+    #[tokio::test]
+    async fn welcome_comes_first_and_lists_the_enabled_features() {
+        let state = AppState::new_for_test().with_config(Config {
+            allow_shorts: false,
+            broadcast_strategy: BroadcastStrategy::Subscribers,
+            pnl_history_cap: 0,
+            ..Config::default()
+        });
+
+        let mut client = warp::test::ws().handshake(ws_route("alice", &state)).await.expect("handshake");
+        let welcome = recv_json(&mut client).await;
+
+        assert_eq!(welcome["type"], "welcome");
+        assert_eq!(welcome["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(welcome["server_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(welcome["features"], serde_json::json!(["subscriptions", "trade_history"]));
+        assert_eq!(recv_json(&mut client).await["type"], "initial_state");
+    }
+
+    // A sink whose sends never complete, like a socket whose peer vanished
+    struct WedgedSink;
+
+    impl Sink<Message> for WedgedSink {
+        type Error = warp::Error;
+
+        fn poll_ready(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Pending
+        }
+
+        fn start_send(self: std::pin::Pin<&mut Self>, _: Message) -> Result<(), Self::Error> {
+            unreachable!("never ready")
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_close(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn wedged_socket_times_out_and_drops_client() {
+        let state = AppState::new_for_test().with_config(Config { ws_send_timeout_ms: 20, ..Config::default() });
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let client_id = Uuid::new_v4();
+        state.clients.insert(client_id, Client::new("alice", sender.clone()));
+        sender.send(Ok(Message::text("hello"))).unwrap();
+
+        let exit = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_forwarder(client_id, WedgedSink, UnboundedReceiverStream::new(receiver), &state),
+        ).await.expect("forwarder gave up on the wedged socket");
+
+        assert_eq!(exit, ForwarderExit::SendTimedOut);
+        assert!(!state.clients.contains_key(&client_id));
+    }
+
+    #[tokio::test]
+    async fn post_trade_syncs_for_many_users_are_not_paced_per_user() {
+        let post_id = Uuid::new_v4();
+        let user_count = 400;
+        let mut state = AppState::new_for_test().with_post(post_id, "creator", 0.0);
+        let mut receivers = Vec::new();
+        let mut affected = HashSet::new();
+        for i in 0..user_count {
+            let user_id = format!("user{}", i);
+            state = state.with_user(&user_id, 1000.0).with_position(&user_id, post_id, 1.0, 1.5);
+            let (sender, receiver) = mpsc::unbounded_channel();
+            state.clients.insert(Uuid::new_v4(), Client::new(user_id.clone(), sender));
+            receivers.push(receiver);
+            affected.insert(user_id);
+        }
+
+        let started = std::time::Instant::now();
+        send_post_trade_syncs(post_id, Uuid::nil(), &affected, &state).await;
+        let elapsed = started.elapsed();
+
+        // A per-user pause of even 1ms would take at least user_count ms
+        assert!(elapsed < Duration::from_millis(user_count as u64 / 2), "fan-out to {} users took {:?}", user_count, elapsed);
+        for receiver in &mut receivers {
+            let mut types = Vec::new();
+            while let Ok(Ok(message)) = receiver.try_recv() {
+                let json: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+                types.push(json["type"].as_str().unwrap().to_string());
+            }
+            assert_eq!(types, ["user_sync", "portfolio_summary", "equity_update"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_reports_the_session_totals() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0);
+        let mut alice = crate::test_transport::TestClient::connect("alice", &state);
+        alice.send(serde_json::json!({ "type": "create_post", "content": "stats" }));
+        let post_id = alice.recv_type("new_post").await["post"]["id"].clone();
+        alice.send(serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 3.0 }));
+        alice.recv_type("trade_confirmation").await;
+        alice.send(serde_json::json!({ "type": "sell", "post_id": post_id, "quantity": 1.0 }));
+        alice.recv_type("trade_confirmation").await;
+        alice.send(serde_json::json!({ "type": "batch_trade", "trades": [
+            { "post_id": post_id, "side": "buy", "quantity": 1.0 },
+            { "post_id": post_id, "side": "sell", "quantity": 0.5 },
+        ] }));
+        alice.recv_type("batch_result").await;
+        alice.send(serde_json::json!({ "type": "get_positions" }));
+        alice.recv_type("positions").await;
+        let bytes_received = alice.bytes_received;
+
+        drop(alice);
+        wait_for_active_connections(&state, 0).await;
+
+        let metrics = &state.metrics;
+        assert_eq!(metrics.connection_duration.count(), 1, "reported once");
+        assert_eq!(metrics.connection_messages_received.load(Ordering::Relaxed), 5);
+        assert_eq!(metrics.connection_trades_executed.load(Ordering::Relaxed), 4, "two trades and two batch legs");
+        assert_eq!(metrics.connection_bytes_sent.load(Ordering::Relaxed), bytes_received);
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_trade_confirmation_is_resent_until_acked() {
+        let config = Config { ack_timeout_ms: 20, ack_max_retries: 3, ..Config::default() };
+        let state = AppState::new_for_test().with_config(config).with_user("alice", 1000.0);
+        let mut alice = crate::test_transport::TestClient::connect("alice", &state);
+        alice.send(serde_json::json!({ "type": "create_post", "content": "acks" }));
+        let new_post = alice.recv_type("new_post").await;
+        assert!(new_post.get("ack_id").is_none(), "only critical messages are stamped");
+        let post_id = new_post["post"]["id"].clone();
+        alice.send(serde_json::json!({ "type": "buy", "post_id": post_id, "quantity": 2.0 }));
+
+        // The first delivery is lost: the client drops it unread and never acks it
+        let dropped = alice.recv_type("trade_confirmation").await;
+        let ack_id = dropped["ack_id"].as_u64().expect("critical messages carry an ack_id");
+
+        let resent = alice.recv_type("trade_confirmation").await;
+        assert_eq!(resent, dropped, "the same message, ack_id included");
+        alice.send(serde_json::json!({ "type": "ack", "id": ack_id }));
+        for _ in 0..200 {
+            if state.clients.iter().all(|client| client.acks.is_empty()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("ack_id {} still pending after the Ack", ack_id);
+    }
+
+    #[tokio::test]
+    async fn an_expired_token_closes_with_a_policy_violation() {
+        let state = AppState::new_for_test().with_user("alice", 1000.0);
+        let expired = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as usize - 1;
+        let mut alice = crate::test_transport::TestClient::connect_until("alice", expired, &state);
+
+        assert_eq!(alice.recv_close().await, (CLOSE_POLICY_VIOLATION, "Token expired".to_string()));
+        wait_for_active_connections(&state, 0).await;
+    }
+
+    #[tokio::test]
+    async fn a_client_over_the_rate_limit_is_closed_with_a_policy_violation() {
+        let config = Config { max_messages_per_sec: 3, ..Config::default() };
+        let state = AppState::new_for_test().with_config(config).with_user("alice", 1000.0);
+        let mut alice = crate::test_transport::TestClient::connect("alice", &state);
+        for _ in 0..3 {
+            alice.send(serde_json::json!({ "type": "get_portfolio_summary" }));
+            alice.recv_type("portfolio_summary").await;
+        }
+
+        alice.send(serde_json::json!({ "type": "get_portfolio_summary" }));
+
+        assert_eq!(alice.recv_close().await, (CLOSE_POLICY_VIOLATION, "Rate limit exceeded".to_string()));
+        wait_for_active_connections(&state, 0).await;
+    }
+}
@@ -0,0 +1,295 @@
+use serde::ser::{self, Serialize};
+use std::fmt;
+
+use super::models::ServerMessage;
+
+// --- Wire Encoding ---
+//
+// serde_json writes a non-finite float as `null` without complaint, so a NaN or infinity
+// leaking out of a calculation would reach clients as a null money field. Messages are
+// encoded through here instead. An infinity is clamped to the largest finite value of the
+// same sign, which still says which way the number ran off. A NaN has no finite stand-in
+// that wouldn't be a wrong number, so a message holding one is refused and the caller
+// sends a fallback error (see websocket::encode_or_fallback).
+
+// A non-finite float found in a message, at a JSON pointer into its encoding
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonFiniteField {
+    pub pointer: String,
+    pub value: f64,
+}
+
+// Encode a message as JSON text with every float finite. Err describes why it couldn't be.
+pub fn encode(message: &ServerMessage) -> Result<String, String> {
+    let found = non_finite_fields(message)?;
+    if found.is_empty() {
+        return serde_json::to_string(message).map_err(|e| e.to_string());
+    }
+    if let Some(field) = found.iter().find(|field| field.value.is_nan()) {
+        return Err(format!("NaN at {}", field.pointer));
+    }
+    let mut json = serde_json::to_value(message).map_err(|e| e.to_string())?;
+    for field in &found {
+        let clamped = if field.value > 0.0 { f64::MAX } else { f64::MIN };
+        tracing::warn!(pointer = %field.pointer, value = field.value, clamped, "wire::encode: clamped a non-finite float");
+        *json.pointer_mut(&field.pointer).ok_or_else(|| format!("no field at {}", field.pointer))? = clamped.into();
+    }
+    Ok(json.to_string())
+}
+
+// Every non-finite float in a value, in serialization order
+pub fn non_finite_fields<T: Serialize + ?Sized>(value: &T) -> Result<Vec<NonFiniteField>, String> {
+    let mut found = Vec::new();
+    value.serialize(Scanner { pointer: String::new(), found: &mut found }).map_err(|e| e.0)?;
+    Ok(found)
+}
+
+// JSON pointer (RFC 6901) of `key` under `parent`
+fn child_pointer(parent: &str, key: &str) -> String {
+    format!("{}/{}", parent, key.replace('~', "~0").replace('/', "~1"))
+}
+
+#[derive(Debug)]
+struct ScanError(String);
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+impl ser::Error for ScanError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ScanError(msg.to_string())
+    }
+}
+
+// Walks a value the way serde_json would encode it, recording the non-finite floats and
+// discarding everything else
+struct Scanner<'a> {
+    pointer: String,
+    found: &'a mut Vec<NonFiniteField>,
+}
+
+// Compound values: tracks the pointer of the next element, field or map entry
+struct ScanCompound<'a> {
+    pointer: String,
+    found: &'a mut Vec<NonFiniteField>,
+    index: usize,
+    key: Option<String>,
+}
+
+impl<'a> ScanCompound<'a> {
+    fn new(pointer: String, found: &'a mut Vec<NonFiniteField>) -> Self {
+        ScanCompound { pointer, found, index: 0, key: None }
+    }
+
+    fn scan<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), ScanError> {
+        value.serialize(Scanner { pointer: child_pointer(&self.pointer, key), found: self.found })
+    }
+
+    fn scan_next<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ScanError> {
+        let index = self.index.to_string();
+        self.index += 1;
+        self.scan(&index, value)
+    }
+}
+
+impl<'a> ser::Serializer for Scanner<'a> {
+    type Ok = ();
+    type Error = ScanError;
+    type SerializeSeq = ScanCompound<'a>;
+    type SerializeTuple = ScanCompound<'a>;
+    type SerializeTupleStruct = ScanCompound<'a>;
+    type SerializeTupleVariant = ScanCompound<'a>;
+    type SerializeMap = ScanCompound<'a>;
+    type SerializeStruct = ScanCompound<'a>;
+    type SerializeStructVariant = ScanCompound<'a>;
+
+    fn serialize_f64(self, v: f64) -> Result<(), ScanError> {
+        if !v.is_finite() {
+            self.found.push(NonFiniteField { pointer: self.pointer, value: v });
+        }
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), ScanError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_i8(self, _: i8) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_i16(self, _: i16) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_i32(self, _: i32) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_i64(self, _: i64) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_u8(self, _: u8) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_u16(self, _: u16) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_u32(self, _: u32) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_u64(self, _: u64) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_char(self, _: char) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_str(self, _: &str) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_bytes(self, _: &[u8]) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_none(self) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_unit(self) -> Result<(), ScanError> { Ok(()) }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), ScanError> { Ok(()) }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), ScanError> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), ScanError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), ScanError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, variant: &'static str, value: &T) -> Result<(), ScanError> {
+        ScanCompound::new(self.pointer, self.found).scan(variant, value)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<ScanCompound<'a>, ScanError> {
+        Ok(ScanCompound::new(self.pointer, self.found))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<ScanCompound<'a>, ScanError> {
+        Ok(ScanCompound::new(self.pointer, self.found))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<ScanCompound<'a>, ScanError> {
+        Ok(ScanCompound::new(self.pointer, self.found))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<ScanCompound<'a>, ScanError> {
+        Ok(ScanCompound::new(child_pointer(&self.pointer, variant), self.found))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<ScanCompound<'a>, ScanError> {
+        Ok(ScanCompound::new(self.pointer, self.found))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<ScanCompound<'a>, ScanError> {
+        Ok(ScanCompound::new(self.pointer, self.found))
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<ScanCompound<'a>, ScanError> {
+        Ok(ScanCompound::new(child_pointer(&self.pointer, variant), self.found))
+    }
+}
+
+impl ser::SerializeSeq for ScanCompound<'_> {
+    type Ok = ();
+    type Error = ScanError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ScanError> {
+        self.scan_next(value)
+    }
+
+    fn end(self) -> Result<(), ScanError> { Ok(()) }
+}
+
+impl ser::SerializeTuple for ScanCompound<'_> {
+    type Ok = ();
+    type Error = ScanError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ScanError> {
+        self.scan_next(value)
+    }
+
+    fn end(self) -> Result<(), ScanError> { Ok(()) }
+}
+
+impl ser::SerializeTupleStruct for ScanCompound<'_> {
+    type Ok = ();
+    type Error = ScanError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ScanError> {
+        self.scan_next(value)
+    }
+
+    fn end(self) -> Result<(), ScanError> { Ok(()) }
+}
+
+impl ser::SerializeTupleVariant for ScanCompound<'_> {
+    type Ok = ();
+    type Error = ScanError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ScanError> {
+        self.scan_next(value)
+    }
+
+    fn end(self) -> Result<(), ScanError> { Ok(()) }
+}
+
+impl ser::SerializeMap for ScanCompound<'_> {
+    type Ok = ();
+    type Error = ScanError;
+
+    // serde_json writes map keys as strings, so take the key's JSON form without quotes
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ScanError> {
+        let key = match serde_json::to_value(key).map_err(ser::Error::custom)? {
+            serde_json::Value::String(key) => key,
+            other => other.to_string(),
+        };
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ScanError> {
+        let key = self.key.take().ok_or_else(|| ScanError("map value without a key".to_string()))?;
+        self.scan(&key, value)
+    }
+
+    fn end(self) -> Result<(), ScanError> { Ok(()) }
+}
+
+impl ser::SerializeStruct for ScanCompound<'_> {
+    type Ok = ();
+    type Error = ScanError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), ScanError> {
+        self.scan(key, value)
+    }
+
+    fn end(self) -> Result<(), ScanError> { Ok(()) }
+}
+
+impl ser::SerializeStructVariant for ScanCompound<'_> {
+    type Ok = ();
+    type Error = ScanError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), ScanError> {
+        self.scan(key, value)
+    }
+
+    fn end(self) -> Result<(), ScanError> { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::PositionDetail;
+    use uuid::Uuid;
+
+    #[test]
+    fn finite_messages_encode_as_serde_json_does() {
+        let message = ServerMessage::MarketUpdate { post_id: Uuid::nil(), price: 1.5, supply: 0.25 };
+        assert_eq!(encode(&message).unwrap(), serde_json::to_string(&message).unwrap());
+    }
+
+    #[test]
+    fn infinities_are_clamped_and_nan_is_refused() {
+        let position = |unrealized_pnl| PositionDetail { post_id: Uuid::nil(), size: 1.0, average_price: 1.0, unrealized_pnl, liquidation_price: None };
+        let positions = |pnl| ServerMessage::Positions { positions: vec![position(0.0), position(pnl)], offset: 0, total: 2, has_more: false };
+
+        let found = non_finite_fields(&positions(f64::NEG_INFINITY)).unwrap();
+        assert_eq!(found, vec![NonFiniteField { pointer: "/positions/1/unrealized_pnl".to_string(), value: f64::NEG_INFINITY }]);
+        let clamped: serde_json::Value = serde_json::from_str(&encode(&positions(f64::NEG_INFINITY)).unwrap()).unwrap();
+        assert_eq!(clamped["positions"][1]["unrealized_pnl"], f64::MIN);
+
+        assert_eq!(encode(&positions(f64::NAN)), Err("NaN at /positions/1/unrealized_pnl".to_string()));
+    }
+}